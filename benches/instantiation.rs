@@ -0,0 +1,82 @@
+//! Measures how long it takes to handle a request with a module that does
+//! nothing but write its headers, so that the cost measured is setting up and
+//! instantiating the module. Most modules are linked once, when they are
+//! loaded; modules that import the outbound HTTP API are linked afresh for
+//! each request. The two modules here differ only in that import, so the
+//! difference between them is the cost of linking on every request.
+
+use std::{fmt::Write, net::SocketAddr, path::Path};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use hyper::{Body, Method, Request};
+use wagi::{dispatcher::RoutingTable, wagi_app};
+
+fn module_wat(http_import: bool) -> String {
+    let http_import = if http_import {
+        r#"(import "wasi_experimental_http" "close" (func (param i32) (result i32)))"#
+    } else {
+        ""
+    };
+    format!(
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            {}
+            (memory (export "memory") 1)
+            (data (i32.const 16) "Content-Type: text/plain\n\n")
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 26))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        http_import
+    )
+}
+
+fn build_routing_table(dir: &Path) -> RoutingTable {
+    let mut modules_toml = String::new();
+    for (route, http_import) in [("/prelinked", false), ("/linked-per-request", true)] {
+        let module_path = dir.join(format!("{}.wat", &route[1..]));
+        std::fs::write(&module_path, module_wat(http_import)).unwrap();
+        writeln!(modules_toml, "[[module]]\nroute = \"{}\"\nmodule = {:?}\n", route, module_path).unwrap();
+    }
+    let modules_toml_path = dir.join("modules.toml");
+    std::fs::write(&modules_toml_path, modules_toml).unwrap();
+
+    let matches = wagi_app::wagi_app_definition().get_matches_from(vec![
+        "wagi",
+        "-c", &modules_toml_path.display().to_string(),
+        "--module-cache", &dir.join("cache").display().to_string(),
+        "--log-dir", &dir.join("logs").display().to_string(),
+    ]);
+    let configuration = wagi_app::parse_configuration_from(matches).unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let handlers = rt.block_on(wagi::handler_loader::load_handlers(&configuration)).unwrap();
+    RoutingTable::build(&handlers, configuration.request_global_context()).unwrap()
+}
+
+fn dispatch(rt: &tokio::runtime::Runtime, table: &RoutingTable, path: &str) {
+    let client_addr: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://localhost:3000{}", path))
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(async {
+        let response = table.handle_request(request, client_addr).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    });
+    criterion::black_box(response);
+}
+
+fn instantiation_benchmarks(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let table = build_routing_table(dir.path());
+
+    let mut group = c.benchmark_group("instantiation");
+    group.bench_function("prelinked", |b| b.iter(|| dispatch(&rt, &table, "/prelinked")));
+    group.bench_function("linked_per_request", |b| b.iter(|| dispatch(&rt, &table, "/linked-per-request")));
+    group.finish();
+}
+
+criterion_group!(benches, instantiation_benchmarks);
+criterion_main!(benches);
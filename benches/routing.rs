@@ -0,0 +1,164 @@
+//! Measures routing when there are many routes.
+//!
+//! The `route_lookup` groups measure finding the route for a path, which is
+//! what the route tree changed: once with the tree, and once by testing every
+//! route in turn, as the routing table did before it had the tree. Both choose
+//! among the matches in the same way, so the difference is the tree.
+//!
+//! The `dispatch` group measures whole requests through the routing table. The
+//! requests used are all answered without running a Wasm module (the built-in
+//! health check, 404 and 405), but building the request and response is still
+//! most of the cost.
+
+use std::{fmt::Write, net::SocketAddr, path::Path};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use hyper::{Body, Method, Request};
+use wagi::{
+    dispatcher::{RoutePattern, RoutingTable},
+    route_tree::RouteTree,
+    wagi_app,
+};
+
+const MODULE_COUNT: usize = 20;
+const DYNAMIC_ROUTES_PER_MODULE: [usize; 3] = [5, 50, 250];
+
+/// A module whose `_routes` function declares `route_count` routes.
+fn dynamic_routes_wat(route_count: usize) -> String {
+    let mut routes = String::new();
+    for i in 0..route_count {
+        writeln!(routes, "/items/{}/... _start", i).unwrap();
+        writeln!(routes, "/items/{}/detail _start", i).unwrap();
+    }
+    format!(
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 2)
+            (data (i32.const 16) "{}")
+            (func (export "_start"))
+            (func (export "_routes")
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const {}))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        routes.replace('\n', "\\n"),
+        routes.len()
+    )
+}
+
+fn build_routing_table(dir: &Path, routes_per_module: usize) -> RoutingTable {
+    let module_path = dir.join(format!("routes-{}.wat", routes_per_module));
+    std::fs::write(&module_path, dynamic_routes_wat(routes_per_module)).unwrap();
+
+    let mut modules_toml = String::new();
+    for i in 0..MODULE_COUNT {
+        writeln!(modules_toml, "[[module]]\nroute = \"/app{}/...\"\nmodule = {:?}\nmethods = [\"GET\"]\n", i, module_path).unwrap();
+    }
+    let modules_toml_path = dir.join(format!("modules-{}.toml", routes_per_module));
+    std::fs::write(&modules_toml_path, modules_toml).unwrap();
+
+    let matches = wagi_app::wagi_app_definition().get_matches_from(vec![
+        "wagi",
+        "-c", &modules_toml_path.display().to_string(),
+        "--module-cache", &dir.join("cache").display().to_string(),
+    ]);
+    let configuration = wagi_app::parse_configuration_from(matches).unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let handlers = rt.block_on(wagi::handler_loader::load_handlers(&configuration)).unwrap();
+    RoutingTable::build(&handlers, configuration.request_global_context()).unwrap()
+}
+
+fn dispatch(rt: &tokio::runtime::Runtime, table: &RoutingTable, method: Method, path: &str) {
+    let client_addr: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://localhost:3000{}", path))
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(table.handle_request(request, client_addr)).unwrap();
+    criterion::black_box(response);
+}
+
+// The patterns of the table's routes, in the table's order
+fn route_patterns(table: &RoutingTable) -> Vec<RoutePattern> {
+    table.describe_routes().routes.iter()
+        .filter(|r| r.role == "route")
+        .map(|r| RoutePattern::parse(&r.route).unwrap())
+        .collect()
+}
+
+fn build_route_tree(patterns: &[RoutePattern]) -> RouteTree {
+    let mut tree = RouteTree::default();
+    for (index, pattern) in patterns.iter().enumerate() {
+        let (segments, is_prefix) = pattern.tree_segments();
+        tree.insert(&segments, is_prefix, index);
+    }
+    tree
+}
+
+fn lookup_by_tree<'a>(tree: &RouteTree, patterns: &'a [RoutePattern], path: &str) -> Option<&'a RoutePattern> {
+    tree.matches(path).into_iter()
+        .map(|index| &patterns[index])
+        .max_by_key(|p| p.specificity())
+}
+
+fn lookup_by_scan<'a>(patterns: &'a [RoutePattern], path: &str) -> Option<&'a RoutePattern> {
+    patterns.iter()
+        .filter(|p| p.is_match(path))
+        .max_by_key(|p| p.specificity())
+}
+
+fn lookup_benchmarks(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let tables: Vec<_> = DYNAMIC_ROUTES_PER_MODULE.iter()
+        .map(|routes_per_module| (routes_per_module, build_routing_table(dir.path(), *routes_per_module)))
+        .collect();
+
+    for (name, matched) in [("matched", true), ("unmatched", false)] {
+        let mut group = c.benchmark_group(format!("route_lookup/{}", name));
+        for (routes_per_module, table) in &tables {
+            let patterns = route_patterns(table);
+            let tree = build_route_tree(&patterns);
+            let path = if matched {
+                format!("/app{}/items/{}/detail", MODULE_COUNT - 1, *routes_per_module - 1)
+            } else {
+                "/no/such/route".to_owned()
+            };
+            assert_eq!(matched, lookup_by_tree(&tree, &patterns, &path).is_some());
+            assert_eq!(lookup_by_scan(&patterns, &path), lookup_by_tree(&tree, &patterns, &path));
+
+            group.bench_with_input(BenchmarkId::new("route_tree", patterns.len()), &path, |b, path| {
+                b.iter(|| criterion::black_box(lookup_by_tree(&tree, &patterns, path)))
+            });
+            group.bench_with_input(BenchmarkId::new("linear_scan", patterns.len()), &path, |b, path| {
+                b.iter(|| criterion::black_box(lookup_by_scan(&patterns, path)))
+            });
+        }
+        group.finish();
+    }
+}
+
+fn dispatch_benchmarks(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("dispatch");
+    for routes_per_module in DYNAMIC_ROUTES_PER_MODULE {
+        let table = build_routing_table(dir.path(), routes_per_module);
+        let total_routes = MODULE_COUNT * (2 * routes_per_module + 1);
+        let last_route = format!("/app{}/items/{}/detail", MODULE_COUNT - 1, routes_per_module - 1);
+
+        group.bench_with_input(BenchmarkId::new("healthz", total_routes), &table, |b, table| {
+            b.iter(|| dispatch(&rt, table, Method::GET, "/healthz"))
+        });
+        group.bench_with_input(BenchmarkId::new("not_found", total_routes), &table, |b, table| {
+            b.iter(|| dispatch(&rt, table, Method::GET, "/no/such/route"))
+        });
+        group.bench_with_input(BenchmarkId::new("method_not_allowed", total_routes), &table, |b, table| {
+            b.iter(|| dispatch(&rt, table, Method::POST, &last_route))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup_benchmarks, dispatch_benchmarks);
+criterion_main!(benches);
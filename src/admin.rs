@@ -0,0 +1,147 @@
+//! The admin listener, which reports what the running server does on an
+//! address separate from the one it serves requests on.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_TYPE},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server,
+};
+use serde::Serialize;
+
+use crate::dispatcher::RoutingTable;
+use crate::handler_loader::HandlerSource;
+use crate::http_util::{method_not_allowed, not_found};
+
+const ROUTES_PATH: &str = "/routes";
+
+/// Everything the server dispatches requests to, as reported by `GET /routes`
+/// and `wagi routes`.
+#[derive(Debug, Serialize)]
+pub struct RoutesInfo {
+    pub routes: Vec<RouteInfo>,
+    pub rewrites: Vec<RewriteInfo>,
+}
+
+/// A routing table entry, after `_routes` expansion.
+#[derive(Debug, Serialize)]
+pub struct RouteInfo {
+    pub route: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // None means any method
+    pub methods: Option<Vec<String>>,
+    /// "route", "not_found" or "error_handler".
+    pub role: &'static str,
+    /// "wasm", "static", "redirect", "health_check", "readiness_check" or "metrics".
+    pub kind: &'static str,
+    /// Whether the route was declared by a module's `_routes` function.
+    pub dynamic: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_hosts: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub static_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_status: Option<u16>,
+    // None for built-in routes
+    pub source: Option<HandlerSource>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RewriteInfo {
+    pub route: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub to: String,
+}
+
+impl RoutesInfo {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+pub struct AdminServer {
+    address: SocketAddr,
+    // The routing table does not change while the server runs, so the
+    // response is built once
+    routes_json: Bytes,
+}
+
+impl AdminServer {
+    pub fn new(address: SocketAddr, routing_table: &RoutingTable) -> anyhow::Result<Self> {
+        Ok(Self {
+            address,
+            routes_json: Bytes::from(routing_table.describe_routes().to_json()?),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub async fn serve(&self) -> anyhow::Result<()> {
+        let mk_svc = make_service_fn(move |_conn: &AddrStream| {
+            let routes_json = self.routes_json.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                    let response = handle_admin_request(&req, &routes_json);
+                    async move { Ok::<_, std::convert::Infallible>(response) }
+                }))
+            }
+        });
+        Server::bind(&self.address).serve(mk_svc).await?;
+        Ok(())
+    }
+}
+
+fn handle_admin_request(req: &Request<Body>, routes_json: &Bytes) -> Response<Body> {
+    if req.uri().path() != ROUTES_PATH {
+        return not_found();
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return method_not_allowed(&[Method::GET, Method::HEAD]);
+    }
+    let body = if req.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from(routes_json.clone())
+    };
+    let mut res = Response::new(body);
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn admin_request(method: Method, path: &str) -> Response<Body> {
+        let req = Request::builder().method(method).uri(path).body(Body::empty()).unwrap();
+        handle_admin_request(&req, &Bytes::from_static(b"{}"))
+    }
+
+    #[test]
+    fn only_routes_path_is_served() {
+        let res = admin_request(Method::GET, "/routes");
+        assert_eq!(hyper::StatusCode::OK, res.status());
+        assert_eq!("application/json", res.headers()[CONTENT_TYPE]);
+
+        assert_eq!(hyper::StatusCode::NOT_FOUND, admin_request(Method::GET, "/").status());
+        assert_eq!(hyper::StatusCode::NOT_FOUND, admin_request(Method::GET, "/routes/x").status());
+        assert_eq!(hyper::StatusCode::METHOD_NOT_ALLOWED, admin_request(Method::POST, "/routes").status());
+    }
+}
@@ -1,12 +1,19 @@
-use std::{collections::{HashMap, HashSet}, iter::FromIterator};
+use std::{collections::{BTreeMap, HashMap, HashSet}, iter::FromIterator};
 
 use bindle::{Invoice, Parcel};
 
+use crate::handler_loader::{HandlerRole, HandlerSource, RedirectConfigurationEntry, RewriteConfigurationEntry, UNMOUNTED_HANDLER_ROUTE};
+
 // TODO: this file is a bit of a cop-out but will be useful during
 // the transition.  Find better homes for these things!
 
 pub const WASM_MEDIA_TYPE: &str = "application/wasm";
 
+// Invoice annotations that declare redirects and rewrites, which have no parcel
+// of their own. The rest of the annotation key is the route.
+const REDIRECT_ANNOTATION_PREFIX: &str = "wagi.redirect.";
+const REWRITE_ANNOTATION_PREFIX: &str = "wagi.rewrite.";
+
 pub struct InvoiceUnderstander {
     invoice: Invoice,
     group_dependency_map: HashMap<String, Vec<Parcel>>,
@@ -42,38 +49,104 @@ impl InvoiceUnderstander {
             .collect()
     }
 
-    pub fn classify_parcel(&self, parcel: &Parcel) -> Option<InterestingParcel> {
+    pub fn classify_parcel(&self, parcel: &Parcel) -> anyhow::Result<Option<InterestingParcel>> {
         // Currently only handlers but we have talked of scheduled tasks etc.
-        parcel.label.feature.as_ref().and_then(|features| {
-            features.get("wagi").and_then(|wagi_features| {
-                match wagi_features.get("route") {
-                    Some(route) => {
-                        let handler_info = WagiHandlerInfo {
-                            invoice_id: self.id(),
-                            parcel: parcel.clone(),
-                            route: route.to_owned(),
-                            entrypoint: wagi_features.get("entrypoint").map(|s| s.to_owned()),
-                            allowed_hosts: wagi_features.get("allowed_hosts").map(|h| parse_csv(h)),
-                            argv: wagi_features.get("argv").map(|s| s.to_owned()),
-                            required_parcels: parcels_required_for(parcel, &self.group_dependency_map),
-                        };
-                        Some(InterestingParcel::WagiHandler(handler_info))
+        let wagi_features = match parcel.label.feature.as_ref().and_then(|features| features.get("wagi")) {
+            Some(wagi_features) => wagi_features,
+            None => return Ok(None),
+        };
+
+        // Not found and error handlers are not mounted at a route, so ignore any they are given
+        let unmounted_role = if is_feature_set(wagi_features.get("not_found")) {
+            Some(HandlerRole::NotFound)
+        } else if is_feature_set(wagi_features.get("error_handler")) {
+            Some(HandlerRole::Error)
+        } else {
+            None
+        };
+        let (role, route) = match (unmounted_role, wagi_features.get("route")) {
+            (Some(role), _) => (role, UNMOUNTED_HANDLER_ROUTE.to_owned()),
+            (None, Some(route)) => (HandlerRole::Route, route.to_owned()),
+            (None, None) => return Ok(None),
+        };
+
+        let handler_info = WagiHandlerInfo {
+            invoice_id: self.id(),
+            parcel: parcel.clone(),
+            route,
+            role,
+            entrypoint: wagi_features.get("entrypoint").map(|s| s.to_owned()),
+            allowed_hosts: wagi_features.get("allowed_hosts").map(|h| parse_csv(h)),
+            argv: wagi_features.get("argv").map(|s| s.to_owned()),
+            methods: wagi_features.get("methods").map(|m| parse_csv(m)),
+            host: wagi_features.get("host").map(|s| s.to_owned()),
+            error_format: wagi_features.get("error_format").map(|s| s.to_owned()),
+            timeout_ms: parse_number(parcel, wagi_features, "timeout")?,
+            max_fuel: parse_number(parcel, wagi_features, "max_fuel")?,
+            max_memory_mb: parse_number(parcel, wagi_features, "max_memory_mb")?,
+            max_table_elements: parse_number(parcel, wagi_features, "max_table_elements")?,
+            max_instances: parse_number(parcel, wagi_features, "max_instances")?,
+            max_body_size: parse_number(parcel, wagi_features, "max_body_size")?,
+            nph: is_feature_set(wagi_features.get("nph")),
+            is_static: role == HandlerRole::Route && is_feature_set(wagi_features.get("static")),
+            index_files: wagi_features.get("index").map(|i| parse_csv(i)),
+            required_parcels: parcels_required_for(parcel, &self.group_dependency_map),
+        };
+        Ok(Some(InterestingParcel::WagiHandler(handler_info)))
+    }
+
+    pub fn parse_wagi_handlers(&self) -> anyhow::Result<Vec<WagiHandlerInfo>> {
+        let mut handlers = vec![];
+        for parcel in self.top_modules() {
+            if let Some(InterestingParcel::WagiHandler(h)) = self.classify_parcel(&parcel)? {
+                handlers.push(h);
+            }
+        }
+        Ok(handlers)
+    }
+
+    /// Redirects declared by `wagi.redirect.<route>` annotations on the
+    /// invoice. The value is the target, optionally preceded by the status,
+    /// e.g. `301 /new/...`.
+    pub fn parse_redirects(&self) -> Vec<RedirectConfigurationEntry> {
+        self.annotations_with_prefix(REDIRECT_ANNOTATION_PREFIX)
+            .map(|(route, value)| {
+                let (status, to) = match value.split_once(' ') {
+                    Some((status, to)) => match status.parse() {
+                        Ok(status) => (Some(status), to.trim()),
+                        Err(_) => (None, value),
                     },
-                    None => None,
+                    None => (None, value),
+                };
+                RedirectConfigurationEntry {
+                    route: route.to_owned(),
+                    host: None,
+                    to: to.to_owned(),
+                    status,
+                    source: Some(HandlerSource::Bindle { id: self.id().to_string(), parcel: None }),
                 }
             })
-        })
+            .collect()
     }
 
-    pub fn parse_wagi_handlers(&self) -> Vec<WagiHandlerInfo> {
-        self
-            .top_modules().iter()
-            .filter_map(|parcel| self.classify_parcel(parcel))
-            .map(|parcel| match parcel {    // If there are other cases of InterestingParcel this may need to become a filter_map, but right now that makes Clippy mad
-                InterestingParcel::WagiHandler(h) => h,
+    /// Rewrites declared by `wagi.rewrite.<route>` annotations on the invoice.
+    /// The value is the path to rewrite to.
+    pub fn parse_rewrites(&self) -> Vec<RewriteConfigurationEntry> {
+        self.annotations_with_prefix(REWRITE_ANNOTATION_PREFIX)
+            .map(|(route, value)| RewriteConfigurationEntry {
+                route: route.to_owned(),
+                host: None,
+                to: value.trim().to_owned(),
             })
             .collect()
     }
+
+    fn annotations_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.invoice.annotations
+            .iter()
+            .flatten()
+            .filter_map(move |(key, value)| key.strip_prefix(prefix).map(|route| (route, value.as_str())))
+    }
 }
 
 pub enum InterestingParcel {
@@ -85,13 +158,34 @@ pub struct WagiHandlerInfo {
     pub invoice_id: bindle::Id,
     pub parcel: Parcel,
     pub route: String,
+    pub role: HandlerRole,
     pub entrypoint: Option<String>,
     pub allowed_hosts: Option<Vec<String>>,
     pub required_parcels: Vec<Parcel>,
     pub argv: Option<String>,
+    pub methods: Option<Vec<String>>,
+    pub host: Option<String>,
+    pub error_format: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub max_fuel: Option<u64>,
+    pub max_memory_mb: Option<u64>,
+    pub max_table_elements: Option<u32>,
+    pub max_instances: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub nph: bool,
+    // Whether to serve the handler's asset parcels directly instead of running it
+    pub is_static: bool,
+    pub index_files: Option<Vec<String>>,
 }
 
 impl WagiHandlerInfo {
+    pub fn source(&self) -> HandlerSource {
+        HandlerSource::Bindle {
+            id: self.invoice_id.to_string(),
+            parcel: Some(self.parcel.label.sha256.clone()),
+        }
+    }
+
     pub fn asset_parcels(&self) -> Vec<Parcel> {
         self.required_parcels.iter().filter(|p| is_file(p)).cloned().collect()
     }
@@ -101,15 +195,33 @@ const NO_PARCELS: Vec<Parcel> = vec![];
 
 pub fn is_file(parcel: &Parcel) -> bool {
     parcel.label.feature.as_ref().and_then(|features| {
-        features.get("wagi").map(|wagi_features| {
-            match wagi_features.get("file") {
-                Some(s) => s == "true",
-                _ => false,
-            }
-        })
+        features.get("wagi").map(|wagi_features| is_feature_set(wagi_features.get("file")))
     }).unwrap_or(false)
 }
 
+// For features such as `timeout` (a number of milliseconds) and `max_fuel`.
+// These are limits, so a value that doesn't parse is an error rather than
+// being ignored: ignoring it would leave the module with no limit at all.
+fn parse_number<T: std::str::FromStr>(parcel: &Parcel, wagi_features: &BTreeMap<String, String>, feature: &str) -> anyhow::Result<Option<T>> {
+    match wagi_features.get(feature) {
+        None => Ok(None),
+        Some(value) => match value.trim().parse() {
+            Ok(number) => Ok(Some(number)),
+            Err(_) => Err(anyhow::anyhow!(
+                "Invalid wagi.{} feature '{}' on parcel {}: expected a whole number",
+                feature, value, parcel.label.name
+            )),
+        },
+    }
+}
+
+fn is_feature_set(value: Option<&String>) -> bool {
+    match value {
+        Some(s) => s == "true",
+        _ => false,
+    }
+}
+
 pub fn parcels_required_for(parcel: &Parcel, full_dep_map: &HashMap<String, Vec<Parcel>>) -> Vec<Parcel> {
     let mut required = HashSet::new();
     for group in parcel.directly_requires() {
@@ -386,6 +498,166 @@ mod test {
         assert!(super::is_file(&p));
     }
 
+    fn classify_wasm_parcel(wagi_features: &[(&str, &str)]) -> Option<InterestingParcel> {
+        try_classify_wasm_parcel(wagi_features).expect("Parcel features should be valid")
+    }
+
+    fn try_classify_wasm_parcel(wagi_features: &[(&str, &str)]) -> anyhow::Result<Option<InterestingParcel>> {
+        let wagifeatures = wagi_features.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut features = BTreeMap::new();
+        features.insert("wagi".to_owned(), wagifeatures);
+        let parcel = Parcel {
+            label: Label {
+                sha256: "yubbadubbadoonow".to_owned(),
+                name: "water".to_owned(),
+                media_type: WASM_MEDIA_TYPE.to_owned(),
+                size: 1234,
+                annotations: None,
+                feature: Some(features),
+                origin: None,
+            },
+            conditions: None,
+        };
+        let inv = InvoiceUnderstander::new(&Invoice {
+            bindle_version: "v1".to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            signature: None,
+            annotations: None,
+            bindle: BindleSpec {
+                id: "drink/1.2.3"
+                    .to_owned()
+                    .try_into()
+                    .expect("This should parse"),
+                description: None,
+                authors: None,
+            },
+            group: None,
+            parcel: Some(vec![parcel.clone()]),
+        });
+
+        inv.classify_parcel(&parcel)
+    }
+
+    #[test]
+    fn test_classify_parcel_routing_features() {
+        let classified = classify_wasm_parcel(&[
+            ("route", "/things/..."),
+            ("methods", "GET,POST"),
+            ("host", "*.example.com"),
+            ("error_format", "problem+json"),
+            ("static", "true"),
+            ("index", "home.html"),
+        ]);
+
+        match classified {
+            Some(InterestingParcel::WagiHandler(h)) => {
+                assert_eq!(HandlerRole::Route, h.role);
+                assert_eq!(Some(vec!["GET".to_owned(), "POST".to_owned()]), h.methods);
+                assert_eq!(Some("*.example.com".to_owned()), h.host);
+                assert_eq!(Some("problem+json".to_owned()), h.error_format);
+                assert!(h.is_static);
+                assert_eq!(Some(vec!["home.html".to_owned()]), h.index_files);
+            },
+            None => panic!("Expected parcel to be classified as a handler"),
+        }
+    }
+
+    #[test]
+    fn test_classify_not_found_parcel() {
+        for features in [vec![("not_found", "true")], vec![("not_found", "true"), ("route", "/ignored")]] {
+            match classify_wasm_parcel(&features) {
+                Some(InterestingParcel::WagiHandler(h)) => {
+                    assert_eq!(HandlerRole::NotFound, h.role);
+                    assert_eq!(UNMOUNTED_HANDLER_ROUTE, h.route);
+                },
+                None => panic!("Expected parcel to be classified as a handler"),
+            }
+        }
+
+        assert!(classify_wasm_parcel(&[("not_found", "false")]).is_none());
+        match classify_wasm_parcel(&[("not_found", "false"), ("route", "/")]) {
+            Some(InterestingParcel::WagiHandler(h)) => assert_eq!(HandlerRole::Route, h.role),
+            None => panic!("Expected parcel to be classified as a handler"),
+        }
+    }
+
+    #[test]
+    fn test_classify_error_handler_parcel() {
+        match classify_wasm_parcel(&[("error_handler", "true"), ("route", "/ignored")]) {
+            Some(InterestingParcel::WagiHandler(h)) => {
+                assert_eq!(HandlerRole::Error, h.role);
+                assert_eq!(UNMOUNTED_HANDLER_ROUTE, h.route);
+            },
+            None => panic!("Expected parcel to be classified as a handler"),
+        }
+
+        assert!(classify_wasm_parcel(&[("error_handler", "false")]).is_none());
+    }
+
+    #[test]
+    fn test_classify_parcel_limits() {
+        match classify_wasm_parcel(&[("route", "/"), ("max_fuel", "1000"), ("timeout", " 250 ")]) {
+            Some(InterestingParcel::WagiHandler(h)) => {
+                assert_eq!(Some(1000), h.max_fuel);
+                assert_eq!(Some(250), h.timeout_ms);
+                assert_eq!(None, h.max_memory_mb);
+            },
+            None => panic!("Expected parcel to be classified as a handler"),
+        }
+    }
+
+    #[test]
+    fn test_classify_parcel_rejects_invalid_limits() {
+        for feature in ["max_fuel", "timeout", "max_memory_mb"] {
+            let err = match try_classify_wasm_parcel(&[("route", "/"), (feature, "lots")]) {
+                Ok(_) => panic!("Invalid wagi.{} should be rejected", feature),
+                Err(e) => e,
+            };
+            assert!(format!("{:#}", err).contains(feature), "Error should name {}: {:#}", feature, err);
+        }
+    }
+
+    #[test]
+    fn test_redirect_and_rewrite_annotations() {
+        let annotations = [
+            ("wagi.redirect./old/...", "301 /new/..."),
+            ("wagi.redirect./elsewhere", "https://example.com/"),
+            ("wagi.rewrite./api/...", "/v2/..."),
+            ("unrelated", "value"),
+        ];
+        let inv = InvoiceUnderstander::new(&Invoice {
+            bindle_version: "v1".to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            signature: None,
+            annotations: Some(annotations.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+            bindle: BindleSpec {
+                id: "drink/1.2.3"
+                    .to_owned()
+                    .try_into()
+                    .expect("This should parse"),
+                description: None,
+                authors: None,
+            },
+            group: None,
+            parcel: None,
+        });
+
+        let redirects = inv.parse_redirects();
+        assert_eq!(2, redirects.len());
+        let redirect = |route: &str| redirects.iter().find(|r| r.route == route).expect("Expected redirect");
+        assert_eq!(Some(301), redirect("/old/...").status);
+        assert_eq!("/new/...", redirect("/old/...").to);
+        assert_eq!(None, redirect("/elsewhere").status);
+        assert_eq!("https://example.com/", redirect("/elsewhere").to);
+
+        let rewrites = inv.parse_rewrites();
+        assert_eq!(1, rewrites.len());
+        assert_eq!("/api/...", rewrites[0].route);
+        assert_eq!("/v2/...", rewrites[0].to);
+    }
+
     #[test]
     fn test_group_members() {
         let inv = Invoice {
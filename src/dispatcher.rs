@@ -1,36 +1,130 @@
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Context;
 use hyper::{
     http::request::Parts,
-    Body, Request, Response, StatusCode,
+    Body, Method, Request, Response, StatusCode,
 };
 use sha2::{Digest, Sha256};
 use tracing::{instrument};
 
-use crate::dynamic_route::{DynamicRoutes, interpret_routes};
-use crate::handlers::{RouteHandler, WasmRouteHandler};
-use crate::http_util::{not_found};
+use crate::admin::{RewriteInfo, RouteInfo, RoutesInfo};
+use crate::dynamic_route::{DynamicRoute, DynamicRoutes, interpret_routes};
+use crate::epoch::EpochTicker;
+use crate::error_response::{ErrorFormat, FailureKind, ModuleFailure};
+use crate::handlers::{HeaderMode, RouteHandler, WasmRouteHandler};
+use crate::http_util::{method_not_allowed, not_found, parse_host_header_uri, parse_method, route_parameter_variable, service_unavailable};
+use crate::metrics::{METRICS_ROUTE, UNMATCHED_ROUTE_LABEL};
+use crate::readiness::{ReadinessCheck, READINESS_ROUTE};
+use crate::redirect::{find_rewrite, RedirectRouteHandler, RewriteRule};
 use crate::request::{RequestContext, RequestGlobalContext};
+use crate::request_body::{self, RequestBody, RequestBodyError};
+use crate::response_stream::{ClientWait, ResponseSender};
+use crate::route_tree::{PatternSegment, RouteTree};
+use crate::static_files::{StaticFilesRouteHandler, DEFAULT_INDEX_FILES};
+use crate::worker_pool::WorkerPoolError;
 
-use crate::handler_loader::{WasmHandlerConfigurationEntry, WasmHandlerConfiguration};
+use crate::handler_loader::{HandlerRole, HandlerSource, RedirectConfigurationEntry, StaticFilesConfigurationEntry, WasmHandlerConfigurationEntry, WasmHandlerConfiguration};
 use crate::wasm_runner::{RunWasmResult, prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance_if_present, WasmLinkOptions};
 
 #[derive(Clone, Debug)]
 pub struct RoutingTable {
-    entries: Vec<RoutingTableEntry>,
+    // The table is cloned for every request, so the entries and the tree
+    // that indexes them are shared rather than copied.
+    entries: Arc<Vec<Arc<RoutingTableEntry>>>,
+    tree: Arc<RouteTree>,
+    // Handles requests that match no entry
+    not_found: Option<Arc<RoutingTableEntry>>,
+    // Produces the response when another entry fails
+    error_handler: Option<Arc<RoutingTableEntry>>,
+    // Applied to the request path before it is routed
+    rewrites: Arc<Vec<RewriteRule>>,
     global_context: RequestGlobalContext,
+    // Lets modules time out, for as long as the table is in use
+    _epoch_ticker: Arc<EpochTicker>,
 }
 
 #[derive(Clone, Debug)]
 struct RoutingTableEntry {
     pub route_pattern: RoutePattern,
+    // If None, the entry serves any host
+    pub host: Option<HostPattern>,
     pub handler_info: RouteHandler,
+    // If None, the entry accepts any method
+    pub methods: Option<Vec<Method>>,
+    // Whether the entry was declared by a module's _routes function
+    pub is_dynamic: bool,
+    pub error_format: ErrorFormat,
+    // Where the entry was declared (None for built-in entries)
+    pub source: Option<HandlerSource>,
+}
+
+/// Why a request could not be routed.
+#[derive(Debug, PartialEq)]
+enum RoutingFailure {
+    /// No entry matches the path.
+    NotFound,
+    /// One or more entries match the path, but none of them accepts the
+    /// request method. Carries the methods that would have been accepted.
+    MethodNotAllowed(Vec<Method>),
+}
+
+/// A problem with the routing table, found by analysing it at startup.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteConflict {
+    /// The route can never handle a request, because for every request it
+    /// matches, a built-in route or a later declaration of the same route
+    /// takes precedence.
+    Unreachable { route: String, handler: String, shadowed_by: String },
+    /// The route is declared again later, and the later declaration takes
+    /// precedence for some (but not all) of the methods it accepts.
+    Duplicate { route: String, handler: String, duplicated_by: String },
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum RoutePattern {
     Exact(String),
     Prefix(String),
+    /// A route with named parameters, such as `/users/:id/orders/:order`. If
+    /// `is_prefix` is set, the route was written with a trailing `/...` and
+    /// also matches subpaths.
+    Parameterised { template: String, is_prefix: bool },
+}
+
+/// The host name(s) a route is served on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    /// A pattern such as `*.example.com`, which matches any subdomain of
+    /// `example.com` (but not `example.com` itself). Holds the domain part.
+    Wildcard(String),
+}
+
+/// How narrowly an entry constrains the hosts it serves. As with
+/// `RouteSpecificity`, variants go from least to most specific.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HostSpecificity {
+    Any,
+    /// A wildcard, ranked by the number of labels in its domain.
+    Wildcard(usize),
+    Exact,
+}
+
+/// How narrowly a route pattern constrains the paths it matches. Variants are
+/// declared from least to most specific so that the derived ordering can be used
+/// to rank competing matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RouteSpecificity {
+    /// A wildcard, ranked by the number of path segments before the `/...`,
+    /// then by how many of those are literal rather than parameters.
+    Prefix(usize, usize),
+    /// A parameterised route, ranked by how many of its segments are literal.
+    Parameterised(usize),
+    Exact,
 }
 
 impl RoutingTable {
@@ -40,55 +134,302 @@ impl RoutingTable {
         client_addr: SocketAddr,
     ) -> Result<Response<Body>, hyper::Error> {
         tracing::trace!("Processing request");
+        let _in_flight = self.global_context.metrics.as_ref().map(|m| m.start_request());
 
-        let uri_path = req.uri().path().to_owned();
-
-        let (parts, body) = req.into_parts();
-        let data = hyper::body::to_bytes(body)
-            .await
-            .unwrap_or_default()
-            .to_vec();
-
-        match self.route_for(&uri_path) {
-            Ok(rte) => {
-                let request_context = RequestContext {
-                    client_addr,
-                };
-                let response = rte.handle_request(&parts, data, &request_context, &self.global_context);
-                Ok(response)
-            },
-            Err(_) => Ok(not_found()),
+        let (mut parts, body) = req.into_parts();
+
+        let (host, _) = parse_host_header_uri(&parts.headers, &parts.uri, &self.global_context.default_host);
+
+        // Rewrites are applied once, so a rewritten path is not rewritten again
+        if let Some(rule) = find_rewrite(&self.rewrites, &host, parts.uri.path()) {
+            match rule.rewrite(&parts.uri, &host) {
+                Ok(uri) => {
+                    tracing::debug!(from = %parts.uri, to = %uri, "Rewrote request URI");
+                    parts.uri = uri;
+                }
+                Err(e) => tracing::error!(error = %e, uri = %parts.uri, "Failed to rewrite request URI"),
+            }
         }
 
+        let uri_path = parts.uri.path().to_owned();
+
+        let routed = self.route_for(&host, &uri_path, &parts.method);
+
+        let route_metrics = self.global_context.metrics.as_ref().map(|m| match &routed {
+            Ok(rte) => m.for_route(&rte.route_pattern.original_text()),
+            Err(_) => m.for_route(UNMATCHED_ROUTE_LABEL),
+        });
+        let request_context = RequestContext {
+            client_addr,
+            route_metrics,
+        };
+
+        let error_responses = &self.global_context.error_responses;
+
+        let response = match routed {
+            Ok(rte) => self.respond_with_body(rte, parts, body, request_context.clone()).await,
+            Err(RoutingFailure::MethodNotAllowed(allowed)) => error_responses.apply_page(method_not_allowed(&allowed)),
+            Err(RoutingFailure::NotFound) => match &self.not_found {
+                Some(rte) => self.respond_with_body(rte.clone(), parts, body, request_context.clone()).await,
+                None => error_responses.apply_page(not_found()),
+            },
+        };
+
+        if let Some(route_metrics) = &request_context.route_metrics {
+            route_metrics.record_response(response.status());
+        }
+        Ok(response)
     }
 
+    /// Finds the entry that should handle the given host, path and method.
+    ///
+    /// Entries that do not serve the host or accept the method are not
+    /// considered. If several entries match, the most specific one wins:
+    /// built-in routes beat user routes, routes for a named host beat routes
+    /// for a wildcard host, which beat routes for any host; then exact routes
+    /// beat wildcards, and longer wildcards beat shorter ones. If two entries
+    /// are equally specific, the one declared *last* wins, as the spec requires
+    /// for `_routes`.
     #[instrument(level = "trace", skip(self))]
-    fn route_for(&self, uri_fragment: &str) -> Result<RoutingTableEntry, anyhow::Error> {
-        for r in &self.entries {
-            // TODO: I THINK THIS IS WRONG.  The spec says we need to match the *last* pattern
-            // if there are multiple matching wildcards (this is mentioned under the docs for
-            // the _routes feature).
-            tracing::trace!(path = ?r.route_pattern, uri_fragment, "Trying route path");
-            if r.is_match(uri_fragment) {
-                return Ok(r.clone());
+    fn route_for(&self, host: &str, uri_fragment: &str, method: &Method) -> Result<Arc<RoutingTableEntry>, RoutingFailure> {
+        let path_matches: Vec<_> = self.tree
+            .matches(uri_fragment)
+            .into_iter()
+            .map(|index| &self.entries[index])
+            .filter(|r| {
+                tracing::trace!(host_pattern = ?r.host, path = ?r.route_pattern, host, uri_fragment, "Trying route host");
+                r.serves_host(host)
+            })
+            .collect();
+
+        if path_matches.is_empty() {
+            return Err(RoutingFailure::NotFound);
+        }
+
+        path_matches
+            .iter()
+            .filter(|r| r.accepts_method(method))
+            // max_by_key returns the last of several equal maxima, which gives us
+            // the "last declared wins" tie-break.
+            .max_by_key(|r| r.precedence())
+            .map(|r| Arc::clone(r))
+            .ok_or_else(|| RoutingFailure::MethodNotAllowed(allowed_methods(&path_matches)))
+    }
+
+    /// Prepares the request body, within the entry's size limit, before
+    /// handing the request to the entry. A body whose length is known is
+    /// streamed to the handler; others are read first, and if large, spooled
+    /// to disk rather than held in memory.
+    async fn respond_with_body(&self, rte: Arc<RoutingTableEntry>, req: Parts, body: Body, request_context: RequestContext) -> Response<Body> {
+        let content_length = request_body::content_length(&req.headers);
+        match RequestBody::read(body, content_length, rte.max_body_size(&self.global_context)).await {
+            Ok(body) => self.respond(rte, req, body, request_context).await,
+            Err(e) => request_body_error_response(&e, &req, &self.global_context),
+        }
+    }
+
+    /// Handlers that run Wasm are run on the worker pool, so as not to block
+    /// the async worker serving this connection (and others). Other handlers
+    /// either do no I/O or do it asynchronously, so run here. The response
+    /// is returned as soon as the handler has sent its head, which for a
+    /// module is when it has written its headers; the body follows as the
+    /// module writes it.
+    async fn respond(&self, rte: Arc<RoutingTableEntry>, req: Parts, body: RequestBody, request_context: RequestContext) -> Response<Body> {
+        if !rte.handler_info.runs_wasm() {
+            return self.respond_now(&rte, &req, body, &request_context).await;
+        }
+        let (sender, response) = ResponseSender::channel();
+        let table = self.clone();
+        let run = move || table.respond_streaming(&rte, &req, body, &request_context, &sender);
+        let worker_pool = self.global_context.worker_pool.clone();
+        let running = tokio::spawn(async move { worker_pool.run(run).await });
+        if let Ok(response) = response.await {
+            return response;
+        }
+        // The handler never got to run, or panicked
+        let error_responses = &self.global_context.error_responses;
+        let error: anyhow::Error = match running.await {
+            Ok(Err(WorkerPoolError::Full)) => {
+                tracing::warn!("Worker pool queue is full; rejecting request");
+                return error_responses.apply_page(service_unavailable());
+            }
+            Ok(Err(e)) => e.into(),
+            Ok(Ok(())) => anyhow::anyhow!("Handler finished without sending a response"),
+            Err(e) => e.into(),
+        };
+        tracing::error!(error = %error, "Worker failed");
+        error_responses.failure_response(&ModuleFailure::from_error(&error))
+    }
+
+    // Sends exactly one response through `sender`, unless the handler fails
+    // after it has started streaming one.
+    fn respond_streaming(&self, rte: &RoutingTableEntry, req: &Parts, body: RequestBody, request_context: &RequestContext, sender: &ResponseSender) {
+        if let Err(failure) = rte.stream_request(req, body, request_context, &self.global_context, sender) {
+            if !sender.is_sent() {
+                sender.send(self.failure_response(rte, req, &failure, request_context));
             }
         }
+    }
+
+    async fn respond_now(&self, rte: &RoutingTableEntry, req: &Parts, body: RequestBody, request_context: &RequestContext) -> Response<Body> {
+        match rte.handle_request(req, body, request_context, &self.global_context).await {
+            Ok(response) => response,
+            Err(failure) => self.failure_response(rte, req, &failure, request_context),
+        }
+    }
+
+    /// Builds the response for a request whose handler failed. Routes that
+    /// want `problem+json` always get it; otherwise the error handler module,
+    /// if there is one, gets a chance to produce the response.
+    fn failure_response(&self, rte: &RoutingTableEntry, req: &Parts, failure: &ModuleFailure, request_context: &RequestContext) -> Response<Body> {
+        let error_responses = &self.global_context.error_responses;
+        if rte.error_format == ErrorFormat::ProblemJson {
+            return error_responses.problem_response(failure, req.uri.path());
+        }
+        if let Some(error_handler) = &self.error_handler {
+            match error_handler.handle_failure_of(rte, failure, req, request_context, &self.global_context) {
+                Ok(response) => return response,
+                Err(e) => tracing::error!(error = %e, "error running error handler module"),
+            }
+        }
+        error_responses.failure_response(failure)
+    }
+}
 
-        Err(anyhow::anyhow!("No handler for path {}", uri_fragment))
+// Only called when none of the entries accepts all methods, so every entry
+// has a method list.
+fn allowed_methods(entries: &[&Arc<RoutingTableEntry>]) -> Vec<Method> {
+    let mut allowed: Vec<Method> = vec![];
+    for method in entries.iter().flat_map(|e| e.methods.iter().flatten()) {
+        if !allowed.contains(method) {
+            allowed.push(method.clone());
+        }
     }
+    allowed
 }
 
 const DEFAULT_ENTRYPOINT: &str = "_start";
 
 impl RoutingTableEntry {
-    pub fn is_match(&self, uri_fragment: &str) -> bool {
-        self.route_pattern.is_match(uri_fragment)
+    pub fn serves_host(&self, host: &str) -> bool {
+        match &self.host {
+            None => true,
+            Some(pattern) => pattern.is_match(host),
+        }
+    }
+
+    pub fn accepts_method(&self, method: &Method) -> bool {
+        match &self.methods {
+            None => true,
+            Some(methods) => methods.contains(method),
+        }
+    }
+
+    /// The largest request body the entry accepts. Modules can set their own
+    /// limit; other handlers have the server-wide one.
+    fn max_body_size(&self, global_context: &RequestGlobalContext) -> Option<u64> {
+        match &self.handler_info {
+            RouteHandler::Wasm(w) => w.max_body_size.or(global_context.max_body_size),
+            RouteHandler::HealthCheck | RouteHandler::Metrics | RouteHandler::Readiness(_) | RouteHandler::StaticFiles(_) | RouteHandler::Redirect(_) => global_context.max_body_size,
+        }
+    }
+
+    fn accepts_any_method_of(&self, other: &RoutingTableEntry) -> bool {
+        match (&self.methods, &other.methods) {
+            (None, _) | (_, None) => true,
+            (Some(methods), Some(other_methods)) => methods.iter().any(|m| other_methods.contains(m)),
+        }
+    }
+
+    // A description of the entry for diagnostics, e.g. "module 'foo.wasm', entrypoint '_start'"
+    fn describe(&self) -> String {
+        let handler = match &self.handler_info {
+            RouteHandler::HealthCheck => "the built-in health check".to_owned(),
+            RouteHandler::Metrics => "the built-in metrics endpoint".to_owned(),
+            RouteHandler::Readiness(_) => "the built-in readiness check".to_owned(),
+            RouteHandler::Wasm(w) => format!("module '{}', entrypoint '{}'", w.wasm_module_name, w.entrypoint),
+            RouteHandler::StaticFiles(s) => format!("static files from '{}'", s.root.display()),
+            RouteHandler::Redirect(r) => format!("redirect to '{}'", r.target.original_text()),
+        };
+        let origin = if self.is_dynamic { ", declared by _routes" } else { "" };
+        match &self.host {
+            None => format!("{}{}", handler, origin),
+            Some(host) => format!("{}{}, host '{}'", handler, origin, host.original_text()),
+        }
+    }
+
+    fn info(&self, role: HandlerRole) -> RouteInfo {
+        let mut info = RouteInfo {
+            route: self.route_pattern.original_text(),
+            host: self.host.as_ref().map(|h| h.original_text()),
+            methods: self.methods.as_ref().map(|methods| methods.iter().map(|m| m.to_string()).collect()),
+            role: match role {
+                HandlerRole::Route => "route",
+                HandlerRole::NotFound => "not_found",
+                HandlerRole::Error => "error_handler",
+            },
+            kind: "",
+            dynamic: self.is_dynamic,
+            module: None,
+            entrypoint: None,
+            volumes: None,
+            allowed_hosts: None,
+            static_dir: None,
+            redirect_to: None,
+            redirect_status: None,
+            source: self.source.clone(),
+        };
+        match &self.handler_info {
+            RouteHandler::HealthCheck => info.kind = "health_check",
+            RouteHandler::Metrics => info.kind = "metrics",
+            RouteHandler::Readiness(_) => info.kind = "readiness_check",
+            RouteHandler::Wasm(w) => {
+                info.kind = "wasm";
+                info.module = Some(w.wasm_module_name.clone());
+                info.entrypoint = Some(w.entrypoint.clone());
+                info.volumes = Some(w.volumes.clone());
+                info.allowed_hosts = w.allowed_hosts.clone();
+            }
+            RouteHandler::StaticFiles(s) => {
+                info.kind = "static";
+                info.static_dir = Some(s.root.clone());
+            }
+            RouteHandler::Redirect(r) => {
+                info.kind = "redirect";
+                info.redirect_to = Some(r.target.original_text());
+                info.redirect_status = Some(r.status.as_u16());
+            }
+        }
+        info
+    }
+
+    fn precedence(&self) -> (bool, HostSpecificity, RouteSpecificity) {
+        let host_specificity = match &self.host {
+            None => HostSpecificity::Any,
+            Some(pattern) => pattern.specificity(),
+        };
+        (self.handler_info.is_inbuilt(), host_specificity, self.route_pattern.specificity())
     }
 
     fn build_from_handler_config_entry(
         source: &WasmHandlerConfigurationEntry,
     ) -> Option<anyhow::Result<RoutingTableEntry>> {
-        let route_pattern = RoutePattern::parse(&source.info.route);
+        let route_pattern = match RoutePattern::parse(&source.info.route) {
+            Ok(route_pattern) => route_pattern,
+            Err(e) => return Some(Err(e).with_context(|| format!("Invalid route {}", source.info.route))),
+        };
+        let methods = match parse_methods(source.info.methods.as_deref()) {
+            Ok(methods) => methods,
+            Err(e) => return Some(Err(e).with_context(|| format!("Invalid methods for route {}", source.info.route))),
+        };
+        let error_format = match source.info.error_format.as_deref().map(ErrorFormat::parse).transpose() {
+            Ok(error_format) => error_format.unwrap_or(ErrorFormat::Default),
+            Err(e) => return Some(Err(e).with_context(|| format!("Invalid error format for route {}", source.info.route))),
+        };
+        let host = match source.info.host.as_deref().map(HostPattern::parse).transpose() {
+            Ok(host) => host,
+            Err(e) => return Some(Err(e).with_context(|| format!("Invalid host for route {}", source.info.route))),
+        };
         let wasm_route_handler = WasmRouteHandler {
             wasm_module_source: source.module.clone(),
             wasm_module_name: source.info.name.clone(),
@@ -101,81 +442,329 @@ impl RoutingTableEntry {
             allowed_hosts: source.info.allowed_hosts.clone(),
             http_max_concurrency: source.info.http_max_concurrency,
             argv: source.info.argv.clone(),
+            timeout: source.info.timeout_ms.map(Duration::from_millis),
+            max_fuel: source.info.max_fuel,
+            resource_limits: source.info.resource_limits,
+            max_body_size: source.info.max_body_size,
+            header_mode: if source.info.nph { HeaderMode::Nph } else { HeaderMode::Cgi },
         };
         let handler_info = RouteHandler::Wasm(wasm_route_handler);
 
         Some(Ok(Self {
             route_pattern,
+            host,
             handler_info,
+            methods,
+            is_dynamic: false,
+            error_format,
+            source: Some(source.info.source.clone()),
         }))
     }
 
+    fn build_from_static_files_config_entry(source: &StaticFilesConfigurationEntry) -> anyhow::Result<Self> {
+        let route_pattern = RoutePattern::parse(&source.route)
+            .with_context(|| format!("Invalid route {}", source.route))?;
+        let host = source.host.as_deref().map(HostPattern::parse).transpose()
+            .with_context(|| format!("Invalid host for route {}", source.route))?;
+        let index_files = match &source.index_files {
+            Some(index_files) => index_files.clone(),
+            None => DEFAULT_INDEX_FILES.iter().map(|f| f.to_string()).collect(),
+        };
+        let handler = StaticFilesRouteHandler {
+            root: source.root.clone(),
+            index_files,
+        };
+        Ok(Self {
+            route_pattern,
+            host,
+            handler_info: RouteHandler::StaticFiles(handler),
+            methods: Some(vec![Method::GET, Method::HEAD]),
+            is_dynamic: false,
+            error_format: ErrorFormat::Default,
+            source: Some(source.source.clone()),
+        })
+    }
+
+    fn build_from_redirect_config_entry(source: &RedirectConfigurationEntry) -> anyhow::Result<Self> {
+        let route_pattern = RoutePattern::parse(&source.route)
+            .with_context(|| format!("Invalid route {}", source.route))?;
+        let host = source.host.as_deref().map(HostPattern::parse).transpose()
+            .with_context(|| format!("Invalid host for route {}", source.route))?;
+        let handler = RedirectRouteHandler::build(source, &route_pattern)
+            .with_context(|| format!("Invalid redirect for route {}", source.route))?;
+        Ok(Self {
+            route_pattern,
+            host,
+            handler_info: RouteHandler::Redirect(handler),
+            methods: None,
+            is_dynamic: false,
+            error_format: ErrorFormat::Default,
+            source: source.source.clone(),
+        })
+    }
+
     fn inbuilt(path: &str, handler: RouteHandler) -> Self {
         Self {
             route_pattern: RoutePattern::Exact(path.to_owned()),
+            host: None,
             handler_info: handler,
+            methods: None,
+            is_dynamic: false,
+            error_format: ErrorFormat::Default,
+            source: None,
         }
     }
 
     /// Returns a unique ID for the routing table entry.
     ///
-    /// This is the SHA256 sum of the route.
+    /// This is the SHA256 sum of the route, and of the host if there is one.
     fn unique_key(&self) -> String {
         let mut hasher = Sha256::new();
         hasher.update(&self.route_pattern.original_text());
+        if let Some(host) = &self.host {
+            hasher.update(&host.original_text());
+        }
         format!("{:x}", hasher.finalize())
     }
 
     // TODO: I don't think this rightly belongs here. But
     // reasonable place to at least understand the decomposition and
     // dependencies.
-    pub fn handle_request(
+    pub async fn handle_request(
         &self,
         req: &Parts,
-        body: Vec<u8>,
+        body: RequestBody,
         request_context: &RequestContext,
         global_context: &RequestGlobalContext,
-    ) -> Response<Body> {
+    ) -> Result<Response<Body>, ModuleFailure> {
         match &self.handler_info {
-            RouteHandler::HealthCheck => Response::new(Body::from("OK")),
+            RouteHandler::HealthCheck => Ok(Response::new(Body::from("OK"))),
+            RouteHandler::Metrics => match &global_context.metrics {
+                Some(metrics) => Ok(metrics.response()),
+                None => Ok(not_found()),
+            },
+            RouteHandler::Readiness(r) => Ok(r.response(global_context)),
+            RouteHandler::StaticFiles(s) => Ok(global_context.error_responses.apply_page(s.handle_request(&self.route_pattern, req).await)),
+            RouteHandler::Redirect(r) => {
+                let (host, _) = parse_host_header_uri(&req.headers, &req.uri, &global_context.default_host);
+                Ok(r.handle_request(&self.route_pattern, req, &host))
+            }
             RouteHandler::Wasm(w) => {
                 let response = w.handle_request(&self.route_pattern, req, body, request_context, global_context, self.unique_key());
-                match response {
-                    Ok(res) => res,
-                    Err(e) => {
-                        tracing::error!(error = %e, "error running WASM module");
-                        // A 500 error makes sense here
-                        let mut srv_err = Response::default();
-                        *srv_err.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                        srv_err
+                response.map_err(|e| self.module_failure(e, request_context))
+            }
+        }
+    }
+
+    /// Handles the request, sending the response through `sender`. Wasm
+    /// modules stream their output as they write it; other handlers send
+    /// their response when it is complete.
+    pub fn stream_request(
+        &self,
+        req: &Parts,
+        body: RequestBody,
+        request_context: &RequestContext,
+        global_context: &RequestGlobalContext,
+        sender: &ResponseSender,
+    ) -> Result<(), ModuleFailure> {
+        match &self.handler_info {
+            RouteHandler::Wasm(w) => {
+                let streamed = w.stream_request(&self.route_pattern, req, body, request_context, global_context, self.unique_key(), sender);
+                match streamed.map_err(|e| e.downcast::<RequestBodyError>()) {
+                    Ok(()) => Ok(()),
+                    // A streamed body that the client failed to send is not
+                    // the module's failure. If the module had already sent
+                    // its response head, the body is cut short.
+                    Err(Ok(body_error)) => {
+                        if !sender.is_sent() {
+                            sender.send(request_body_error_response(&body_error, req, global_context));
+                        }
+                        Ok(())
                     }
+                    Err(Err(e)) => Err(self.module_failure(e, request_context)),
                 }
-        
+            }
+            RouteHandler::HealthCheck | RouteHandler::Metrics | RouteHandler::Readiness(_) | RouteHandler::StaticFiles(_) | RouteHandler::Redirect(_) => {
+                // This runs on a worker's blocking thread, which may wait on
+                // the runtime
+                let response = tokio::runtime::Handle::current().block_on(self.handle_request(req, body, request_context, global_context))?;
+                sender.send(response);
+                Ok(())
             }
         }
     }
+
+    fn module_failure(&self, error: anyhow::Error, request_context: &RequestContext) -> ModuleFailure {
+        tracing::error!(error = %error, "error running WASM module");
+        let failure = ModuleFailure::from_error(&error);
+        if let (FailureKind::Trap, Some(route_metrics)) = (failure.kind, &request_context.route_metrics) {
+            route_metrics.record_trap();
+        }
+        failure
+    }
+
+    /// Runs this entry as the error handler for a request that `failed_entry`
+    /// could not handle. The request body has already been consumed, so the
+    /// handler sees an empty body.
+    fn handle_failure_of(
+        &self,
+        failed_entry: &RoutingTableEntry,
+        failure: &ModuleFailure,
+        req: &Parts,
+        request_context: &RequestContext,
+        global_context: &RequestGlobalContext,
+    ) -> anyhow::Result<Response<Body>> {
+        let w = match &self.handler_info {
+            RouteHandler::Wasm(w) => w,
+            RouteHandler::HealthCheck | RouteHandler::Metrics | RouteHandler::Readiness(_) | RouteHandler::StaticFiles(_) | RouteHandler::Redirect(_) => anyhow::bail!("The error handler must be a Wasm module"),
+        };
+        // The error handler's timings would be mistaken for the failed route's
+        let request_context = RequestContext {
+            route_metrics: None,
+            ..request_context.clone()
+        };
+        let mut error_context = global_context.clone();
+        error_context.global_env_vars.extend(failed_entry.failure_env_vars(failure));
+        let mut response = w.handle_request(&self.route_pattern, req, RequestBody::empty(), &request_context, &error_context, self.unique_key())?;
+        // An error page should not report success, so a module that doesn't
+        // set a status gets the status of the failure.
+        if response.status() == StatusCode::OK {
+            *response.status_mut() = failure.status;
+        }
+        Ok(response)
+    }
+
+    /// The environment variables that tell the error handler why this entry
+    /// failed.
+    fn failure_env_vars(&self, failure: &ModuleFailure) -> HashMap<String, String> {
+        let module = match &self.handler_info {
+            RouteHandler::Wasm(w) => w.wasm_module_name.clone(),
+            RouteHandler::HealthCheck | RouteHandler::Metrics | RouteHandler::Readiness(_) | RouteHandler::StaticFiles(_) | RouteHandler::Redirect(_) => String::new(),
+        };
+        [
+            ("X_ERROR_STATUS", failure.status.as_u16().to_string()),
+            ("X_ERROR_KIND", failure.kind.as_str().to_owned()),
+            ("X_ERROR_MESSAGE", failure.message.clone()),
+            ("X_ERROR_BACKTRACE", failure.backtrace.join("\n")),
+            ("X_ERROR_ROUTE", self.route_pattern.original_text()),
+            ("X_ERROR_MODULE", module),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect()
+    }
 }
 
 impl RoutePattern {
-    pub fn parse(path_text: &str) -> Self {
-        match path_text.strip_suffix("/...") {
-            Some(prefix) => Self::Prefix(prefix.to_owned()),
-            None => Self::Exact(path_text.to_owned())
+    pub fn parse(path_text: &str) -> anyhow::Result<Self> {
+        let (path, is_prefix) = match path_text.strip_suffix("/...") {
+            Some(prefix) => (prefix, true),
+            None => (path_text, false),
+        };
+        Self::from_path(path.to_owned(), is_prefix)
+    }
+
+    fn from_path(path: String, is_prefix: bool) -> anyhow::Result<Self> {
+        let pattern = if path.split('/').any(|segment| parameter_name(segment).is_some()) {
+            Self::Parameterised { template: path, is_prefix }
+        } else if is_prefix {
+            Self::Prefix(path)
+        } else {
+            Self::Exact(path)
+        };
+        pattern.check_parameter_names()?;
+        Ok(pattern)
+    }
+
+    // Parameters are passed to the module as environment variables, so their
+    // names must make valid variable names, and no two of them may map to the
+    // same one.
+    fn check_parameter_names(&self) -> anyhow::Result<()> {
+        let mut variables: HashMap<String, String> = HashMap::new();
+        for name in self.parameter_names() {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                anyhow::bail!("Route parameter :{} has an invalid name. Names may only contain letters, digits, '_' and '-'", name);
+            }
+            let variable = route_parameter_variable(&name);
+            match variables.insert(variable.clone(), name.clone()) {
+                Some(other) if other == name => anyhow::bail!("Route parameter :{} appears more than once", name),
+                Some(other) => anyhow::bail!("Route parameters :{} and :{} would both be passed to the module as {}", other, name, variable),
+                None => (),
+            }
         }
+        Ok(())
     }
 
-    // TODO: there is probably scope for an optimisation so we don't need to do the
-    // format! on every match attempt.
-    //
     // The intent is that '/foo/...' should match '/foo' and '/foo/bar' but not '/foobar'
     pub fn is_match(&self, uri_fragment: &str) -> bool {
         match self {
             Self::Exact(path) => path == uri_fragment,
-            Self::Prefix(prefix) => prefix == uri_fragment || uri_fragment.starts_with(&format!("{}/", prefix)),
+            Self::Prefix(prefix) => match uri_fragment.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            },
+            Self::Parameterised { .. } => self.match_parameters(uri_fragment).is_some(),
+        }
+    }
+
+    pub fn specificity(&self) -> RouteSpecificity {
+        match self {
+            Self::Exact(_) => RouteSpecificity::Exact,
+            Self::Prefix(prefix) => {
+                let segments = prefix.matches('/').count();
+                RouteSpecificity::Prefix(segments, segments)
+            },
+            Self::Parameterised { template, is_prefix } => {
+                let segments = template.matches('/').count();
+                let parameters = template.split('/').filter(|s| parameter_name(s).is_some()).count();
+                // Segments are counted by their slashes, so a template with no
+                // leading slash, such as `:id`, has more parameters than slashes
+                let literals = segments.saturating_sub(parameters);
+                if *is_prefix {
+                    RouteSpecificity::Prefix(segments, literals)
+                } else {
+                    RouteSpecificity::Parameterised(literals)
+                }
+            },
         }
     }
 
-    pub fn script_name(&self) -> String {
+    /// The pattern's path segments, for indexing in a `RouteTree`, and whether
+    /// it also matches subpaths.
+    pub fn tree_segments(&self) -> (Vec<PatternSegment<'_>>, bool) {
+        let (path, is_prefix) = match self {
+            Self::Exact(path) => (path, false),
+            Self::Prefix(prefix) => (prefix, true),
+            Self::Parameterised { template, is_prefix } => (template, *is_prefix),
+        };
+        let segments = path
+            .split('/')
+            .map(|segment| match parameter_name(segment) {
+                Some(_) => PatternSegment::Parameter,
+                None => PatternSegment::Literal(segment),
+            })
+            .collect();
+        (segments, is_prefix)
+    }
+
+    /// Identifies the paths the pattern matches: two patterns with the same key
+    /// match exactly the same paths (they differ at most in parameter names).
+    fn path_key(&self) -> (Vec<Option<String>>, bool) {
+        let (segments, is_prefix) = self.tree_segments();
+        let key = segments
+            .iter()
+            .map(|segment| match segment {
+                PatternSegment::Literal(text) => Some(text.to_string()),
+                PatternSegment::Parameter => None,
+            })
+            .collect();
+        (key, is_prefix)
+    }
+
+    /// The part of the request path that identifies the script, i.e. everything
+    /// except the path info. For a parameterised route, this is the request path
+    /// up to the end of the template, with the parameter values filled in.
+    pub fn script_name(&self, uri_path: &str) -> String {
         match self {
             Self::Exact(path) => path.clone(),
             Self::Prefix(prefix) =>
@@ -183,7 +772,11 @@ impl RoutePattern {
                     prefix.to_owned()
                 } else {
                     format!("/{}", prefix)
-                }
+                },
+            Self::Parameterised { .. } => match self.match_parameters(uri_path) {
+                Some(m) => uri_path[..m.matched_len].to_owned(),
+                None => "".to_owned(),
+            },
         }
     }
 
@@ -191,6 +784,8 @@ impl RoutePattern {
         match self {
             Self::Exact(path) => path.to_owned(),
             Self::Prefix(prefix) => format!("{}/...", prefix),
+            Self::Parameterised { template, is_prefix: false } => template.to_owned(),
+            Self::Parameterised { template, is_prefix: true } => format!("{}/...", template),
         }
     }
 
@@ -202,25 +797,153 @@ impl RoutePattern {
         let path_base = match self {
             Self::Exact(path) => path,
             Self::Prefix(prefix) => prefix,
+            Self::Parameterised { .. } => return match self.match_parameters(uri_path) {
+                Some(m) => uri_path[m.matched_len..].to_owned(),
+                None => "".to_owned(),
+            },
         };
         // It is possible that a root path request matching /... returns a None here,
         // so in that case the appropriate return is "".
         uri_path.strip_prefix(path_base).unwrap_or("").to_owned()
     }
 
-    pub fn append(&self, other: &RoutePattern) -> Self {
+    /// The names of the route's parameters, in the order they appear in the route.
+    pub fn parameter_names(&self) -> Vec<String> {
+        match self {
+            Self::Parameterised { template, .. } => template.split('/').filter_map(parameter_name).map(|n| n.to_owned()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The values of the route's named parameters in the given path, in the order
+    /// they appear in the route. Values are URL-decoded. Routes without parameters
+    /// (or paths that do not match) produce an empty list.
+    pub fn parameters(&self, uri_path: &str) -> Vec<(String, String)> {
+        match self.match_parameters(uri_path) {
+            Some(m) => m.parameters,
+            None => vec![],
+        }
+    }
+
+    fn match_parameters(&self, uri_path: &str) -> Option<ParameterMatch> {
+        let (template, is_prefix) = match self {
+            Self::Parameterised { template, is_prefix } => (template, *is_prefix),
+            _ => return None,
+        };
+
+        let template_segments: Vec<&str> = template.split('/').collect();
+        let path_segments: Vec<&str> = uri_path.split('/').collect();
+        let length_ok = if is_prefix {
+            path_segments.len() >= template_segments.len()
+        } else {
+            path_segments.len() == template_segments.len()
+        };
+        if !length_ok {
+            return None;
+        }
+
+        let mut parameters = vec![];
+        for (template_segment, path_segment) in template_segments.iter().zip(&path_segments) {
+            match parameter_name(template_segment) {
+                Some(name) => {
+                    if path_segment.is_empty() {
+                        return None;
+                    }
+                    parameters.push((name.to_owned(), url_escape::decode(path_segment).to_string()));
+                },
+                None => if template_segment != path_segment {
+                    return None;
+                },
+            }
+        }
+
+        // The matched segments plus the slashes between them
+        let segment_count = template_segments.len();
+        let matched_len = path_segments[..segment_count].iter().map(|s| s.len()).sum::<usize>() + segment_count - 1;
+        Some(ParameterMatch { parameters, matched_len })
+    }
+
+    pub fn append(&self, other: &RoutePattern) -> anyhow::Result<Self> {
         match self {
             Self::Exact(path) => other.prepend(path),
             Self::Prefix(prefix) => other.prepend(prefix),
+            Self::Parameterised { template, .. } => other.prepend(template),
+        }
+    }
+
+    fn prepend(&self, prefix: &str) -> anyhow::Result<Self> {
+        match self {
+            Self::Exact(subpath) => Self::from_path(concat_no_duplicate_slash(prefix, subpath), false),
+            Self::Prefix(subpath) => Self::from_path(concat_no_duplicate_slash(prefix, subpath), true),
+            Self::Parameterised { template, is_prefix } => Self::from_path(concat_no_duplicate_slash(prefix, template), *is_prefix),
+        }
+    }
+}
+
+impl HostPattern {
+    pub fn parse(host_text: &str) -> anyhow::Result<Self> {
+        let host = normalise_host(host_text);
+        if host.is_empty() {
+            anyhow::bail!("Host must not be empty");
+        }
+        if host.contains(':') || host.contains('/') {
+            anyhow::bail!("Invalid host '{}': expected a host name without scheme, port or path", host_text);
+        }
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => Ok(Self::Wildcard(domain.to_owned())),
+            None if !host.contains('*') => Ok(Self::Exact(host)),
+            _ => Err(anyhow::anyhow!("Invalid host '{}': a wildcard may only be used as the first label, e.g. '*.example.com'", host_text)),
         }
     }
 
-    fn prepend(&self, prefix: &str) -> Self {
+    pub fn is_match(&self, host: &str) -> bool {
+        let host = normalise_host(host);
         match self {
-            Self::Exact(subpath) => Self::Exact(concat_no_duplicate_slash(prefix, subpath)),
-            Self::Prefix(subpath) => Self::Prefix(concat_no_duplicate_slash(prefix, subpath)),
+            Self::Exact(name) => *name == host,
+            Self::Wildcard(domain) => host
+                .strip_suffix(domain.as_str())
+                .map(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.'))
+                .unwrap_or(false),
         }
     }
+
+    pub fn specificity(&self) -> HostSpecificity {
+        match self {
+            Self::Exact(_) => HostSpecificity::Exact,
+            Self::Wildcard(domain) => HostSpecificity::Wildcard(domain.split('.').count()),
+        }
+    }
+
+    pub fn original_text(&self) -> String {
+        match self {
+            Self::Exact(name) => name.to_owned(),
+            Self::Wildcard(domain) => format!("*.{}", domain),
+        }
+    }
+}
+
+// Host names are case-insensitive, and may be written fully qualified (with a trailing dot)
+fn normalise_host(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+const PARAMETER_MARKER: char = ':';
+
+// A segment such as `:id` is a parameter called `id`
+pub(crate) fn parameter_name(segment: &str) -> Option<&str> {
+    segment.strip_prefix(PARAMETER_MARKER).filter(|name| !name.is_empty())
+}
+
+struct ParameterMatch {
+    parameters: Vec<(String, String)>,
+    // The number of bytes of the path matched by the template
+    matched_len: usize,
+}
+
+fn parse_methods(methods: Option<&[String]>) -> anyhow::Result<Option<Vec<Method>>> {
+    methods
+        .map(|m| m.iter().map(|text| parse_method(text)).collect())
+        .transpose()
 }
 
 fn concat_no_duplicate_slash(prefix: &str, suffix: &str) -> String {
@@ -241,29 +964,226 @@ fn concat_no_duplicate_slash(prefix: &str, suffix: &str) -> String {
 
 impl RoutingTable {
     pub fn build(source: &WasmHandlerConfiguration, global_context: RequestGlobalContext) -> anyhow::Result<RoutingTable> {
-        let user_entries = Self::build_from_handler_config_entries(&source.entries)?;
+        let user_entries = Self::build_from_handler_config_entries(source.entries_with_role(HandlerRole::Route))?;
+        // Started before the _routes functions run, so that they can time out too
+        let epoch_ticker = EpochTicker::start(source.entries.iter().filter_map(|e| e.module.get_compiled_module().ok()).map(|(_, engine)| engine));
         let full_user_entries = augment_dynamic_routes(user_entries, &global_context)?;
+        let static_entries = source.static_entries.iter()
+            .map(RoutingTableEntry::build_from_static_files_config_entry)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let redirect_entries = source.redirect_entries.iter()
+            .map(RoutingTableEntry::build_from_redirect_config_entry)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let rewrites = source.rewrite_entries.iter()
+            .map(|r| RewriteRule::build(r).with_context(|| format!("Invalid rewrite for route {}", r.route)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-        let built_in_entries = Self::inbuilt_patterns();
+        let not_found = Self::build_unmounted_entry(source, HandlerRole::NotFound)?;
+        let error_handler = Self::build_unmounted_entry(source, HandlerRole::Error)?;
+
+        // The readiness route is newer than the other built-ins, so a user
+        // route already at that path on every host replaces it rather than
+        // being shadowed. One for only some hosts is shadowed, as for the
+        // other built-ins, so that the check is still served on every host.
+        let readiness_check = if declares_route(full_user_entries.iter().chain(&static_entries).chain(&redirect_entries), READINESS_ROUTE) {
+            tracing::info!(route = READINESS_ROUTE, "Built-in readiness check replaced by user route");
+            None
+        } else {
+            Some(Self::build_readiness_check(full_user_entries.iter().chain(&not_found).chain(&error_handler)))
+        };
+        let built_in_entries = Self::inbuilt_patterns(&global_context, readiness_check);
+
+        let entries: Vec<_> = built_in_entries.into_iter()
+            .chain(full_user_entries)
+            .chain(static_entries)
+            .chain(redirect_entries)
+            .map(Arc::new)
+            .collect();
+        let tree = Self::build_tree(&entries);
 
-        let entries = built_in_entries.into_iter().chain(full_user_entries).collect();
         Ok(Self {
-            entries,
+            entries: Arc::new(entries),
+            tree: Arc::new(tree),
+            not_found: not_found.map(Arc::new),
+            error_handler: error_handler.map(Arc::new),
+            rewrites: Arc::new(rewrites),
             global_context,
+            _epoch_ticker: epoch_ticker,
         })
     }
 
-    fn build_from_handler_config_entries(entries: &[WasmHandlerConfigurationEntry]) -> anyhow::Result<Vec<RoutingTableEntry>> {
+    fn build_unmounted_entry(source: &WasmHandlerConfiguration, role: HandlerRole) -> anyhow::Result<Option<RoutingTableEntry>> {
+        let mut entries = Self::build_from_handler_config_entries(source.entries_with_role(role))?;
+        match entries.len() {
+            0 | 1 => Ok(entries.pop()),
+            _ => Err(anyhow::anyhow!("Only one {:?} handler may be specified, but found {}", role, entries.len())),
+        }
+    }
+
+    /// Describes every entry in the table, including those declared by
+    /// `_routes` functions, and the rewrite rules.
+    pub fn describe_routes(&self) -> RoutesInfo {
+        let unmounted = [
+            (&self.not_found, HandlerRole::NotFound),
+            (&self.error_handler, HandlerRole::Error),
+        ];
+        let routes = self.entries.iter()
+            .map(|e| e.info(HandlerRole::Route))
+            .chain(unmounted.iter().filter_map(|(e, role)| e.as_ref().map(|e| e.info(*role))))
+            .collect();
+        let rewrites = self.rewrites.iter()
+            .map(|r| RewriteInfo {
+                route: r.route_pattern.original_text(),
+                host: r.host.as_ref().map(|h| h.original_text()),
+                to: r.target.original_text(),
+            })
+            .collect();
+        RoutesInfo { routes, rewrites }
+    }
+
+    /// Finds routes that are declared more than once, or that can never be
+    /// reached because other routes always take precedence over them.
+    pub fn conflicts(&self) -> Vec<RouteConflict> {
+        find_conflicts(&self.entries)
+    }
+
+    /// Reports any route conflicts. Normally these are logged as warnings, but
+    /// in strict mode they are returned as an error.
+    pub fn check_conflicts(&self, strict: bool) -> anyhow::Result<()> {
+        let conflicts = self.conflicts();
+        if strict && !conflicts.is_empty() {
+            let descriptions: Vec<_> = conflicts.iter().map(|c| format!("  - {}", c)).collect();
+            anyhow::bail!("Found {} route conflict(s):\n{}", conflicts.len(), descriptions.join("\n"));
+        }
+        for conflict in &conflicts {
+            tracing::warn!(%conflict, "Route conflict");
+        }
+        Ok(())
+    }
+
+    fn build_tree(entries: &[Arc<RoutingTableEntry>]) -> RouteTree {
+        let mut tree = RouteTree::default();
+        for (index, entry) in entries.iter().enumerate() {
+            let (segments, is_prefix) = entry.route_pattern.tree_segments();
+            tree.insert(&segments, is_prefix, index);
+        }
+        tree
+    }
+
+    fn build_from_handler_config_entries<'a>(entries: impl Iterator<Item = &'a WasmHandlerConfigurationEntry>) -> anyhow::Result<Vec<RoutingTableEntry>> {
         entries
-            .iter()
             .filter_map(|e| RoutingTableEntry::build_from_handler_config_entry(e))
             .collect()
     }
 
-    fn inbuilt_patterns() -> Vec<RoutingTableEntry> {
-        vec![
+    fn build_readiness_check<'a>(entries: impl Iterator<Item = &'a RoutingTableEntry>) -> ReadinessCheck {
+        let mut readiness_check = ReadinessCheck::default();
+        for entry in entries {
+            if let RouteHandler::Wasm(w) = &entry.handler_info {
+                readiness_check.add_module(w, entry.unique_key());
+            }
+        }
+        readiness_check
+    }
+
+    fn inbuilt_patterns(global_context: &RequestGlobalContext, readiness_check: Option<ReadinessCheck>) -> Vec<RoutingTableEntry> {
+        let mut entries = vec![
             RoutingTableEntry::inbuilt("/healthz", RouteHandler::HealthCheck),
-        ]
+        ];
+        if let Some(readiness_check) = readiness_check {
+            entries.push(RoutingTableEntry::inbuilt(READINESS_ROUTE, RouteHandler::Readiness(Arc::new(readiness_check))));
+        }
+        if global_context.metrics.is_some() {
+            entries.push(RoutingTableEntry::inbuilt(METRICS_ROUTE, RouteHandler::Metrics));
+        }
+        entries
+    }
+}
+
+fn request_body_error_response(error: &RequestBodyError, req: &Parts, global_context: &RequestGlobalContext) -> Response<Body> {
+    match error {
+        RequestBodyError::TooLarge(_) | RequestBodyError::Read(_) | RequestBodyError::TimedOut => tracing::info!(%error, uri = %req.uri, "Rejected request body"),
+        RequestBodyError::Spool(_) => tracing::error!(%error, uri = %req.uri, "Failed to store request body"),
+    }
+    global_context.error_responses.apply_page(error.response())
+}
+
+type PathKey = (Vec<Option<String>>, bool);
+
+// Whether any of the entries is declared at exactly the given path for all
+// hosts
+fn declares_route<'a>(mut entries: impl Iterator<Item = &'a RoutingTableEntry>, path: &str) -> bool {
+    let path_key = RoutePattern::Exact(path.to_owned()).path_key();
+    entries.any(|e| e.host.is_none() && e.route_pattern.path_key() == path_key)
+}
+
+fn find_conflicts(entries: &[Arc<RoutingTableEntry>]) -> Vec<RouteConflict> {
+    // Built-in routes take precedence on every host, so shadow user routes
+    // regardless of their host. User routes only compete with other declarations
+    // of the same path on the same host.
+    let mut inbuilt_paths: HashMap<PathKey, usize> = HashMap::new();
+    let mut declarations: HashMap<(Option<String>, PathKey), Vec<usize>> = HashMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let path_key = entry.route_pattern.path_key();
+        if entry.handler_info.is_inbuilt() {
+            inbuilt_paths.insert(path_key, index);
+        } else {
+            let host_key = entry.host.as_ref().map(|h| h.original_text());
+            declarations.entry((host_key, path_key)).or_default().push(index);
+        }
+    }
+
+    let mut conflicts = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.handler_info.is_inbuilt() {
+            continue;
+        }
+        let route = entry.route_pattern.original_text();
+        let path_key = entry.route_pattern.path_key();
+
+        if let Some(inbuilt_index) = inbuilt_paths.get(&path_key) {
+            conflicts.push(RouteConflict::Unreachable {
+                route,
+                handler: entry.describe(),
+                shadowed_by: entries[*inbuilt_index].describe(),
+            });
+            continue;
+        }
+
+        // Among declarations of the same route, the last one wins
+        let host_key = entry.host.as_ref().map(|h| h.original_text());
+        let later_overlapping: Vec<_> = declarations[&(host_key, path_key)]
+            .iter()
+            .filter(|i| **i > index)
+            .map(|i| &entries[*i])
+            .filter(|later| later.accepts_any_method_of(entry))
+            .collect();
+        let first_overlapping = match later_overlapping.first() {
+            None => continue,
+            Some(later) => later.describe(),
+        };
+
+        let is_fully_shadowed = match &entry.methods {
+            None => later_overlapping.iter().any(|later| later.methods.is_none()),
+            Some(methods) => methods.iter().all(|m| later_overlapping.iter().any(|later| later.accepts_method(m))),
+        };
+        if is_fully_shadowed {
+            conflicts.push(RouteConflict::Unreachable { route, handler: entry.describe(), shadowed_by: first_overlapping });
+        } else {
+            conflicts.push(RouteConflict::Duplicate { route, handler: entry.describe(), duplicated_by: first_overlapping });
+        }
+    }
+    conflicts
+}
+
+impl Display for RouteConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable { route, handler, shadowed_by } =>
+                write!(f, "Route {} ({}) can never be reached: {} takes precedence", route, handler, shadowed_by),
+            Self::Duplicate { route, handler, duplicated_by } =>
+                write!(f, "Route {} ({}) is declared again by {}, which takes precedence for the methods they share", route, handler, duplicated_by),
+        }
     }
 }
 
@@ -276,16 +1196,17 @@ fn augment_dynamic_routes(base_entries: Vec<RoutingTableEntry>, global_context:
 fn augment_one_with_dynamic_routes(routing_table_entry: RoutingTableEntry, global_context: &RequestGlobalContext) -> anyhow::Result<Vec<RoutingTableEntry>> {
     match &routing_table_entry.handler_info {
         RouteHandler::Wasm(w) => augment_one_wasm_with_dynamic_routes(&routing_table_entry, w, global_context),
-        RouteHandler::HealthCheck => Ok(vec![routing_table_entry]),
+        RouteHandler::HealthCheck | RouteHandler::Metrics | RouteHandler::Readiness(_) | RouteHandler::StaticFiles(_) | RouteHandler::Redirect(_) => Ok(vec![routing_table_entry]),
     }
 }
 
 fn augment_one_wasm_with_dynamic_routes(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, global_context: &RequestGlobalContext) -> anyhow::Result<Vec<RoutingTableEntry>> {
-    let redirects = prepare_stdio_streams(vec![] /* TODO: eww */, global_context, routing_table_entry.unique_key())?;
+    let redirects = prepare_stdio_streams(RequestBody::empty(), ClientWait::default(), global_context, routing_table_entry.unique_key())?;
 
     let ctx = build_wasi_context_for_dynamic_route_query(redirects.streams);
     let link_options = WasmLinkOptions::none();
-    let (store, instance) = prepare_wasm_instance(ctx, &wasm_route_handler.wasm_module_source, link_options)?;
+    let limits = wasm_route_handler.execution_limits(global_context);
+    let (store, instance) = prepare_wasm_instance(ctx, &wasm_route_handler.wasm_module_source, link_options, limits)?;
 
     match run_prepared_wasm_instance_if_present(instance, store, "_routes") {
         RunWasmResult::WasmError(e) => Err(e),
@@ -295,58 +1216,527 @@ fn augment_one_wasm_with_dynamic_routes(routing_table_entry: &RoutingTableEntry,
             let dynamic_routes_text = std::str::from_utf8(&*out)?;
             let dynamic_routes = interpret_routes(dynamic_routes_text)?;
         
-            let mut dynamic_route_entries = append_all_dynamic_routes(routing_table_entry, wasm_route_handler, dynamic_routes);
-            dynamic_route_entries.reverse();
-            dynamic_route_entries.push(routing_table_entry.clone());
-            Ok(dynamic_route_entries)
+            // Declaration order matters only for ties, where the last entry wins, so
+            // _routes entries go after the parent they were declared by.
+            let dynamic_route_entries = append_all_dynamic_routes(routing_table_entry, wasm_route_handler, dynamic_routes)?;
+            Ok(std::iter::once(routing_table_entry.clone()).chain(dynamic_route_entries).collect())
         }
     }
 }
 
-fn append_all_dynamic_routes(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_routes: DynamicRoutes) -> Vec<RoutingTableEntry> {
+fn append_all_dynamic_routes(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_routes: DynamicRoutes) -> anyhow::Result<Vec<RoutingTableEntry>> {
     dynamic_routes
         .subpath_entrypoints.iter()
-        .map(|dr| append_one_dynamic_route(routing_table_entry, wasm_route_handler, &dr.0, &dr.1))
+        .map(|dr| append_one_dynamic_route(routing_table_entry, wasm_route_handler, dr))
         .collect()
 }
 
-fn append_one_dynamic_route(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_route_pattern: &RoutePattern, entrypoint: &str) -> RoutingTableEntry {
+fn append_one_dynamic_route(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_route: &DynamicRoute) -> anyhow::Result<RoutingTableEntry> {
+    let route_pattern = routing_table_entry.route_pattern.append(&dynamic_route.route_pattern)
+        .with_context(|| format!("Invalid route {} from _routes of route {}", dynamic_route.route_pattern.original_text(), routing_table_entry.route_pattern.original_text()))?;
     let mut subpath_handler = wasm_route_handler.clone();
-    subpath_handler.entrypoint = entrypoint.to_owned();
-    RoutingTableEntry {
-        route_pattern: routing_table_entry.route_pattern.append(dynamic_route_pattern),
+    subpath_handler.entrypoint = dynamic_route.entrypoint.clone();
+    Ok(RoutingTableEntry {
+        route_pattern,
+        host: routing_table_entry.host.clone(),
         handler_info: RouteHandler::Wasm(subpath_handler),
-    }
+        methods: dynamic_route.methods.clone().or_else(|| routing_table_entry.methods.clone()),
+        is_dynamic: true,
+        error_format: routing_table_entry.error_format,
+        source: routing_table_entry.source.clone(),
+    })
 }
 
 fn build_wasi_context_for_dynamic_route_query(redirects: crate::wasm_module::IOStreamRedirects) -> wasi_common::WasiCtx {
     let builder = wasi_cap_std_sync::WasiCtxBuilder::new()
         .stderr(Box::new(redirects.stderr))
-        .stdout(Box::new(redirects.stdout));
+        .stdout(redirects.stdout);
 
     builder.build()
 }
 
 #[cfg(test)]
 mod test {
+    use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
     use super::*;
+    use crate::error_response::ErrorResponseSettings;
+    use crate::handler_loader::HandlerInfo;
+    use crate::resource_limits::ResourceLimits;
+    use crate::wasm_module::{WasmEngines, WasmModuleSource};
+    use crate::response_stream::DEFAULT_CLIENT_TIMEOUT;
+    use crate::worker_pool::{WorkerPool, DEFAULT_QUEUE_DEPTH};
+
+    const TEST_HOST: &str = "localhost";
+    const EMPTY_MODULE_WAT: &str = r#"(module (func (export "_start")) (func (export "other")))"#;
+
+    fn test_global_context() -> RequestGlobalContext {
+        RequestGlobalContext {
+            base_log_dir: tempfile::tempdir().expect("Failed to create log dir").into_path(),
+            default_host: "localhost:3000".to_owned(),
+            use_tls: false,
+            global_env_vars: HashMap::new(),
+            error_responses: Arc::new(ErrorResponseSettings::default()),
+            metrics: None,
+            module_timeout: None,
+            resource_limits: ResourceLimits::default(),
+            max_body_size: None,
+            client_timeout: DEFAULT_CLIENT_TIMEOUT,
+            worker_pool: Arc::new(WorkerPool::new(2, DEFAULT_QUEUE_DEPTH).unwrap()),
+        }
+    }
+
+    fn handler_entry(route: &str, entrypoint: Option<&str>, wat: &[u8]) -> WasmHandlerConfigurationEntry {
+        let module = WasmModuleSource::from_module_bytes(Arc::new(wat.to_vec()), WasmEngines::new(&PathBuf::from("no-such-cache.toml"), None, false).unwrap().for_module(false).unwrap())
+            .expect("Failed to compile test module");
+        WasmHandlerConfigurationEntry {
+            info: HandlerInfo {
+                name: route.to_owned(),
+                route: route.to_owned(),
+                entrypoint: entrypoint.map(|s| s.to_owned()),
+                allowed_hosts: None,
+                http_max_concurrency: None,
+                volume_mounts: HashMap::new(),
+                argv: None,
+                methods: None,
+                host: None,
+                error_format: None,
+                timeout_ms: None,
+                max_fuel: None,
+                max_body_size: None,
+                nph: false,
+                resource_limits: ResourceLimits::default(),
+                role: HandlerRole::Route,
+                source: HandlerSource::ModuleMap { path: PathBuf::from("modules.toml") },
+            },
+            module,
+        }
+    }
+
+    fn with_methods(mut entry: WasmHandlerConfigurationEntry, methods: &[&str]) -> WasmHandlerConfigurationEntry {
+        entry.info.methods = Some(methods.iter().map(|m| m.to_string()).collect());
+        entry
+    }
+
+    fn with_host(mut entry: WasmHandlerConfigurationEntry, host: &str) -> WasmHandlerConfigurationEntry {
+        entry.info.host = Some(host.to_owned());
+        entry
+    }
+
+    fn with_role(mut entry: WasmHandlerConfigurationEntry, role: HandlerRole) -> WasmHandlerConfigurationEntry {
+        entry.info.role = role;
+        entry
+    }
+
+    fn build_table(entries: Vec<WasmHandlerConfigurationEntry>) -> RoutingTable {
+        RoutingTable::build(&WasmHandlerConfiguration { entries, ..Default::default() }, test_global_context())
+            .expect("Failed to build routing table")
+    }
+
+    fn matched_route(table: &RoutingTable, path: &str) -> String {
+        table.route_for(TEST_HOST, path, &Method::GET).expect("Expected a route to match").route_pattern.original_text()
+    }
+
+    fn matched_entrypoint(table: &RoutingTable, path: &str) -> String {
+        matched_entrypoint_for_method(table, path, &Method::GET)
+    }
+
+    fn matched_entrypoint_for_method(table: &RoutingTable, path: &str, method: &Method) -> String {
+        match &table.route_for(TEST_HOST, path, method).expect("Expected a route to match").handler_info {
+            RouteHandler::Wasm(w) => w.entrypoint.clone(),
+            other => panic!("Expected a Wasm handler but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn most_specific_route_wins_regardless_of_declaration_order() {
+        let routes = vec!["/...", "/api/...", "/api/admin/...", "/api/admin/status"];
+        let mut reversed = routes.clone();
+        reversed.reverse();
+
+        for declared in &[routes, reversed] {
+            let table = build_table(declared.iter().map(|r| handler_entry(r, None, EMPTY_MODULE_WAT.as_bytes())).collect());
+
+            assert_eq!("/api/admin/status", matched_route(&table, "/api/admin/status"));
+            assert_eq!("/api/admin/...", matched_route(&table, "/api/admin/status/detail"));
+            assert_eq!("/api/admin/...", matched_route(&table, "/api/admin"));
+            assert_eq!("/api/...", matched_route(&table, "/api/users"));
+            assert_eq!("/api/...", matched_route(&table, "/api/administrator"));
+            assert_eq!("/...", matched_route(&table, "/apis"));
+        }
+    }
+
+    #[test]
+    fn route_tree_agrees_with_pattern_matching() {
+        let patterns = ["/", "/...", "/foo", "/foo/...", "/foo/bar", "/foo/:id", "/foo/:id/...", "/:a/:b", "/foo/:", "/foo//..."];
+        let paths = ["", "/", "/foo", "/foo/", "/foobar", "/foo/bar", "/foo/bar/baz", "/foo//x", "/foo/:", "/x/y", "foo", "/healthz"];
+        let table = build_table(patterns.iter().map(|p| handler_entry(p, None, EMPTY_MODULE_WAT.as_bytes())).collect());
+
+        for path in paths {
+            let tree_matches = table.tree.matches(path);
+            for (index, entry) in table.entries.iter().enumerate() {
+                assert_eq!(
+                    entry.route_pattern.is_match(path),
+                    tree_matches.contains(&index),
+                    "Tree and pattern disagree on whether {} matches {}", entry.route_pattern.original_text(), path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn last_declared_route_wins_ties() {
+        let table = build_table(vec![
+            handler_entry("/dup/...", Some("_start"), EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/dup/...", Some("other"), EMPTY_MODULE_WAT.as_bytes()),
+        ]);
+
+        assert_eq!("other", matched_entrypoint(&table, "/dup/thing"));
+    }
+
+    #[test]
+    fn inbuilt_routes_take_precedence_over_user_routes() {
+        let table = build_table(vec![
+            handler_entry("/healthz", None, EMPTY_MODULE_WAT.as_bytes()),
+        ]);
+
+        let entry = table.route_for(TEST_HOST, "/healthz", &Method::GET).expect("Expected a route to match");
+        assert!(matches!(entry.handler_info, RouteHandler::HealthCheck));
+    }
+
+    #[test]
+    fn user_routes_replace_the_readiness_route() {
+        let table = build_table(vec![
+            handler_entry("/readyz", Some("ready"), EMPTY_MODULE_WAT.as_bytes()),
+        ]);
+
+        assert_eq!("ready", matched_entrypoint(&table, "/readyz"));
+        assert!(table.conflicts().is_empty());
+        assert!(!table.describe_routes().routes.iter().any(|r| r.kind == "readiness_check"));
+    }
+
+    #[test]
+    fn host_specific_user_routes_do_not_replace_the_readiness_route() {
+        let table = build_table(vec![
+            with_host(handler_entry("/readyz", Some("ready"), EMPTY_MODULE_WAT.as_bytes()), "api.example.com"),
+        ]);
+
+        for host in ["api.example.com", TEST_HOST] {
+            let entry = table.route_for(host, "/readyz", &Method::GET).expect("Expected a route to match");
+            assert!(matches!(entry.handler_info, RouteHandler::Readiness(_)));
+        }
+        assert_eq!(
+            vec![RouteConflict::Unreachable {
+                route: "/readyz".to_owned(),
+                handler: "module '/readyz', entrypoint 'ready', host 'api.example.com'".to_owned(),
+                shadowed_by: "the built-in readiness check".to_owned(),
+            }],
+            table.conflicts()
+        );
+    }
+
+    #[test]
+    fn readiness_route_is_built_in_if_no_user_route_declares_it() {
+        let table = build_table(vec![
+            handler_entry("/readyz/...", None, EMPTY_MODULE_WAT.as_bytes()),
+        ]);
+
+        let entry = table.route_for(TEST_HOST, "/readyz", &Method::GET).expect("Expected a route to match");
+        assert!(matches!(entry.handler_info, RouteHandler::Readiness(_)));
+    }
+
+    fn matched_entrypoint_for_host(table: &RoutingTable, host: &str, path: &str) -> String {
+        match &table.route_for(host, path, &Method::GET).expect("Expected a route to match").handler_info {
+            RouteHandler::Wasm(w) => w.entrypoint.clone(),
+            other => panic!("Expected a Wasm handler but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn routes_are_selected_by_host() {
+        let table = build_table(vec![
+            handler_entry("/...", Some("_start"), EMPTY_MODULE_WAT.as_bytes()),
+            with_host(handler_entry("/...", Some("api"), EMPTY_MODULE_WAT.as_bytes()), "api.example.com"),
+            with_host(handler_entry("/...", Some("wildcard"), EMPTY_MODULE_WAT.as_bytes()), "*.example.com"),
+            with_host(handler_entry("/...", Some("deep_wildcard"), EMPTY_MODULE_WAT.as_bytes()), "*.eu.example.com"),
+            with_host(handler_entry("/admin", Some("admin"), EMPTY_MODULE_WAT.as_bytes()), "admin.example.com"),
+        ]);
+
+        assert_eq!("api", matched_entrypoint_for_host(&table, "api.example.com", "/users"));
+        assert_eq!("api", matched_entrypoint_for_host(&table, "API.Example.com.", "/users"));
+        assert_eq!("wildcard", matched_entrypoint_for_host(&table, "www.example.com", "/users"));
+        assert_eq!("wildcard", matched_entrypoint_for_host(&table, "a.b.example.com", "/users"));
+        assert_eq!("deep_wildcard", matched_entrypoint_for_host(&table, "fr.eu.example.com", "/users"));
+        // The wildcard does not match the bare domain
+        assert_eq!("_start", matched_entrypoint_for_host(&table, "example.com", "/users"));
+        assert_eq!("_start", matched_entrypoint_for_host(&table, "notexample.com", "/users"));
+        assert_eq!("_start", matched_entrypoint_for_host(&table, "localhost", "/users"));
+        // If the host's own routes do not match the path, other hosts' routes are tried
+        assert_eq!("admin", matched_entrypoint_for_host(&table, "admin.example.com", "/admin"));
+        assert_eq!("wildcard", matched_entrypoint_for_host(&table, "admin.example.com", "/users"));
+    }
+
+    #[test]
+    fn host_match_takes_precedence_over_route_specificity() {
+        let table = build_table(vec![
+            handler_entry("/status", Some("_start"), EMPTY_MODULE_WAT.as_bytes()),
+            with_host(handler_entry("/...", Some("other"), EMPTY_MODULE_WAT.as_bytes()), "api.example.com"),
+        ]);
+
+        assert_eq!("other", matched_entrypoint_for_host(&table, "api.example.com", "/status"));
+        assert_eq!("_start", matched_entrypoint_for_host(&table, "www.example.com", "/status"));
+        assert_eq!(RoutingFailure::NotFound, table.route_for("www.example.com", "/other", &Method::GET).unwrap_err());
+    }
+
+    #[test]
+    fn inbuilt_routes_serve_all_hosts() {
+        let table = build_table(vec![
+            with_host(handler_entry("/...", None, EMPTY_MODULE_WAT.as_bytes()), "api.example.com"),
+        ]);
+
+        let entry = table.route_for("api.example.com", "/healthz", &Method::GET).expect("Expected a route to match");
+        assert!(matches!(entry.handler_info, RouteHandler::HealthCheck));
+    }
+
+    #[test]
+    fn invalid_host_is_an_error() {
+        for host in &["", "*", "*.", "api.*.com", "example.com:3000", "http://example.com"] {
+            let source = WasmHandlerConfiguration {
+                entries: vec![with_host(handler_entry("/...", None, EMPTY_MODULE_WAT.as_bytes()), host)],
+                ..Default::default()
+            };
+            let result = RoutingTable::build(&source, test_global_context());
+            assert!(result.is_err(), "Expected host '{}' to be rejected", host);
+        }
+    }
+
+    #[test]
+    fn can_parse_host_patterns() {
+        assert_eq!(HostPattern::Exact("api.example.com".to_owned()), HostPattern::parse("API.example.com").unwrap());
+        assert_eq!(HostPattern::Wildcard("example.com".to_owned()), HostPattern::parse("*.example.com").unwrap());
+        assert_eq!("*.example.com", HostPattern::parse("*.example.com").unwrap().original_text());
+        assert!(HostPattern::parse("*.example.com").unwrap().specificity() < HostPattern::parse("*.eu.example.com").unwrap().specificity());
+        assert!(HostPattern::parse("*.eu.example.com").unwrap().specificity() < HostPattern::parse("example.com").unwrap().specificity());
+    }
+
+    #[test]
+    fn parameterised_routes_without_a_leading_slash_have_a_specificity() {
+        assert_eq!(RouteSpecificity::Parameterised(0), RoutePattern::parse(":id").unwrap().specificity());
+        assert_eq!(RouteSpecificity::Prefix(0, 0), RoutePattern::parse(":id/...").unwrap().specificity());
+        assert!(RoutePattern::parse(":id").unwrap().specificity() < RoutePattern::parse("/things").unwrap().specificity());
+    }
+
+    #[test]
+    fn not_found_handler_is_not_routed() {
+        let table = build_table(vec![
+            handler_entry("/things", None, EMPTY_MODULE_WAT.as_bytes()),
+            with_role(handler_entry("/...", Some("other"), EMPTY_MODULE_WAT.as_bytes()), HandlerRole::NotFound),
+        ]);
+
+        assert_eq!(RoutingFailure::NotFound, table.route_for(TEST_HOST, "/other", &Method::GET).unwrap_err());
+        match table.not_found.as_ref().map(|e| &e.handler_info) {
+            Some(RouteHandler::Wasm(w)) => assert_eq!("other", w.entrypoint),
+            other => panic!("Expected a Wasm not found handler but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_one_not_found_handler_is_allowed() {
+        let source = WasmHandlerConfiguration {
+            entries: vec![
+                with_role(handler_entry("/...", None, EMPTY_MODULE_WAT.as_bytes()), HandlerRole::NotFound),
+                with_role(handler_entry("/...", None, EMPTY_MODULE_WAT.as_bytes()), HandlerRole::NotFound),
+            ],
+            ..Default::default()
+        };
+        assert!(RoutingTable::build(&source, test_global_context()).is_err());
+    }
+
+    #[test]
+    fn routes_only_match_accepted_methods() {
+        let table = build_table(vec![
+            handler_entry("/api/...", Some("_start"), EMPTY_MODULE_WAT.as_bytes()),
+            with_methods(handler_entry("/api/admin/...", Some("other"), EMPTY_MODULE_WAT.as_bytes()), &["get", "HEAD"]),
+        ]);
+
+        assert_eq!("other", matched_entrypoint_for_method(&table, "/api/admin/users", &Method::GET));
+        assert_eq!("other", matched_entrypoint_for_method(&table, "/api/admin/users", &Method::HEAD));
+        // A less specific route that accepts the method is used in preference to a 405
+        assert_eq!("_start", matched_entrypoint_for_method(&table, "/api/admin/users", &Method::POST));
+    }
+
+    #[test]
+    fn unaccepted_method_is_reported_with_allowed_methods() {
+        let table = build_table(vec![
+            with_methods(handler_entry("/things/...", Some("_start"), EMPTY_MODULE_WAT.as_bytes()), &["GET"]),
+            with_methods(handler_entry("/things/...", Some("other"), EMPTY_MODULE_WAT.as_bytes()), &["PUT", "GET"]),
+        ]);
+
+        assert_eq!("other", matched_entrypoint_for_method(&table, "/things/1", &Method::GET));
+        assert_eq!("other", matched_entrypoint_for_method(&table, "/things/1", &Method::PUT));
+        assert_eq!(
+            RoutingFailure::MethodNotAllowed(vec![Method::GET, Method::PUT]),
+            table.route_for(TEST_HOST, "/things/1", &Method::DELETE).unwrap_err()
+        );
+        assert_eq!(RoutingFailure::NotFound, table.route_for(TEST_HOST, "/other", &Method::DELETE).unwrap_err());
+    }
+
+    #[test]
+    fn invalid_methods_are_rejected_at_build_time() {
+        let entries = vec![
+            with_methods(handler_entry("/", None, EMPTY_MODULE_WAT.as_bytes()), &["GET", "NOT A METHOD"]),
+        ];
+        let result = RoutingTable::build(&WasmHandlerConfiguration { entries, ..Default::default() }, test_global_context());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parameterised_routes_rank_between_exact_and_prefix() {
+        let table = build_table(
+            vec!["/users/:id/...", "/users/me", "/users/...", "/users/:id", "/users/:id/orders/:order", "/users/:id/:section/:item"]
+                .iter()
+                .map(|r| handler_entry(r, None, EMPTY_MODULE_WAT.as_bytes()))
+                .collect()
+        );
+
+        assert_eq!("/users/me", matched_route(&table, "/users/me"));
+        assert_eq!("/users/:id", matched_route(&table, "/users/42"));
+        assert_eq!("/users/:id/orders/:order", matched_route(&table, "/users/42/orders/7"));
+        assert_eq!("/users/:id/:section/:item", matched_route(&table, "/users/42/invoices/7"));
+        assert_eq!("/users/:id/...", matched_route(&table, "/users/42/orders"));
+        assert_eq!("/users/...", matched_route(&table, "/users"));
+    }
+
+    #[test]
+    fn dynamic_routes_use_most_specific_match() {
+        let wat = std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/module-maps/route-precedence.wat"))
+            .expect("Failed to read test module");
+        let table = build_table(vec![handler_entry("/dynamic/...", None, &wat)]);
+
+        assert_eq!("three", matched_entrypoint(&table, "/dynamic/one/two/three/four"));
+        assert_eq!("three", matched_entrypoint(&table, "/dynamic/one/two/three"));
+        assert_eq!("two", matched_entrypoint(&table, "/dynamic/one/two/four"));
+        assert_eq!("one", matched_entrypoint(&table, "/dynamic/one/four"));
+        assert_eq!("dup_second", matched_entrypoint(&table, "/dynamic/dup"));
+        assert_eq!("_start", matched_entrypoint(&table, "/dynamic/other"));
+    }
+
+    fn conflict_routes(table: &RoutingTable) -> Vec<(String, &'static str)> {
+        table.conflicts().into_iter().map(|c| match c {
+            RouteConflict::Unreachable { route, .. } => (route, "unreachable"),
+            RouteConflict::Duplicate { route, .. } => (route, "duplicate"),
+        }).collect()
+    }
+
+    #[test]
+    fn distinct_routes_do_not_conflict() {
+        let table = build_table(vec![
+            handler_entry("/...", None, EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/things", None, EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/things/...", None, EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/healthz/...", None, EMPTY_MODULE_WAT.as_bytes()),
+            with_methods(handler_entry("/forms", Some("_start"), EMPTY_MODULE_WAT.as_bytes()), &["GET"]),
+            with_methods(handler_entry("/forms", Some("other"), EMPTY_MODULE_WAT.as_bytes()), &["POST"]),
+            with_host(handler_entry("/things", None, EMPTY_MODULE_WAT.as_bytes()), "api.example.com"),
+        ]);
+
+        assert!(table.conflicts().is_empty());
+        assert!(table.check_conflicts(true).is_ok());
+    }
+
+    #[test]
+    fn duplicate_routes_are_reported() {
+        let table = build_table(vec![
+            handler_entry("/things", Some("_start"), EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/things", Some("other"), EMPTY_MODULE_WAT.as_bytes()),
+            with_methods(handler_entry("/forms", Some("_start"), EMPTY_MODULE_WAT.as_bytes()), &["GET", "POST"]),
+            with_methods(handler_entry("/forms", Some("other"), EMPTY_MODULE_WAT.as_bytes()), &["POST"]),
+            with_methods(handler_entry("/split", Some("_start"), EMPTY_MODULE_WAT.as_bytes()), &["GET", "POST"]),
+            with_methods(handler_entry("/split", Some("other"), EMPTY_MODULE_WAT.as_bytes()), &["POST"]),
+            with_methods(handler_entry("/split", Some("other"), EMPTY_MODULE_WAT.as_bytes()), &["GET"]),
+            handler_entry("/users/:id", Some("_start"), EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/users/:name", Some("other"), EMPTY_MODULE_WAT.as_bytes()),
+            with_host(handler_entry("/...", Some("_start"), EMPTY_MODULE_WAT.as_bytes()), "*.example.com"),
+            with_host(handler_entry("/...", Some("other"), EMPTY_MODULE_WAT.as_bytes()), "*.Example.com"),
+        ]);
+
+        assert_eq!(
+            vec![
+                ("/things".to_owned(), "unreachable"),
+                ("/forms".to_owned(), "duplicate"),
+                ("/split".to_owned(), "unreachable"),
+                ("/users/:id".to_owned(), "unreachable"),
+                ("/...".to_owned(), "unreachable"),
+            ],
+            conflict_routes(&table)
+        );
+    }
+
+    #[test]
+    fn routes_shadowed_by_inbuilt_routes_are_reported() {
+        let table = build_table(vec![
+            with_host(handler_entry("/healthz", None, EMPTY_MODULE_WAT.as_bytes()), "api.example.com"),
+        ]);
+
+        assert_eq!(
+            vec![RouteConflict::Unreachable {
+                route: "/healthz".to_owned(),
+                handler: "module '/healthz', entrypoint '_start', host 'api.example.com'".to_owned(),
+                shadowed_by: "the built-in health check".to_owned(),
+            }],
+            table.conflicts()
+        );
+    }
+
+    #[test]
+    fn conflicts_involving_dynamic_routes_are_reported() {
+        let wat = std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/module-maps/route-precedence.wat"))
+            .expect("Failed to read test module");
+        let table = build_table(vec![
+            handler_entry("/dynamic/...", None, &wat),
+            handler_entry("/dynamic/one/...", None, EMPTY_MODULE_WAT.as_bytes()),
+        ]);
+
+        let conflicts = table.conflicts();
+        assert_eq!(2, conflicts.len());
+        assert_eq!(
+            "Route /dynamic/one/... (module '/dynamic/...', entrypoint 'one', declared by _routes) can never be reached: module '/dynamic/one/...', entrypoint '_start' takes precedence",
+            conflicts[0].to_string()
+        );
+        assert_eq!(
+            "Route /dynamic/dup (module '/dynamic/...', entrypoint 'dup_first', declared by _routes) can never be reached: module '/dynamic/...', entrypoint 'dup_second', declared by _routes takes precedence",
+            conflicts[1].to_string()
+        );
+    }
+
+    #[test]
+    fn strict_mode_turns_conflicts_into_errors() {
+        let table = build_table(vec![
+            handler_entry("/things", None, EMPTY_MODULE_WAT.as_bytes()),
+            handler_entry("/things", None, EMPTY_MODULE_WAT.as_bytes()),
+        ]);
+
+        assert!(table.check_conflicts(false).is_ok());
+        let err = table.check_conflicts(true).expect_err("Expected conflicts to be an error in strict mode");
+        assert!(err.to_string().contains("Route /things"));
+    }
 
     #[test]
     fn should_produce_relative_path() {
         let uri_path = "/static/images/icon.png";
 
-        let rp1 = RoutePattern::parse("/static/...");
+        let rp1 = RoutePattern::parse("/static/...").unwrap();
         assert_eq!("/images/icon.png", rp1.relative_path(uri_path));
 
-        let rp2 = RoutePattern::parse("/static/images/icon.png");
+        let rp2 = RoutePattern::parse("/static/images/icon.png").unwrap();
         assert_eq!("", rp2.relative_path(uri_path));
 
         // According to the spec, if "/" matches "/...", then a single "/" should be set
-        let rp3 = RoutePattern::parse("/...");
+        let rp3 = RoutePattern::parse("/...").unwrap();
         assert_eq!("/", rp3.relative_path("/"));
 
         // According to the spec, if "/" matches the SCRIPT_NAME, then "" should be set
-        let rp4 = RoutePattern::parse("/");
+        let rp4 = RoutePattern::parse("/").unwrap();
         assert_eq!("", rp4.relative_path("/"));
 
         // As a degenerate case, if the path does not match the prefix,
@@ -354,58 +1744,161 @@ mod test {
         // a relative path from the given path. While this is a no-op in
         // current Wagi, conceivably we could some day have to alter this
         // behavior. So this test is a canary for a breaking change.
-        let rp5 = RoutePattern::parse("/foo");
+        let rp5 = RoutePattern::parse("/foo").unwrap();
         assert_eq!("", rp5.relative_path("/bar"));
     }
 
     #[test]
     fn exact_patterns_should_match_exact() {
-        let pattern = RoutePattern::parse("/foo");
+        let pattern = RoutePattern::parse("/foo").unwrap();
         assert!(pattern.is_match("/foo"));
     }
 
     #[test]
     fn exact_patterns_should_consider_trailing_slash() {
-        let pattern1 = RoutePattern::parse("/foo");
+        let pattern1 = RoutePattern::parse("/foo").unwrap();
         assert!(pattern1.is_match("/foo"));
         assert!(!pattern1.is_match("/foo/"));
 
-        let pattern2 = RoutePattern::parse("/foo/");
+        let pattern2 = RoutePattern::parse("/foo/").unwrap();
         assert!(!pattern2.is_match("/foo"));
         assert!(pattern2.is_match("/foo/"));
     }
 
     #[test]
     fn exact_patterns_should_not_match_subpaths() {
-        let pattern = RoutePattern::parse("/foo");
+        let pattern = RoutePattern::parse("/foo").unwrap();
         assert!(!pattern.is_match("/foo/bar"));
         assert!(!pattern.is_match("/foo/wizz/skronk"));
     }
 
     #[test]
     fn exact_patterns_should_not_match_superstrings() {
-        let pattern = RoutePattern::parse("/foo");
+        let pattern = RoutePattern::parse("/foo").unwrap();
         assert!(!pattern.is_match("/foobar"));
         assert!(!pattern.is_match("/foowizz/foo/skronk"));
     }
 
     #[test]
     fn prefix_patterns_should_match_exact() {
-        let pattern = RoutePattern::parse("/foo/...");
+        let pattern = RoutePattern::parse("/foo/...").unwrap();
         assert!(pattern.is_match("/foo"));
         assert!(pattern.is_match("/foo/"));
     }
 
     #[test]
     fn prefix_patterns_should_match_subpaths() {
-        let pattern = RoutePattern::parse("/foo/...");
+        let pattern = RoutePattern::parse("/foo/...").unwrap();
         assert!(pattern.is_match("/foo/bar"));
         assert!(pattern.is_match("/foo/wizz/skronk"));
     }
 
+    #[test]
+    fn parameterised_patterns_should_match_any_segment_value() {
+        let pattern = RoutePattern::parse("/users/:id/orders/:order").unwrap();
+        assert!(pattern.is_match("/users/42/orders/7"));
+        assert!(pattern.is_match("/users/fred/orders/latest"));
+        assert!(!pattern.is_match("/users/42/orders"));
+        assert!(!pattern.is_match("/users/42/orders/"));
+        assert!(!pattern.is_match("/users//orders/7"));
+        assert!(!pattern.is_match("/users/42/orders/7/items"));
+        assert!(!pattern.is_match("/customers/42/orders/7"));
+    }
+
+    #[test]
+    fn parameterised_prefix_patterns_should_match_subpaths() {
+        let pattern = RoutePattern::parse("/users/:id/...").unwrap();
+        assert!(pattern.is_match("/users/42"));
+        assert!(pattern.is_match("/users/42/"));
+        assert!(pattern.is_match("/users/42/orders/7"));
+        assert!(!pattern.is_match("/users"));
+        assert!(!pattern.is_match("/users/"));
+    }
+
+    #[test]
+    fn bare_colon_is_not_a_parameter() {
+        assert_eq!(RoutePattern::Exact("/foo/:".to_owned()), RoutePattern::parse("/foo/:").unwrap());
+    }
+
+    #[test]
+    fn should_extract_parameters() {
+        let pattern = RoutePattern::parse("/users/:id/orders/:order-id/...").unwrap();
+        assert_eq!(
+            vec![("id".to_owned(), "fred smith".to_owned()), ("order-id".to_owned(), "7".to_owned())],
+            pattern.parameters("/users/fred%20smith/orders/7/items")
+        );
+        assert!(pattern.parameters("/users/fred").is_empty());
+        assert!(RoutePattern::parse("/users/...").unwrap().parameters("/users/fred").is_empty());
+    }
+
+    #[test]
+    fn parameters_passed_in_the_same_variable_are_rejected() {
+        let error = RoutePattern::parse("/orders/:order-id/items/:order_id").unwrap_err().to_string();
+        assert!(error.contains(":order-id"), "error was {}", error);
+        assert!(error.contains(":order_id"), "error was {}", error);
+        assert!(error.contains("X_ROUTE_PARAM_ORDER_ID"), "error was {}", error);
+
+        let error = RoutePattern::parse("/users/:Id/...").unwrap().append(&RoutePattern::parse("/:id").unwrap()).unwrap_err().to_string();
+        assert!(error.contains(":Id") && error.contains(":id"), "error was {}", error);
+
+        assert!(RoutePattern::parse("/users/:id/friends/:id").is_err());
+        assert!(RoutePattern::parse("/users/:user-id/orders/:order-id").is_ok());
+    }
+
+    #[test]
+    fn parameters_that_are_not_valid_variable_names_are_rejected() {
+        for route in ["/users/:a.b", "/x/:id=1/...", "/x/:caf\u{e9}", "/x/:id y"] {
+            let error = RoutePattern::parse(route).unwrap_err().to_string();
+            assert!(error.contains("invalid name"), "error for {} was {}", route, error);
+        }
+        let error = RoutePattern::parse("/users/:a.b").unwrap_err().to_string();
+        assert!(error.contains(":a.b"), "error was {}", error);
+        assert!(RoutePattern::parse("/users/:User_ID-2").is_ok());
+    }
+
+    #[test]
+    fn routes_with_colliding_parameters_fail_the_build() {
+        let source = WasmHandlerConfiguration {
+            entries: vec![handler_entry("/users/:user-id/:USER-ID", None, EMPTY_MODULE_WAT.as_bytes())],
+            ..Default::default()
+        };
+        let error = RoutingTable::build(&source, test_global_context()).err().expect("Expected the build to fail");
+        assert!(format!("{:#}", error).contains(":user-id and :USER-ID"), "error was {:#}", error);
+    }
+
+    #[test]
+    fn parameterised_script_name_is_the_matched_path() {
+        let pattern = RoutePattern::parse("/users/:id/...").unwrap();
+        assert_eq!("/users/42", pattern.script_name("/users/42/orders/7"));
+        assert_eq!("/orders/7", pattern.relative_path("/users/42/orders/7"));
+        assert_eq!("/users/42", pattern.script_name("/users/42"));
+        assert_eq!("", pattern.relative_path("/users/42"));
+
+        let pattern = RoutePattern::parse("/users/:id/orders/:order").unwrap();
+        assert_eq!("/users/42/orders/7", pattern.script_name("/users/42/orders/7"));
+        assert_eq!("", pattern.relative_path("/users/42/orders/7"));
+    }
+
+    #[test]
+    fn appending_to_parameterised_patterns_keeps_parameters() {
+        let parent = RoutePattern::parse("/users/:id/...").unwrap();
+        assert_eq!(
+            RoutePattern::parse("/users/:id/orders/:order").unwrap(),
+            parent.append(&RoutePattern::parse("/orders/:order").unwrap()).unwrap()
+        );
+        assert_eq!(
+            RoutePattern::parse("/users/:id/profile").unwrap(),
+            parent.append(&RoutePattern::parse("/profile").unwrap()).unwrap()
+        );
+        assert_eq!(
+            RoutePattern::parse("/users/:id/profile/...").unwrap(),
+            RoutePattern::parse("/users").unwrap().append(&RoutePattern::parse("/:id/profile/...").unwrap()).unwrap()
+        );
+    }
+
     #[test]
     fn prefix_patterns_should_not_match_superstrings() {
-        let pattern = RoutePattern::parse("/foo/...");
+        let pattern = RoutePattern::parse("/foo/...").unwrap();
         assert!(!pattern.is_match("/foobar"));
         assert!(!pattern.is_match("/foowizz/foo/skronk"));
     }
@@ -1,9 +1,19 @@
+use hyper::Method;
+
 use crate::dispatcher::RoutePattern;
+use crate::http_util::parse_method;
 
 pub struct DynamicRoutes {
     // Using a Vec rather than a HashMap because order matters
     // (and direct lookup doesn't because some routes may be prefixes)
-    pub subpath_entrypoints: Vec<(RoutePattern, String)>,  // TODO: private
+    pub subpath_entrypoints: Vec<DynamicRoute>,  // TODO: private
+}
+
+pub struct DynamicRoute {
+    pub route_pattern: RoutePattern,
+    pub entrypoint: String,
+    // If None, the route accepts the same methods as its parent
+    pub methods: Option<Vec<Method>>,
 }
 
 pub fn interpret_routes(route_text: impl Into<String>) -> anyhow::Result<DynamicRoutes> {
@@ -20,21 +30,32 @@ pub fn interpret_routes(route_text: impl Into<String>) -> anyhow::Result<Dynamic
     Ok(DynamicRoutes { subpath_entrypoints: routes })
 }
 
-fn parse_dynamic_route(line: &str) -> anyhow::Result<(RoutePattern, String)> {
+// A line is `<route> <entrypoint> [<methods>]`, where methods is a
+// comma-separated list such as `GET,POST`.
+fn parse_dynamic_route(line: &str) -> anyhow::Result<DynamicRoute> {
     let parts: Vec<&str> = line.trim().split_whitespace().collect();
 
     if parts.is_empty() {
         return Err(anyhow::anyhow!("Dynamic routes contained empty line"));
     }
-    if parts.len() != 2 {
+    if parts.len() != 2 && parts.len() != 3 {
         return Err(anyhow::anyhow!("Dynamic routes contained invalid line {}", line));
     }
 
     let path_text = parts.get(0).unwrap_or(&"/");
     let entrypoint = parts.get(1).unwrap_or(&"_start").to_string();
-
-    let route_pattern = RoutePattern::parse(path_text);
-    Ok((route_pattern, entrypoint))
+    let methods = match parts.get(2) {
+        None => None,
+        Some(methods_text) => Some(
+            methods_text
+                .split(',')
+                .map(parse_method)
+                .collect::<anyhow::Result<Vec<_>>>()?
+        ),
+    };
+
+    let route_pattern = RoutePattern::parse(path_text)?;
+    Ok(DynamicRoute { route_pattern, entrypoint, methods })
 }
 
 #[cfg(test)]
@@ -53,9 +74,15 @@ mod test {
     }
 
     #[test]
-    pub fn route_map_with_three_columns_is_error() {
-        assert!(interpret_routes("/hello hello heya").is_err());
-        assert!(interpret_routes("/hello hello\n/goodbye goodbye and_farewell").is_err());
+    pub fn route_map_with_four_columns_is_error() {
+        assert!(interpret_routes("/hello hello GET heya").is_err());
+        assert!(interpret_routes("/hello hello\n/goodbye goodbye GET and_farewell").is_err());
+    }
+
+    #[test]
+    pub fn route_map_with_invalid_methods_is_error() {
+        assert!(interpret_routes("/hello hello GET,").is_err());
+        assert!(interpret_routes("/hello hello GET,(POST)").is_err());
     }
 
     #[test]
@@ -65,6 +92,12 @@ mod test {
         assert!(interpret_routes("/hello hello\n/goodbye goodbye").is_ok());
     }
 
+    #[test]
+    pub fn route_map_with_three_columns_is_ok() {
+        assert!(interpret_routes("/hello hello GET").is_ok());
+        assert!(interpret_routes("/hello hello GET,POST\n/goodbye goodbye").is_ok());
+    }
+
     #[test]
     pub fn can_parse_plain_routes() {
         let routes = interpret_routes("/hello hello\n/goodbye farewell").unwrap();
@@ -72,10 +105,10 @@ mod test {
 
         assert_eq!(2, entrypoints.len());
 
-        assert_eq!(RoutePattern::Exact("/hello".to_owned()), entrypoints[0].0);
-        assert_eq!("hello", entrypoints[0].1);
-        assert_eq!(RoutePattern::Exact("/goodbye".to_owned()), entrypoints[1].0);
-        assert_eq!("farewell", entrypoints[1].1);
+        assert_eq!(RoutePattern::Exact("/hello".to_owned()), entrypoints[0].route_pattern);
+        assert_eq!("hello", entrypoints[0].entrypoint);
+        assert_eq!(RoutePattern::Exact("/goodbye".to_owned()), entrypoints[1].route_pattern);
+        assert_eq!("farewell", entrypoints[1].entrypoint);
     }
 
     #[test]
@@ -85,9 +118,18 @@ mod test {
 
         assert_eq!(2, entrypoints.len());
 
-        assert_eq!(RoutePattern::Prefix("/hello".to_owned()), entrypoints[0].0);
-        assert_eq!("hello", entrypoints[0].1);
-        assert_eq!(RoutePattern::Prefix("/goodbye".to_owned()), entrypoints[1].0);
-        assert_eq!("au_revoir", entrypoints[1].1);
+        assert_eq!(RoutePattern::Prefix("/hello".to_owned()), entrypoints[0].route_pattern);
+        assert_eq!("hello", entrypoints[0].entrypoint);
+        assert_eq!(RoutePattern::Prefix("/goodbye".to_owned()), entrypoints[1].route_pattern);
+        assert_eq!("au_revoir", entrypoints[1].entrypoint);
+    }
+
+    #[test]
+    pub fn can_parse_route_methods() {
+        let routes = interpret_routes("/hello hello get,POST\n/goodbye farewell").unwrap();
+        let entrypoints = routes.subpath_entrypoints;
+
+        assert_eq!(Some(vec![Method::GET, Method::POST]), entrypoints[0].methods);
+        assert_eq!(None, entrypoints[1].methods);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,84 @@
+//! Advances the epoch of Wasmtime engines, so that modules can be interrupted
+//! when they run past their deadline.
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use wasmtime::Engine;
+
+/// How often the epoch advances. Timeouts are accurate to about this much.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+// Far enough in the future never to be reached, but not so far that adding
+// it to the current epoch overflows
+const NO_DEADLINE_TICKS: u64 = u64::MAX / 2;
+
+/// Advances the epoch of each of its engines every `EPOCH_TICK`, for as long
+/// as it is alive.
+pub struct EpochTicker {
+    engines: Vec<Engine>,
+}
+
+impl EpochTicker {
+    pub fn start(engines: impl IntoIterator<Item = Engine>) -> Arc<Self> {
+        let mut unique_engines: Vec<Engine> = vec![];
+        for engine in engines {
+            // Modules share an engine with their dynamic routes, and an engine
+            // ticked twice would time out twice as fast
+            if !unique_engines.iter().any(|e| Engine::same(e, &engine)) {
+                unique_engines.push(engine);
+            }
+        }
+        let ticker = Arc::new(Self { engines: unique_engines });
+        let weak_ticker = Arc::downgrade(&ticker);
+        std::thread::spawn(move || Self::run(weak_ticker));
+        ticker
+    }
+
+    fn run(ticker: Weak<Self>) {
+        loop {
+            std::thread::sleep(EPOCH_TICK);
+            match ticker.upgrade() {
+                Some(ticker) => ticker.engines.iter().for_each(|e| e.increment_epoch()),
+                None => break,
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for EpochTicker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EpochTicker").field("engines", &self.engines.len()).finish()
+    }
+}
+
+/// The number of ticks after which a module with the given timeout should be
+/// interrupted.
+pub fn deadline_ticks(timeout: Option<Duration>) -> u64 {
+    match timeout {
+        None => NO_DEADLINE_TICKS,
+        Some(timeout) => {
+            let tick = EPOCH_TICK.as_nanos();
+            let ticks = (timeout.as_nanos() + tick - 1) / tick;
+            // The deadline is counted from whenever the next tick happens to
+            // come, which may be straight away, so allow one more so that the
+            // module always gets at least its full timeout
+            u64::try_from(ticks).map_or(NO_DEADLINE_TICKS, |t| t + 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deadline_rounds_up_to_whole_ticks_plus_one() {
+        assert_eq!(1, deadline_ticks(Some(Duration::from_millis(0))));
+        assert_eq!(2, deadline_ticks(Some(Duration::from_millis(1))));
+        assert_eq!(2, deadline_ticks(Some(Duration::from_millis(10))));
+        assert_eq!(3, deadline_ticks(Some(Duration::from_millis(11))));
+        assert_eq!(3001, deadline_ticks(Some(Duration::from_secs(30))));
+        assert_eq!(NO_DEADLINE_TICKS, deadline_ticks(None));
+    }
+}
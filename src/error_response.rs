@@ -0,0 +1,460 @@
+//! Responses for requests that could not be served normally: because the
+//! module failed or produced an invalid response, or because WAGI itself
+//! rejected the request (for example, because no route matched).
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::Context;
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+
+/// How a route reports module failures to the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFormat {
+    /// The error handler module's response if there is one, otherwise the
+    /// error page for the status code if there is one, otherwise an empty body.
+    Default,
+    /// An RFC 7807 problem details document. Intended for API routes, whose
+    /// clients expect errors to be machine-readable.
+    ProblemJson,
+}
+
+impl ErrorFormat {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text.trim().to_lowercase().as_str() {
+            "default" => Ok(Self::Default),
+            "problem+json" | PROBLEM_JSON_CONTENT_TYPE => Ok(Self::ProblemJson),
+            _ => Err(anyhow::anyhow!("'{}' is not a valid error format: expected 'default' or 'problem+json'", text)),
+        }
+    }
+}
+
+/// A static response body for a particular status code.
+#[derive(Clone, Debug)]
+pub struct ErrorPage {
+    content_type: &'static str,
+    content: Bytes,
+}
+
+impl ErrorPage {
+    /// Reads the page from a file. The content type is inferred from the
+    /// file extension.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("Couldn't read error page {}", path.display()))?;
+        Ok(Self {
+            content_type: content_type_for(path),
+            content: content.into(),
+        })
+    }
+}
+
+// Pages of unknown types are assumed to be text
+fn content_type_for(path: &Path) -> &'static str {
+    crate::http_util::content_type_for_path(path).unwrap_or("text/plain; charset=utf-8")
+}
+
+/// Server-wide settings for error responses.
+#[derive(Clone, Debug, Default)]
+pub struct ErrorResponseSettings {
+    pub pages: HashMap<StatusCode, ErrorPage>,
+    /// Whether to include failure messages and Wasm backtraces in response
+    /// bodies. These expose the internals of modules, so this is intended for
+    /// development only.
+    pub show_details: bool,
+}
+
+/// What kind of failure prevented a module from producing a response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureKind {
+    /// The module trapped. Wasmtime reports calls to `proc_exit` as traps too.
+    Trap,
+    /// The module ran for longer than its timeout allows.
+    Timeout,
+    /// The module used all the fuel it is allowed for a request.
+    OutOfFuel,
+    /// The module tried to use more memory, table elements or instances than
+    /// it is allowed.
+    ResourceLimit,
+    /// The module ran, but its output was not a valid CGI response.
+    InvalidResponse,
+    /// WAGI could not run the module, for example because it could not be
+    /// instantiated.
+    Error,
+}
+
+impl FailureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trap => "trap",
+            Self::Timeout => "timeout",
+            Self::OutOfFuel => "out_of_fuel",
+            Self::ResourceLimit => "resource_limit",
+            Self::InvalidResponse => "invalid_response",
+            Self::Error => "error",
+        }
+    }
+
+    fn public_description(&self) -> &'static str {
+        match self {
+            Self::Trap | Self::ResourceLimit | Self::Error => "The handler for this request failed",
+            Self::Timeout => "The handler for this request took too long",
+            Self::OutOfFuel => "The handler for this request used up its CPU budget",
+            Self::InvalidResponse => "The handler for this request produced an invalid response",
+        }
+    }
+}
+
+/// The reason a module could not produce a response.
+#[derive(Clone, Debug)]
+pub struct ModuleFailure {
+    pub status: StatusCode,
+    pub kind: FailureKind,
+    pub message: String,
+    /// The Wasm call stack at the point of a trap, innermost frame first.
+    /// Empty for other kinds of failure.
+    pub backtrace: Vec<String>,
+}
+
+impl ModuleFailure {
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        if let Some(timed_out) = error.downcast_ref::<ModuleTimedOut>() {
+            Self {
+                status: StatusCode::GATEWAY_TIMEOUT,
+                kind: FailureKind::Timeout,
+                message: timed_out.to_string(),
+                backtrace: vec![],
+            }
+        } else if let Some(out_of_fuel) = error.downcast_ref::<ModuleOutOfFuel>() {
+            Self {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                kind: FailureKind::OutOfFuel,
+                message: out_of_fuel.to_string(),
+                backtrace: vec![],
+            }
+        } else if let Some(exceeded) = error.downcast_ref::<ModuleResourceLimitExceeded>() {
+            Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: FailureKind::ResourceLimit,
+                message: exceeded.to_string(),
+                backtrace: vec![],
+            }
+        } else if let Some(trap) = error.downcast_ref::<wasmtime::Trap>() {
+            Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: FailureKind::Trap,
+                message: trap.display_reason().to_string(),
+                backtrace: trap.trace().iter().enumerate().map(|(i, f)| format_frame(i, f)).collect(),
+            }
+        } else if let Some(invalid) = error.downcast_ref::<InvalidHeaderValue>() {
+            Self {
+                status: StatusCode::BAD_GATEWAY,
+                kind: FailureKind::InvalidResponse,
+                message: invalid.to_string(),
+                backtrace: vec![],
+            }
+        } else if let Some(invalid) = error.downcast_ref::<InvalidResponse>() {
+            Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: FailureKind::InvalidResponse,
+                message: invalid.to_string(),
+                backtrace: vec![],
+            }
+        } else {
+            Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: FailureKind::Error,
+                message: format!("{:#}", error),
+                backtrace: vec![],
+            }
+        }
+    }
+
+    fn details(&self) -> String {
+        let mut text = format!("{}: {}\n", self.kind.as_str(), self.message);
+        if !self.backtrace.is_empty() {
+            text.push_str("\nwasm backtrace:\n");
+            for frame in &self.backtrace {
+                text.push_str(&format!("  {}\n", frame));
+            }
+        }
+        text
+    }
+}
+
+// Follows the layout of Wasmtime's own trap messages
+fn format_frame(index: usize, frame: &wasmtime::FrameInfo) -> String {
+    let module = frame.module_name().unwrap_or("<unknown>");
+    let func = match frame.func_name() {
+        Some(name) => name.to_owned(),
+        None => format!("<wasm function {}>", frame.func_index()),
+    };
+    match frame.module_offset() {
+        Some(offset) => format!("{:>3}: {:#6x} - {}!{}", index, offset, module, func),
+        None => format!("{:>3}: {}!{}", index, module, func),
+    }
+}
+
+/// The output of a module was not a valid CGI response.
+#[derive(Debug)]
+pub struct InvalidResponse(pub String);
+
+impl Display for InvalidResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidResponse {}
+
+/// The module wrote a CGI header whose value cannot be sent in an HTTP
+/// response, for example because it contains control characters.
+#[derive(Debug)]
+pub struct InvalidHeaderValue(pub String);
+
+impl Display for InvalidHeaderValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid value for header '{}'", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHeaderValue {}
+
+/// The module was interrupted because it ran for longer than its timeout.
+#[derive(Debug)]
+pub struct ModuleTimedOut(pub std::time::Duration);
+
+impl Display for ModuleTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The module did not finish within its timeout of {} ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for ModuleTimedOut {}
+
+/// The module was interrupted because it used all of its fuel.
+#[derive(Debug)]
+pub struct ModuleOutOfFuel(pub u64);
+
+impl Display for ModuleOutOfFuel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The module ran out of fuel: it is allowed {} units per request", self.0)
+    }
+}
+
+impl std::error::Error for ModuleOutOfFuel {}
+
+/// The module failed after it was refused memory, table elements or
+/// instances because of its resource limits. Holds the limit, e.g.
+/// "memory limit of 16 MB".
+#[derive(Debug)]
+pub struct ModuleResourceLimitExceeded(pub String);
+
+impl Display for ModuleResourceLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The module exceeded its {}", self.0)
+    }
+}
+
+impl std::error::Error for ModuleResourceLimitExceeded {}
+
+impl ErrorResponseSettings {
+    /// If there is an error page for the status of the response, replaces the
+    /// body of the response with it. This is for responses that WAGI itself
+    /// generates, such as 404 Not Found, rather than those from modules.
+    pub fn apply_page(&self, mut response: Response<Body>) -> Response<Body> {
+        if let Some(page) = self.pages.get(&response.status()) {
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(page.content_type));
+            *response.body_mut() = Body::from(page.content.clone());
+        }
+        response
+    }
+
+    /// The response to a module failure when there is no error handler
+    /// module, or the error handler failed too.
+    pub fn failure_response(&self, failure: &ModuleFailure) -> Response<Body> {
+        if self.show_details {
+            let mut response = Response::new(Body::from(failure.details()));
+            *response.status_mut() = failure.status;
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+            return response;
+        }
+        let mut response = Response::default();
+        *response.status_mut() = failure.status;
+        self.apply_page(response)
+    }
+
+    /// The response to a module failure on a route whose error format is
+    /// `problem+json`. `instance` identifies the request that failed.
+    pub fn problem_response(&self, failure: &ModuleFailure, instance: &str) -> Response<Body> {
+        let mut problem = serde_json::json!({
+            "type": "about:blank",
+            "title": failure.status.canonical_reason().unwrap_or_default(),
+            "status": failure.status.as_u16(),
+            "detail": failure.kind.public_description(),
+            "instance": instance,
+        });
+        if self.show_details {
+            problem["detail"] = serde_json::Value::from(failure.message.as_str());
+            problem["kind"] = serde_json::Value::from(failure.kind.as_str());
+            problem["backtrace"] = serde_json::Value::from(failure.backtrace.clone());
+        }
+        let mut response = Response::new(Body::from(problem.to_string()));
+        *response.status_mut() = failure.status;
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON_CONTENT_TYPE));
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trap_failure() -> ModuleFailure {
+        ModuleFailure {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            kind: FailureKind::Trap,
+            message: "wasm trap: wasm `unreachable` instruction executed".to_owned(),
+            backtrace: vec!["  0:   0x2a - <unknown>!fail".to_owned()],
+        }
+    }
+
+    fn settings_with_page(show_details: bool) -> ErrorResponseSettings {
+        let page = ErrorPage {
+            content_type: "text/html; charset=utf-8",
+            content: Bytes::from_static(b"<h1>Oops</h1>"),
+        };
+        ErrorResponseSettings {
+            pages: vec![(StatusCode::INTERNAL_SERVER_ERROR, page)].into_iter().collect(),
+            show_details,
+        }
+    }
+
+    async fn body_text(response: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+        String::from_utf8(bytes.to_vec()).expect("Body was not UTF-8")
+    }
+
+    #[test]
+    fn error_formats_are_parsed() {
+        assert_eq!(ErrorFormat::Default, ErrorFormat::parse("default").unwrap());
+        assert_eq!(ErrorFormat::ProblemJson, ErrorFormat::parse("problem+json").unwrap());
+        assert_eq!(ErrorFormat::ProblemJson, ErrorFormat::parse("Application/Problem+JSON").unwrap());
+        assert!(ErrorFormat::parse("xml").is_err());
+    }
+
+    #[test]
+    fn error_page_content_type_is_inferred_from_extension() {
+        assert_eq!("text/html; charset=utf-8", content_type_for(Path::new("/errors/500.HTML")));
+        assert_eq!("application/json", content_type_for(Path::new("500.json")));
+        assert_eq!("text/plain; charset=utf-8", content_type_for(Path::new("500")));
+    }
+
+    #[test]
+    fn invalid_responses_are_distinguished_from_other_errors() {
+        let invalid = anyhow::Error::from(InvalidResponse("no content type".to_owned()));
+        let failure = ModuleFailure::from_error(&invalid);
+        assert_eq!(FailureKind::InvalidResponse, failure.kind);
+        assert_eq!("no content type", failure.message);
+
+        let invalid_header = anyhow::Error::from(InvalidHeaderValue("x-bad".to_owned()));
+        let failure = ModuleFailure::from_error(&invalid_header);
+        assert_eq!(FailureKind::InvalidResponse, failure.kind);
+        assert_eq!(StatusCode::BAD_GATEWAY, failure.status);
+
+        let other = anyhow::anyhow!("no such function");
+        assert_eq!(FailureKind::Error, ModuleFailure::from_error(&other).kind);
+    }
+
+    #[test]
+    fn timeouts_are_reported_as_gateway_timeout() {
+        let timed_out = anyhow::Error::from(ModuleTimedOut(std::time::Duration::from_millis(250)));
+        let failure = ModuleFailure::from_error(&timed_out);
+        assert_eq!(FailureKind::Timeout, failure.kind);
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, failure.status);
+        assert_eq!("The module did not finish within its timeout of 250 ms", failure.message);
+    }
+
+    #[test]
+    fn running_out_of_fuel_is_reported_as_service_unavailable() {
+        let out_of_fuel = anyhow::Error::from(ModuleOutOfFuel(1000));
+        let failure = ModuleFailure::from_error(&out_of_fuel);
+        assert_eq!(FailureKind::OutOfFuel, failure.kind);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, failure.status);
+        assert_eq!("The module ran out of fuel: it is allowed 1000 units per request", failure.message);
+    }
+
+    #[test]
+    fn traps_caused_by_resource_limits_are_reported_as_such() {
+        let trap = anyhow::Error::from(wasmtime::Trap::new("unreachable"))
+            .context(ModuleResourceLimitExceeded("memory limit of 1 MB".to_owned()));
+        let failure = ModuleFailure::from_error(&trap);
+        assert_eq!(FailureKind::ResourceLimit, failure.kind);
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, failure.status);
+        assert_eq!("The module exceeded its memory limit of 1 MB", failure.message);
+    }
+
+    #[tokio::test]
+    async fn failure_response_uses_page_for_status() {
+        let response = settings_with_page(false).failure_response(&trap_failure());
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!("text/html; charset=utf-8", response.headers()[CONTENT_TYPE]);
+        assert_eq!("<h1>Oops</h1>", body_text(response).await);
+    }
+
+    #[tokio::test]
+    async fn failure_response_without_page_is_empty() {
+        let response = ErrorResponseSettings::default().failure_response(&trap_failure());
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!("", body_text(response).await);
+    }
+
+    #[tokio::test]
+    async fn failure_response_shows_details_if_enabled() {
+        let body = body_text(settings_with_page(true).failure_response(&trap_failure())).await;
+        assert!(body.contains("trap: wasm trap: wasm `unreachable` instruction executed"));
+        assert!(body.contains("<unknown>!fail"));
+    }
+
+    #[tokio::test]
+    async fn problem_response_hides_details_by_default() {
+        let response = ErrorResponseSettings::default().problem_response(&trap_failure(), "/api/thing");
+        assert_eq!(PROBLEM_JSON_CONTENT_TYPE, response.headers()[CONTENT_TYPE]);
+        let problem: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(500, problem["status"]);
+        assert_eq!("Internal Server Error", problem["title"]);
+        assert_eq!("/api/thing", problem["instance"]);
+        assert_eq!("The handler for this request failed", problem["detail"]);
+        assert!(problem.get("backtrace").is_none());
+    }
+
+    #[tokio::test]
+    async fn problem_response_shows_details_if_enabled() {
+        let settings = ErrorResponseSettings { show_details: true, ..Default::default() };
+        let response = settings.problem_response(&trap_failure(), "/api/thing");
+        let problem: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!("wasm trap: wasm `unreachable` instruction executed", problem["detail"]);
+        assert_eq!("trap", problem["kind"]);
+        assert_eq!(1, problem["backtrace"].as_array().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn pages_replace_body_of_inbuilt_responses() {
+        let mut settings = settings_with_page(false);
+        settings.pages.insert(StatusCode::NOT_FOUND, ErrorPage { content_type: "text/plain; charset=utf-8", content: Bytes::from_static(b"gone") });
+        let response = settings.apply_page(crate::http_util::not_found());
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!("gone", body_text(response).await);
+
+        let response = settings.apply_page(crate::http_util::method_not_allowed(&[hyper::Method::GET]));
+        assert_eq!("GET", response.headers()[hyper::header::ALLOW]);
+        assert_eq!("", body_text(response).await);
+    }
+}
@@ -1,50 +1,260 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
+use sha2::{Digest, Sha256};
 
-use crate::wasm_module::WasmModuleSource;
+use crate::resource_limits::ResourceLimits;
+use crate::wasm_module::{is_precompiled, InstancePoolConfig, WasmEngines, WasmModuleSource};
+use crate::wasm_runner::ExecutionLimits;
+use crate::wasm_snapshot;
 
 use super::{
     loader::{LoadedHandlerConfiguration, LoadedHandlerConfigurationEntry},
-    WasmHandlerConfiguration, WasmHandlerConfigurationEntry,
+    HandlerInfo, WasmHandlerConfiguration, WasmHandlerConfigurationEntry,
 };
 
 pub struct WasmCompilationSettings {
     pub cache_config_path: PathBuf,
+    /// If set, instances are allocated from a pool of this size.
+    pub instance_pool: Option<InstancePoolConfig>,
+    /// The server-wide limits, for modules that do not set their own.
+    pub resource_limits: ResourceLimits,
+    /// The server-wide timeout, for modules that do not set their own.
+    pub module_timeout: Option<Duration>,
+    /// If set, modules precompiled by `wagi compile` are loaded from here
+    /// rather than compiled.
+    pub precompiled_dir: Option<PathBuf>,
+    /// Where pre-initialised modules are cached.
+    pub asset_cache_dir: PathBuf,
 }
 
 pub fn compile(
     uncompiled_handlers: LoadedHandlerConfiguration,
     compilation_settings: WasmCompilationSettings,
 ) -> anyhow::Result<WasmHandlerConfiguration> {
-    uncompiled_handlers.compile_modules(|module_bytes| {
-        crate::wasm_module::WasmModuleSource::from_module_bytes(
-            module_bytes,
-            &compilation_settings.cache_config_path,
-        )
+    let instance_pool = compilation_settings.instance_pool.as_ref();
+    let metered = uncompiled_handlers.entries.iter().any(|e| e.info.max_fuel.is_some());
+    let engines = WasmEngines::new(&compilation_settings.cache_config_path, instance_pool, metered)?;
+    uncompiled_handlers.compile_modules(|module_bytes, info| {
+        // Precompiled code is run as it is, so is only accepted from the
+        // operator's precompiled directory, wherever else it came from
+        if is_precompiled(&module_bytes) {
+            anyhow::bail!("The module for route {} is precompiled. Precompiled modules can only be loaded from --precompiled-dir", info.route);
+        }
+        if let Some(instance_pool) = instance_pool {
+            check_fits_instance_pool(info, compilation_settings.resource_limits, instance_pool)
+                .with_context(|| format!("The module for route {} does not fit in the instance pool", info.route))?;
+        }
+        let engine = engines.for_module(info.max_fuel.is_some())?;
+        let precompiled = compilation_settings.precompiled_dir.as_deref()
+            .and_then(|dir| load_precompiled(dir, &module_bytes, info, engine));
+        match precompiled {
+            Some(module) => Ok(module),
+            None => {
+                // Initialisation can grow a module's memory, so it is the
+                // initialised module that has to fit
+                let module_bytes = preinitialize(module_bytes, info, &compilation_settings)?;
+                if let Some(instance_pool) = instance_pool {
+                    instance_pool.check_module_fits(&module_bytes)
+                        .with_context(|| format!("The module for route {} does not fit in the instance pool", info.route))?;
+                }
+                WasmModuleSource::from_module_bytes(module_bytes, engine)
+            }
+        }
     })
 }
 
+/// Compiles every module ahead of time, writing each to `out_dir` under a
+/// name `compile` will look for if given the same directory as
+/// `precompiled_dir`. Returns the paths written.
+pub fn precompile(
+    uncompiled_handlers: LoadedHandlerConfiguration,
+    compilation_settings: WasmCompilationSettings,
+    out_dir: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create output directory {}", out_dir.display()))?;
+    let instance_pool = compilation_settings.instance_pool.as_ref();
+    let metered = uncompiled_handlers.entries.iter().any(|e| e.info.max_fuel.is_some());
+    let engines = WasmEngines::new(&compilation_settings.cache_config_path, instance_pool, metered)?;
+
+    let mut written = HashSet::new();
+    for entry in &uncompiled_handlers.entries {
+        let consume_fuel = entry.info.max_fuel.is_some();
+        let path = precompiled_path(out_dir, &entry.module, consume_fuel);
+        // The same module may serve several routes
+        if written.contains(&path) {
+            continue;
+        }
+        if is_precompiled(&entry.module) {
+            anyhow::bail!("The module for route {} is already precompiled", entry.info.route);
+        }
+        let module_bytes = preinitialize(entry.module.clone(), &entry.info, &compilation_settings)?;
+        let module = WasmModuleSource::from_module_bytes(module_bytes, engines.for_module(consume_fuel)?)
+            .with_context(|| format!("Error compiling Wasm module {}", &entry.info.name))?;
+        std::fs::write(&path, module.serialize()?)
+            .with_context(|| format!("Failed to write precompiled module {}", path.display()))?;
+        if let Some(instances_needed) = module.instances_needed() {
+            let instances_path = instances_path(&path);
+            std::fs::write(&instances_path, instances_needed.to_string())
+                .with_context(|| format!("Failed to write {}", instances_path.display()))?;
+        }
+        written.insert(path);
+    }
+
+    let mut written: Vec<_> = written.into_iter().collect();
+    written.sort();
+    Ok(written)
+}
+
+// The module as it is once its initializer has run, if it has one. The
+// initializer runs under the limits the module's requests would.
+fn preinitialize(module_bytes: Arc<Vec<u8>>, info: &HandlerInfo, compilation_settings: &WasmCompilationSettings) -> anyhow::Result<Arc<Vec<u8>>> {
+    let limits = ExecutionLimits {
+        timeout: info.timeout_ms.map(Duration::from_millis).or(compilation_settings.module_timeout),
+        max_fuel: info.max_fuel,
+        resources: info.resource_limits.or(compilation_settings.resource_limits),
+    };
+    match wasm_snapshot::preinitialize(&module_bytes, &compilation_settings.asset_cache_dir, limits)
+        .with_context(|| format!("Failed to pre-initialise the module for route {}", info.route))?
+    {
+        Some(initialized) => {
+            tracing::debug!(route = %info.route, "Pre-initialised module");
+            Ok(Arc::new(initialized))
+        }
+        None => Ok(module_bytes),
+    }
+}
+
+// Precompiled modules are named for the Wasm they were compiled from, so
+// that they are found however the Wasm was loaded, and are not used if it
+// changes. Fuel metering changes the compiled code, so is part of the name.
+fn precompiled_path(dir: &Path, module_bytes: &[u8], consume_fuel: bool) -> PathBuf {
+    let digest = Sha256::digest(module_bytes);
+    let suffix = if consume_fuel { ".metered" } else { "" };
+    dir.join(format!("{:x}{}.cwasm", digest, suffix))
+}
+
+// The number of instances a precompiled module creates, which the native code
+// no longer says, is recorded alongside it so that a module which exceeds its
+// instance limit is reported as it would be had it been compiled at startup.
+fn instances_path(precompiled_path: &Path) -> PathBuf {
+    precompiled_path.with_extension("instances")
+}
+
+fn read_instances_needed(precompiled_path: &Path) -> anyhow::Result<usize> {
+    let path = instances_path(precompiled_path);
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    text.trim().parse()
+        .with_context(|| format!("{} does not contain an instance count", path.display()))
+}
+
+// Loads the precompiled form of a module, if there is one that this server
+// can use. If there is not, the module is compiled as usual.
+fn load_precompiled(dir: &Path, module_bytes: &[u8], info: &HandlerInfo, engine: &wasmtime::Engine) -> Option<WasmModuleSource> {
+    let path = precompiled_path(dir, module_bytes, info.max_fuel.is_some());
+    let precompiled = match std::fs::read(&path) {
+        Ok(precompiled) => precompiled,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::debug!(route = %info.route, path = %path.display(), "No precompiled module; compiling");
+            return None;
+        }
+        Err(e) => {
+            tracing::warn!(route = %info.route, path = %path.display(), error = %e, "Failed to read precompiled module; compiling");
+            return None;
+        }
+    };
+    if !is_precompiled(&precompiled) {
+        tracing::warn!(route = %info.route, path = %path.display(), "File is not a precompiled module; compiling");
+        return None;
+    }
+    let instances_needed = match read_instances_needed(&path) {
+        Ok(instances_needed) => instances_needed,
+        Err(e) => {
+            tracing::warn!(route = %info.route, path = %path.display(), error = %format!("{:#}", e), "Precompiled module has no instance count; compiling");
+            return None;
+        }
+    };
+    // Safety: the directory is the operator's, who vouches for its contents
+    // as they do for the WAGI binary
+    match unsafe { WasmModuleSource::from_precompiled(&precompiled, instances_needed, engine) } {
+        Ok(module) => {
+            tracing::debug!(route = %info.route, path = %path.display(), "Loaded precompiled module");
+            Some(module)
+        }
+        Err(e) => {
+            tracing::warn!(route = %info.route, path = %path.display(), error = %format!("{:#}", e), "Precompiled module cannot be used; compiling");
+            None
+        }
+    }
+}
+
+/// Checks that the limits a module runs under do not allow it more than an
+/// instance slot has room for. Wasmtime checks the module's own declarations
+/// when it is compiled, but a module may grow its memory or table at run time,
+/// and it would be a surprise for that to fail before reaching its limits.
+/// (A module with no limit is simply limited by the slot.)
+fn check_fits_instance_pool(info: &HandlerInfo, global_limits: ResourceLimits, instance_pool: &InstancePoolConfig) -> anyhow::Result<()> {
+    let limits = info.resource_limits.or(global_limits);
+    if let Some(max_memory_mb) = limits.max_memory_mb {
+        if max_memory_mb > instance_pool.max_memory_mb {
+            anyhow::bail!(
+                "The module's memory limit of {} MB is more than an instance pool slot holds ({} MB). Lower its max_memory_mb, or raise --instance-pool-memory-mb",
+                max_memory_mb,
+                instance_pool.max_memory_mb
+            );
+        }
+    }
+    if let Some(max_table_elements) = limits.max_table_elements {
+        if max_table_elements > instance_pool.max_table_elements {
+            anyhow::bail!(
+                "The module's table limit of {} elements is more than an instance pool slot holds ({}). Lower its max_table_elements, or raise --instance-pool-table-elements",
+                max_table_elements,
+                instance_pool.max_table_elements
+            );
+        }
+    }
+    if let Some(max_instances) = limits.max_instances {
+        if max_instances > instance_pool.size as usize {
+            anyhow::bail!(
+                "The module's instance limit of {} is more than the instance pool holds ({}). Lower its max_instances, or raise --instance-pool-size",
+                max_instances,
+                instance_pool.size
+            );
+        }
+    }
+    Ok(())
+}
+
 impl LoadedHandlerConfiguration {
     pub fn compile_modules(
         self,
-        compile: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
+        compile: impl Fn(std::sync::Arc<Vec<u8>>, &HandlerInfo) -> anyhow::Result<WasmModuleSource>,
     ) -> anyhow::Result<WasmHandlerConfiguration> {
         let result: anyhow::Result<Vec<WasmHandlerConfigurationEntry>> = self
             .entries
             .into_iter()
-            .map(|e| e.compile_module(|m| compile(m)))
+            .map(|e| e.compile_module(&compile))
             .collect();
-        Ok(WasmHandlerConfiguration { entries: result? })
+        Ok(WasmHandlerConfiguration {
+            entries: result?,
+            static_entries: self.static_entries,
+            redirect_entries: self.redirect_entries,
+            rewrite_entries: self.rewrite_entries,
+        })
     }
 }
 
 impl LoadedHandlerConfigurationEntry {
     pub fn compile_module(
         self,
-        compile: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
+        compile: impl Fn(std::sync::Arc<Vec<u8>>, &HandlerInfo) -> anyhow::Result<WasmModuleSource>,
     ) -> anyhow::Result<WasmHandlerConfigurationEntry> {
-        let compiled_module = compile(self.module)
+        let compiled_module = compile(self.module, &self.info)
             .with_context(|| format!("Error compiling Wasm module {}", &self.info.name))?;
         Ok(WasmHandlerConfigurationEntry {
             info: self.info,
@@ -109,7 +109,7 @@ impl Emplacer {
 
         let invoice = InvoiceUnderstander::new(&invoice_raw);
 
-        let module_parcels = invoice.parse_wagi_handlers();
+        let module_parcels = invoice.parse_wagi_handlers()?;
 
         let module_placements = module_parcels.iter().map(|h| self.emplace_module_and_assets(reader, id, h));
         let all_module_placements = futures::future::join_all(module_placements).await;
@@ -1,21 +1,25 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, path::{Path, PathBuf}};
 
 use anyhow::Context;
 use serde::Deserialize;
 
 use crate::{
     bindle_util::{InvoiceUnderstander, WagiHandlerInfo},
+    resource_limits::ResourceLimits,
     wagi_config::WagiConfiguration,
 };
 
 use super::{
     emplacer::{EmplacedHandlerConfiguration, Emplacer},
     module_loader::{self, Loaded},
-    HandlerInfo,
+    HandlerInfo, HandlerRole, HandlerSource, RedirectConfigurationEntry, RewriteConfigurationEntry, StaticFilesConfigurationEntry, UNMOUNTED_HANDLER_ROUTE,
 };
 
 pub struct LoadedHandlerConfiguration {
     pub entries: Vec<LoadedHandlerConfigurationEntry>,
+    pub static_entries: Vec<StaticFilesConfigurationEntry>,
+    pub redirect_entries: Vec<RedirectConfigurationEntry>,
+    pub rewrite_entries: Vec<RewriteConfigurationEntry>,
 }
 
 pub struct LoadedHandlerConfigurationEntry {
@@ -25,8 +29,21 @@ pub struct LoadedHandlerConfigurationEntry {
 
 #[derive(Clone, Debug, Deserialize)]
 struct ModuleMapConfiguration {
-    #[serde(rename = "module")]
+    #[serde(rename = "module", default)]
     pub entries: Vec<ModuleMapConfigurationEntry>,
+    // Routes that serve files directly from disk
+    #[serde(rename = "static", default)]
+    pub static_entries: Vec<StaticModuleMapConfigurationEntry>,
+    // Routes that redirect the client elsewhere
+    #[serde(rename = "redirect", default)]
+    pub redirect_entries: Vec<RedirectConfigurationEntry>,
+    // Rules that change request paths before routing
+    #[serde(rename = "rewrite", default)]
+    pub rewrite_entries: Vec<RewriteConfigurationEntry>,
+    // The module to run for requests that match no route
+    pub not_found: Option<UnmountedModuleMapConfigurationEntry>,
+    // The module to run when another module fails
+    pub error_handler: Option<UnmountedModuleMapConfigurationEntry>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -42,6 +59,93 @@ pub struct ModuleMapConfigurationEntry {
     pub allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
     pub argv: Option<String>,
+    // The HTTP methods it accepts (all if not specified)
+    pub methods: Option<Vec<String>>,
+    // The host name it serves (all if not specified)
+    pub host: Option<String>,
+    // How to report failures to the client ("default" if not specified)
+    pub error_format: Option<String>,
+    // How long the module may run for each request (the server default if not specified)
+    pub timeout_ms: Option<u64>,
+    // How much fuel the module may consume for each request (unlimited if not specified)
+    pub max_fuel: Option<u64>,
+    // Limits on what the module may allocate for each request (the server defaults if not specified)
+    pub max_memory_mb: Option<u64>,
+    pub max_table_elements: Option<u32>,
+    pub max_instances: Option<usize>,
+    // The largest request body the module accepts, in bytes (the server default if not specified)
+    pub max_body_size: Option<u64>,
+    // Whether the module writes the HTTP status line and headers itself (false if not specified)
+    pub nph: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StaticModuleMapConfigurationEntry {
+    pub route: String,
+    // The directory to serve files from
+    pub static_dir: PathBuf,
+    // The files to serve for requests that name a directory
+    pub index: Option<Vec<String>>,
+    pub host: Option<String>,
+}
+
+impl StaticModuleMapConfigurationEntry {
+    fn load(&self, source: &HandlerSource) -> anyhow::Result<StaticFilesConfigurationEntry> {
+        if !self.static_dir.is_dir() {
+            anyhow::bail!("Static directory {} for route {} does not exist or is not a directory", self.static_dir.display(), self.route);
+        }
+        Ok(StaticFilesConfigurationEntry {
+            route: self.route.clone(),
+            host: self.host.clone(),
+            root: self.static_dir.clone(),
+            index_files: self.index.clone(),
+            source: source.clone(),
+        })
+    }
+}
+
+// A module that is not mounted at a route, such as the not found or error handler
+#[derive(Clone, Debug, Deserialize)]
+pub struct UnmountedModuleMapConfigurationEntry {
+    pub module: String,
+    pub entrypoint: Option<String>,
+    pub bindle_server: Option<String>,
+    pub volumes: Option<HashMap<String, String>>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub http_max_concurrency: Option<u32>,
+    pub argv: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub max_fuel: Option<u64>,
+    pub max_memory_mb: Option<u64>,
+    pub max_table_elements: Option<u32>,
+    pub max_instances: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub nph: Option<bool>,
+}
+
+impl UnmountedModuleMapConfigurationEntry {
+    fn mount(&self) -> ModuleMapConfigurationEntry {
+        ModuleMapConfigurationEntry {
+            route: UNMOUNTED_HANDLER_ROUTE.to_owned(),
+            module: self.module.clone(),
+            entrypoint: self.entrypoint.clone(),
+            bindle_server: self.bindle_server.clone(),
+            volumes: self.volumes.clone(),
+            allowed_hosts: self.allowed_hosts.clone(),
+            http_max_concurrency: self.http_max_concurrency,
+            argv: self.argv.clone(),
+            methods: None,
+            host: None,
+            error_format: None,
+            timeout_ms: self.timeout_ms,
+            max_fuel: self.max_fuel,
+            max_memory_mb: self.max_memory_mb,
+            max_table_elements: self.max_table_elements,
+            max_instances: self.max_instances,
+            max_body_size: self.max_body_size,
+            nph: self.nph,
+        }
+    }
 }
 
 pub async fn load(
@@ -55,7 +159,7 @@ pub async fn load_handler_configuration(pre_handler_config: EmplacedHandlerConfi
     match pre_handler_config {
         EmplacedHandlerConfiguration::ModuleMapFile(path) => {
             let module_map_configuration = read_module_map_configuration(&path).await?;
-            handlers_for_module_map(&module_map_configuration, configuration).await
+            handlers_for_module_map(&module_map_configuration, &path, configuration).await
         },
         EmplacedHandlerConfiguration::Bindle(emplacer, invoice) =>
             handlers_for_bindle(&invoice, &emplacer).await,
@@ -82,7 +186,9 @@ async fn read_module_map_configuration(path: &Path) -> anyhow::Result<ModuleMapC
     Ok(modules)
 }
 
-async fn handlers_for_module_map(module_map: &ModuleMapConfiguration, configuration: &WagiConfiguration) -> anyhow::Result<LoadedHandlerConfiguration> {
+async fn handlers_for_module_map(module_map: &ModuleMapConfiguration, path: &Path, configuration: &WagiConfiguration) -> anyhow::Result<LoadedHandlerConfiguration> {
+    let source = HandlerSource::ModuleMap { path: path.to_owned() };
+
     let loaders = module_map
         .entries
         .iter()
@@ -90,19 +196,54 @@ async fn handlers_for_module_map(module_map: &ModuleMapConfiguration, configurat
 
     let loadeds: anyhow::Result<Vec<_>> = futures::future::join_all(loaders).await.into_iter().collect();
     
-    let entries =
+    let mut entries: Vec<_> =
         loadeds?
         .into_iter()
-        .map(LoadedHandlerConfigurationEntry::from_loaded_module_map_entry)
+        .map(|loaded| LoadedHandlerConfigurationEntry::from_loaded_module_map_entry(loaded, &source))
         .collect();
 
-    Ok(LoadedHandlerConfiguration { entries })
+    let unmounted = [
+        (&module_map.not_found, HandlerRole::NotFound, "not_found"),
+        (&module_map.error_handler, HandlerRole::Error, "error_handler"),
+    ];
+    for (unmounted_entry, role, section) in unmounted {
+        if let Some(unmounted_entry) = unmounted_entry {
+            let loaded = handler_for_module_map_entry(&unmounted_entry.mount(), configuration).await
+                .with_context(|| format!("Failed to load {} module", section))?;
+            let mut entry = LoadedHandlerConfigurationEntry::from_loaded_module_map_entry(loaded, &source);
+            entry.info.role = role;
+            entries.push(entry);
+        }
+    }
+
+    let static_entries = module_map
+        .static_entries
+        .iter()
+        .map(|e| e.load(&source))
+        .collect::<anyhow::Result<_>>()?;
+    let redirect_entries = module_map
+        .redirect_entries
+        .iter()
+        .map(|e| RedirectConfigurationEntry { source: Some(source.clone()), ..e.clone() })
+        .collect();
+
+    Ok(LoadedHandlerConfiguration {
+        entries,
+        static_entries,
+        redirect_entries,
+        rewrite_entries: module_map.rewrite_entries.clone(),
+    })
 }
 
 async fn handlers_for_bindle(invoice: &bindle::Invoice, emplacer: &Emplacer) -> anyhow::Result<LoadedHandlerConfiguration> {
     let invoice = InvoiceUnderstander::new(invoice);
 
-    let wagi_handlers = invoice.parse_wagi_handlers();
+    // Static handlers are served from the invoice's asset directory, so their
+    // modules are never loaded
+    let (static_handlers, wagi_handlers): (Vec<_>, Vec<_>) = invoice
+        .parse_wagi_handlers()?
+        .into_iter()
+        .partition(|h| h.is_static);
 
     let loaders = wagi_handlers.iter().map(|h| emplacer.get_bits_for(h));
     let loadeds: anyhow::Result<Vec<_>> = futures::future::join_all(loaders).await.into_iter().collect();
@@ -114,7 +255,23 @@ async fn handlers_for_bindle(invoice: &bindle::Invoice, emplacer: &Emplacer) ->
         .map(LoadedHandlerConfigurationEntry::from_loaded_bindle_handler)
         .collect();
 
-    Ok(LoadedHandlerConfiguration { entries })
+    let static_entries = static_handlers
+        .into_iter()
+        .map(|h| StaticFilesConfigurationEntry {
+            root: emplacer.asset_path_for(&h.invoice_id),
+            source: h.source(),
+            route: h.route,
+            host: h.host,
+            index_files: h.index_files,
+        })
+        .collect();
+
+    Ok(LoadedHandlerConfiguration {
+        entries,
+        static_entries,
+        redirect_entries: invoice.parse_redirects(),
+        rewrite_entries: invoice.parse_rewrites(),
+    })
 }
 
 async fn handler_for_module_map_entry(module_map_entry: &ModuleMapConfigurationEntry, configuration: &WagiConfiguration) -> anyhow::Result<Loaded<ModuleMapConfigurationEntry>> {
@@ -125,7 +282,7 @@ async fn handler_for_module_map_entry(module_map_entry: &ModuleMapConfigurationE
 
 // TODO: consider replacing these functions with Into implementations
 impl LoadedHandlerConfigurationEntry {
-    fn from_loaded_module_map_entry(lmmce: Loaded<ModuleMapConfigurationEntry>) -> Self {
+    fn from_loaded_module_map_entry(lmmce: Loaded<ModuleMapConfigurationEntry>, source: &HandlerSource) -> Self {
         let info = HandlerInfo {
             name: lmmce.metadata.module,
             route: lmmce.metadata.route,
@@ -134,6 +291,20 @@ impl LoadedHandlerConfigurationEntry {
             http_max_concurrency: lmmce.metadata.http_max_concurrency,
             volume_mounts: lmmce.metadata.volumes.unwrap_or_default(),
             argv: lmmce.metadata.argv,
+            methods: lmmce.metadata.methods,
+            host: lmmce.metadata.host,
+            error_format: lmmce.metadata.error_format,
+            timeout_ms: lmmce.metadata.timeout_ms,
+            max_fuel: lmmce.metadata.max_fuel,
+            max_body_size: lmmce.metadata.max_body_size,
+            nph: lmmce.metadata.nph.unwrap_or(false),
+            resource_limits: ResourceLimits {
+                max_memory_mb: lmmce.metadata.max_memory_mb,
+                max_table_elements: lmmce.metadata.max_table_elements,
+                max_instances: lmmce.metadata.max_instances,
+            },
+            role: HandlerRole::Route,
+            source: source.clone(),
         };
         Self {
             info,
@@ -143,6 +314,7 @@ impl LoadedHandlerConfigurationEntry {
 
     fn from_loaded_bindle_handler(whib: (WagiHandlerInfo, super::emplacer::Bits)) -> Self {
         let (whi, bits) = whib;
+        let source = whi.source();
         let info = HandlerInfo {
             name: whi.parcel.label.name,
             route: whi.route,
@@ -151,6 +323,20 @@ impl LoadedHandlerConfigurationEntry {
             http_max_concurrency: None,
             volume_mounts: bits.volume_mounts,
             argv: whi.argv,
+            methods: whi.methods,
+            host: whi.host,
+            error_format: whi.error_format,
+            timeout_ms: whi.timeout_ms,
+            max_fuel: whi.max_fuel,
+            max_body_size: whi.max_body_size,
+            nph: whi.nph,
+            resource_limits: ResourceLimits {
+                max_memory_mb: whi.max_memory_mb,
+                max_table_elements: whi.max_table_elements,
+                max_instances: whi.max_instances,
+            },
+            role: whi.role,
+            source,
         };
         Self {
             info,
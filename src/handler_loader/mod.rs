@@ -1,8 +1,10 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
+use serde::{Deserialize, Serialize};
 
-use crate::{wagi_config::WagiConfiguration, wasm_module::WasmModuleSource};
+use crate::{resource_limits::ResourceLimits, wagi_config::WagiConfiguration, wasm_module::WasmModuleSource};
 
 mod compiler;
 mod emplacer;
@@ -21,6 +23,18 @@ pub async fn load_handlers(configuration: &WagiConfiguration) -> anyhow::Result<
     Ok(handlers)
 }
 
+/// Loads the handlers' modules as for `load_handlers`, and compiles them
+/// ahead of time into `out_dir`, so that `load_handlers` can load them
+/// without compiling them. Returns the paths written.
+pub async fn precompile_handlers(configuration: &WagiConfiguration, out_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let emplaced_handlers = emplacer::emplace(configuration).await
+        .with_context(|| "Failed to copy modules and assets to local cache")?;
+    let loaded_handlers = loader::load(emplaced_handlers, configuration).await
+        .with_context(|| "Failed to load one or more Wasm modules from source")?;
+    compiler::precompile(loaded_handlers, configuration.wasm_compilation_settings(), out_dir)
+        .with_context(|| "Failed to precompile one or more Wasm modules")
+}
+
 pub struct HandlerInfo {
     pub name: String,
     pub route: String,
@@ -28,14 +42,95 @@ pub struct HandlerInfo {
     pub allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
     pub volume_mounts: HashMap<String, String>,
-    pub argv: Option<String>
+    pub argv: Option<String>,
+    pub methods: Option<Vec<String>>,
+    pub host: Option<String>,
+    pub error_format: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub max_fuel: Option<u64>,
+    pub max_body_size: Option<u64>,
+    pub nph: bool,
+    pub resource_limits: ResourceLimits,
+    pub role: HandlerRole,
+    pub source: HandlerSource,
+}
+
+/// Where a handler was declared, so that it can be traced back from the
+/// running server.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HandlerSource {
+    /// A `modules.toml` file.
+    ModuleMap { path: PathBuf },
+    /// A bindle, and the SHA256 of the handler's parcel if it has one.
+    Bindle { id: String, parcel: Option<String> },
+}
+
+/// What a handler is used for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerRole {
+    /// Handles requests that match its route.
+    Route,
+    /// Handles requests that do not match any route.
+    NotFound,
+    /// Produces the response when another handler fails.
+    Error,
 }
 
+/// The route given to handlers that are not mounted at a route of their own,
+/// such as the not found and error handlers. This means they see the whole request path
+/// as `PATH_INFO`.
+pub const UNMOUNTED_HANDLER_ROUTE: &str = "/...";
+
+#[derive(Default)]
 pub struct WasmHandlerConfiguration {
     pub entries: Vec<WasmHandlerConfigurationEntry>,
+    pub static_entries: Vec<StaticFilesConfigurationEntry>,
+    pub redirect_entries: Vec<RedirectConfigurationEntry>,
+    pub rewrite_entries: Vec<RewriteConfigurationEntry>,
+}
+
+impl WasmHandlerConfiguration {
+    pub fn entries_with_role(&self, role: HandlerRole) -> impl Iterator<Item = &WasmHandlerConfigurationEntry> {
+        self.entries.iter().filter(move |e| e.info.role == role)
+    }
 }
 
 pub struct WasmHandlerConfigurationEntry {
     pub info: HandlerInfo,
     pub module: WasmModuleSource,
 }
+
+/// A route that serves files from a directory, without running a module.
+#[derive(Clone, Debug)]
+pub struct StaticFilesConfigurationEntry {
+    pub route: String,
+    pub host: Option<String>,
+    pub root: PathBuf,
+    // The files to serve for requests that name a directory (the defaults if None)
+    pub index_files: Option<Vec<String>>,
+    pub source: HandlerSource,
+}
+
+/// A route that redirects the client to another URL, without running a module.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RedirectConfigurationEntry {
+    pub route: String,
+    pub host: Option<String>,
+    // The URL to redirect to, e.g. `/new/...` or `https://${HOST}/...`
+    pub to: String,
+    // 301, 302, 307 or 308 (302 if not specified)
+    pub status: Option<u16>,
+    // Filled in by the loader, as it is not part of the configuration
+    #[serde(skip)]
+    pub source: Option<HandlerSource>,
+}
+
+/// A rule that changes the path of matching requests before they are routed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RewriteConfigurationEntry {
+    pub route: String,
+    pub host: Option<String>,
+    // The path to route the request as, e.g. `/v2/...`
+    pub to: String,
+}
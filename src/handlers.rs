@@ -1,5 +1,7 @@
 use std::{collections::HashMap};
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use wasi_cap_std_sync::Dir;
 use hyper::{
@@ -13,16 +15,45 @@ use wasmtime::*;
 use wasmtime_wasi::*;
 
 use crate::dispatcher::RoutePattern;
-use crate::http_util::{internal_error, parse_cgi_headers};
+use crate::error_response::{InvalidHeaderValue, InvalidResponse, ModuleResourceLimitExceeded};
+use crate::http_util::parse_cgi_headers;
+use crate::readiness::{ModuleHealth, ReadinessCheck, HEALTH_CHECK_ENTRYPOINT};
+use crate::redirect::RedirectRouteHandler;
 use crate::request::{RequestContext, RequestGlobalContext};
+use crate::request_body::RequestBody;
+use crate::resource_limits::ResourceLimits;
+use crate::response_stream::{ClientWait, ResponseSender, StreamingStdout};
+use crate::static_files::StaticFilesRouteHandler;
 
 use crate::wasm_module::WasmModuleSource;
-use crate::wasm_runner::{prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance, WasmLinkOptions};
+use crate::wasm_runner::{ExecutionLimits, ModuleState, RunWasmResult, append_to_module_log, prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance, run_prepared_wasm_instance_if_present, WasmLinkOptions};
 
 #[derive(Clone, Debug)]
 pub enum RouteHandler {
     HealthCheck,
     Wasm(WasmRouteHandler),
+    StaticFiles(StaticFilesRouteHandler),
+    Redirect(RedirectRouteHandler),
+    Metrics,
+    Readiness(Arc<ReadinessCheck>),
+}
+
+impl RouteHandler {
+    /// Built-in handlers take precedence over any user route with the same path.
+    pub fn is_inbuilt(&self) -> bool {
+        match self {
+            Self::HealthCheck | Self::Metrics | Self::Readiness(_) => true,
+            Self::Wasm(_) | Self::StaticFiles(_) | Self::Redirect(_) => false,
+        }
+    }
+
+    /// Whether handling a request runs Wasm, and so can take arbitrarily long.
+    pub fn runs_wasm(&self) -> bool {
+        match self {
+            Self::Wasm(_) | Self::Readiness(_) => true,
+            Self::HealthCheck | Self::Metrics | Self::StaticFiles(_) | Self::Redirect(_) => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -34,19 +65,106 @@ pub struct WasmRouteHandler {
     pub allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
     pub argv: Option<String>,
+    // If None, the server-wide timeout applies
+    pub timeout: Option<Duration>,
+    // If None, fuel is not metered
+    pub max_fuel: Option<u64>,
+    // Any limits not set here are the server-wide ones
+    pub resource_limits: ResourceLimits,
+    // If None, the server-wide limit applies
+    pub max_body_size: Option<u64>,
+    pub header_mode: HeaderMode,
+}
+
+/// How the headers a module writes are turned into a response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderMode {
+    /// CGI headers, which WAGI parses into a response (RFC 3875 section 6.3).
+    Cgi,
+    /// Non-parsed headers (NPH): the module writes the HTTP status line and
+    /// headers itself (RFC 3875 section 5).
+    Nph,
+}
+
+impl HeaderMode {
+    pub fn compose_response(self, output: &[u8]) -> Result<Response<Body>, Error> {
+        match self {
+            Self::Cgi => compose_response(output),
+            Self::Nph => {
+                debug!("composing NPH response");
+                match split_cgi_output(output) {
+                    Some((head, body)) => build_nph_response(head, Body::from(body.to_vec()), Some(body.len())),
+                    None => build_nph_response(output.to_vec(), Body::empty(), Some(0)),
+                }
+            }
+        }
+    }
+
+    pub fn build_response(self, out_headers: Vec<u8>, body: Body) -> Result<Response<Body>, Error> {
+        match self {
+            Self::Cgi => build_response(out_headers, body),
+            Self::Nph => build_nph_response(out_headers, body, None),
+        }
+    }
 }
 
 impl WasmRouteHandler {
+    /// Runs the module, and builds the response from its output once it has
+    /// finished.
     pub fn handle_request(
         &self,
         matched_route: &RoutePattern,
         req: &Parts,
-        body: Vec<u8>,
+        body: RequestBody,
         request_context: &RequestContext,
         global_context: &RequestGlobalContext,
         logging_key: String,
     ) -> Result<Response<Body>, anyhow::Error> {
+        let stdout_mutex = self.run(matched_route, req, body, request_context, global_context, logging_key, None)?;
+        let output = stdout_mutex.read().unwrap();
+        self.header_mode.compose_response(&output)
+    }
+
+    /// Runs the module, sending the response through `sender` as the module
+    /// writes it. If this fails before the response head has been sent, the
+    /// caller should send an error response; if after, the body is cut short.
+    #[allow(clippy::too_many_arguments)]
+    pub fn stream_request(
+        &self,
+        matched_route: &RoutePattern,
+        req: &Parts,
+        body: RequestBody,
+        request_context: &RequestContext,
+        global_context: &RequestGlobalContext,
+        logging_key: String,
+        sender: &ResponseSender,
+    ) -> Result<(), anyhow::Error> {
+        let stdout = StreamingStdout::new(sender.clone(), self.header_mode, self.client_wait(global_context, Instant::now()));
+        match self.run(matched_route, req, body, request_context, global_context, logging_key, Some(stdout.pipe())) {
+            Ok(_) => stdout.finish(),
+            Err(e) => {
+                stdout.abort();
+                Err(e)
+            }
+        }
+    }
+
+    // Runs the module with the given stdout, or if none is given, with stdout
+    // captured in the buffer that is returned.
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        &self,
+        matched_route: &RoutePattern,
+        req: &Parts,
+        body: RequestBody,
+        request_context: &RequestContext,
+        global_context: &RequestGlobalContext,
+        logging_key: String,
+        stdout: Option<Box<dyn wasi_common::WasiFile>>,
+    ) -> Result<Arc<RwLock<Vec<u8>>>, anyhow::Error> {
         let startup_span = tracing::info_span!("module instantiation").entered();
+        let instantiation_start = Instant::now();
+        let limits = self.execution_limits(global_context);
         let headers = crate::http_util::build_headers(
             matched_route,
             req,
@@ -57,18 +175,111 @@ impl WasmRouteHandler {
             &global_context.global_env_vars,
         );
 
-        let redirects = prepare_stdio_streams(body, global_context, logging_key)?;
+        let mut redirects = prepare_stdio_streams(body, self.client_wait(global_context, instantiation_start), global_context, logging_key.clone())?;
+        if let Some(stdout) = stdout {
+            redirects.streams.stdout = stdout;
+        }
 
         let ctx = self.build_wasi_context_for_request(req, headers, redirects.streams)?;
 
-        let (store, instance) = self.prepare_wasm_instance(ctx)?;
+        let (mut store, instance) = self.prepare_wasm_instance(ctx, request_context, limits)
+            .map_err(|e| self.check_limits(e, limits, instantiation_start, None, global_context, &logging_key))?;
 
         // Drop manually to get instantiation time
         drop(startup_span);
+        let execution_start = Instant::now();
+        if let Some(route_metrics) = &request_context.route_metrics {
+            route_metrics.record_instantiation(execution_start - instantiation_start);
+        }
 
-        run_prepared_wasm_instance(instance, store, &self.entrypoint, &self.wasm_module_name)?;
+        let result = run_prepared_wasm_instance(instance, &mut store, &self.entrypoint, &self.wasm_module_name);
+        // Only known if the module has a fuel limit
+        let fuel_consumed = store.fuel_consumed();
+        let result = result.map_err(|e| match redirects.body_failure.take() {
+            // The module traps when its request body cannot be read, but the
+            // client is at fault, not the module
+            Some(body_error) => body_error.into(),
+            None => self.check_limits(e, limits, instantiation_start, fuel_consumed, global_context, &logging_key),
+        });
+        if let Some(route_metrics) = &request_context.route_metrics {
+            route_metrics.record_execution(execution_start.elapsed());
+            if let Some(fuel_consumed) = fuel_consumed {
+                route_metrics.record_fuel_consumed(fuel_consumed);
+            }
+        }
+        if let Some(fuel_consumed) = fuel_consumed {
+            tracing::info!(uri = %req.uri, module = %self.wasm_module_name, fuel_consumed, max_fuel = ?limits.max_fuel, "Module fuel usage");
+        }
+        result?;
 
-        compose_response(redirects.stdout_mutex)
+        Ok(redirects.stdout_mutex)
+    }
+
+    // Reads from and writes to a client that has stalled cannot be interrupted
+    // by the module's timeout, so they give up by themselves: when the module's
+    // timeout would have expired, if not after the server's client timeout
+    fn client_wait(&self, global_context: &RequestGlobalContext, started: Instant) -> ClientWait {
+        ClientWait {
+            deadline: self.execution_limits(global_context).timeout.map(|timeout| started + timeout),
+            client_timeout: global_context.client_timeout,
+        }
+    }
+
+    pub fn execution_limits(&self, global_context: &RequestGlobalContext) -> ExecutionLimits {
+        ExecutionLimits {
+            timeout: self.timeout.or(global_context.module_timeout),
+            max_fuel: self.max_fuel,
+            resources: self.resource_limits.or(global_context.resource_limits),
+        }
+    }
+
+    // A module that is interrupted because it ran out of time or fuel traps,
+    // but we want to report it as a timeout or as running out of fuel. The
+    // trap, or the failure blamed on a resource limit, is recorded in the
+    // module's log.
+    fn check_limits(&self, error: Error, limits: ExecutionLimits, started: Instant, fuel_consumed: Option<u64>, global_context: &RequestGlobalContext, logging_key: &str) -> Error {
+        if let Some(exceeded) = error.downcast_ref::<ModuleResourceLimitExceeded>() {
+            append_to_module_log(global_context, logging_key, &format!("{} (entrypoint '{}'): {}", exceeded, self.entrypoint, error.root_cause()));
+            return error;
+        }
+        let exceeded = match limits.interruption(&error, started, fuel_consumed) {
+            Some(exceeded) => exceeded,
+            None => return error,
+        };
+        append_to_module_log(global_context, logging_key, &format!("{} (entrypoint '{}'): {}", exceeded, self.entrypoint, error));
+        exceeded
+    }
+
+    /// Calls the module's `_health` export, if it has one. The module is
+    /// healthy if the export returns or exits with status 0.
+    pub fn check_health(&self, global_context: &RequestGlobalContext, logging_key: String) -> ModuleHealth {
+        let redirects = match prepare_stdio_streams(RequestBody::empty(), ClientWait::default(), global_context, logging_key) {
+            Ok(redirects) => redirects,
+            Err(e) => return ModuleHealth::Unhealthy(e.to_string()),
+        };
+        let builder = WasiCtxBuilder::new()
+            .stderr(Box::new(redirects.streams.stderr))
+            .stdout(redirects.streams.stdout);
+        let prepared = self.preopen_volumes(builder)
+            .map(|builder| builder.build())
+            .and_then(|ctx| prepare_wasm_instance(ctx, &self.wasm_module_source, self.link_options(None), self.execution_limits(global_context)));
+        let (store, instance) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => return ModuleHealth::Unhealthy(e.to_string()),
+        };
+
+        let result = run_prepared_wasm_instance_if_present(instance, store, HEALTH_CHECK_ENTRYPOINT);
+        let output = String::from_utf8_lossy(&redirects.stdout_mutex.read().unwrap()).trim().to_owned();
+        let message = if output.is_empty() { None } else { Some(output) };
+        match result {
+            RunWasmResult::EntrypointNotFound => ModuleHealth::NoCheck,
+            RunWasmResult::Ok(()) => ModuleHealth::Healthy(message),
+            RunWasmResult::WasmError(e) => match e.downcast_ref::<Trap>().and_then(|t| t.i32_exit_status()) {
+                Some(0) => ModuleHealth::Healthy(message),
+                Some(status) => ModuleHealth::Unhealthy(message.unwrap_or_else(|| format!("{} exited with status {}", HEALTH_CHECK_ENTRYPOINT, status))),
+                None => ModuleHealth::Unhealthy(message.unwrap_or_else(|| e.to_string())),
+            },
+        }
     }
 
     fn build_wasi_context_for_request(&self, req: &Parts, headers: HashMap<String, String>, redirects: crate::wasm_module::IOStreamRedirects) -> Result<WasiCtx, Error> {
@@ -81,9 +292,15 @@ impl WasmRouteHandler {
             .args(&args)?
             .envs(&headers)?
             .stderr(Box::new(redirects.stderr)) // STDERR goes to the console of the server
-            .stdout(Box::new(redirects.stdout)) // STDOUT is sent to a Vec<u8>, which becomes the Body later
-            .stdin(Box::new(redirects.stdin));
+            .stdout(redirects.stdout) // STDOUT becomes the response, either buffered or streamed
+            .stdin(redirects.stdin);
+        builder = self.preopen_volumes(builder)?;
 
+        let ctx = builder.build();
+        Ok(ctx)
+    }
+
+    fn preopen_volumes(&self, mut builder: WasiCtxBuilder) -> Result<WasiCtxBuilder, Error> {
         for (guest, host) in &self.volumes {
             debug!(%host, %guest, "Mapping volume from host to guest");
             // Try to open the dir or log an error.
@@ -94,9 +311,7 @@ impl WasmRouteHandler {
                 Err(e) => tracing::error!(%host, %guest, error = %e, "Error opening directory"),
             };
         }
-
-        let ctx = builder.build();
-        Ok(ctx)
+        Ok(builder)
     }
 
     /// Build the argv array that will be passed to the module.
@@ -132,100 +347,217 @@ impl WasmRouteHandler {
         }
     }
 
-    fn prepare_wasm_instance(&self,  ctx: WasiCtx) -> Result<(Store<WasiCtx>, Instance), Error> {
+    fn prepare_wasm_instance(&self,  ctx: WasiCtx, request_context: &RequestContext, limits: ExecutionLimits) -> Result<(Store<ModuleState>, Instance), Error> {
         debug!("Preparing Wasm instance.");
-        let link_options = WasmLinkOptions::default()
-            .with_http(self.allowed_hosts.clone(), self.http_max_concurrency);
-        prepare_wasm_instance(ctx, &self.wasm_module_source, link_options)
+        let link_options = self.link_options(request_context.route_metrics.as_ref().map(|m| m.outbound_http_requests()));
+        prepare_wasm_instance(ctx, &self.wasm_module_source, link_options, limits)
+    }
+
+    fn link_options(&self, http_request_counter: Option<Arc<AtomicU64>>) -> WasmLinkOptions {
+        WasmLinkOptions::default()
+            .with_http(self.allowed_hosts.clone(), self.http_max_concurrency)
+            .with_http_request_counter(http_request_counter)
     }
 }
 
-pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<Body>, Error> {
+/// Builds the response from everything the module wrote to stdout.
+pub fn compose_response(output: &[u8]) -> Result<Response<Body>, Error> {
     // Okay, once we get here, all the information we need to send back in the response
     // should be written to the STDOUT buffer. We fetch that, format it, and send
     // it back. In the process, we might need to alter the status code of the result.
-    //
-    // This is a little janky, but basically we are looping through the output once,
-    // looking for the double-newline that distinguishes the headers from the body.
-    // The headers can then be parsed separately, while the body can be sent back
-    // to the client.
     debug!("composing response");
-    let out = stdout_mutex.read().unwrap();
+    match split_cgi_output(output) {
+        Some((headers, body)) => build_response(headers, Body::from(body.to_vec())),
+        // Without a blank line there are no headers, which is not enough
+        None => build_response(vec![], Body::from(output.to_vec())),
+    }
+}
+
+/// Splits a module's output into its CGI headers and the start of the body,
+/// once the output includes the blank line that ends the headers. CRs are
+/// dropped from the headers, so that lines may end in either CRLF or LF.
+pub fn split_cgi_output(output: &[u8]) -> Option<(Vec<u8>, &[u8])> {
     let mut last = 0;
-    let mut scan_headers = true;
-    let mut buffer: Vec<u8> = Vec::new();
-    let mut out_headers: Vec<u8> = Vec::new();
-    out.iter().for_each(|i| {
-        // Ignore CR in headers
-        if scan_headers && *i == 13 {
-            return;
-        } else if scan_headers && *i == 10 && last == 10 {
-            out_headers.append(&mut buffer);
-            buffer = Vec::new();
-            scan_headers = false;
-            return; // Consume the linefeed
-        }
-        last = *i;
-        buffer.push(*i)
-    });
-    let mut res = Response::new(Body::from(buffer));
+    let mut headers: Vec<u8> = Vec::new();
+    for (index, byte) in output.iter().enumerate() {
+        if *byte == b'\r' {
+            continue;
+        }
+        if *byte == b'\n' && last == b'\n' {
+            // Consume the linefeed
+            return Some((headers, &output[index + 1..]));
+        }
+        last = *byte;
+        headers.push(*byte);
+    }
+    None
+}
+
+/// Builds a response with the given CGI headers, which must include at least
+/// a content type, status or location.
+pub fn build_response(out_headers: Vec<u8>, body: Body) -> Result<Response<Body>, Error> {
+    let mut res = Response::new(body);
     let mut sufficient_response = false;
-    parse_cgi_headers(String::from_utf8(out_headers)?)
-        .iter()
-        .for_each(|h| {
-            use hyper::header::{CONTENT_TYPE, LOCATION};
-            match h.0.to_lowercase().as_str() {
-                "content-type" => {
-                    sufficient_response = true;
-                    res.headers_mut().insert(CONTENT_TYPE, h.1.parse().unwrap());
-                }
-                "status" => {
-                    // The spec does not say that status is a sufficient response.
-                    // (It says that it may be added along with Content-Type, because
-                    // a status has a content type). However, CGI libraries in the wild
-                    // do not set content type correctly if a status is an error.
-                    // See https://datatracker.ietf.org/doc/html/rfc3875#section-6.2
-                    sufficient_response = true;
-                    // Status can be `Status CODE [STRING]`, and we just want the CODE.
-                    let status_code = h.1.split_once(' ').map(|(code, _)| code).unwrap_or(h.1);
-                    tracing::debug!(status_code, "Raw status code");
-                    match status_code.parse::<StatusCode>() {
-                        Ok(code) => *res.status_mut() = code,
-                        Err(e) => {
-                            tracing::log::warn!("Failed to parse code: {}", e);
-                            *res.status_mut() = StatusCode::BAD_GATEWAY;
-                        }
+    let out_headers = String::from_utf8(out_headers)
+        .map_err(|e| InvalidResponse(format!("Response headers were not valid UTF-8: {}", e)))?;
+    for (name, value) in parse_cgi_headers(out_headers) {
+        use hyper::header::{CONTENT_TYPE, LOCATION};
+        match name.to_lowercase().as_str() {
+            "content-type" => {
+                sufficient_response = true;
+                res.headers_mut().insert(CONTENT_TYPE, header_value(&name, &value)?);
+            }
+            "status" => {
+                // The spec does not say that status is a sufficient response.
+                // (It says that it may be added along with Content-Type, because
+                // a status has a content type). However, CGI libraries in the wild
+                // do not set content type correctly if a status is an error.
+                // See https://datatracker.ietf.org/doc/html/rfc3875#section-6.2
+                sufficient_response = true;
+                // Status can be `Status CODE [STRING]`, and we just want the CODE.
+                let status_code = value.split_once(' ').map(|(code, _)| code).unwrap_or(&value);
+                tracing::debug!(status_code, "Raw status code");
+                match status_code.parse::<StatusCode>() {
+                    Ok(code) => *res.status_mut() = code,
+                    Err(e) => {
+                        tracing::log::warn!("Failed to parse code: {}", e);
+                        *res.status_mut() = StatusCode::BAD_GATEWAY;
                     }
                 }
-                "location" => {
-                    sufficient_response = true;
-                    res.headers_mut()
-                        .insert(LOCATION, HeaderValue::from_str(h.1).unwrap());
-                    *res.status_mut() = StatusCode::from_u16(302).unwrap();
-                }
-                _ => {
-                    // If the header can be parsed into a valid HTTP header, it is
-                    // added to the headers. Otherwise it is ignored.
-                    match HeaderName::from_lowercase(h.0.as_str().to_lowercase().as_bytes()) {
-                        Ok(hdr) => {
-                            res.headers_mut()
-                                .insert(hdr, HeaderValue::from_str(h.1).unwrap());
-                        }
-                        Err(e) => {
-                            tracing::error!(error = %e, header_name = %h.0, "Invalid header name")
-                        }
+            }
+            "location" => {
+                sufficient_response = true;
+                res.headers_mut().insert(LOCATION, header_value(&name, &value)?);
+                *res.status_mut() = StatusCode::from_u16(302).unwrap();
+            }
+            lower_name => {
+                // If the header can be parsed into a valid HTTP header, it is
+                // added to the headers. Otherwise it is ignored. Repeated headers,
+                // such as `Set-Cookie`, are all kept.
+                match HeaderName::from_lowercase(lower_name.as_bytes()) {
+                    Ok(hdr) => {
+                        let value = header_value(&name, &value)?;
+                        res.headers_mut().append(hdr, value);
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, header_name = %name, "Invalid header name")
                     }
                 }
             }
-        });
+        }
+    }
     if !sufficient_response {
-        tracing::debug!("{:?}", res.body());
-        return Ok(internal_error(
+        return Err(InvalidResponse(
             // Technically, we let `status` be sufficient, but this is more lenient
             // than the specification.
-            "Exactly one of 'location' or 'content-type' must be specified",
-        ));
+            "Exactly one of 'location' or 'content-type' must be specified".to_owned(),
+        ).into());
     }
-    debug!("Response successfully sent");
+    debug!("Response composed");
     Ok(res)
-}
\ No newline at end of file
+}
+
+fn header_value(name: &str, value: &str) -> Result<HeaderValue, InvalidHeaderValue> {
+    HeaderValue::from_str(value).map_err(|_| InvalidHeaderValue(name.to_owned()))
+}
+
+/// Builds a response from an NPH module's status line and headers. Unlike CGI
+/// headers, these are passed on as they are: every value of a repeated header
+/// is kept, as is the reason phrase. If the whole body is known, its length is
+/// given, and a `Content-Length` that disagrees with it is refused.
+pub fn build_nph_response(head: Vec<u8>, body: Body, body_len: Option<usize>) -> Result<Response<Body>, Error> {
+    let head = String::from_utf8(head)
+        .map_err(|e| InvalidResponse(format!("Response head was not valid UTF-8: {}", e)))?;
+    let (status_line, headers) = head.split_once('\n').unwrap_or((&head, ""));
+    let (status, reason) = parse_status_line(status_line.trim_end())?;
+
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    if !reason.is_empty() && status.canonical_reason() != Some(reason) {
+        let reason = hyper::ext::ReasonPhrase::try_from(reason.as_bytes())
+            .map_err(|_| InvalidResponse(format!("Invalid reason phrase '{}'", reason)))?;
+        res.extensions_mut().insert(reason);
+    }
+
+    for (name, value) in parse_cgi_headers(headers.to_owned()) {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| InvalidResponse(format!("Invalid header name '{}'", name)))?;
+        let value = header_value(name.as_str(), &value)?;
+        // The server frames the body itself
+        if name == hyper::header::TRANSFER_ENCODING {
+            continue;
+        }
+        if name == hyper::header::CONTENT_LENGTH {
+            check_content_length(&value, body_len)?;
+        }
+        res.headers_mut().append(name, value);
+    }
+    debug!("NPH response composed");
+    Ok(res)
+}
+
+// A wrong length would leave the client waiting for more, or reading the
+// end of the body as the next response. A streamed body's length is not
+// known in advance, so Hyper checks that as it is sent instead.
+fn check_content_length(value: &HeaderValue, body_len: Option<usize>) -> Result<(), Error> {
+    let declared = value.to_str().ok().and_then(|v| v.trim().parse::<usize>().ok())
+        .ok_or_else(|| InvalidResponse(format!("Invalid Content-Length '{}'", String::from_utf8_lossy(value.as_bytes()))))?;
+    match body_len {
+        Some(body_len) if body_len != declared => Err(InvalidResponse(format!(
+            "Content-Length of {} does not match the body of {} bytes",
+            declared, body_len
+        )).into()),
+        _ => Ok(()),
+    }
+}
+
+// Parses `HTTP/1.1 200 OK` into the status and reason phrase
+fn parse_status_line(line: &str) -> Result<(StatusCode, &str), Error> {
+    let invalid = || InvalidResponse(format!("Invalid status line '{}'", line));
+    let (version, rest) = line.split_once(' ').ok_or_else(invalid)?;
+    if !version.starts_with("HTTP/") {
+        return Err(invalid().into());
+    }
+    let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    let status = code.parse::<StatusCode>().map_err(|_| invalid())?;
+    Ok((status, reason.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nph_output_is_passed_on_as_written() {
+        let output = b"HTTP/1.0 404 Nowhere To Be Found\r\nVary: Accept\r\nVary: Cookie\r\nTransfer-Encoding: chunked\r\n\r\nGone";
+        let response = HeaderMode::Nph.compose_response(output).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!(b"Nowhere To Be Found", response.extensions().get::<hyper::ext::ReasonPhrase>().unwrap().as_bytes());
+        assert_eq!(vec!["Accept", "Cookie"], response.headers().get_all("vary").iter().collect::<Vec<_>>());
+        assert!(response.headers().get("transfer-encoding").is_none());
+
+        // The standard reason phrase needs no extension, and none is needed at all
+        let response = HeaderMode::Nph.compose_response(b"HTTP/1.1 200 OK\n\n").unwrap();
+        assert!(response.extensions().get::<hyper::ext::ReasonPhrase>().is_none());
+        let response = HeaderMode::Nph.compose_response(b"HTTP/1.1 204\n\n").unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+    }
+
+    #[test]
+    fn nph_headers_are_parsed_as_cgi_headers_are() {
+        let output = b"HTTP/1.1 200 OK\r\nX-Folded: one\r\n  two\r\nContent-Length: 5\r\nNot a header\r\n\r\nhello";
+        let response = HeaderMode::Nph.compose_response(output).unwrap();
+        assert_eq!("one two", response.headers()["x-folded"]);
+        assert_eq!("5", response.headers()["content-length"]);
+        assert_eq!(2, response.headers().len());
+    }
+
+    #[test]
+    fn invalid_nph_output_is_refused() {
+        let bad_lengths = [&b"HTTP/1.1 200 OK\nContent-Length: 10\n\nhello"[..], b"HTTP/1.1 200 OK\nContent-Length: 2\n\nhello", b"HTTP/1.1 204\nContent-Length: five\n\n"];
+        for output in [&b"Content-Type: text/plain\n\nhello"[..], b"HTTP/1.1 abc Bad\n\n", b""].into_iter().chain(bad_lengths) {
+            let error = HeaderMode::Nph.compose_response(output).unwrap_err();
+            assert!(error.is::<InvalidResponse>(), "Unexpected error for {:?}: {}", String::from_utf8_lossy(output), error);
+        }
+    }
+}
@@ -5,9 +5,9 @@ use std::net::SocketAddr;
 
 use hyper::HeaderMap;
 use hyper::{
-    header::HOST,
+    header::{ALLOW, HOST},
     http::request::Parts,
-    Body, Response, StatusCode,
+    Body, Method, Response, StatusCode,
 };
 
 use crate::dispatcher::RoutePattern;
@@ -20,26 +20,130 @@ pub(crate) fn not_found() -> Response<Body> {
     not_found
 }
 
+/// Create an HTTP 503 response
+pub(crate) fn service_unavailable() -> Response<Body> {
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    res
+}
+
+/// Create an HTTP 400 response
+pub(crate) fn bad_request() -> Response<Body> {
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    res
+}
+
+/// Create an HTTP 408 response
+pub(crate) fn request_timeout() -> Response<Body> {
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::REQUEST_TIMEOUT;
+    res
+}
+
+/// Create an HTTP 413 response
+pub(crate) fn payload_too_large() -> Response<Body> {
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+    res
+}
+
 /// Create an HTTP 500 response
-pub(crate) fn internal_error(msg: impl std::string::ToString) -> Response<Body> {
-    let message = msg.to_string();
-    tracing::error!(error = %message, "HTTP 500 error");
-    let mut res = Response::new(Body::from(message));
+pub(crate) fn internal_server_error() -> Response<Body> {
+    let mut res = Response::default();
     *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
     res
 }
 
-pub(crate) fn parse_cgi_headers(headers: String) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    headers.trim().split('\n').for_each(|h| {
-        let parts: Vec<&str> = h.splitn(2, ':').collect();
-        if parts.len() != 2 {
-            tracing::warn!(header = h, "corrupt header");
-            return;
+/// Create an HTTP 405 response, listing the methods that the resource does allow
+pub(crate) fn method_not_allowed(allowed: &[Method]) -> Response<Body> {
+    let allow = allowed.iter().map(|m| m.as_str()).collect::<Vec<_>>().join(", ");
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    match allow.parse() {
+        Ok(value) => {
+            res.headers_mut().insert(ALLOW, value);
         }
-        map.insert(parts[0].trim().to_owned(), parts[1].trim().to_owned());
-    });
-    map
+        Err(e) => tracing::error!(error = %e, %allow, "Invalid Allow header"),
+    }
+    res
+}
+
+/// Parse an HTTP method name from configuration. Names are not case sensitive.
+pub(crate) fn parse_method(text: &str) -> anyhow::Result<Method> {
+    Method::from_bytes(text.trim().to_uppercase().as_bytes())
+        .map_err(|_| anyhow::anyhow!("'{}' is not a valid HTTP method", text))
+}
+
+/// Guess the content type of a file from its extension. Returns `None` for
+/// extensions that are not recognised.
+pub(crate) fn content_type_for_path(path: &std::path::Path) -> Option<&'static str> {
+    let extension = path.extension().and_then(|e| e.to_str())?.to_lowercase();
+    let content_type = match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+/// Parse the CGI headers written by a module into name-value pairs, in the
+/// order they were written. Repeated headers are kept, and a line that starts
+/// with whitespace continues the value of the header before it.
+pub(crate) fn parse_cgi_headers(headers: String) -> Vec<(String, String)> {
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    for h in headers.trim_end().split('\n') {
+        if h.starts_with(' ') || h.starts_with('\t') {
+            match parsed.last_mut() {
+                Some((_, value)) => {
+                    let continuation = h.trim();
+                    if !continuation.is_empty() {
+                        value.push(' ');
+                        value.push_str(continuation);
+                    }
+                }
+                None => tracing::warn!(header = h, "continuation line with no header"),
+            }
+            continue;
+        }
+        match h.split_once(':') {
+            Some((name, value)) => parsed.push((name.trim().to_owned(), value.trim().to_owned())),
+            None => tracing::warn!(header = h, "corrupt header"),
+        }
+    }
+    parsed
+}
+
+/// The environment variable a route parameter's value is passed in, e.g. the
+/// `:user-id` parameter is passed as `X_ROUTE_PARAM_USER_ID`.
+pub(crate) fn route_parameter_variable(name: &str) -> String {
+    format!("X_ROUTE_PARAM_{}", name.to_uppercase().replace('-', "_"))
 }
 
 // TODO: doesn't properly belong here - more about parsing headers into
@@ -47,7 +151,7 @@ pub(crate) fn parse_cgi_headers(headers: String) -> HashMap<String, String> {
 pub fn build_headers(
     route: &RoutePattern,
     req: &Parts,
-    content_length: usize,
+    content_length: u64,
     client_addr: SocketAddr,
     default_host: &str,
     use_tls: bool,
@@ -114,6 +218,12 @@ pub fn build_headers(
     // have a trailing '/...'
     headers.insert("X_MATCHED_ROUTE".to_owned(), route.original_text());
 
+    // Values of named parameters in the route, e.g. /users/:id gives X_ROUTE_PARAM_ID.
+    // These are not in the specification, hence the X_.
+    for (name, value) in route.parameters(req.uri.path()) {
+        headers.insert(route_parameter_variable(&name), value);
+    }
+
     headers.insert(
         "QUERY_STRING".to_owned(),
         req.uri.query().unwrap_or("").to_owned(),
@@ -127,7 +237,7 @@ pub fn build_headers(
     // The Path component is /$SCRIPT_NAME/$PATH_INFO
     // SCRIPT_NAME is the route that matched.
     // https://datatracker.ietf.org/doc/html/rfc3875#section-4.1.13
-    let script_name = route.script_name();
+    let script_name = route.script_name(req.uri.path());
     headers.insert("SCRIPT_NAME".to_owned(), script_name);
     // PATH_INFO is any path information after SCRIPT_NAME
     //
@@ -187,7 +297,7 @@ pub fn build_headers(
 /// - If none of these provide sufficient data, which is definitely a possiblity,
 ///   we go with `localhost` as host and `80` as port. This, of course, is problematic,
 ///   but should only manifest if both the server and the client are behaving badly.
-fn parse_host_header_uri(
+pub(crate) fn parse_host_header_uri(
     headers: &HeaderMap,
     uri: &hyper::Uri,
     default_host: &str,
@@ -296,9 +406,25 @@ mod test {
         }
     }
 
+    #[test]
+    fn cgi_headers_are_parsed_in_order() {
+        let headers = "Content-Type: text/plain\nSet-Cookie: a=1\nX-Folded: one\n  two\n\tthree\nnot a header\nSet-Cookie: b=2".to_owned();
+        let parsed = parse_cgi_headers(headers);
+        let expected = [
+            ("Content-Type", "text/plain"),
+            ("Set-Cookie", "a=1"),
+            ("X-Folded", "one two three"),
+            ("Set-Cookie", "b=2"),
+        ];
+        assert_eq!(
+            expected.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect::<Vec<_>>(),
+            parsed
+        );
+    }
+
     #[test]
     fn test_headers() {
-        let route = RoutePattern::parse("/path/...");
+        let route = RoutePattern::parse("/path/...").unwrap();
             // "file:///no/such/path.wasm".to_owned(),
         let (req, _) = Request::builder()
             .uri("https://example.com:3000/path/test%3brun?foo=bar")
@@ -367,4 +493,23 @@ mod test {
         assert!(headers.get("HTTP_AUTHORIZATION").is_none());
         assert!(headers.get("HTTP_CONNECTION").is_none());
     }
+
+    #[test]
+    fn test_headers_for_parameterised_route() {
+        let route = RoutePattern::parse("/users/:user-id/orders/:order/...").unwrap();
+        let (req, _) = Request::builder()
+            .uri("https://example.com:3000/users/fred%20smith/orders/7/items/3")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let client_addr = "192.168.0.1:3000".parse().expect("Should parse IP");
+        let env = std::collections::HashMap::with_capacity(0);
+        let headers = build_headers(&route, &req, 0, client_addr, "example.com:3000", true, &env);
+
+        assert_eq!("fred smith", headers["X_ROUTE_PARAM_USER_ID"]);
+        assert_eq!("7", headers["X_ROUTE_PARAM_ORDER"]);
+        assert_eq!("/users/:user-id/orders/:order/...", headers["X_MATCHED_ROUTE"]);
+        assert_eq!("/users/fred%20smith/orders/7", headers["SCRIPT_NAME"]);
+        assert_eq!("/items/3", headers["PATH_INFO"]);
+    }
 }
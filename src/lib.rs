@@ -1,17 +1,30 @@
+pub mod admin;
 pub(crate) mod bindle_util;
 pub mod dispatcher;
 pub(crate) mod dynamic_route;
+pub(crate) mod epoch;
+pub mod error_response;
 pub mod handler_loader;
 pub mod handlers;
 pub mod http_util;
+pub mod metrics;
+pub mod readiness;
+pub mod redirect;
 mod request;
+pub mod request_body;
+pub mod resource_limits;
+pub mod response_stream;
+pub mod route_tree;
+pub mod static_files;
 mod tls;
 pub mod version;
 pub mod wagi_app;
 pub mod wagi_config;
 pub mod wagi_server;
 pub mod wasm_module;
+pub mod wasm_snapshot;
 pub(crate) mod wasm_runner;
+pub mod worker_pool;
 
 #[cfg(test)]
 mod upstream;
@@ -73,6 +86,7 @@ mod test {
     const DYNAMIC_ROUTES_SA_ID: &str = "dynamic-routes/0.1.0";
     const HTTP_TEST_ID: &str = "http-test/0.2.0";
     const PRINT_ENV_SA_ID: &str = "print-env/0.1.0";
+    const STATIC_ASSETS_SA_ID: &str = "static-assets/0.1.0";
     const TOAST_ON_DEMAND_SA_ID: &str = "itowlson/toast-on-demand/0.1.0-ivan-20210924170616069";
     const TEST1_MODULE_MAP_FILE: &str = "test1.toml";
     #[cfg(target_os = "windows")]
@@ -81,6 +95,31 @@ mod test {
     const WAT_MODULE_MAP_FILE: &str = "wat.toml";
     const TEST_HEALTHZ_MODULE_MAP_FILE: &str = "test_healthz_override.toml";
     const TEST_DYNAMIC_ROUTES_MODULE_MAP_FILE: &str = "test_dynamic_routes.toml";
+    const TEST_ROUTE_PRECEDENCE_MODULE_MAP_FILE: &str = "test_route_precedence.toml";
+    const TEST_METHODS_MODULE_MAP_FILE: &str = "test_methods.toml";
+    const TEST_PARAMETERISED_ROUTES_MODULE_MAP_FILE: &str = "test_parameterised_routes.toml";
+    const TEST_VIRTUAL_HOSTS_MODULE_MAP_FILE: &str = "test_virtual_hosts.toml";
+    const TEST_NOT_FOUND_MODULE_MAP_FILE: &str = "test_not_found.toml";
+    const TEST_NOT_FOUND_ENV_MODULE_MAP_FILE: &str = "test_not_found_env.toml";
+    const TEST_MODULE_FAILURES_MODULE_MAP_FILE: &str = "test_module_failures.toml";
+    const TEST_ERROR_HANDLER_MODULE_MAP_FILE: &str = "test_error_handler.toml";
+    const TEST_STATIC_FILES_MODULE_MAP_FILE: &str = "test_static_files.toml";
+    const TEST_REDIRECTS_MODULE_MAP_FILE: &str = "test_redirects.toml";
+    const TEST_METRICS_MODULE_MAP_FILE: &str = "test_metrics.toml";
+    const TEST_PRECOMPILED_MODULE_MAP_FILE: &str = "test_precompiled_module.toml";
+    const TEST_READINESS_MODULE_MAP_FILE: &str = "test_readiness.toml";
+    const TEST_READINESS_UNHEALTHY_MODULE_MAP_FILE: &str = "test_readiness_unhealthy.toml";
+    const TEST_WORKER_POOL_MODULE_MAP_FILE: &str = "test_worker_pool.toml";
+    const TEST_TIMEOUTS_MODULE_MAP_FILE: &str = "test_timeouts.toml";
+    const TEST_FUEL_MODULE_MAP_FILE: &str = "test_fuel.toml";
+    const TEST_RESOURCE_LIMITS_MODULE_MAP_FILE: &str = "test_resource_limits.toml";
+    const TEST_INSTANCE_LIMITS_MODULE_MAP_FILE: &str = "test_instance_limits.toml";
+    const TEST_STREAMING_MODULE_MAP_FILE: &str = "test_streaming.toml";
+    const TEST_BODY_SIZE_MODULE_MAP_FILE: &str = "test_body_size.toml";
+    const TEST_PREINIT_MODULE_MAP_FILE: &str = "test_preinit.toml";
+    const TEST_NPH_MODULE_MAP_FILE: &str = "test_nph.toml";
+    const TEST_CGI_HEADERS_MODULE_MAP_FILE: &str = "test_cgi_headers.toml";
+    const TEST_CLIENT_TIMEOUT_MODULE_MAP_FILE: &str = "test_client_timeout.toml";
 
     async fn build_routing_table_for_standalone_bindle(bindle_id: &str) -> RoutingTable {
         // Clear any env vars that would cause conflicts if set
@@ -112,21 +151,30 @@ mod test {
     }
 
     async fn build_routing_table_for_module_map(map_file: &str, custom_subs: Option<HashMap<String, String>>) -> RoutingTable {
+        build_routing_table_for_module_map_with_args(map_file, custom_subs, &[]).await
+    }
+
+    async fn build_routing_table_for_module_map_with_args(map_file: &str, custom_subs: Option<HashMap<String, String>>, extra_args: &[&str]) -> RoutingTable {
+        try_build_routing_table_for_module_map_with_args(map_file, custom_subs, extra_args).await
+            .expect("Failed to build routing table")
+    }
+
+    async fn try_build_routing_table_for_module_map_with_args(map_file: &str, custom_subs: Option<HashMap<String, String>>, extra_args: &[&str]) -> anyhow::Result<RoutingTable> {
         // Clear any env vars that would cause conflicts if set
         std::env::remove_var("BINDLE_URL");
 
         let modules_toml_path = replace_placeholders(&map_file, custom_subs).await;
-        let matches = wagi_app::wagi_app_definition().get_matches_from(vec![
+        let modules_toml_arg = modules_toml_path.display().to_string();
+        let mut args = vec![
             "wagi",
-            "-c", &modules_toml_path.display().to_string(),
-        ]);
+            "-c", &modules_toml_arg,
+        ];
+        args.extend_from_slice(extra_args);
+        let matches = wagi_app::wagi_app_definition().get_matches_from(args);
 
-        let configuration = wagi_app::parse_configuration_from(matches)
-            .expect("Fake command line was not valid");
-        let handlers = crate::handler_loader::load_handlers(&configuration).await
-            .expect("Failed to load handlers");
+        let configuration = wagi_app::parse_configuration_from(matches)?;
+        let handlers = crate::handler_loader::load_handlers(&configuration).await?;
         crate::dispatcher::RoutingTable::build(&handlers, configuration.request_global_context())
-            .expect("Failed to build routing table")
     }
 
     async fn send_request_to_module_map(map_file: &str, custom_subs: Option<HashMap<String, String>>, request: hyper::http::Result<hyper::Request<hyper::body::Body>>) -> hyper::Response<hyper::body::Body> {
@@ -664,6 +712,941 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    pub async fn most_specific_route_is_used_across_modules() {
+        let map_file = TEST_ROUTE_PRECEDENCE_MODULE_MAP_FILE;
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/api/users").await;
+        assert_eq!("Entrypoint 1\n", response);
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/api/admin/users").await;
+        assert_eq!("Entrypoint 2\n", response);
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/api/admin/status").await;
+        assert_eq!("Default entrypoint\n", response);
+    }
+
+    #[tokio::test]
+    pub async fn most_specific_route_is_used_for_dynamic_routes() {
+        let map_file = TEST_ROUTE_PRECEDENCE_MODULE_MAP_FILE;
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/dynamic/one/two/three/four").await;
+        assert_eq!("three\n", response);
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/dynamic/one/two/four").await;
+        assert_eq!("two\n", response);
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/dynamic/one").await;
+        assert_eq!("one\n", response);
+
+        // Equally specific routes are resolved in favour of the last one declared
+        let response = get_plain_text_response_from_module_map(map_file, None, "/dynamic/dup").await;
+        assert_eq!("dup_second\n", response);
+
+        let response = get_plain_text_response_from_module_map(map_file, None, "/dynamic/elsewhere").await;
+        assert_eq!("main\n", response);
+    }
+
+    async fn send_method_request(routing_table: &RoutingTable, method: hyper::Method, route: &str) -> hyper::Response<hyper::body::Body> {
+        let uri = format!("http://127.0.0.1:3000{}", route);
+        let request = hyper::Request::builder()
+            .method(method)
+            .uri(&uri)
+            .body(hyper::body::Body::empty())
+            .expect("Failed to construct mock request");
+        routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response")
+    }
+
+    async fn assert_method_not_allowed(routing_table: &RoutingTable, method: hyper::Method, route: &str, expected_allow: &str) {
+        let response = send_method_request(routing_table, method.clone(), route).await;
+        assert_eq!(hyper::StatusCode::METHOD_NOT_ALLOWED, response.status(), "Unexpected status for {} {}", method, route);
+        assert_eq!(expected_allow, response.headers().get("Allow").expect("Expected Allow header"));
+    }
+
+    async fn get_response_text_for_method(routing_table: &RoutingTable, method: hyper::Method, route: &str) -> String {
+        let response = send_method_request(routing_table, method.clone(), route).await;
+        assert_eq!(hyper::StatusCode::OK, response.status(), "Non-OK status for {} {}", method, route);
+        let response_body = hyper::body::to_bytes(response.into_body()).await
+            .expect("Could not get bytes from response body");
+        std::str::from_utf8(&response_body)
+            .expect("Could not read body as string")
+            .to_owned()
+    }
+
+    async fn get_response_text_for_host(routing_table: &RoutingTable, host: &str, route: &str) -> String {
+        let uri = format!("http://127.0.0.1:3000{}", route);
+        let request = hyper::Request::get(&uri)
+            .header("Host", host)
+            .body(hyper::body::Body::empty())
+            .expect("Failed to construct mock request");
+        let response = routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response");
+        assert_eq!(hyper::StatusCode::OK, response.status(), "Non-OK status for {}{}", host, route);
+        let response_body = hyper::body::to_bytes(response.into_body()).await
+            .expect("Could not get bytes from response body");
+        std::str::from_utf8(&response_body)
+            .expect("Could not read body as string")
+            .to_owned()
+    }
+
+    #[tokio::test]
+    pub async fn not_found_module_handles_unmatched_paths() {
+        let routing_table = build_routing_table_for_module_map(TEST_NOT_FOUND_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/does/not/exist").await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+        assert_eq!("text/html", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+        let response_body = hyper::body::to_bytes(response.into_body()).await
+            .expect("Could not get bytes from response body");
+        assert_eq!("<h1>Nothing here</h1>\n", std::str::from_utf8(&response_body).expect("Could not read body as string"));
+
+        // Matched routes are unaffected
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/exists").await;
+        assert_eq!("Default entrypoint\n", response);
+    }
+
+    #[tokio::test]
+    pub async fn not_found_module_gets_cgi_environment() {
+        let (description, parsed_response) = get_decription_and_evs_from_module_map(TEST_NOT_FOUND_ENV_MODULE_MAP_FILE, None, "/does/not/exist?a=1").await;
+
+        assert_eq!("This is the main entry point", description);
+        assert_eq!("/does/not/exist", parsed_response["PATH_INFO"]);
+        assert_eq!("a=1", parsed_response["QUERY_STRING"]);
+        assert_eq!("GET", parsed_response["REQUEST_METHOD"]);
+        assert_eq!("http://localhost:3000/does/not/exist?a=1", parsed_response["X_FULL_URL"]);
+    }
+
+    async fn response_status_and_text(response: hyper::Response<hyper::body::Body>) -> (hyper::StatusCode, String) {
+        let status = response.status();
+        let response_body = hyper::body::to_bytes(response.into_body()).await
+            .expect("Could not get bytes from response body");
+        let text = std::str::from_utf8(&response_body)
+            .expect("Could not read body as string")
+            .to_owned();
+        (status, text)
+    }
+
+    fn error_page_arg(status: u16) -> String {
+        format!("{}={}", status, module_map_path("error-500.html").display())
+    }
+
+    #[tokio::test]
+    pub async fn module_failures_return_empty_500_by_default() {
+        let routing_table = build_routing_table_for_module_map(TEST_MODULE_FAILURES_MODULE_MAP_FILE, None).await;
+
+        for route in ["/trap", "/invalid"] {
+            let response = send_method_request(&routing_table, hyper::Method::GET, route).await;
+            let (status, text) = response_status_and_text(response).await;
+            assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status, "Unexpected status for {}", route);
+            assert_eq!("", text, "Unexpected body for {}", route);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn module_failures_use_error_pages() {
+        let page_arg = error_page_arg(500);
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_MODULE_FAILURES_MODULE_MAP_FILE, None, &["--error-page", &page_arg]).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/trap").await;
+        assert_eq!("text/html; charset=utf-8", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert_eq!("<h1>Something went wrong</h1>\n", text);
+    }
+
+    #[tokio::test]
+    pub async fn error_pages_apply_to_not_found() {
+        let page_arg = error_page_arg(404);
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_MODULE_FAILURES_MODULE_MAP_FILE, None, &["--error-page", &page_arg]).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/does/not/exist").await;
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, status);
+        assert_eq!("<h1>Something went wrong</h1>\n", text);
+    }
+
+    #[tokio::test]
+    pub async fn module_failures_show_details_if_enabled() {
+        let page_arg = error_page_arg(500);
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_MODULE_FAILURES_MODULE_MAP_FILE, None, &["--error-page", &page_arg, "--show-error-details"]).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/trap").await;
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert!(text.starts_with("trap: wasm trap: wasm `unreachable` instruction executed"), "Unexpected body {}", text);
+        assert!(text.contains("wasm backtrace:"), "Unexpected body {}", text);
+        assert!(text.contains("!fail"), "Unexpected body {}", text);
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/invalid").await;
+        let (_, text) = response_status_and_text(response).await;
+        assert!(text.starts_with("invalid_response: Exactly one of"), "Unexpected body {}", text);
+    }
+
+    #[tokio::test]
+    pub async fn problem_json_routes_return_problem_details() {
+        let routing_table = build_routing_table_for_module_map(TEST_MODULE_FAILURES_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/api/trap").await;
+        assert_eq!("application/problem+json", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        let problem: serde_json::Value = serde_json::from_str(&text).expect("Body was not JSON");
+        assert_eq!(500, problem["status"]);
+        assert_eq!("/api/trap", problem["instance"]);
+        assert!(problem.get("backtrace").is_none());
+    }
+
+    #[tokio::test]
+    pub async fn error_handler_module_gets_failure_reason() {
+        let routing_table = build_routing_table_for_module_map(TEST_ERROR_HANDLER_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/trap?a=1").await;
+        let (status, text) = response_status_and_text(response).await;
+        // The handler does not set a status, so gets that of the failure
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        let env_vars: HashMap<_, _> = text.lines().skip(1).filter_map(parse_ev_line).collect();
+        assert_eq!("500", env_vars["X_ERROR_STATUS"]);
+        assert_eq!("trap", env_vars["X_ERROR_KIND"]);
+        assert_eq!("wasm trap: wasm `unreachable` instruction executed", env_vars["X_ERROR_MESSAGE"]);
+        assert_eq!("/trap", env_vars["X_ERROR_ROUTE"]);
+        assert_eq!("a=1", env_vars["QUERY_STRING"]);
+
+        // Routes that want problem+json still get it
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/api/trap").await;
+        assert_eq!("application/problem+json", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+    }
+
+    #[tokio::test]
+    pub async fn module_map_static_routes_serve_files() {
+        let routing_table = build_routing_table_for_module_map(TEST_STATIC_FILES_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/static/docs/hello.txt").await;
+        assert_eq!("text/plain; charset=utf-8", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+        assert!(response.headers().contains_key("ETag"));
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::OK, status);
+        assert_eq!("Hello, static world\n", text);
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/static/").await;
+        let (_, text) = response_status_and_text(response).await;
+        assert_eq!("<h1>Welcome</h1>\n", text);
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/static/nope.txt").await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+
+        // Static routes accept only GET and HEAD, so other methods, and other
+        // paths, go to the module
+        let response = get_response_text_for_method(&routing_table, hyper::Method::POST, "/static/docs/hello.txt").await;
+        assert_eq!("Default entrypoint\n", response);
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/other").await;
+        assert_eq!("Default entrypoint\n", response);
+    }
+
+    #[tokio::test]
+    pub async fn bindle_static_routes_serve_asset_parcels() {
+        let empty_body = hyper::body::Body::empty();
+        let request = hyper::Request::get("http://127.0.0.1:3000/assets/texts/hello.txt")
+            .header("Range", "bytes=0-4")
+            .body(empty_body);
+
+        let response = send_request_to_standalone_bindle(STATIC_ASSETS_SA_ID, request).await;
+        assert_eq!("bytes 0-4/20", response.headers().get("Content-Range").expect("Expected Content-Range header"));
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::PARTIAL_CONTENT, status);
+        assert_eq!("Hello", text);
+    }
+
+    #[tokio::test]
+    pub async fn module_map_redirects_preserve_path_suffix() {
+        let routing_table = build_routing_table_for_module_map(TEST_REDIRECTS_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/old/a/b?c=d").await;
+        assert_eq!(hyper::StatusCode::MOVED_PERMANENTLY, response.status());
+        assert_eq!("/v2/a/b?c=d", response.headers().get("Location").expect("Expected Location header"));
+
+        let request = hyper::Request::get("http://127.0.0.1:3000/profile/alice")
+            .header("Host", "example.com:3000")
+            .body(hyper::body::Body::empty())
+            .expect("Failed to construct mock request");
+        let response = routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response");
+        assert_eq!(hyper::StatusCode::FOUND, response.status());
+        assert_eq!("https://example.com/users/alice", response.headers().get("Location").expect("Expected Location header"));
+    }
+
+    #[tokio::test]
+    pub async fn module_map_rewrites_route_to_new_path() {
+        let routing_table = build_routing_table_for_module_map(TEST_REDIRECTS_MODULE_MAP_FILE, None).await;
+
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/api/things").await;
+        assert_eq!("Entrypoint 1\n", response);
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/api/latest/things").await;
+        assert_eq!("Entrypoint 2\n", response);
+
+        // The rewritten path is routed as is, not rewritten again
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/v1/loop/things").await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    pub async fn metrics_are_served_only_if_enabled() {
+        let routing_table = build_routing_table_for_module_map(TEST_METRICS_MODULE_MAP_FILE, None).await;
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/metrics").await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    pub async fn metrics_report_requests_traps_and_outbound_http() {
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_METRICS_MODULE_MAP_FILE, None, &["--metrics"]).await;
+
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/outbound").await;
+        assert_eq!("Done\n", response);
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/trap").await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/nope").await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/metrics").await;
+        assert_eq!("text/plain; version=0.0.4", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+        let (status, text) = response_status_and_text(response).await;
+        assert_eq!(hyper::StatusCode::OK, status);
+
+        assert!(text.contains("wagi_requests_total{route=\"/outbound\",status=\"200\"} 1\n"), "Unexpected metrics: {}", text);
+        assert!(text.contains("wagi_requests_total{route=\"/trap\",status=\"500\"} 1\n"));
+        assert!(text.contains("wagi_requests_total{route=\"(unmatched)\",status=\"404\"} 1\n"));
+        assert!(text.contains("wagi_module_traps_total{route=\"/trap\"} 1\n"));
+        assert!(text.contains("wagi_module_traps_total{route=\"/outbound\"} 0\n"));
+        assert!(text.contains("wagi_outbound_http_requests_total{route=\"/outbound\"} 2\n"));
+        assert!(text.contains("wagi_module_instantiation_seconds_count{route=\"/outbound\"} 1\n"));
+        assert!(text.contains("wagi_module_execution_seconds_count{route=\"/trap\"} 1\n"));
+        // The metrics request itself is in flight
+        assert!(text.contains("wagi_requests_in_flight 1\n"));
+    }
+
+    async fn readiness_report(routing_table: &RoutingTable) -> (hyper::StatusCode, serde_json::Value) {
+        let response = send_method_request(routing_table, hyper::Method::GET, "/readyz").await;
+        assert_eq!("application/json", response.headers().get("Content-Type").expect("Expected Content-Type header"));
+        let (status, text) = response_status_and_text(response).await;
+        let report = serde_json::from_str(&text).expect("Readiness report was not valid JSON");
+        (status, report)
+    }
+
+    fn module_readiness<'a>(report: &'a serde_json::Value, module_file: &str) -> &'a serde_json::Value {
+        report["modules"].as_array().expect("Expected modules array")
+            .iter()
+            .find(|m| m["module"].as_str().unwrap_or_default().ends_with(module_file))
+            .unwrap_or_else(|| panic!("Expected status for {} in {}", module_file, report))
+    }
+
+    #[tokio::test]
+    pub async fn readiness_reports_each_module() {
+        let routing_table = build_routing_table_for_module_map(TEST_READINESS_MODULE_MAP_FILE, None).await;
+        let (status, report) = readiness_report(&routing_table).await;
+
+        assert_eq!(hyper::StatusCode::OK, status);
+        assert_eq!(true, report["ready"]);
+        assert_eq!(2, report["modules"].as_array().unwrap().len());
+
+        let healthy = module_readiness(&report, "/healthy.wat");
+        assert_eq!("healthy", healthy["status"]);
+        assert_eq!("All good", healthy["message"]);
+
+        let unchecked = module_readiness(&report, "/crlf.wat");
+        assert_eq!("no_check", unchecked["status"]);
+        assert!(unchecked.get("message").is_none());
+    }
+
+    #[tokio::test]
+    pub async fn readiness_fails_if_a_health_check_fails() {
+        let routing_table = build_routing_table_for_module_map(TEST_READINESS_UNHEALTHY_MODULE_MAP_FILE, None).await;
+        let (status, report) = readiness_report(&routing_table).await;
+
+        assert_eq!(hyper::StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!(false, report["ready"]);
+        assert_eq!("healthy", module_readiness(&report, "/healthy.wat")["status"]);
+
+        let unhealthy = module_readiness(&report, "/unhealthy.wat");
+        assert_eq!("unhealthy", unhealthy["status"]);
+        assert_eq!("Database unreachable", unhealthy["message"]);
+
+        // The module still serves requests, and liveness is unaffected
+        assert_eq!("Hello\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/unhealthy").await);
+        assert_eq!(hyper::StatusCode::OK, send_method_request(&routing_table, hyper::Method::GET, "/healthz").await.status());
+    }
+
+    // Starts a request to the spinning module, which holds a worker for a
+    // second, and waits for it to start running
+    async fn start_spinning(routing_table: &RoutingTable) -> tokio::task::JoinHandle<(hyper::StatusCode, std::time::Instant)> {
+        let routing_table = routing_table.clone();
+        let spinning = tokio::spawn(async move {
+            let response = send_method_request(&routing_table, hyper::Method::GET, "/spin").await;
+            let status = response.status();
+            hyper::body::to_bytes(response.into_body()).await.expect("Failed to read spinning response");
+            (status, std::time::Instant::now())
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        spinning
+    }
+
+    #[tokio::test]
+    pub async fn spinning_module_does_not_block_health_check() {
+        let routing_table = build_routing_table_for_module_map(TEST_WORKER_POOL_MODULE_MAP_FILE, None).await;
+        let spinning = start_spinning(&routing_table).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/healthz").await;
+        let healthz_done = std::time::Instant::now();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+
+        let (spin_status, spin_done) = spinning.await.expect("Spinning request failed");
+        assert_eq!(hyper::StatusCode::OK, spin_status);
+        assert!(healthz_done < spin_done, "Health check waited for the spinning module");
+    }
+
+    #[tokio::test]
+    pub async fn requests_are_rejected_when_worker_queue_is_full() {
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_WORKER_POOL_MODULE_MAP_FILE, None, &["--workers", "1", "--worker-queue-depth", "0"]).await;
+        let spinning = start_spinning(&routing_table).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/spin").await;
+        assert_eq!(hyper::StatusCode::SERVICE_UNAVAILABLE, response.status());
+        // Built-in routes don't need a worker
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/healthz").await;
+        assert_eq!(hyper::StatusCode::OK, response.status());
+
+        let (spin_status, _) = spinning.await.expect("Spinning request failed");
+        assert_eq!(hyper::StatusCode::OK, spin_status);
+        // The response is streamed, so the worker is freed a moment after the
+        // client has all of it, rather than before
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!("Done spinning\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/spin").await);
+    }
+
+    // The contents of all the module stderr logs under the log directory
+    fn module_logs(log_dir: &std::path::Path) -> String {
+        std::fs::read_dir(log_dir).expect("Failed to read log dir")
+            .map(|entry| entry.expect("Failed to read log dir entry").path().join("module.stderr"))
+            .filter(|path| path.is_file())
+            .map(|path| std::fs::read_to_string(path).expect("Failed to read module log"))
+            .collect()
+    }
+
+    #[tokio::test]
+    pub async fn modules_that_run_too_long_time_out() {
+        let log_dir = tempfile::tempdir().expect("Failed to create log dir");
+        let log_dir_arg = log_dir.path().display().to_string();
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_TIMEOUTS_MODULE_MAP_FILE, None, &["--module-timeout", "300", "--log-dir", &log_dir_arg]).await;
+
+        // Per-module timeout
+        let started = std::time::Instant::now();
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/forever").await;
+        assert_eq!(hyper::StatusCode::GATEWAY_TIMEOUT, response.status());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(200) && elapsed < std::time::Duration::from_secs(2), "Timed out after {:?}", elapsed);
+
+        // Server-wide timeout
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/forever/default").await;
+        assert_eq!(hyper::StatusCode::GATEWAY_TIMEOUT, response.status());
+
+        // A module's own timeout can be longer than the server-wide one
+        assert_eq!("Done spinning\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/spin").await);
+
+        let logs = module_logs(log_dir.path());
+        assert!(logs.contains("The module did not finish within its timeout of 200 ms (entrypoint '_start')"), "Unexpected module logs: {}", logs);
+        assert!(logs.contains("The module did not finish within its timeout of 300 ms"), "Unexpected module logs: {}", logs);
+    }
+
+    #[tokio::test]
+    pub async fn module_output_is_streamed_as_it_is_written() {
+        let routing_table = build_routing_table_for_module_map(TEST_STREAMING_MODULE_MAP_FILE, None).await;
+
+        // The module spins for half a second after writing its headers
+        let started = std::time::Instant::now();
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/stream").await;
+        let head_elapsed = started.elapsed();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        assert!(head_elapsed < std::time::Duration::from_millis(400), "Head took {:?}", head_elapsed);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+        assert!(started.elapsed() >= std::time::Duration::from_millis(500));
+        assert_eq!(b"First\nSecond\n", &body[..]);
+
+        // A module that fails after sending its head can only cut the body short
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/stream/timeout").await;
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn modules_are_stopped_when_the_client_stops_reading() {
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_CLIENT_TIMEOUT_MODULE_MAP_FILE, None, &["--workers", "1", "--client-timeout", "200"]).await;
+
+        // The module has no timeout, and writes for as long as it can. The
+        // response is held, but its body is never read.
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/flood").await;
+        assert_eq!(hyper::StatusCode::OK, response.status());
+
+        // The only worker is freed once the module gives up on the client
+        let next = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            get_response_text_for_method(&routing_table, hyper::Method::GET, "/healthy"),
+        ).await.expect("The worker was not freed");
+        assert_eq!("Hello\n", next);
+
+        // The body was cut short
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+
+    async fn post_body(routing_table: &RoutingTable, route: &str, body: Vec<u8>) -> hyper::Response<hyper::body::Body> {
+        let request = hyper::Request::post(format!("http://127.0.0.1:3000{}", route))
+            .body(hyper::body::Body::from(body))
+            .expect("Failed to construct mock request");
+        routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response")
+    }
+
+    #[tokio::test]
+    pub async fn request_bodies_are_limited_in_size() {
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_BODY_SIZE_MODULE_MAP_FILE, None, &["--max-body-size", "1000"]).await;
+
+        let response = post_body(&routing_table, "/echo", vec![b'a'; 1000]).await;
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let echoed = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+        assert_eq!(vec![b'a'; 1000], echoed.to_vec());
+
+        // Server-wide limit
+        let response = post_body(&routing_table, "/echo", vec![b'a'; 1001]).await;
+        assert_eq!(hyper::StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        // Module limits, smaller and larger
+        let response = post_body(&routing_table, "/echo/small", vec![b'a'; 17]).await;
+        assert_eq!(hyper::StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        // Large enough to be spooled to disk
+        let body: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+        let response = post_body(&routing_table, "/echo/large", body.clone()).await;
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let echoed = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+        assert!(body == echoed.to_vec(), "Echoed body differed from the request body");
+
+        // Streamed to the module, as the client says how long it is
+        let (mut sender, streamed) = hyper::body::Body::channel();
+        let request = hyper::Request::post("http://127.0.0.1:3000/echo/large")
+            .header("Content-Length", body.len())
+            .body(streamed)
+            .expect("Failed to construct mock request");
+        let sending = tokio::spawn(async move {
+            for chunk in body.chunks(100_000) {
+                sender.send_data(hyper::body::Bytes::copy_from_slice(chunk)).await.expect("Failed to send body");
+            }
+            body
+        });
+        let response = routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response");
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let echoed = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+        let body = sending.await.unwrap();
+        assert!(body == echoed.to_vec(), "Echoed body differed from the request body");
+
+        // Over the limit, going by its length
+        let request = hyper::Request::post("http://127.0.0.1:3000/echo/small")
+            .header("Content-Length", 17)
+            .body(hyper::body::Body::from(vec![b'a'; 17]))
+            .expect("Failed to construct mock request");
+        let response = routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response");
+        assert_eq!(hyper::StatusCode::PAYLOAD_TOO_LARGE, response.status());
+    }
+
+    #[tokio::test]
+    pub async fn streamed_bodies_the_client_fails_to_send_are_client_errors() {
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_BODY_SIZE_MODULE_MAP_FILE, None, &["--metrics", "--client-timeout", "200"]).await;
+
+        // The client gives up part way through the body
+        let (mut sender, streamed) = hyper::body::Body::channel();
+        let request = hyper::Request::post("http://127.0.0.1:3000/consume")
+            .header("Content-Length", 100)
+            .body(streamed)
+            .expect("Failed to construct mock request");
+        let sending = tokio::spawn(async move {
+            sender.send_data(hyper::body::Bytes::from("partial")).await.expect("Failed to send body");
+            sender.abort();
+        });
+        let response = routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response");
+        sending.await.unwrap();
+        assert_eq!(hyper::StatusCode::BAD_REQUEST, response.status());
+
+        // The client stops sending the body
+        let (_sender, streamed) = hyper::body::Body::channel();
+        let request = hyper::Request::post("http://127.0.0.1:3000/consume")
+            .header("Content-Length", 100)
+            .body(streamed)
+            .expect("Failed to construct mock request");
+        let response = routing_table.handle_request(request, mock_client_addr()).await
+            .expect("Error producing HTTP response");
+        assert_eq!(hyper::StatusCode::REQUEST_TIMEOUT, response.status());
+
+        // Neither is the module's failure
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/metrics").await;
+        let (_, text) = response_status_and_text(response).await;
+        assert!(text.contains("wagi_module_traps_total{route=\"/consume\"} 0\n"), "Unexpected metrics {}", text);
+    }
+
+    #[tokio::test]
+    pub async fn modules_that_run_out_of_fuel_are_stopped() {
+        let log_dir = tempfile::tempdir().expect("Failed to create log dir");
+        let log_dir_arg = log_dir.path().display().to_string();
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_FUEL_MODULE_MAP_FILE, None, &["--metrics", "--log-dir", &log_dir_arg]).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/forever").await;
+        assert_eq!(hyper::StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+        assert_eq!("Hello\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/hello").await);
+
+        let logs = module_logs(log_dir.path());
+        assert!(logs.contains("The module ran out of fuel: it is allowed 100000 units per request (entrypoint '_start')"), "Unexpected module logs: {}", logs);
+
+        let (_, text) = response_status_and_text(send_method_request(&routing_table, hyper::Method::GET, "/metrics").await).await;
+        assert!(text.contains("wagi_module_fuel_consumed_total{route=\"/forever\"} 100000\n"), "Unexpected metrics: {}", text);
+        let hello_fuel = text.lines()
+            .find_map(|line| line.strip_prefix("wagi_module_fuel_consumed_total{route=\"/hello\"} "))
+            .and_then(|fuel| fuel.parse::<u64>().ok())
+            .expect("Expected fuel consumed by /hello");
+        assert!(hello_fuel > 0 && hello_fuel < 100000, "Unexpected fuel consumed by /hello: {}", hello_fuel);
+    }
+
+    #[tokio::test]
+    pub async fn modules_that_exceed_memory_limits_fail() {
+        let log_dir = tempfile::tempdir().expect("Failed to create log dir");
+        let log_dir_arg = log_dir.path().display().to_string();
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_RESOURCE_LIMITS_MODULE_MAP_FILE, None, &["--max-memory-mb", "3", "--show-error-details", "--log-dir", &log_dir_arg]).await;
+
+        let (status, text) = response_status_and_text(send_method_request(&routing_table, hyper::Method::GET, "/grow/limited").await).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert!(text.starts_with("resource_limit: The module exceeded its memory limit of 2 MB"), "Unexpected response: {}", text);
+
+        assert_eq!("Grew\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/grow/generous").await);
+
+        let (status, text) = response_status_and_text(send_method_request(&routing_table, hyper::Method::GET, "/grow/default").await).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert!(text.starts_with("resource_limit: The module exceeded its memory limit of 3 MB"), "Unexpected response: {}", text);
+
+
+        let logs = module_logs(log_dir.path());
+        assert!(logs.contains("The module exceeded its memory limit of 2 MB (it asked for 4194304 bytes in total) (entrypoint '_start'): wasm trap: wasm `unreachable` instruction executed"), "Unexpected module logs: {}", logs);
+    }
+
+    #[tokio::test]
+    pub async fn modules_can_be_instantiated_from_a_pool() {
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_RESOURCE_LIMITS_MODULE_MAP_FILE, None, &["--max-memory-mb", "3", "--instance-pool-size", "4", "--instance-pool-memory-mb", "8", "--workers", "2"]).await;
+
+        let (status, _) = response_status_and_text(send_method_request(&routing_table, hyper::Method::GET, "/grow/limited").await).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+        for _ in 0..8 {
+            assert_eq!("Grew\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/grow/generous").await);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn modules_that_do_not_fit_the_instance_pool_are_refused_at_startup() {
+        let error = try_build_routing_table_for_module_map_with_args(TEST_RESOURCE_LIMITS_MODULE_MAP_FILE, None, &["--instance-pool-size", "4", "--instance-pool-memory-mb", "4", "--workers", "2"]).await
+            .expect_err("Expected a module not to fit the pool");
+        let message = format!("{:#}", error);
+        assert!(message.contains("The module for route /grow/generous does not fit in the instance pool"), "Unexpected error: {}", message);
+        assert!(message.contains("memory limit of 8 MB is more than an instance pool slot holds (4 MB)"), "Unexpected error: {}", message);
+
+        // The slot's table size is set separately from the table limit
+        let pool_args = ["--instance-pool-size", "4", "--instance-pool-memory-mb", "8", "--workers", "2", "--max-table-elements", "20000"];
+        let error = try_build_routing_table_for_module_map_with_args(TEST_RESOURCE_LIMITS_MODULE_MAP_FILE, None, &pool_args).await
+            .expect_err("Expected a module not to fit the pool");
+        let message = format!("{:#}", error);
+        assert!(message.contains("table limit of 20000 elements is more than an instance pool slot holds (10000)"), "Unexpected error: {}", message);
+        assert!(message.contains("raise --instance-pool-table-elements"), "Unexpected error: {}", message);
+        let pool_args = [&pool_args[..], &["--instance-pool-table-elements", "20000"]].concat();
+        try_build_routing_table_for_module_map_with_args(TEST_RESOURCE_LIMITS_MODULE_MAP_FILE, None, &pool_args).await
+            .expect("Expected the modules to fit a pool with larger tables");
+
+        let error = try_build_routing_table_for_module_map_with_args(TEST_RESOURCE_LIMITS_MODULE_MAP_FILE, None, &["--instance-pool-size", "1", "--workers", "2"]).await
+            .expect_err("Expected the pool to be refused as smaller than the worker pool");
+        assert!(error.to_string().contains("must be at least the number of workers (2)"), "Unexpected error: {}", error);
+    }
+
+    async fn precompile_module_map(map_file: &str, custom_subs: Option<HashMap<String, String>>, out_dir: &std::path::Path) -> Vec<PathBuf> {
+        let modules_toml_path = replace_placeholders(map_file, custom_subs).await;
+        let modules_toml_arg = modules_toml_path.display().to_string();
+        let out_dir_arg = out_dir.display().to_string();
+        let matches = wagi_app::wagi_app_definition().get_matches_from(vec!["wagi", "compile", "-c", &modules_toml_arg, "--out-dir", &out_dir_arg]);
+        let configuration = match wagi_app::parse_command_from(matches).expect("Fake command line was not valid") {
+            wagi_app::WagiCommand::Compile(configuration, _) => configuration,
+            _ => panic!("Expected compile command"),
+        };
+        crate::handler_loader::precompile_handlers(&configuration, out_dir).await
+            .expect("Failed to precompile modules")
+    }
+
+    fn precompiled_path_for(out_dir: &std::path::Path, module_file: &str) -> PathBuf {
+        use sha2::Digest;
+        let module_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/module-maps").join(module_file);
+        let module_bytes = std::fs::read(module_path).expect("Failed to read test module");
+        out_dir.join(format!("{:x}.cwasm", sha2::Sha256::digest(&module_bytes)))
+    }
+
+    #[tokio::test]
+    pub async fn precompiled_modules_are_loaded_instead_of_compiled() {
+        let out_dir = tempfile::tempdir().expect("Failed to create output dir");
+        let written = precompile_module_map(TEST_READINESS_MODULE_MAP_FILE, None, out_dir.path()).await;
+        let healthy_path = precompiled_path_for(out_dir.path(), "healthy.wat");
+        let crlf_path = precompiled_path_for(out_dir.path(), "crlf.wat");
+        assert_eq!(vec![healthy_path.clone(), crlf_path.clone()].into_iter().collect::<std::collections::HashSet<_>>(), written.into_iter().collect());
+
+        // Swapping in another module's compiled code shows which was loaded
+        std::fs::copy(&crlf_path, &healthy_path).expect("Failed to replace precompiled module");
+        // A precompiled module that cannot be loaded is compiled as usual
+        std::fs::write(&crlf_path, b"\x7fELF but not really").expect("Failed to corrupt precompiled module");
+
+        let precompiled_dir_arg = out_dir.path().display().to_string();
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_READINESS_MODULE_MAP_FILE, None, &["--precompiled-dir", &precompiled_dir_arg]).await;
+        assert_eq!("Oh hi world\r\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/healthy").await);
+        assert_eq!("Oh hi world\r\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/unchecked").await);
+
+        // Without the directory, the modules are compiled
+        let routing_table = build_routing_table_for_module_map(TEST_READINESS_MODULE_MAP_FILE, None).await;
+        assert_eq!("Hello\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/healthy").await);
+    }
+
+    #[tokio::test]
+    pub async fn precompiled_modules_report_instance_limits_as_compiled_ones_do() {
+        // The text format no longer has module linking, so this is built by
+        // hand: an empty module, instantiated by its parent
+        let leaf = b"\0asm\x01\0\0\0";
+        let mut modules = vec![1, leaf.len() as u8];
+        modules.extend(leaf);
+        let mut linking = leaf.to_vec();
+        linking.extend([14, modules.len() as u8]);
+        linking.extend(&modules);
+        linking.extend([15, 4, 1, 0, 0, 0]);
+
+        let module_dir = tempfile::tempdir().expect("Failed to create module dir");
+        let module_path = module_dir.path().join("linking.wasm");
+        std::fs::write(&module_path, &linking).expect("Failed to write test module");
+        let subs = HashMap::from([("LINKING_MODULE".to_owned(), module_path.display().to_string())]);
+
+        let out_dir = tempfile::tempdir().expect("Failed to create output dir");
+        let written = precompile_module_map(TEST_INSTANCE_LIMITS_MODULE_MAP_FILE, Some(subs.clone()), out_dir.path()).await;
+        assert_eq!(1, written.len());
+        let instances_path = written[0].with_extension("instances");
+        assert_eq!("2", std::fs::read_to_string(&instances_path).expect("Expected the instance count to be recorded"));
+
+        // Looking for dynamic routes instantiates the module
+        let precompiled_dir_arg = out_dir.path().display().to_string();
+        for extra_args in [&[][..], &["--precompiled-dir", &precompiled_dir_arg][..]] {
+            let error = try_build_routing_table_for_module_map_with_args(TEST_INSTANCE_LIMITS_MODULE_MAP_FILE, Some(subs.clone()), extra_args).await
+                .expect_err("Expected the module to exceed its instance limit");
+            assert_eq!("The module exceeded its instance limit of 1 (it needs 2)", error.to_string(), "Unexpected error with {:?}", extra_args);
+        }
+
+        // Without its instance count, a precompiled module is compiled as usual
+        std::fs::remove_file(&instances_path).expect("Failed to remove instance count");
+        let error = try_build_routing_table_for_module_map_with_args(TEST_INSTANCE_LIMITS_MODULE_MAP_FILE, Some(subs), &["--precompiled-dir", &precompiled_dir_arg]).await
+            .expect_err("Expected the module to exceed its instance limit");
+        assert_eq!("The module exceeded its instance limit of 1 (it needs 2)", error.to_string());
+    }
+
+    #[tokio::test]
+    pub async fn precompiled_modules_are_refused_outside_the_precompiled_dir() {
+        let out_dir = tempfile::tempdir().expect("Failed to create output dir");
+        precompile_module_map(TEST_READINESS_MODULE_MAP_FILE, None, out_dir.path()).await;
+        let precompiled = precompiled_path_for(out_dir.path(), "healthy.wat");
+
+        let subs = HashMap::from([("PRECOMPILED_MODULE".to_owned(), precompiled.display().to_string())]);
+        let error = try_build_routing_table_for_module_map_with_args(TEST_PRECOMPILED_MODULE_MAP_FILE, Some(subs), &[]).await
+            .expect_err("Expected a precompiled module to be refused");
+        let message = format!("{:#}", error);
+        assert!(message.contains("Precompiled modules can only be loaded from --precompiled-dir"), "Unexpected error: {}", message);
+    }
+
+    #[tokio::test]
+    pub async fn modules_with_an_initializer_start_from_the_initialised_state() {
+        let cache_dir = tempfile::tempdir().expect("Failed to create module cache dir");
+        let cache_dir_arg = cache_dir.path().display().to_string();
+        let routing_table = build_routing_table_for_module_map_with_args(TEST_PREINIT_MODULE_MAP_FILE, None, &["--module-cache", &cache_dir_arg]).await;
+
+        assert_eq!("Initialised\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/preinit").await);
+        assert_eq!("Initialised\n", get_response_text_for_method(&routing_table, hyper::Method::GET, "/preinit").await);
+
+        let cached: Vec<_> = std::fs::read_dir(cache_dir.path()).expect("Failed to read module cache dir")
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".initialized.wasm"))
+            .collect();
+        assert_eq!(1, cached.len());
+    }
+
+    #[tokio::test]
+    pub async fn nph_modules_write_their_own_status_line_and_headers() {
+        let routing_table = build_routing_table_for_module_map(TEST_NPH_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/nph").await;
+        assert_eq!(299, response.status().as_u16());
+        let reason = response.extensions().get::<hyper::ext::ReasonPhrase>().expect("Expected a custom reason phrase");
+        assert_eq!(b"Mostly Fine", reason.as_bytes());
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(vec!["a=1", "b=2"], cookies);
+        assert_eq!("yes", response.headers()["x-custom"]);
+        let (_, text) = response_status_and_text(response).await;
+        assert_eq!("NPH body\n", text);
+
+        // Without nph, the status line is not a valid CGI header
+        let (status, _) = response_status_and_text(send_method_request(&routing_table, hyper::Method::GET, "/cgi").await).await;
+        assert_eq!(hyper::StatusCode::INTERNAL_SERVER_ERROR, status);
+    }
+
+    #[tokio::test]
+    pub async fn repeated_and_folded_cgi_headers_are_preserved() {
+        let routing_table = build_routing_table_for_module_map(TEST_CGI_HEADERS_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/headers").await;
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(vec!["a=1", "b=2"], cookies);
+        assert_eq!("one two", response.headers()["x-folded"]);
+        let (_, text) = response_status_and_text(response).await;
+        assert_eq!("body\n", text);
+    }
+
+    #[tokio::test]
+    pub async fn invalid_cgi_header_values_are_a_bad_gateway() {
+        let routing_table = build_routing_table_for_module_map(TEST_CGI_HEADERS_MODULE_MAP_FILE, None).await;
+
+        let response = send_method_request(&routing_table, hyper::Method::GET, "/invalid").await;
+        assert_eq!(hyper::StatusCode::BAD_GATEWAY, response.status());
+        assert!(response.headers().get("x-bad").is_none());
+    }
+
+    async fn route_descriptions(routing_table: &RoutingTable) -> Vec<serde_json::Value> {
+        let json = routing_table.describe_routes().to_json().expect("Failed to serialise routes");
+        let parsed: serde_json::Value = serde_json::from_str(&json).expect("Routes were not valid JSON");
+        parsed["routes"].as_array().expect("Expected routes array").clone()
+    }
+
+    #[tokio::test]
+    pub async fn route_descriptions_include_dynamic_routes_from_module_map() {
+        let routing_table = build_routing_table_for_module_map(TEST_DYNAMIC_ROUTES_MODULE_MAP_FILE, None).await;
+        let routes = route_descriptions(&routing_table).await;
+
+        let healthz = routes.iter().find(|r| r["route"] == "/healthz").expect("Expected /healthz route");
+        assert_eq!("health_check", healthz["kind"]);
+        assert!(healthz["source"].is_null());
+
+        let wasm_routes: Vec<_> = routes.iter().filter(|r| r["kind"] == "wasm").collect();
+        assert!(wasm_routes.iter().any(|r| r["dynamic"] == true));
+        assert!(wasm_routes.iter().any(|r| r["dynamic"] == false));
+        for route in wasm_routes {
+            assert!(route["module"].as_str().expect("Expected module name").ends_with("dynamic-routes.wasm"));
+            assert!(route["entrypoint"].is_string());
+            assert_eq!("module_map", route["source"]["type"]);
+            assert!(route["source"]["path"].as_str().expect("Expected source path").ends_with(".toml"));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn route_descriptions_include_bindle_parcels() {
+        let routing_table = build_routing_table_for_standalone_bindle(DYNAMIC_ROUTES_SA_ID).await;
+        let routes = route_descriptions(&routing_table).await;
+
+        let exact_parent = routes.iter().find(|r| r["route"] == "/exactparent").expect("Expected /exactparent route");
+        assert_eq!("bindle", exact_parent["source"]["type"]);
+        assert_eq!(DYNAMIC_ROUTES_SA_ID, exact_parent["source"]["id"]);
+        assert_eq!("73496d7c3d8be18a5f276785c3f0d36b809c180e2f5c7febbdd8b8f034ff3a4a", exact_parent["source"]["parcel"]);
+    }
+
+    #[tokio::test]
+    pub async fn route_descriptions_include_redirects_and_rewrites() {
+        let routing_table = build_routing_table_for_module_map(TEST_REDIRECTS_MODULE_MAP_FILE, None).await;
+        let routes = route_descriptions(&routing_table).await;
+
+        let redirect = routes.iter().find(|r| r["route"] == "/old/...").expect("Expected /old/... route");
+        assert_eq!("redirect", redirect["kind"]);
+        assert_eq!("/v2/...", redirect["redirect_to"]);
+        assert_eq!(301, redirect["redirect_status"]);
+
+        let rewrites = routing_table.describe_routes().rewrites;
+        assert_eq!(3, rewrites.len());
+        assert_eq!("/api/...", rewrites[0].route);
+        assert_eq!("/v1/...", rewrites[0].to);
+    }
+
+    #[tokio::test]
+    pub async fn module_map_hosts_select_routes() {
+        let routing_table = build_routing_table_for_module_map(TEST_VIRTUAL_HOSTS_MODULE_MAP_FILE, None).await;
+
+        let response = get_response_text_for_host(&routing_table, "api.example.com:3000", "/thing").await;
+        assert_eq!("Entrypoint 1\n", response);
+
+        let response = get_response_text_for_host(&routing_table, "www.example.com", "/thing").await;
+        assert_eq!("Entrypoint 2\n", response);
+
+        let response = get_response_text_for_host(&routing_table, "example.org", "/thing").await;
+        assert_eq!("Default entrypoint\n", response);
+    }
+
+    #[tokio::test]
+    pub async fn module_map_methods_restrict_routes() {
+        let routing_table = build_routing_table_for_module_map(TEST_METHODS_MODULE_MAP_FILE, None).await;
+
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/readonly/thing").await;
+        assert_eq!("Default entrypoint\n", response);
+
+        assert_method_not_allowed(&routing_table, hyper::Method::POST, "/readonly/thing", "GET").await;
+    }
+
+    #[tokio::test]
+    pub async fn dynamic_route_methods_restrict_routes() {
+        let routing_table = build_routing_table_for_module_map(TEST_METHODS_MODULE_MAP_FILE, None).await;
+
+        let response = get_response_text_for_method(&routing_table, hyper::Method::PUT, "/forms/submit").await;
+        assert_eq!("submit\n", response);
+        // The parent wildcard accepts GET, so it picks up requests the more specific route rejects
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/forms/submit").await;
+        assert_eq!("main\n", response);
+        assert_method_not_allowed(&routing_table, hyper::Method::DELETE, "/forms/submit", "GET, HEAD, POST, PUT").await;
+
+        // A _routes entry without methods inherits them from the parent module
+        let response = get_response_text_for_method(&routing_table, hyper::Method::GET, "/forms/inherit").await;
+        assert_eq!("inherit\n", response);
+        assert_method_not_allowed(&routing_table, hyper::Method::POST, "/forms/inherit", "GET, HEAD").await;
+    }
+
+    #[tokio::test]
+    pub async fn parameterised_routes_set_env_vars_correctly() {
+        let map_file = TEST_PARAMETERISED_ROUTES_MODULE_MAP_FILE;
+
+        {
+            let route = "/users/42/fizz/buzz";
+
+            let (description, parsed_response) = get_decription_and_evs_from_module_map(map_file, None, route).await;
+            assert_eq!("This is the main entry point", description);
+
+            assert_eq!("42", parsed_response["X_ROUTE_PARAM_USER_ID"]);
+            assert_eq!("/fizz/buzz", parsed_response["PATH_INFO"]);
+            assert_eq!("/users/:user-id/...", parsed_response["X_MATCHED_ROUTE"]);
+            assert_eq!("/users/42", parsed_response["SCRIPT_NAME"]);
+        }
+
+        {
+            // Routes declared by _routes are appended to the parameterised parent
+            let route = "/users/42/exact";
+
+            let (description, parsed_response) = get_decription_and_evs_from_module_map(map_file, None, route).await;
+            assert_eq!("This is the .../exact handler", description);
+
+            assert_eq!("42", parsed_response["X_ROUTE_PARAM_USER_ID"]);
+            assert_eq!("", parsed_response["PATH_INFO"]);
+            assert_eq!("/users/:user-id/exact", parsed_response["X_MATCHED_ROUTE"]);
+            assert_eq!("/users/42/exact", parsed_response["SCRIPT_NAME"]);
+        }
+    }
+
     #[tokio::test]
     pub async fn health_check_builtin_takes_precedence_over_user_routes() {
         let empty_body = hyper::body::Body::empty();
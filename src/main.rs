@@ -1,21 +1,59 @@
-use wagi::{wagi_app, wagi_server::WagiServer};
+use wagi::{admin::AdminServer, wagi_app, wagi_app::WagiCommand, wagi_config::WagiConfiguration, wagi_server::WagiServer};
+use std::path::PathBuf;
+
+use wagi::dispatcher::RoutingTable;
 
 #[tokio::main]
 pub async fn main() -> Result<(), anyhow::Error> {
-    let startup_span = tracing::info_span!("total startup").entered();
+    match wagi_app::parse_command_line()? {
+        WagiCommand::Serve(configuration) => serve(configuration).await,
+        WagiCommand::PrintRoutes(configuration) => print_routes(configuration).await,
+        WagiCommand::Compile(configuration, out_dir) => compile(configuration, out_dir).await,
+    }
+}
 
-    let configuration = wagi_app::parse_command_line()?;
+async fn serve(configuration: WagiConfiguration) -> anyhow::Result<()> {
+    let startup_span = tracing::info_span!("total startup").entered();
 
-    // TODO: this can all go into lib.rs as "build_routing_table"
-    let handlers = wagi::handler_loader::load_handlers(&configuration).await?;
-    // Possibly this should go into a 'routing table builder' so we cleanly separate
-    // prep-time and serve-time responsibilities.
-    let routing_table = wagi::dispatcher::RoutingTable::build(&handlers, configuration.request_global_context())?;
+    let routing_table = build_routing_table(&configuration).await?;
+    routing_table.check_conflicts(configuration.strict_routes)?;
 
+    let admin_server = configuration.http_configuration.admin_listen_on
+        .map(|address| AdminServer::new(address, &routing_table))
+        .transpose()?;
     let server = WagiServer::new(&configuration, routing_table).await?;
 
     drop(startup_span);
 
     println!("Ready: serving on http://{}", configuration.http_configuration.listen_on);
-    server.serve().await
+    match admin_server {
+        Some(admin_server) => {
+            println!("Admin endpoints on http://{}", admin_server.address());
+            tokio::try_join!(server.serve(), admin_server.serve())?;
+            Ok(())
+        },
+        None => server.serve().await,
+    }
+}
+
+async fn print_routes(configuration: WagiConfiguration) -> anyhow::Result<()> {
+    let routing_table = build_routing_table(&configuration).await?;
+    println!("{}", routing_table.describe_routes().to_json()?);
+    Ok(())
+}
+
+async fn compile(configuration: WagiConfiguration, out_dir: PathBuf) -> anyhow::Result<()> {
+    let written = wagi::handler_loader::precompile_handlers(&configuration, &out_dir).await?;
+    for path in written {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+async fn build_routing_table(configuration: &WagiConfiguration) -> anyhow::Result<RoutingTable> {
+    // TODO: this can all go into lib.rs as "build_routing_table"
+    let handlers = wagi::handler_loader::load_handlers(configuration).await?;
+    // Possibly this should go into a 'routing table builder' so we cleanly separate
+    // prep-time and serve-time responsibilities.
+    RoutingTable::build(&handlers, configuration.request_global_context())
 }
@@ -0,0 +1,247 @@
+//! Request metrics, reported in the Prometheus text format at `/metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+
+pub const METRICS_ROUTE: &str = "/metrics";
+
+/// The route label for requests that matched no route.
+pub const UNMATCHED_ROUTE_LABEL: &str = "(unmatched)";
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Upper bounds, in seconds, of the latency histogram buckets
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Keyed by the route's original text
+    routes: RwLock<BTreeMap<String, Arc<RouteMetrics>>>,
+    in_flight: AtomicI64,
+}
+
+#[derive(Debug, Default)]
+pub struct RouteMetrics {
+    // Keyed by status code
+    responses: Mutex<BTreeMap<u16, u64>>,
+    instantiation: Histogram,
+    execution: Histogram,
+    traps: AtomicU64,
+    fuel_consumed: AtomicU64,
+    outbound_http_requests: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct Histogram {
+    // One count per bucket in LATENCY_BUCKETS; the +Inf bucket is `count`
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+/// Counts a request as in flight until it is dropped.
+pub struct InFlightGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Metrics {
+    pub fn for_route(&self, route: &str) -> Arc<RouteMetrics> {
+        if let Some(route_metrics) = self.routes.read().unwrap().get(route) {
+            return route_metrics.clone();
+        }
+        self.routes.write().unwrap().entry(route.to_owned()).or_default().clone()
+    }
+
+    pub fn start_request(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { metrics: self }
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.render()));
+        *res.status_mut() = StatusCode::OK;
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(PROMETHEUS_CONTENT_TYPE));
+        res
+    }
+
+    pub fn render(&self) -> String {
+        let routes = self.routes.read().unwrap();
+        let mut out = String::new();
+
+        write_header(&mut out, "wagi_requests_total", "counter", "Requests handled, by route and response status.");
+        for (route, m) in routes.iter() {
+            for (status, count) in m.responses.lock().unwrap().iter() {
+                let _ = writeln!(out, "wagi_requests_total{{route=\"{}\",status=\"{}\"}} {}", escape_label(route), status, count);
+            }
+        }
+
+        write_header(&mut out, "wagi_requests_in_flight", "gauge", "Requests currently being handled.");
+        let _ = writeln!(out, "wagi_requests_in_flight {}", self.in_flight.load(Ordering::Relaxed));
+
+        write_header(&mut out, "wagi_module_instantiation_seconds", "histogram", "Time taken to set up and instantiate the module for a request.");
+        for (route, m) in routes.iter() {
+            m.instantiation.render(&mut out, "wagi_module_instantiation_seconds", route);
+        }
+
+        write_header(&mut out, "wagi_module_execution_seconds", "histogram", "Time taken to run the module's entrypoint for a request.");
+        for (route, m) in routes.iter() {
+            m.execution.render(&mut out, "wagi_module_execution_seconds", route);
+        }
+
+        write_header(&mut out, "wagi_module_traps_total", "counter", "Requests on which the module trapped.");
+        for (route, m) in routes.iter() {
+            let _ = writeln!(out, "wagi_module_traps_total{{route=\"{}\"}} {}", escape_label(route), m.traps.load(Ordering::Relaxed));
+        }
+
+        write_header(&mut out, "wagi_module_fuel_consumed_total", "counter", "Fuel consumed by modules that have a fuel limit.");
+        for (route, m) in routes.iter() {
+            let _ = writeln!(out, "wagi_module_fuel_consumed_total{{route=\"{}\"}} {}", escape_label(route), m.fuel_consumed.load(Ordering::Relaxed));
+        }
+
+        write_header(&mut out, "wagi_outbound_http_requests_total", "counter", "HTTP requests made by modules.");
+        for (route, m) in routes.iter() {
+            let _ = writeln!(out, "wagi_outbound_http_requests_total{{route=\"{}\"}} {}", escape_label(route), m.outbound_http_requests.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RouteMetrics {
+    pub fn record_response(&self, status: StatusCode) {
+        *self.responses.lock().unwrap().entry(status.as_u16()).or_default() += 1;
+    }
+
+    pub fn record_instantiation(&self, duration: Duration) {
+        self.instantiation.observe(duration);
+    }
+
+    pub fn record_execution(&self, duration: Duration) {
+        self.execution.observe(duration);
+    }
+
+    pub fn record_trap(&self) {
+        self.traps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fuel_consumed(&self, fuel: u64) {
+        self.fuel_consumed.fetch_add(fuel, Ordering::Relaxed);
+    }
+
+    /// The counter to increment for each outbound HTTP request.
+    pub fn outbound_http_requests(&self) -> Arc<AtomicU64> {
+        self.outbound_http_requests.clone()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, route: &str) {
+        let route = escape_label(route);
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{}_bucket{{route=\"{}\",le=\"{}\"}} {}", name, route, bound, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{route=\"{}\",le=\"+Inf\"}} {}", name, route, count);
+        let _ = writeln!(out, "{}_sum{{route=\"{}\"}} {}", name, route, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count{{route=\"{}\"}} {}", name, route, count);
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn responses_are_counted_by_route_and_status() {
+        let metrics = Metrics::default();
+        metrics.for_route("/a").record_response(StatusCode::OK);
+        metrics.for_route("/a").record_response(StatusCode::OK);
+        metrics.for_route("/a").record_response(StatusCode::NOT_FOUND);
+        metrics.for_route("/b").record_trap();
+        metrics.for_route("/b").record_fuel_consumed(300);
+        metrics.for_route("/b").record_fuel_consumed(200);
+
+        let text = metrics.render();
+        assert!(text.contains("wagi_requests_total{route=\"/a\",status=\"200\"} 2\n"));
+        assert!(text.contains("wagi_requests_total{route=\"/a\",status=\"404\"} 1\n"));
+        assert!(text.contains("wagi_module_traps_total{route=\"/b\"} 1\n"));
+        assert!(text.contains("wagi_module_traps_total{route=\"/a\"} 0\n"));
+        assert!(text.contains("wagi_module_fuel_consumed_total{route=\"/b\"} 500\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        let route = metrics.for_route("/a");
+        route.record_execution(Duration::from_millis(3));
+        route.record_execution(Duration::from_millis(30));
+        route.record_execution(Duration::from_secs(20));
+
+        let text = metrics.render();
+        assert!(text.contains("wagi_module_execution_seconds_bucket{route=\"/a\",le=\"0.001\"} 0\n"));
+        assert!(text.contains("wagi_module_execution_seconds_bucket{route=\"/a\",le=\"0.005\"} 1\n"));
+        assert!(text.contains("wagi_module_execution_seconds_bucket{route=\"/a\",le=\"0.05\"} 2\n"));
+        assert!(text.contains("wagi_module_execution_seconds_bucket{route=\"/a\",le=\"10\"} 2\n"));
+        assert!(text.contains("wagi_module_execution_seconds_bucket{route=\"/a\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("wagi_module_execution_seconds_sum{route=\"/a\"} 20.033\n"));
+        assert!(text.contains("wagi_module_execution_seconds_count{route=\"/a\"} 3\n"));
+    }
+
+    #[test]
+    fn in_flight_requests_are_counted_until_done() {
+        let metrics = Metrics::default();
+        let guard = metrics.start_request();
+        assert!(metrics.render().contains("wagi_requests_in_flight 1\n"));
+        drop(guard);
+        assert!(metrics.render().contains("wagi_requests_in_flight 0\n"));
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!("a\\\"b\\\\c\\nd", escape_label("a\"b\\c\nd"));
+    }
+}
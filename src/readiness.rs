@@ -0,0 +1,149 @@
+//! The readiness check at `/readyz`, which reports whether every module is
+//! able to handle requests.
+//!
+//! WAGI only starts serving once all handlers are compiled and their dynamic
+//! routes resolved, so a server that answers `/readyz` at all has got that far.
+//! Beyond that, a module may export a `_health` function to say whether it is
+//! healthy (for example, whether a backend it depends on is reachable).
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use serde::Serialize;
+
+use crate::handlers::WasmRouteHandler;
+use crate::request::RequestGlobalContext;
+
+pub const READINESS_ROUTE: &str = "/readyz";
+
+/// The export called to check a module's health.
+pub const HEALTH_CHECK_ENTRYPOINT: &str = "_health";
+
+/// The modules to check, one per distinct module in the routing table.
+#[derive(Clone, Debug, Default)]
+pub struct ReadinessCheck {
+    modules: Vec<ModuleToCheck>,
+}
+
+#[derive(Clone, Debug)]
+struct ModuleToCheck {
+    handler: WasmRouteHandler,
+    // Where the module's stderr is logged
+    logging_key: String,
+}
+
+/// The result of a module's `_health` function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModuleHealth {
+    /// The module has no `_health` function.
+    NoCheck,
+    /// `_health` returned, or exited with status 0. Carries anything it
+    /// wrote to stdout.
+    Healthy(Option<String>),
+    /// `_health` trapped or exited with a non-zero status, or the module could
+    /// not be instantiated. Carries what it wrote to stdout, or else the error.
+    Unhealthy(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub modules: Vec<ModuleReadiness>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModuleReadiness {
+    pub module: String,
+    /// "healthy", "unhealthy" or "no_check".
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ReadinessCheck {
+    /// Adds a module to the check, unless a module of the same name has
+    /// already been added (as happens for routes declared by `_routes`).
+    pub fn add_module(&mut self, handler: &WasmRouteHandler, logging_key: String) {
+        if self.modules.iter().any(|m| m.handler.wasm_module_name == handler.wasm_module_name) {
+            return;
+        }
+        self.modules.push(ModuleToCheck {
+            handler: handler.clone(),
+            logging_key,
+        });
+    }
+
+    pub fn run(&self, global_context: &RequestGlobalContext) -> ReadinessReport {
+        let modules: Vec<_> = self.modules.iter()
+            .map(|m| {
+                let health = m.handler.check_health(global_context, m.logging_key.clone());
+                ModuleReadiness::new(&m.handler.wasm_module_name, health)
+            })
+            .collect();
+        ReadinessReport {
+            ready: modules.iter().all(|m| m.status != "unhealthy"),
+            modules,
+        }
+    }
+
+    pub fn response(&self, global_context: &RequestGlobalContext) -> Response<Body> {
+        let report = self.run(global_context);
+        let mut res = match serde_json::to_string_pretty(&report) {
+            Ok(json) => Response::new(Body::from(json)),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to serialise readiness report");
+                let mut res = Response::default();
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return res;
+            }
+        };
+        *res.status_mut() = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        res
+    }
+}
+
+impl ModuleReadiness {
+    fn new(module: &str, health: ModuleHealth) -> Self {
+        let (status, message) = match health {
+            ModuleHealth::NoCheck => ("no_check", None),
+            ModuleHealth::Healthy(message) => ("healthy", message),
+            ModuleHealth::Unhealthy(message) => ("unhealthy", Some(message)),
+        };
+        Self {
+            module: module.to_owned(),
+            status,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn module_status_is_reported_with_any_message() {
+        let no_check = ModuleReadiness::new("a.wasm", ModuleHealth::NoCheck);
+        assert_eq!(("no_check", None), (no_check.status, no_check.message));
+
+        let healthy = ModuleReadiness::new("a.wasm", ModuleHealth::Healthy(Some("fine".to_owned())));
+        assert_eq!(("healthy", Some("fine".to_owned())), (healthy.status, healthy.message));
+
+        let unhealthy = ModuleReadiness::new("a.wasm", ModuleHealth::Unhealthy("broken".to_owned()));
+        assert_eq!(("unhealthy", Some("broken".to_owned())), (unhealthy.status, unhealthy.message));
+    }
+
+    #[test]
+    fn messages_are_omitted_from_json_if_absent() {
+        let report = ReadinessReport {
+            ready: true,
+            modules: vec![ModuleReadiness::new("a.wasm", ModuleHealth::Healthy(None))],
+        };
+        assert_eq!(
+            r#"{"ready":true,"modules":[{"module":"a.wasm","status":"healthy"}]}"#,
+            serde_json::to_string(&report).unwrap()
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Redirects and rewrites: routes that send the client, or the request, to a
+//! different URL without running a Wasm module.
+
+use hyper::{
+    header::{HeaderValue, LOCATION},
+    http::request::Parts,
+    Body, Response, StatusCode, Uri,
+};
+
+use crate::dispatcher::{parameter_name, HostPattern, HostSpecificity, RoutePattern, RouteSpecificity};
+use crate::handler_loader::{RedirectConfigurationEntry, RewriteConfigurationEntry};
+
+const DEFAULT_REDIRECT_STATUS: StatusCode = StatusCode::FOUND;
+const HOST_PLACEHOLDER: &str = "${HOST}";
+const SUFFIX_MARKER: &str = "/...";
+
+/// Where a redirect or rewrite sends a request, e.g. `/new/:id/...` or
+/// `https://${HOST}/...`.
+///
+/// Segments such as `:id` are replaced by the value of the matched route's
+/// parameter of the same name, and `${HOST}` by the host the request was
+/// made to. If the target ends in `/...`, the part of the request path after
+/// the route is appended to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteTarget {
+    template: String,
+    preserve_suffix: bool,
+}
+
+impl RouteTarget {
+    pub fn parse(text: &str, route: &RoutePattern) -> anyhow::Result<Self> {
+        if text.is_empty() {
+            anyhow::bail!("The target of route {} is empty", route.original_text());
+        }
+        let (template, preserve_suffix) = match text.strip_suffix(SUFFIX_MARKER) {
+            Some(template) => (template, true),
+            None => (text, false),
+        };
+        let route_parameters = route.parameter_names();
+        if let Some(name) = template.split('/').filter_map(parameter_name).find(|name| !route_parameters.iter().any(|p| p == name)) {
+            anyhow::bail!("Target '{}' uses parameter ':{}', which route {} does not have", text, name, route.original_text());
+        }
+        Ok(Self {
+            template: template.to_owned(),
+            preserve_suffix,
+        })
+    }
+
+    pub fn original_text(&self) -> String {
+        if self.preserve_suffix {
+            format!("{}{}", self.template, SUFFIX_MARKER)
+        } else {
+            self.template.clone()
+        }
+    }
+
+    // Whether the target is a path on this server, rather than a full URL
+    fn is_path(&self) -> bool {
+        self.template.is_empty() || self.template.starts_with('/')
+    }
+
+    /// The URL for a request to `uri` that matched `route`. The request's
+    /// query string is kept unless the target has one of its own.
+    pub fn expand(&self, route: &RoutePattern, uri: &Uri, host: &str) -> String {
+        let parameters = route.parameters(uri.path());
+        let mut target = self.template
+            .split('/')
+            .map(|segment| match parameter_name(segment) {
+                Some(name) => parameters.iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, value)| url_escape::encode_component(value).to_string())
+                    .unwrap_or_default(),
+                None => segment.replace(HOST_PLACEHOLDER, host),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        if self.preserve_suffix {
+            target.push_str(&route.relative_path(uri.path()));
+        }
+        if target.is_empty() {
+            target.push('/');
+        }
+        if let Some(query) = uri.query() {
+            if !target.contains('?') {
+                target.push('?');
+                target.push_str(query);
+            }
+        }
+        target
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RedirectRouteHandler {
+    pub target: RouteTarget,
+    pub status: StatusCode,
+}
+
+impl RedirectRouteHandler {
+    pub fn build(source: &RedirectConfigurationEntry, route: &RoutePattern) -> anyhow::Result<Self> {
+        Ok(Self {
+            target: RouteTarget::parse(&source.to, route)?,
+            status: parse_redirect_status(source.status)?,
+        })
+    }
+
+    /// `host` is the host the request was made to, without any port.
+    pub fn handle_request(&self, matched_route: &RoutePattern, req: &Parts, host: &str) -> Response<Body> {
+        let location = self.target.expand(matched_route, &req.uri, host);
+        let mut res = Response::new(Body::empty());
+        match HeaderValue::from_str(&location) {
+            Ok(location) => {
+                *res.status_mut() = self.status;
+                res.headers_mut().insert(LOCATION, location);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, %location, "Redirect target is not a valid header value");
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        }
+        res
+    }
+}
+
+fn parse_redirect_status(status: Option<u16>) -> anyhow::Result<StatusCode> {
+    match status {
+        None => Ok(DEFAULT_REDIRECT_STATUS),
+        Some(code @ (301 | 302 | 307 | 308)) => Ok(StatusCode::from_u16(code)?),
+        Some(code) => Err(anyhow::anyhow!("Redirect status must be 301, 302, 307 or 308, but was {}", code)),
+    }
+}
+
+/// Changes the path of matching requests before they are routed, so that
+/// they are handled as if the client had requested the new path.
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    pub route_pattern: RoutePattern,
+    // If None, the rule applies to any host
+    pub host: Option<HostPattern>,
+    pub target: RouteTarget,
+}
+
+impl RewriteRule {
+    pub fn build(source: &RewriteConfigurationEntry) -> anyhow::Result<Self> {
+        let route_pattern = RoutePattern::parse(&source.route)?;
+        let host = source.host.as_deref().map(HostPattern::parse).transpose()?;
+        let target = RouteTarget::parse(&source.to, &route_pattern)?;
+        if !target.is_path() {
+            anyhow::bail!("Rewrite target '{}' must be a path starting with '/'", source.to);
+        }
+        Ok(Self { route_pattern, host, target })
+    }
+
+    fn is_match(&self, host: &str, uri_path: &str) -> bool {
+        self.route_pattern.is_match(uri_path) && self.host.as_ref().map_or(true, |h| h.is_match(host))
+    }
+
+    fn precedence(&self) -> (HostSpecificity, RouteSpecificity) {
+        let host_specificity = match &self.host {
+            None => HostSpecificity::Any,
+            Some(pattern) => pattern.specificity(),
+        };
+        (host_specificity, self.route_pattern.specificity())
+    }
+
+    /// The URI the request should be routed as. The scheme and authority of
+    /// the original URI, if any, are kept.
+    pub fn rewrite(&self, uri: &Uri, host: &str) -> anyhow::Result<Uri> {
+        let path_and_query = self.target.expand(&self.route_pattern, uri, host);
+        let mut builder = Uri::builder();
+        if let Some(scheme) = uri.scheme() {
+            builder = builder.scheme(scheme.clone());
+        }
+        if let Some(authority) = uri.authority() {
+            builder = builder.authority(authority.clone());
+        }
+        Ok(builder.path_and_query(path_and_query).build()?)
+    }
+}
+
+/// The rule that applies to a request, if any. Rules are chosen in the same
+/// way as routes: the most specific host, then the most specific path, and
+/// the last declared of equals.
+pub fn find_rewrite<'a>(rules: &'a [RewriteRule], host: &str, uri_path: &str) -> Option<&'a RewriteRule> {
+    rules.iter()
+        .filter(|r| r.is_match(host, uri_path))
+        .max_by_key(|r| r.precedence())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expand(route: &str, to: &str, uri: &str) -> String {
+        let route = RoutePattern::parse(route).unwrap();
+        let target = RouteTarget::parse(to, &route).unwrap();
+        target.expand(&route, &uri.parse().unwrap(), "example.com")
+    }
+
+    fn rewrite_rule(route: &str, to: &str, host: Option<&str>) -> RewriteRule {
+        RewriteRule::build(&RewriteConfigurationEntry {
+            route: route.to_owned(),
+            host: host.map(|h| h.to_owned()),
+            to: to.to_owned(),
+        }).unwrap()
+    }
+
+    #[test]
+    fn plain_targets_are_used_as_is() {
+        assert_eq!("/new", expand("/old", "/new", "/old"));
+        assert_eq!("/new", expand("/old/...", "/new", "/old/a/b"));
+        assert_eq!("https://example.org/", expand("/old", "https://example.org/", "/old"));
+    }
+
+    #[test]
+    fn suffix_is_preserved_if_requested() {
+        assert_eq!("/new/a/b", expand("/old/...", "/new/...", "/old/a/b"));
+        assert_eq!("/new", expand("/old/...", "/new/...", "/old"));
+        assert_eq!("/a/b", expand("/old/...", "/...", "/old/a/b"));
+        assert_eq!("/", expand("/old/...", "/...", "/old"));
+    }
+
+    #[test]
+    fn host_is_substituted() {
+        assert_eq!("https://example.com/a/b", expand("/...", "https://${HOST}/...", "/a/b"));
+        assert_eq!("https://example.com:8443/", expand("/...", "https://${HOST}:8443/...", "/"));
+    }
+
+    #[test]
+    fn parameters_are_substituted() {
+        assert_eq!("/users/123/profile", expand("/profile/:id", "/users/:id/profile", "/profile/123"));
+        assert_eq!("/users/a%20b/x", expand("/profile/:id/...", "/users/:id/...", "/profile/a%20b/x"));
+    }
+
+    #[test]
+    fn query_is_kept_unless_target_has_one() {
+        assert_eq!("/new/a?x=1", expand("/old/...", "/new/...", "/old/a?x=1"));
+        assert_eq!("/new?y=2", expand("/old", "/new?y=2", "/old?x=1"));
+    }
+
+    #[test]
+    fn unknown_parameters_are_rejected() {
+        let route = RoutePattern::parse("/profile/:id").unwrap();
+        assert!(RouteTarget::parse("/users/:name", &route).is_err());
+        assert!(RouteTarget::parse("", &route).is_err());
+    }
+
+    #[test]
+    fn only_redirect_statuses_are_allowed() {
+        assert_eq!(StatusCode::FOUND, parse_redirect_status(None).unwrap());
+        for code in [301, 302, 307, 308] {
+            assert_eq!(code, parse_redirect_status(Some(code)).unwrap().as_u16());
+        }
+        assert!(parse_redirect_status(Some(200)).is_err());
+        assert!(parse_redirect_status(Some(303)).is_err());
+    }
+
+    #[test]
+    fn redirect_sets_location_and_status() {
+        let route = RoutePattern::parse("/old/...").unwrap();
+        let handler = RedirectRouteHandler::build(&RedirectConfigurationEntry {
+            route: "/old/...".to_owned(),
+            host: None,
+            to: "/new/...".to_owned(),
+            status: Some(308),
+            source: None,
+        }, &route).unwrap();
+        let (req, _) = hyper::Request::get("/old/page?q=1").body(()).unwrap().into_parts();
+        let res = handler.handle_request(&route, &req, "example.com");
+        assert_eq!(StatusCode::PERMANENT_REDIRECT, res.status());
+        assert_eq!("/new/page?q=1", res.headers()[LOCATION]);
+    }
+
+    #[test]
+    fn rewrite_targets_must_be_paths() {
+        assert!(RewriteRule::build(&RewriteConfigurationEntry {
+            route: "/old".to_owned(),
+            host: None,
+            to: "https://example.org/".to_owned(),
+        }).is_err());
+    }
+
+    #[test]
+    fn most_specific_rewrite_wins() {
+        let rules = vec![
+            rewrite_rule("/api/v2/special", "/special", None),
+            rewrite_rule("/api/...", "/v1/...", None),
+            rewrite_rule("/api/v2/...", "/v2/...", None),
+            rewrite_rule("/api/...", "/v3/...", Some("new.example.com")),
+        ];
+        let rewritten = |host: &str, uri: &str| find_rewrite(&rules, host, uri)
+            .map(|r| r.rewrite(&uri.parse().unwrap(), host).unwrap().to_string());
+
+        assert_eq!(Some("/v1/x".to_owned()), rewritten("example.com", "/api/x"));
+        assert_eq!(Some("/v2/x".to_owned()), rewritten("example.com", "/api/v2/x"));
+        assert_eq!(Some("/special".to_owned()), rewritten("example.com", "/api/v2/special"));
+        assert_eq!(Some("/v3/v2/x".to_owned()), rewritten("new.example.com", "/api/v2/x"));
+        assert_eq!(None, rewritten("example.com", "/other"));
+    }
+
+    #[test]
+    fn rewrite_keeps_scheme_and_authority() {
+        let rule = rewrite_rule("/old/...", "/new/...", None);
+        let uri = "http://example.com:3000/old/a?b=c".parse().unwrap();
+        assert_eq!("http://example.com:3000/new/a?b=c", rule.rewrite(&uri, "example.com").unwrap().to_string());
+    }
+}
@@ -1,8 +1,15 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::error_response::ErrorResponseSettings;
+use crate::metrics::{Metrics, RouteMetrics};
+use crate::resource_limits::ResourceLimits;
+use crate::worker_pool::WorkerPool;
 
 #[derive(Clone, Debug)]
 pub struct RequestContext {
     pub client_addr: SocketAddr,
+    // Where to record metrics for the request, if metrics are enabled
+    pub route_metrics: Option<Arc<RouteMetrics>>,
 }
 
 #[derive(Clone, Debug)]
@@ -11,4 +18,16 @@ pub struct RequestGlobalContext {
     pub default_host: String,
     pub use_tls: bool,
     pub global_env_vars: HashMap<String, String>,
+    pub error_responses: Arc<ErrorResponseSettings>,
+    pub metrics: Option<Arc<Metrics>>,
+    // How long modules may run, unless they set their own timeout
+    pub module_timeout: Option<Duration>,
+    // Limits on what modules may allocate, unless they set their own
+    pub resource_limits: ResourceLimits,
+    // The largest request body accepted, unless a module sets its own limit
+    pub max_body_size: Option<u64>,
+    // How long a module waits for a client that has stopped sending or receiving
+    pub client_timeout: Duration,
+    // Runs the handlers that execute Wasm
+    pub worker_pool: Arc<WorkerPool>,
 }
@@ -0,0 +1,336 @@
+//! Reads request bodies, which modules receive on stdin. A body whose length
+//! the client gave (in `Content-Length`) is streamed to the module as the
+//! module reads stdin, so the module starts straight away and a module that
+//! reads slowly holds up the client rather than filling memory.
+//!
+//! CGI requires that the module be told the body's length (in `CONTENT_LENGTH`)
+//! before it starts, so a body sent without a length is read in full first.
+//! Small bodies are held in memory; larger ones are spooled to a temporary
+//! file, so that a large upload does not have to fit in memory.
+
+use std::io::{self, Cursor, Read, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, CONTENT_LENGTH};
+use hyper::{Body, Response};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use wasi_common::pipe::ReadPipe;
+use wasi_common::WasiFile;
+
+use crate::http_util::{bad_request, internal_server_error, payload_too_large, request_timeout};
+use crate::response_stream::{block_on_until, ClientWait};
+
+/// Bodies larger than this are spooled to a temporary file.
+pub const MAX_IN_MEMORY_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum RequestBody {
+    InMemory(Vec<u8>),
+    Spooled { file: std::fs::File, len: u64 },
+    /// Not yet read from the client. The client said how long it is.
+    Streaming { body: Body, len: u64, max_size: Option<u64> },
+}
+
+#[derive(Debug)]
+pub enum RequestBodyError {
+    /// The body is larger than the limit, which it carries.
+    TooLarge(u64),
+    /// The body could not be read from the client.
+    Read(hyper::Error),
+    /// The client did not send the body in time.
+    TimedOut,
+    /// The body could not be written to a temporary file.
+    Spool(std::io::Error),
+}
+
+impl RequestBody {
+    pub fn empty() -> Self {
+        Self::InMemory(vec![])
+    }
+
+    /// Prepares the body to be given to the module, failing if it is known to
+    /// be larger than `max_size`. A body whose `content_length` is too large is
+    /// refused without being read at all; otherwise, a body with a
+    /// `content_length` is left to be streamed, and one without is read in
+    /// full.
+    pub async fn read(body: Body, content_length: Option<u64>, max_size: Option<u64>) -> Result<Self, RequestBodyError> {
+        match content_length {
+            Some(len) if exceeds(len, max_size) => Err(RequestBodyError::TooLarge(max_size.unwrap_or_default())),
+            Some(0) => Ok(Self::empty()),
+            Some(len) => Ok(Self::Streaming { body, len, max_size }),
+            None => Self::read_all(body, max_size).await,
+        }
+    }
+
+    // Reads the whole body, failing as soon as it is known to be larger than
+    // `max_size`
+    async fn read_all(mut body: Body, max_size: Option<u64>) -> Result<Self, RequestBodyError> {
+        let too_large = |len: u64| exceeds(len, max_size);
+        if too_large(body.size_hint().lower()) {
+            return Err(RequestBodyError::TooLarge(max_size.unwrap_or_default()));
+        }
+
+        let mut buffer: Vec<u8> = vec![];
+        let mut spool: Option<tokio::fs::File> = None;
+        let mut len: u64 = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(RequestBodyError::Read)?;
+            len += chunk.len() as u64;
+            if too_large(len) {
+                return Err(RequestBodyError::TooLarge(max_size.unwrap_or_default()));
+            }
+            match &mut spool {
+                Some(file) => file.write_all(&chunk).await.map_err(RequestBodyError::Spool)?,
+                None if buffer.len() + chunk.len() > MAX_IN_MEMORY_BODY_SIZE => {
+                    let mut file = tokio::fs::File::from_std(create_spool_file().await.map_err(RequestBodyError::Spool)?);
+                    file.write_all(&buffer).await.map_err(RequestBodyError::Spool)?;
+                    file.write_all(&chunk).await.map_err(RequestBodyError::Spool)?;
+                    buffer = vec![];
+                    spool = Some(file);
+                }
+                None => buffer.extend_from_slice(&chunk),
+            }
+        }
+
+        match spool {
+            None => Ok(Self::InMemory(buffer)),
+            Some(mut file) => {
+                file.flush().await.map_err(RequestBodyError::Spool)?;
+                file.seek(SeekFrom::Start(0)).await.map_err(RequestBodyError::Spool)?;
+                Ok(Self::Spooled { file: file.into_std().await, len })
+            }
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::InMemory(bytes) => bytes.len() as u64,
+            Self::Spooled { len, .. } | Self::Streaming { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pipe to give the module as its stdin. A spooled body is read from
+    /// its file, and a streaming body from the client, as the module reads
+    /// stdin. Reads that wait for the client for longer than `wait` allows
+    /// fail, and a streaming body records why it failed in `failure`.
+    pub fn into_stdin(self, wait: ClientWait, failure: BodyReadFailure) -> Box<dyn WasiFile> {
+        match self {
+            Self::InMemory(bytes) => Box::new(ReadPipe::new(Cursor::new(bytes))),
+            Self::Spooled { file, .. } => Box::new(ReadPipe::new(file)),
+            Self::Streaming { body, max_size, .. } => Box::new(ReadPipe::new(StreamingStdin {
+                body,
+                pending: Bytes::new(),
+                received: 0,
+                max_size,
+                wait,
+                failure,
+            })),
+        }
+    }
+}
+
+/// Why a streamed body could not be read. The module only sees a failed read
+/// of stdin (which traps it), so this is how the handler finds out that the
+/// client, not the module, was at fault.
+#[derive(Clone, Debug, Default)]
+pub struct BodyReadFailure(Arc<Mutex<Option<RequestBodyError>>>);
+
+impl BodyReadFailure {
+    pub fn take(&self) -> Option<RequestBodyError> {
+        self.0.lock().unwrap().take()
+    }
+
+    // Records the error, and returns the one to give the module
+    fn record(&self, kind: io::ErrorKind, error: RequestBodyError) -> io::Error {
+        let io_error = io::Error::new(kind, error.to_string());
+        *self.0.lock().unwrap() = Some(error);
+        io_error
+    }
+}
+
+/// The length the client gave for the request body, if it gave a valid one.
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+fn exceeds(len: u64, max_size: Option<u64>) -> bool {
+    matches!(max_size, Some(max_size) if len > max_size)
+}
+
+// Creating the file is a blocking call, so is kept off the async worker
+async fn create_spool_file() -> io::Result<std::fs::File> {
+    tokio::task::spawn_blocking(tempfile::tempfile)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+/// A request body read from the client as the module reads its stdin.
+struct StreamingStdin {
+    body: Body,
+    // Received from the client, but not yet read by the module
+    pending: Bytes,
+    received: u64,
+    max_size: Option<u64>,
+    wait: ClientWait,
+    failure: BodyReadFailure,
+}
+
+impl Read for StreamingStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            // This runs on a worker thread, not an async one, so blocking
+            // until the client sends more is what we want
+            let chunk = match block_on_until(self.body.data(), self.wait.until()) {
+                Some(Some(chunk)) => chunk.map_err(|e| self.failure.record(io::ErrorKind::ConnectionAborted, RequestBodyError::Read(e)))?,
+                Some(None) => return Ok(0),
+                None => return Err(self.failure.record(io::ErrorKind::TimedOut, RequestBodyError::TimedOut)),
+            };
+            self.received += chunk.len() as u64;
+            // The client cannot send more than it said it would, but the
+            // limit is checked here too rather than relying on that
+            if exceeds(self.received, self.max_size) {
+                return Err(self.failure.record(io::ErrorKind::InvalidData, RequestBodyError::TooLarge(self.max_size.unwrap_or_default())));
+            }
+            self.pending = chunk;
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending.split_to(len));
+        Ok(len)
+    }
+}
+
+impl RequestBodyError {
+    pub fn response(&self) -> Response<Body> {
+        match self {
+            Self::TooLarge(_) => payload_too_large(),
+            Self::Read(_) => bad_request(),
+            Self::TimedOut => request_timeout(),
+            Self::Spool(_) => internal_server_error(),
+        }
+    }
+}
+
+impl std::fmt::Display for RequestBodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge(max_size) => write!(f, "The request body is larger than the limit of {} bytes", max_size),
+            Self::Read(e) => write!(f, "Failed to read the request body: {}", e),
+            Self::TimedOut => write!(f, "The client did not send the request body in time"),
+            Self::Spool(e) => write!(f, "Failed to spool the request body to a temporary file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestBodyError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    fn chunked(chunks: Vec<Result<Vec<u8>, std::io::Error>>) -> Body {
+        Body::wrap_stream(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn small_bodies_are_kept_in_memory() {
+        let body = RequestBody::read(Body::from("hello"), None, Some(10)).await.unwrap();
+        assert!(matches!(&body, RequestBody::InMemory(bytes) if bytes == b"hello"));
+        assert_eq!(5, body.len());
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_spooled_to_a_file() {
+        let chunk = vec![7u8; MAX_IN_MEMORY_BODY_SIZE / 2 + 1];
+        let body = RequestBody::read(chunked(vec![Ok(chunk.clone()), Ok(chunk)]), None, None).await.unwrap();
+        let expected_len = (MAX_IN_MEMORY_BODY_SIZE + 2) as u64;
+        match body {
+            RequestBody::Spooled { mut file, len } => {
+                assert_eq!(expected_len, len);
+                let mut contents = vec![];
+                file.read_to_end(&mut contents).unwrap();
+                assert_eq!(expected_len, contents.len() as u64);
+                assert!(contents.iter().all(|b| *b == 7));
+            }
+            _ => panic!("Expected body to be spooled"),
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_refused() {
+        // Known from the Content-Length
+        let error = RequestBody::read(Body::from("hello world"), Some(11), Some(10)).await.unwrap_err();
+        assert!(matches!(error, RequestBodyError::TooLarge(10)));
+
+        // Only known once read
+        let body = chunked(vec![Ok(b"hello".to_vec()), Ok(b" world".to_vec())]);
+        let error = RequestBody::read(body, None, Some(10)).await.unwrap_err();
+        assert!(matches!(error, RequestBodyError::TooLarge(10)));
+        assert_eq!(hyper::StatusCode::PAYLOAD_TOO_LARGE, error.response().status());
+    }
+
+    #[tokio::test]
+    async fn read_errors_are_bad_requests() {
+        let body = chunked(vec![Ok(b"hello".to_vec()), Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "gone"))]);
+        let error = RequestBody::read(body, None, None).await.unwrap_err();
+        assert!(matches!(error, RequestBodyError::Read(_)));
+        assert_eq!(hyper::StatusCode::BAD_REQUEST, error.response().status());
+    }
+
+    fn streaming_stdin(body: Body, max_size: Option<u64>, wait: ClientWait) -> StreamingStdin {
+        StreamingStdin { body, pending: Bytes::new(), received: 0, max_size, wait, failure: BodyReadFailure::default() }
+    }
+
+    #[tokio::test]
+    async fn bodies_with_a_length_are_streamed_as_they_are_read() {
+        let (mut sender, body) = Body::channel();
+        // Ready before any of the body has arrived
+        let body = RequestBody::read(body, Some(11), Some(20)).await.unwrap();
+        assert!(matches!(&body, RequestBody::Streaming { len: 11, .. }));
+
+        let mut stdin = match body {
+            RequestBody::Streaming { body, max_size, .. } => streaming_stdin(body, max_size, ClientWait::default()),
+            _ => unreachable!(),
+        };
+        let reader = std::thread::spawn(move || {
+            let mut first = [0u8; 3];
+            stdin.read_exact(&mut first).unwrap();
+            let mut rest = vec![];
+            stdin.read_to_end(&mut rest).unwrap();
+            (first, rest)
+        });
+        sender.send_data(Bytes::from("hello")).await.unwrap();
+        sender.send_data(Bytes::from(" world")).await.unwrap();
+        drop(sender);
+
+        let (first, rest) = reader.join().unwrap();
+        assert_eq!(b"hel", &first);
+        assert_eq!(b"lo world", &rest[..]);
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_held_to_the_limit_and_deadline() {
+        let body = chunked(vec![Ok(b"hello".to_vec()), Ok(b" world".to_vec())]);
+        let mut stdin = streaming_stdin(body, Some(10), ClientWait::default());
+        let failure = stdin.failure.clone();
+        let error = std::thread::spawn(move || stdin.read_to_end(&mut vec![]).unwrap_err()).join().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(matches!(failure.take(), Some(RequestBodyError::TooLarge(10))));
+
+        // The client never sends anything
+        let (_sender, body) = Body::channel();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(100);
+        let wait = ClientWait { deadline: Some(deadline), ..Default::default() };
+        let mut stdin = streaming_stdin(body, None, wait);
+        let failure = stdin.failure.clone();
+        let error = std::thread::spawn(move || stdin.read(&mut [0u8; 8]).unwrap_err()).join().unwrap();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        assert!(std::time::Instant::now() >= deadline);
+        assert_eq!(hyper::StatusCode::REQUEST_TIMEOUT, failure.take().unwrap().response().status());
+    }
+}
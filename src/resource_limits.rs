@@ -0,0 +1,314 @@
+//! Limits on the memory, table elements and instances a module may use while
+//! handling a request, enforced by a Wasmtime `ResourceLimiter` attached to
+//! the request's store.
+
+use wasmparser::{Alias, ExternalKind, ImportSectionEntryType, Parser, Payload};
+use wasmtime::{ResourceLimiter, Trap, TrapCode, DEFAULT_INSTANCE_LIMIT};
+
+use crate::error_response::ModuleResourceLimitExceeded;
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Limits on what a module may allocate. A limit that is not set is up to
+/// Wasmtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// The total size of the module's linear memories.
+    pub max_memory_mb: Option<u64>,
+    /// The number of elements in any one table.
+    pub max_table_elements: Option<u32>,
+    /// The number of instances, including those created by module linking.
+    pub max_instances: Option<usize>,
+}
+
+impl ResourceLimits {
+    /// These limits, with any that are not set taken from `defaults`.
+    pub fn or(self, defaults: Self) -> Self {
+        Self {
+            max_memory_mb: self.max_memory_mb.or(defaults.max_memory_mb),
+            max_table_elements: self.max_table_elements.or(defaults.max_table_elements),
+            max_instances: self.max_instances.or(defaults.max_instances),
+        }
+    }
+}
+
+/// Enforces `ResourceLimits` for a single store. A module that is refused
+/// memory or table space is not stopped there and then (`memory.grow` just
+/// returns -1), so the limiter remembers what it refused, so that if the
+/// module then fails because of it, the failure can be put down to the limit.
+#[derive(Debug)]
+pub struct ModuleLimiter {
+    limits: ResourceLimits,
+    // The number of instances instantiating the module creates, if known
+    instances_needed: Option<usize>,
+    memory_bytes: usize,
+    refused: Option<String>,
+}
+
+impl ModuleLimiter {
+    pub fn new(limits: ResourceLimits, instances_needed: Option<usize>) -> Self {
+        Self {
+            limits,
+            instances_needed,
+            memory_bytes: 0,
+            refused: None,
+        }
+    }
+
+    /// Attributes `error` to the limit the module exceeded, if it exceeded one.
+    pub fn explain(&self, error: anyhow::Error) -> anyhow::Error {
+        match self.exceeded_by(&error) {
+            Some(limit) => error.context(ModuleResourceLimitExceeded(limit)),
+            None => error,
+        }
+    }
+
+    fn exceeded_by(&self, error: &anyhow::Error) -> Option<String> {
+        // Wasmtime checks the instance count itself rather than asking the
+        // limiter, so a module that needs more than it allows never starts
+        if let Some(instances_needed) = self.instances_needed {
+            if instances_needed > self.instances() {
+                return Some(format!("instance limit of {} (it needs {})", self.instances(), instances_needed));
+            }
+        }
+        let refused = self.refused.clone()?;
+        match error.downcast_ref::<Trap>() {
+            // A module that cannot get the memory it needs typically aborts,
+            // which traps as unreachable code. Timeouts and other traps are
+            // not the limit's doing, even if a growth was refused earlier.
+            Some(trap) => (trap.trap_code() == Some(TrapCode::UnreachableCodeReached)).then_some(refused),
+            // Instantiation fails outright if the module's initial memory or
+            // tables are refused
+            None => Some(refused),
+        }
+    }
+
+    fn refuse(&mut self, exceeded: String) -> bool {
+        tracing::debug!(%exceeded, "Module exceeded resource limit");
+        self.refused = Some(exceeded);
+        false
+    }
+
+    // A module that carries on growing after a refusal has coped with it
+    fn grant(&mut self) -> bool {
+        self.refused = None;
+        true
+    }
+}
+
+/// The number of instances instantiating a module creates: one for the module
+/// itself, plus those it creates through module linking. A module it
+/// instantiates that is imported, or is an export of another instance, is
+/// counted as one instance.
+pub fn instances_needed(wasm: &[u8]) -> anyhow::Result<usize> {
+    // For each module being parsed, the instances needed by each module in its
+    // module index space, and the instances it needs so far
+    let mut stack: Vec<(Vec<usize>, usize)> = vec![(vec![], 1)];
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let ImportSectionEntryType::Module(_) = import?.ty {
+                        current(&mut stack).0.push(1);
+                    }
+                }
+            }
+            Payload::AliasSection(aliases) => {
+                for alias in aliases {
+                    let needed = match alias? {
+                        Alias::OuterModule { relative_depth, index } => {
+                            let outer = (stack.len() - 1).checked_sub(relative_depth as usize)
+                                .ok_or_else(|| anyhow::anyhow!("Module alias refers outside the module"))?;
+                            stack[outer].0.get(index as usize).copied().unwrap_or(1)
+                        }
+                        Alias::InstanceExport { kind: ExternalKind::Module, .. } => 1,
+                        _ => continue,
+                    };
+                    current(&mut stack).0.push(needed);
+                }
+            }
+            Payload::InstanceSection(section) => {
+                for instance in section {
+                    let (modules, instances) = current(&mut stack);
+                    let needed = modules.get(instance?.module() as usize).copied().unwrap_or(1);
+                    *instances = instances.saturating_add(needed);
+                }
+            }
+            Payload::ModuleSectionEntry { .. } => stack.push((vec![], 1)),
+            Payload::End => {
+                let (_, needed) = stack.pop().expect("Parser reported more module ends than starts");
+                match stack.last_mut() {
+                    Some((modules, _)) => modules.push(needed),
+                    None => return Ok(needed),
+                }
+            }
+            _ => (),
+        }
+    }
+    anyhow::bail!("Module ended unexpectedly")
+}
+
+fn current(stack: &mut [(Vec<usize>, usize)]) -> &mut (Vec<usize>, usize) {
+    stack.last_mut().expect("Parser reported more module ends than starts")
+}
+
+impl ResourceLimiter for ModuleLimiter {
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        let total = self.memory_bytes.saturating_add(desired.saturating_sub(current));
+        match self.limits.max_memory_mb {
+            Some(max_memory_mb) if total as u64 > max_memory_mb.saturating_mul(BYTES_PER_MB) => {
+                self.refuse(format!("memory limit of {} MB (it asked for {} bytes in total)", max_memory_mb, total))
+            }
+            _ => {
+                self.memory_bytes = total;
+                self.grant()
+            }
+        }
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        match self.limits.max_table_elements {
+            Some(max_table_elements) if desired > max_table_elements => {
+                self.refuse(format!("table limit of {} elements (it asked for {})", max_table_elements, desired))
+            }
+            _ => self.grant(),
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.max_instances.unwrap_or(DEFAULT_INSTANCE_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasmtime::{Engine, Instance, Module, Store};
+
+    const MB: usize = BYTES_PER_MB as usize;
+
+    // Runs `wat`'s `_start` under `limits`, returning the error explained
+    fn run_limited(wat: &str, limits: ResourceLimits) -> anyhow::Error {
+        let wasm = wat::parse_str(wat).unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm).unwrap();
+        let mut store = Store::new(&engine, ModuleLimiter::new(limits, Some(instances_needed(&wasm).unwrap())));
+        store.limiter(|limiter| limiter);
+        let result = Instance::new(&mut store, &module, &[])
+            .and_then(|instance| Ok(instance.get_typed_func::<(), (), _>(&mut store, "_start")?.call(&mut store, ())?));
+        let error = result.expect_err("Expected module to fail");
+        store.data().explain(error)
+    }
+
+    fn blamed_limit(error: &anyhow::Error) -> Option<&str> {
+        error.downcast_ref::<ModuleResourceLimitExceeded>().map(|e| e.0.as_str())
+    }
+
+    #[test]
+    fn module_limits_take_precedence_over_defaults() {
+        let module = ResourceLimits { max_memory_mb: Some(8), ..Default::default() };
+        let defaults = ResourceLimits { max_memory_mb: Some(64), max_table_elements: Some(1000), max_instances: None };
+        assert_eq!(
+            ResourceLimits { max_memory_mb: Some(8), max_table_elements: Some(1000), max_instances: None },
+            module.or(defaults)
+        );
+    }
+
+    #[test]
+    fn memory_limit_applies_to_all_memories_together() {
+        let mut limiter = ModuleLimiter::new(ResourceLimits { max_memory_mb: Some(4), ..Default::default() }, None);
+        assert!(limiter.memory_growing(0, 2 * MB, None));
+        assert!(limiter.memory_growing(0, 2 * MB, None));
+        assert!(!limiter.memory_growing(2 * MB, 2 * MB + 1, None));
+    }
+
+    #[test]
+    fn table_limit_is_enforced() {
+        let mut limiter = ModuleLimiter::new(ResourceLimits { max_table_elements: Some(10), ..Default::default() }, None);
+        assert!(limiter.table_growing(0, 10, None));
+        assert!(!limiter.table_growing(10, 11, None));
+    }
+
+    #[test]
+    fn modules_that_abort_after_a_refusal_are_blamed_on_the_limit() {
+        let limits = ResourceLimits { max_memory_mb: Some(1), ..Default::default() };
+        let error = run_limited(r#"(module (memory 1)
+            (func (export "_start")
+                (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1)) (then unreachable))))"#, limits);
+        let limit = blamed_limit(&error).expect("Expected limit to be blamed");
+        assert!(limit.starts_with("memory limit of 1 MB"), "Unexpected limit: {}", limit);
+
+        // The module's initial memory is too much
+        let error = run_limited(r#"(module (memory 17) (func (export "_start")))"#, limits);
+        assert!(blamed_limit(&error).is_some(), "Expected limit to be blamed: {:#}", error);
+    }
+
+    #[test]
+    fn failures_after_a_handled_refusal_are_not_blamed_on_the_limit() {
+        let limits = ResourceLimits { max_memory_mb: Some(1), ..Default::default() };
+
+        // The module copes with the refusal, then fails for some other reason
+        let error = run_limited(r#"(module (memory 1)
+            (func (export "_start")
+                (drop (memory.grow (i32.const 16)))
+                (drop (i32.div_u (i32.const 1) (i32.const 0)))))"#, limits);
+        assert!(blamed_limit(&error).is_none(), "Unexpected limit blamed: {:#}", error);
+
+        // The module carries on growing by less after the refusal
+        let error = run_limited(r#"(module (memory 1)
+            (func (export "_start")
+                (drop (memory.grow (i32.const 16)))
+                (drop (memory.grow (i32.const 1)))
+                unreachable))"#, limits);
+        assert!(blamed_limit(&error).is_none(), "Unexpected limit blamed: {:#}", error);
+    }
+
+    #[test]
+    fn instance_limit_is_recognised_from_instances_needed() {
+        let limiter = ModuleLimiter::new(ResourceLimits { max_instances: Some(1), ..Default::default() }, Some(2));
+        assert_eq!(1, limiter.instances());
+        let error = limiter.explain(anyhow::anyhow!("Instantiation failed"));
+        assert_eq!(Some("instance limit of 1 (it needs 2)"), blamed_limit(&error));
+
+        let limiter = ModuleLimiter::new(ResourceLimits { max_instances: Some(2), ..Default::default() }, Some(2));
+        assert!(blamed_limit(&limiter.explain(anyhow::anyhow!("Instantiation failed"))).is_none());
+    }
+
+    // The text format no longer has module linking, so these are built by hand
+    const WASM_HEADER: &[u8] = b"\0asm\x01\0\0\0";
+    const MODULE_SECTION: u8 = 14;
+    const INSTANCE_SECTION: u8 = 15;
+    const ALIAS_SECTION: u8 = 16;
+
+    fn module(sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut wasm = WASM_HEADER.to_vec();
+        for (id, contents) in sections {
+            wasm.push(*id);
+            wasm.push(contents.len() as u8);
+            wasm.extend(contents);
+        }
+        wasm
+    }
+
+    #[test]
+    fn instances_needed_counts_nested_instantiations() {
+        let leaf = module(&[]);
+        assert_eq!(1, instances_needed(&leaf).unwrap());
+
+        // Instantiates the first module of its parent twice
+        let branch = module(&[
+            (ALIAS_SECTION, vec![1, 0x01, 1, 0x05, 0]),
+            (INSTANCE_SECTION, vec![2, 0, 0, 0, 0, 0, 0]),
+        ]);
+        // Declares the two modules above, then instantiates each once
+        let mut nested = vec![2, leaf.len() as u8];
+        nested.extend(&leaf);
+        nested.push(branch.len() as u8);
+        nested.extend(&branch);
+        let top = module(&[
+            (MODULE_SECTION, nested),
+            (INSTANCE_SECTION, vec![2, 0, 1, 0, 0, 0, 0]),
+        ]);
+        assert_eq!(5, instances_needed(&top).unwrap());
+    }
+}
@@ -0,0 +1,314 @@
+//! Sends a module's output to the client as the module writes it, rather than
+//! once the module has finished. The response head goes as soon as the module
+//! has written its CGI headers; the body follows in chunks, and a module that
+//! writes faster than the client reads is held up until the client catches up,
+//! but only for so long (see `ClientWait`): a module blocked writing to a
+//! client that has stopped reading cannot be interrupted by its timeout, so
+//! the write itself gives up, and the failed write stops the module.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::task::ArcWake;
+use hyper::{body::Bytes, Body, Response};
+use tokio::sync::oneshot;
+use wasi_common::pipe::WritePipe;
+use wasi_common::WasiFile;
+
+use crate::handlers::{split_cgi_output, HeaderMode};
+
+/// Delivers the response for a request, once. Clones share the same channel.
+#[derive(Clone, Debug)]
+pub struct ResponseSender {
+    sender: Arc<Mutex<Option<oneshot::Sender<Response<Body>>>>>,
+}
+
+impl ResponseSender {
+    pub fn channel() -> (Self, oneshot::Receiver<Response<Body>>) {
+        let (sender, receiver) = oneshot::channel();
+        (Self { sender: Arc::new(Mutex::new(Some(sender))) }, receiver)
+    }
+
+    /// Sends the response, unless one has already been sent. Returns whether
+    /// it was sent (it is not if the client has gone away).
+    pub fn send(&self, response: Response<Body>) -> bool {
+        match self.sender.lock().unwrap().take() {
+            Some(sender) => sender.send(response).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Whether a response has been sent (or the client has gone away), in which
+    /// case it is too late to send another.
+    pub fn is_sent(&self) -> bool {
+        match &*self.sender.lock().unwrap() {
+            Some(sender) => sender.is_closed(),
+            None => true,
+        }
+    }
+}
+
+/// How long a module waits for a client that has stopped sending or
+/// receiving, unless set with `--client-timeout`.
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a module may be held up by its client. Each wait gives up after
+/// `client_timeout`, so that a client that stops reading or sending cannot
+/// keep a worker busy for ever, and none goes on past the module's deadline.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientWait {
+    pub deadline: Option<Instant>,
+    pub client_timeout: Duration,
+}
+
+impl Default for ClientWait {
+    fn default() -> Self {
+        Self { deadline: None, client_timeout: DEFAULT_CLIENT_TIMEOUT }
+    }
+}
+
+impl ClientWait {
+    /// When a wait that starts now has to give up.
+    pub fn until(&self) -> Instant {
+        let stalled = Instant::now() + self.client_timeout;
+        match self.deadline {
+            Some(deadline) => deadline.min(stalled),
+            None => stalled,
+        }
+    }
+}
+
+/// A module's stdout, streamed to the client through a `ResponseSender`.
+#[derive(Clone)]
+pub struct StreamingStdout {
+    state: Arc<RwLock<StdoutState>>,
+}
+
+enum StdoutState {
+    /// The module has not yet finished writing its headers.
+    Headers { output: Vec<u8>, sender: ResponseSender, mode: HeaderMode, wait: ClientWait },
+    /// The headers were not valid. The output is kept so that the failure can
+    /// be reported as usual once the module has finished.
+    InvalidHeaders(Vec<u8>, HeaderMode),
+    /// The response head has been sent, and output goes to the body.
+    Body(hyper::body::Sender, ClientWait),
+    Closed,
+}
+
+impl StreamingStdout {
+    /// Writes that wait for the client for longer than `wait` allows fail,
+    /// and the response is cut short.
+    pub fn new(sender: ResponseSender, mode: HeaderMode, wait: ClientWait) -> Self {
+        let state = StdoutState::Headers { output: vec![], sender, mode, wait };
+        Self { state: Arc::new(RwLock::new(state)) }
+    }
+
+    /// The pipe to give the module as its stdout.
+    pub fn pipe(&self) -> Box<dyn WasiFile> {
+        Box::new(WritePipe::from_shared(self.state.clone()))
+    }
+
+    /// Completes the response once the module has finished. Fails, without
+    /// sending anything, if the module never wrote a valid set of headers.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        match std::mem::replace(&mut *self.state.write().unwrap(), StdoutState::Closed) {
+            StdoutState::Headers { output, sender, mode, .. } => {
+                sender.send(mode.compose_response(&output)?);
+                Ok(())
+            }
+            StdoutState::InvalidHeaders(output, mode) => mode.compose_response(&output).map(|_| ()),
+            // Dropping the body sender ends the body
+            StdoutState::Body(..) | StdoutState::Closed => Ok(()),
+        }
+    }
+
+    /// Cuts the response short after the module failed. If the head has
+    /// already been sent, the client sees the body end abnormally; otherwise
+    /// nothing has been sent, and the caller should send an error response.
+    pub fn abort(&self) {
+        std::mem::replace(&mut *self.state.write().unwrap(), StdoutState::Closed).abort_body();
+    }
+}
+
+impl StdoutState {
+    // Sends the response head if the output so far includes all the headers
+    fn send_head_if_ready(&mut self) -> io::Result<()> {
+        let (response, body_sender, rest) = match self {
+            Self::Headers { output, mode, wait, .. } => {
+                let (headers, rest) = match split_cgi_output(output) {
+                    Some(split) => split,
+                    None => return Ok(()),
+                };
+                let (body_sender, body) = Body::channel();
+                match mode.build_response(headers, body) {
+                    Ok(response) => (response, Self::Body(body_sender, *wait), rest.to_vec()),
+                    Err(e) => {
+                        tracing::debug!(error = %e, "Module wrote invalid headers");
+                        *self = Self::InvalidHeaders(std::mem::take(output), *mode);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => return Ok(()),
+        };
+        if let Self::Headers { sender, .. } = std::mem::replace(self, body_sender) {
+            if !sender.send(response) {
+                return Err(client_gone());
+            }
+        }
+        if !rest.is_empty() {
+            self.send_body(rest)?;
+        }
+        Ok(())
+    }
+
+    fn send_body(&mut self, chunk: Vec<u8>) -> io::Result<()> {
+        if let Self::Body(body, wait) = self {
+            // This runs on a worker thread, not an async one, so blocking
+            // until the client is ready for more is what we want
+            match block_on_until(body.send_data(Bytes::from(chunk)), wait.until()) {
+                Some(sent) => sent.map_err(|_| client_gone())?,
+                None => {
+                    tracing::info!("Client did not receive the response in time");
+                    std::mem::replace(self, Self::Closed).abort_body();
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "The client did not receive the response in time"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Ends the body abnormally, so that the client can tell it is incomplete
+    fn abort_body(self) {
+        if let Self::Body(body, _) = self {
+            body.abort();
+        }
+    }
+}
+
+/// Runs `future` to completion on this thread, or until `deadline` passes,
+/// in which case returns `None`.
+pub(crate) fn block_on_until<F: Future>(future: F, deadline: Instant) -> Option<F::Output> {
+    let waker = futures::task::waker(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    futures::pin_mut!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        // Woken early if the future is ready, and perhaps spuriously
+        std::thread::park_timeout(deadline - now);
+    }
+}
+
+struct ThreadWaker(std::thread::Thread);
+
+impl ArcWake for ThreadWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
+impl Write for StdoutState {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Headers { output, .. } => {
+                output.extend_from_slice(buf);
+                self.send_head_if_ready()?;
+            }
+            Self::InvalidHeaders(output, _) => output.extend_from_slice(buf),
+            Self::Body(..) => self.send_body(buf.to_vec())?,
+            Self::Closed => return Err(client_gone()),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn client_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The client is no longer receiving the response")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_all(stdout: &StreamingStdout, chunks: &[&[u8]]) {
+        let mut state = stdout.state.write().unwrap();
+        for chunk in chunks {
+            state.write_all(chunk).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn head_is_sent_as_soon_as_headers_are_complete() {
+        let (sender, mut receiver) = ResponseSender::channel();
+        let stdout = StreamingStdout::new(sender.clone(), HeaderMode::Cgi, ClientWait::default());
+
+        write_all(&stdout, &[b"Content-Type: text/plain\r\n", b"Status: 201\r\n"]);
+        assert!(receiver.try_recv().is_err());
+
+        // The body channel has room for one chunk before it waits for the client
+        write_all(&stdout, &[b"\r\nHello"]);
+        let response = receiver.try_recv().expect("Head should have been sent");
+        assert_eq!(201, response.status().as_u16());
+        assert!(sender.is_sent());
+
+        stdout.finish().unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(b"Hello", &body[..]);
+    }
+
+    // Returns when the write gave up
+    fn write_to_client_that_is_not_reading(wait: ClientWait) -> Instant {
+        let (sender, mut receiver) = ResponseSender::channel();
+        let stdout = StreamingStdout::new(sender, HeaderMode::Cgi, wait);
+        write_all(&stdout, &[b"Content-Type: text/plain\r\n\r\n", b"fills the channel"]);
+        // Held, but never polled
+        let _response = receiver.try_recv().expect("Head should have been sent");
+
+        let error = stdout.state.write().unwrap().write(b"more").unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        let gave_up = Instant::now();
+
+        let error = stdout.state.write().unwrap().write(b"more").unwrap_err();
+        assert_eq!(io::ErrorKind::BrokenPipe, error.kind());
+        gave_up
+    }
+
+    #[test]
+    fn writes_give_up_at_the_deadline_if_the_client_is_not_reading() {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let gave_up = write_to_client_that_is_not_reading(ClientWait { deadline: Some(deadline), client_timeout: Duration::from_secs(60) });
+        assert!(gave_up >= deadline);
+    }
+
+    #[test]
+    fn writes_give_up_after_the_client_timeout_without_a_deadline() {
+        let started = Instant::now();
+        let gave_up = write_to_client_that_is_not_reading(ClientWait { deadline: None, client_timeout: Duration::from_millis(100) });
+        assert!(gave_up >= started + Duration::from_millis(100));
+        assert!(gave_up < started + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn invalid_headers_fail_without_sending_anything() {
+        let (sender, mut receiver) = ResponseSender::channel();
+        let stdout = StreamingStdout::new(sender.clone(), HeaderMode::Cgi, ClientWait::default());
+        write_all(&stdout, &[b"X-Not-Enough: true\n\n", b"body"]);
+
+        assert!(stdout.finish().is_err());
+        assert!(!sender.is_sent());
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! A prefix tree over the path segments of route patterns. The routing table
+//! uses it to find the patterns that match a request path without testing every
+//! route in turn.
+
+use std::collections::HashMap;
+
+/// One `/`-separated segment of a route pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatternSegment<'a> {
+    Literal(&'a str),
+    /// A named parameter, which matches any non-empty segment.
+    Parameter,
+}
+
+/// Maps route patterns to values (in practice, indexes into the routing
+/// table's entry list).
+#[derive(Clone, Debug, Default)]
+pub struct RouteTree {
+    root: Node,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    literal_children: HashMap<String, Node>,
+    parameter_child: Option<Box<Node>>,
+    // Values whose patterns end at this node: those that match only paths
+    // ending here, and those (with a trailing `/...`) that also match subpaths.
+    exact_values: Vec<usize>,
+    prefix_values: Vec<usize>,
+}
+
+impl RouteTree {
+    pub fn insert(&mut self, segments: &[PatternSegment], is_prefix: bool, value: usize) {
+        let mut node = &mut self.root;
+        for segment in segments {
+            node = match segment {
+                PatternSegment::Literal(text) => node.literal_children.entry(text.to_string()).or_default(),
+                PatternSegment::Parameter => node.parameter_child.get_or_insert_with(Default::default),
+            };
+        }
+        if is_prefix {
+            node.prefix_values.push(value);
+        } else {
+            node.exact_values.push(value);
+        }
+    }
+
+    /// Returns the values of all patterns that match the path, in ascending
+    /// order.
+    pub fn matches(&self, uri_path: &str) -> Vec<usize> {
+        let segments: Vec<&str> = uri_path.split('/').collect();
+        let mut found = vec![];
+        self.root.collect_matches(&segments, &mut found);
+        found.sort_unstable();
+        found
+    }
+}
+
+impl Node {
+    fn collect_matches(&self, remaining: &[&str], found: &mut Vec<usize>) {
+        found.extend(&self.prefix_values);
+        match remaining.split_first() {
+            None => found.extend(&self.exact_values),
+            Some((segment, rest)) => {
+                if let Some(child) = self.literal_children.get(*segment) {
+                    child.collect_matches(rest, found);
+                }
+                if let Some(child) = &self.parameter_child {
+                    if !segment.is_empty() {
+                        child.collect_matches(rest, found);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use PatternSegment::{Literal, Parameter};
+
+    fn tree_of(patterns: &[(&[PatternSegment], bool)]) -> RouteTree {
+        let mut tree = RouteTree::default();
+        for (index, (segments, is_prefix)) in patterns.iter().enumerate() {
+            tree.insert(segments, *is_prefix, index);
+        }
+        tree
+    }
+
+    #[test]
+    fn exact_patterns_match_only_whole_path() {
+        let tree = tree_of(&[
+            (&[Literal(""), Literal("foo")], false),
+            (&[Literal(""), Literal("foo"), Literal("bar")], false),
+        ]);
+        assert_eq!(vec![0], tree.matches("/foo"));
+        assert_eq!(vec![1], tree.matches("/foo/bar"));
+        assert!(tree.matches("/foo/").is_empty());
+        assert!(tree.matches("/foo/bar/baz").is_empty());
+        assert!(tree.matches("/foobar").is_empty());
+    }
+
+    #[test]
+    fn prefix_patterns_match_subpaths() {
+        let tree = tree_of(&[
+            (&[Literal("")], true),
+            (&[Literal(""), Literal("foo")], true),
+        ]);
+        assert_eq!(vec![0, 1], tree.matches("/foo"));
+        assert_eq!(vec![0, 1], tree.matches("/foo/bar/baz"));
+        assert_eq!(vec![0], tree.matches("/foobar"));
+        assert_eq!(vec![0], tree.matches("/"));
+        assert!(tree.matches("foo").is_empty());
+    }
+
+    #[test]
+    fn parameters_match_any_non_empty_segment() {
+        let tree = tree_of(&[
+            (&[Literal(""), Literal("users"), Parameter], false),
+            (&[Literal(""), Literal("users"), Literal("me")], false),
+            (&[Literal(""), Literal("users"), Parameter], true),
+        ]);
+        assert_eq!(vec![0, 1, 2], tree.matches("/users/me"));
+        assert_eq!(vec![0, 2], tree.matches("/users/42"));
+        assert_eq!(vec![2], tree.matches("/users/42/orders"));
+        assert!(tree.matches("/users/").is_empty());
+        assert!(tree.matches("/users").is_empty());
+    }
+
+    #[test]
+    fn matches_are_in_insertion_order() {
+        let tree = tree_of(&[
+            (&[Literal(""), Literal("a"), Literal("b")], false),
+            (&[Literal("")], true),
+            (&[Literal(""), Parameter, Literal("b")], false),
+            (&[Literal(""), Literal("a")], true),
+        ]);
+        assert_eq!(vec![0, 1, 2, 3], tree.matches("/a/b"));
+    }
+}
@@ -0,0 +1,486 @@
+//! Serves files from a directory on disk, without running a Wasm module.
+//! Files are read asynchronously and streamed to the client, so a large file
+//! or a slow disk holds up neither the connection's async worker nor memory
+//! the size of the file.
+
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, Utc};
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+    },
+    http::request::Parts,
+    Body, Method, Response, StatusCode,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::dispatcher::RoutePattern;
+use crate::http_util::{content_type_for_path, not_found};
+
+/// The files served when a request names a directory, if no others are
+/// configured.
+pub const DEFAULT_INDEX_FILES: &[&str] = &["index.html", "index.htm"];
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+#[derive(Clone, Debug)]
+pub struct StaticFilesRouteHandler {
+    pub root: PathBuf,
+    pub index_files: Vec<String>,
+}
+
+/// The part of a file that a request asked for.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    All,
+    /// From the first to the last byte, inclusive.
+    Part(u64, u64),
+    /// A range that lies entirely outside the file.
+    Unsatisfiable,
+}
+
+impl StaticFilesRouteHandler {
+    pub async fn handle_request(&self, matched_route: &RoutePattern, req: &Parts) -> Response<Body> {
+        let relative_path = matched_route.relative_path(req.uri.path());
+        let path = match self.resolve(&relative_path) {
+            Some(path) => path,
+            None => return not_found(),
+        };
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(_) => return not_found(),
+        };
+
+        if !metadata.is_dir() {
+            return serve_file(&path, &metadata, req).await;
+        }
+        // Relative links in an index page only work if the URL ends in a slash
+        if !req.uri.path().ends_with('/') {
+            return redirect_to_directory(req);
+        }
+        match self.index_file(&path).await {
+            Some((index_path, index_metadata)) => serve_file(&index_path, &index_metadata, req).await,
+            None => not_found(),
+        }
+    }
+
+    /// Maps the part of the request path after the route to a path under the
+    /// root directory. Returns `None` if the path tries to escape the root.
+    fn resolve(&self, relative_path: &str) -> Option<PathBuf> {
+        let decoded = url_escape::decode(relative_path);
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                _ if segment.contains(['\\', ':', '\0']) => return None,
+                _ => path.push(segment),
+            }
+        }
+        Some(path)
+    }
+
+    async fn index_file(&self, dir: &Path) -> Option<(PathBuf, Metadata)> {
+        for name in &self.index_files {
+            let path = dir.join(name);
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => return Some((path, metadata)),
+                _ => continue,
+            }
+        }
+        None
+    }
+}
+
+fn redirect_to_directory(req: &Parts) -> Response<Body> {
+    let location = match req.uri.query() {
+        Some(query) => format!("{}/?{}", req.uri.path(), query),
+        None => format!("{}/", req.uri.path()),
+    };
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::MOVED_PERMANENTLY;
+    match HeaderValue::from_str(&location) {
+        Ok(value) => {
+            res.headers_mut().insert(LOCATION, value);
+        }
+        Err(e) => tracing::error!(error = %e, %location, "Invalid Location header"),
+    }
+    res
+}
+
+async fn serve_file(path: &Path, metadata: &Metadata, req: &Parts) -> Response<Body> {
+    let etag = entity_tag(metadata);
+    let last_modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+
+    let mut res = if is_not_modified(req, &etag, last_modified) {
+        let mut res = Response::default();
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        res
+    } else {
+        let len = metadata.len();
+        match requested_range(req, len, &etag, last_modified) {
+            ByteRange::All => file_response(path, 0, len, req, StatusCode::OK).await,
+            ByteRange::Part(first, last) => {
+                let mut res = file_response(path, first, last - first + 1, req, StatusCode::PARTIAL_CONTENT).await;
+                insert_header(&mut res, CONTENT_RANGE, format!("bytes {}-{}/{}", first, last, len));
+                res
+            }
+            ByteRange::Unsatisfiable => {
+                let mut res = Response::default();
+                *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                insert_header(&mut res, CONTENT_RANGE, format!("bytes */{}", len));
+                res
+            }
+        }
+    };
+
+    insert_header(&mut res, ETAG, etag);
+    if let Some(last_modified) = last_modified {
+        insert_header(&mut res, LAST_MODIFIED, last_modified.format(HTTP_DATE_FORMAT).to_string());
+    }
+    res
+}
+
+async fn file_response(path: &Path, start: u64, len: u64, req: &Parts, status: StatusCode) -> Response<Body> {
+    let body = if req.method == Method::HEAD {
+        Body::empty()
+    } else {
+        match open_range(path, start, len).await {
+            Ok(content) => Body::wrap_stream(ReaderStream::new(content)),
+            Err(e) => {
+                tracing::error!(error = %e, path = %path.display(), "Error reading static file");
+                let mut res = Response::default();
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return res;
+            }
+        }
+    };
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    let content_type = content_type_for_path(path).unwrap_or("application/octet-stream");
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res.headers_mut().insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    insert_header(&mut res, CONTENT_LENGTH, len.to_string());
+    res
+}
+
+// The file, positioned to read just the requested range
+async fn open_range(path: &Path, start: u64, len: u64) -> std::io::Result<tokio::io::Take<tokio::fs::File>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(file.take(len))
+}
+
+fn insert_header(res: &mut Response<Body>, name: hyper::header::HeaderName, value: String) {
+    match HeaderValue::from_str(&value) {
+        Ok(value) => {
+            res.headers_mut().insert(name, value);
+        }
+        Err(e) => tracing::error!(error = %e, %value, "Invalid header value"),
+    }
+}
+
+// The modification time and size identify a version of the file well enough
+// for caching, and are much cheaper to get than a hash of the content.
+fn entity_tag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", modified, metadata.len())
+}
+
+fn header_str(req: &Parts, name: hyper::header::HeaderName) -> Option<&str> {
+    req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn parse_http_date(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(text.trim()).ok().map(|d| d.with_timezone(&Utc))
+}
+
+// HTTP dates have a resolution of one second, so modification times must be
+// truncated before comparing them.
+fn same_or_earlier(last_modified: DateTime<Utc>, date: DateTime<Utc>) -> bool {
+    last_modified.timestamp() <= date.timestamp()
+}
+
+/// Whether the client's cached copy is current, per RFC 7232. If-None-Match
+/// takes precedence over If-Modified-Since.
+fn is_not_modified(req: &Parts, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_none_match) = header_str(req, IF_NONE_MATCH) {
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || weak_match(tag, etag));
+    }
+    match (header_str(req, IF_MODIFIED_SINCE).and_then(parse_http_date), last_modified) {
+        (Some(since), Some(last_modified)) => same_or_earlier(last_modified, since),
+        _ => false,
+    }
+}
+
+fn weak_match(tag: &str, etag: &str) -> bool {
+    tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+}
+
+/// The range the client asked for, per RFC 7233. Only single byte ranges are
+/// supported: requests for several ranges get the whole file, which the
+/// specification allows.
+fn requested_range(req: &Parts, len: u64, etag: &str, last_modified: Option<DateTime<Utc>>) -> ByteRange {
+    let range = match header_str(req, RANGE) {
+        Some(range) => range,
+        None => return ByteRange::All,
+    };
+    // If the client's partial copy is out of date, it needs the whole file
+    if let Some(if_range) = header_str(req, IF_RANGE) {
+        let if_range = if_range.trim();
+        let current = if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // If-Range requires a strong comparison
+            if_range == etag
+        } else {
+            match (parse_http_date(if_range), last_modified) {
+                (Some(date), Some(last_modified)) => last_modified.timestamp() == date.timestamp(),
+                _ => false,
+            }
+        };
+        if !current {
+            return ByteRange::All;
+        }
+    }
+    parse_range(range, len)
+}
+
+fn parse_range(range: &str, len: u64) -> ByteRange {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::All,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::All,
+    };
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // A suffix range: the last N bytes
+        (Err(_), Ok(suffix_len)) if first.is_empty() => {
+            if suffix_len == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Part(len.saturating_sub(suffix_len), len - 1)
+            }
+        }
+        (Ok(first), Err(_)) if last.is_empty() => {
+            if first < len {
+                ByteRange::Part(first, len - 1)
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        (Ok(first), Ok(last)) if first <= last => {
+            if first < len {
+                ByteRange::Part(first, last.min(len - 1))
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        _ => ByteRange::All,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONTENT: &str = "0123456789";
+
+    struct TestSite {
+        // Held so that the directory is not deleted until the test ends
+        _dir: tempfile::TempDir,
+        handler: StaticFilesRouteHandler,
+    }
+
+    fn test_site() -> TestSite {
+        let dir = tempfile::tempdir().expect("Failed to create temp dir");
+        std::fs::write(dir.path().join("digits.txt"), CONTENT).unwrap();
+        std::fs::write(dir.path().join("data.unknown"), CONTENT).unwrap();
+        std::fs::create_dir_all(dir.path().join("docs").join("empty")).unwrap();
+        std::fs::write(dir.path().join("docs").join("index.html"), "<h1>Docs</h1>").unwrap();
+        let handler = StaticFilesRouteHandler {
+            root: dir.path().to_owned(),
+            index_files: DEFAULT_INDEX_FILES.iter().map(|s| s.to_string()).collect(),
+        };
+        TestSite { _dir: dir, handler }
+    }
+
+    fn request(method: Method, path: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut builder = hyper::Request::builder().method(method).uri(format!("http://localhost:3000{}", path));
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).expect("Failed to build request").into_parts().0
+    }
+
+    async fn get(site: &TestSite, path: &str, headers: &[(&str, &str)]) -> Response<Body> {
+        site.handler.handle_request(&RoutePattern::parse("/static/...").unwrap(), &request(Method::GET, path, headers)).await
+    }
+
+    async fn body_text(response: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+        String::from_utf8(bytes.to_vec()).expect("Body was not UTF-8")
+    }
+
+    #[tokio::test]
+    async fn serves_files_with_content_type() {
+        let site = test_site();
+
+        let response = get(&site, "/static/digits.txt", &[]).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("text/plain; charset=utf-8", response.headers()[CONTENT_TYPE]);
+        assert_eq!("10", response.headers()[CONTENT_LENGTH]);
+        assert_eq!("bytes", response.headers()[ACCEPT_RANGES]);
+        assert!(response.headers().contains_key(ETAG));
+        assert!(response.headers().contains_key(LAST_MODIFIED));
+        assert_eq!(CONTENT, body_text(response).await);
+
+        let response = get(&site, "/static/data.unknown", &[]).await;
+        assert_eq!("application/octet-stream", response.headers()[CONTENT_TYPE]);
+    }
+
+    #[tokio::test]
+    async fn head_requests_get_headers_only() {
+        let site = test_site();
+        let response = site.handler.handle_request(&RoutePattern::parse("/static/...").unwrap(), &request(Method::HEAD, "/static/digits.txt", &[])).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("10", response.headers()[CONTENT_LENGTH]);
+        assert_eq!("", body_text(response).await);
+    }
+
+    #[tokio::test]
+    async fn directories_serve_index_files() {
+        let site = test_site();
+
+        let response = get(&site, "/static/docs/", &[]).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("text/html; charset=utf-8", response.headers()[CONTENT_TYPE]);
+        assert_eq!("<h1>Docs</h1>", body_text(response).await);
+
+        let response = get(&site, "/static/docs?lang=en", &[]).await;
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, response.status());
+        assert_eq!("/static/docs/?lang=en", response.headers()[LOCATION]);
+
+        assert_eq!(StatusCode::NOT_FOUND, get(&site, "/static/docs/empty/", &[]).await.status());
+    }
+
+    #[tokio::test]
+    async fn missing_files_and_escapes_are_not_found() {
+        let site = test_site();
+        assert_eq!(StatusCode::NOT_FOUND, get(&site, "/static/nope.txt", &[]).await.status());
+        assert_eq!(StatusCode::NOT_FOUND, get(&site, "/static/docs/../../etc/passwd", &[]).await.status());
+        assert_eq!(StatusCode::NOT_FOUND, get(&site, "/static/%2e%2e/etc/passwd", &[]).await.status());
+        assert_eq!(StatusCode::OK, get(&site, "/static/./docs/./index.html", &[]).await.status());
+    }
+
+    #[tokio::test]
+    async fn matching_etag_is_not_modified() {
+        let site = test_site();
+        let etag = get(&site, "/static/digits.txt", &[]).await.headers()[ETAG].to_str().unwrap().to_owned();
+
+        let response = get(&site, "/static/digits.txt", &[("If-None-Match", &etag)]).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+        assert_eq!(etag, response.headers()[ETAG]);
+
+        let weak = format!("\"other\", W/{}", etag);
+        assert_eq!(StatusCode::NOT_MODIFIED, get(&site, "/static/digits.txt", &[("If-None-Match", &weak)]).await.status());
+        assert_eq!(StatusCode::OK, get(&site, "/static/digits.txt", &[("If-None-Match", "\"other\"")]).await.status());
+    }
+
+    #[tokio::test]
+    async fn unchanged_since_date_is_not_modified() {
+        let site = test_site();
+        let last_modified = get(&site, "/static/digits.txt", &[]).await.headers()[LAST_MODIFIED].to_str().unwrap().to_owned();
+
+        let response = get(&site, "/static/digits.txt", &[("If-Modified-Since", &last_modified)]).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        let response = get(&site, "/static/digits.txt", &[("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT")]).await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        // If-None-Match takes precedence
+        let response = get(&site, "/static/digits.txt", &[("If-Modified-Since", &last_modified), ("If-None-Match", "\"other\"")]).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn ranges_return_partial_content() {
+        let site = test_site();
+
+        let response = get(&site, "/static/digits.txt", &[("Range", "bytes=2-4")]).await;
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!("bytes 2-4/10", response.headers()[CONTENT_RANGE]);
+        assert_eq!("3", response.headers()[CONTENT_LENGTH]);
+        assert_eq!("234", body_text(response).await);
+
+        let response = get(&site, "/static/digits.txt", &[("Range", "bytes=-3")]).await;
+        assert_eq!("789", body_text(response).await);
+
+        let response = get(&site, "/static/digits.txt", &[("Range", "bytes=8-")]).await;
+        assert_eq!("89", body_text(response).await);
+
+        let response = get(&site, "/static/digits.txt", &[("Range", "bytes=20-")]).await;
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status());
+        assert_eq!("bytes */10", response.headers()[CONTENT_RANGE]);
+    }
+
+    #[tokio::test]
+    async fn stale_if_range_gets_whole_file() {
+        let site = test_site();
+        let etag = get(&site, "/static/digits.txt", &[]).await.headers()[ETAG].to_str().unwrap().to_owned();
+
+        let response = get(&site, "/static/digits.txt", &[("Range", "bytes=2-4"), ("If-Range", &etag)]).await;
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+
+        let response = get(&site, "/static/digits.txt", &[("Range", "bytes=2-4"), ("If-Range", "\"stale\"")]).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn large_files_are_streamed_in_chunks() {
+        let site = test_site();
+        let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(site.handler.root.join("large.bin"), &content).unwrap();
+
+        let response = get(&site, "/static/large.bin", &[("Range", "bytes=1000-899999")]).await;
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!("899000", response.headers()[CONTENT_LENGTH]);
+
+        let mut body = response.into_body();
+        let mut chunks = 0;
+        let mut received = vec![];
+        while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+            chunks += 1;
+            received.extend_from_slice(&chunk.expect("Failed to read chunk"));
+        }
+        assert!(chunks > 1, "Expected the file to arrive in several chunks");
+        assert_eq!(&content[1000..900000], &received[..]);
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(ByteRange::Part(0, 9), parse_range("bytes=0-", 10));
+        assert_eq!(ByteRange::Part(5, 9), parse_range("bytes=5-100", 10));
+        assert_eq!(ByteRange::Part(0, 9), parse_range("bytes=-100", 10));
+        assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=-0", 10));
+        assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=0-", 0));
+        assert_eq!(ByteRange::All, parse_range("bytes=0-1,4-5", 10));
+        assert_eq!(ByteRange::All, parse_range("bytes=5-2", 10));
+        assert_eq!(ByteRange::All, parse_range("lines=1-2", 10));
+        assert_eq!(ByteRange::All, parse_range("bytes=x-y", 10));
+    }
+}
@@ -1,12 +1,21 @@
-use clap::{App, Arg, ArgMatches, ArgGroup};
+use clap::{App, AppSettings, Arg, ArgMatches, ArgGroup, SubCommand};
 use core::convert::TryFrom;
+use hyper::StatusCode;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use crate::{
     bindle_util::BindleConnectionInfo,
+    error_response::{ErrorPage, ErrorResponseSettings},
+    metrics::Metrics,
+    resource_limits::ResourceLimits,
+    response_stream::DEFAULT_CLIENT_TIMEOUT,
     wagi_config::{
         HandlerConfigurationSource, HttpConfiguration, TlsConfiguration, WagiConfiguration,
     },
+    wasm_module::InstancePoolConfig,
+    worker_pool::{WorkerPool, DEFAULT_QUEUE_DEPTH},
 };
 
 const ABOUT: &str = r#"
@@ -24,6 +33,9 @@ Wagi provides a few ways of speeding up performance. The easiest way is to enabl
 cache, which will cause all modules to be preloaded and cached on startup.
 "#;
 
+const COMPILE_ABOUT: &str = "Compile the modules that the server would serve ahead of time, so that the server can load them with --precompiled-dir instead of compiling them at startup. Takes the same arguments as the server, as some affect how modules are compiled.";
+const ROUTES_ABOUT: &str = "Print the routes that the server would serve, as JSON, without starting it. This loads the modules and runs their _routes functions, so takes the same arguments as the server.";
+
 const ENV_VAR_HELP: &str = "specifies an environment variable that should be used for every module WAGI runs. These will override any set by the module config. Multiple environment variables can be set per flag (e.g. -e FOO=bar BAR=baz) or the flag can be used multiple times (e.g. `-e FOO=bar -e BAR=baz`). Variables can be quoted (e.g. FOO=\"my bar\")";
 const BINDLE_URL: &str = "BINDLE_URL";
 
@@ -47,21 +59,80 @@ const ARG_LISTEN_ON: &str = "listen";
 const ARG_DEFAULT_HOSTNAME: &str = "hostname";
 const ARG_TLS_CERT_FILE: &str = "tls_cert_file";
 const ARG_TLS_KEY_FILE: &str = "tls_key_file";
+const ARG_ADMIN_LISTEN_ON: &str = "admin_listen";
 
 // Program configuration
 const ARG_WASM_CACHE_CONFIG_FILE: &str = "cache";
+const ARG_PRECOMPILED_DIR: &str = "precompiled_dir";
+const ARG_OUT_DIR: &str = "out_dir";
 const ARG_REMOTE_MODULE_CACHE_DIR: &str = "module_cache";
 const ARG_LOG_DIR: &str = "log_dir";
+const ARG_STRICT_ROUTES: &str = "strict_routes";
+const ARG_ERROR_PAGES: &str = "error_pages";
+const ARG_SHOW_ERROR_DETAILS: &str = "show_error_details";
+const ARG_METRICS: &str = "metrics";
+const ARG_MODULE_TIMEOUT: &str = "module_timeout";
+const ARG_WORKERS: &str = "workers";
+const ARG_WORKER_QUEUE_DEPTH: &str = "worker_queue_depth";
+const ARG_MAX_MEMORY_MB: &str = "max_memory_mb";
+const ARG_MAX_TABLE_ELEMENTS: &str = "max_table_elements";
+const ARG_MAX_INSTANCES: &str = "max_instances";
+const ARG_MAX_BODY_SIZE: &str = "max_body_size";
+const ARG_CLIENT_TIMEOUT: &str = "client_timeout";
+const ARG_INSTANCE_POOL_SIZE: &str = "instance_pool_size";
+const ARG_INSTANCE_POOL_MEMORY_MB: &str = "instance_pool_memory_mb";
+const ARG_INSTANCE_POOL_TABLE_ELEMENTS: &str = "instance_pool_table_elements";
 
 // Groups
 const GROUP_MODULE_SOURCE: &str = "module_source";
 const GROUP_BINDLE_SOURCE: &str = "bindle_source";
 
+// Subcommands
+const SUBCOMMAND_ROUTES: &str = "routes";
+const SUBCOMMAND_COMPILE: &str = "compile";
+
+/// What the command line asked WAGI to do.
+pub enum WagiCommand {
+    /// Serve requests.
+    Serve(WagiConfiguration),
+    /// Print the routes that would be served, and exit.
+    PrintRoutes(WagiConfiguration),
+    /// Compile the modules that would be served into the directory, and exit.
+    Compile(WagiConfiguration, std::path::PathBuf),
+}
+
 pub fn wagi_app_definition() -> App<'static, 'static> {
-    App::new("WAGI Server")
-    .version(clap::crate_version!())
-    .author("DeisLabs")
-    .about(ABOUT)
+    let app = App::new("WAGI Server")
+        .version(clap::crate_version!())
+        .author("DeisLabs")
+        .about(ABOUT)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(with_configuration_args(SubCommand::with_name(SUBCOMMAND_ROUTES).about(ROUTES_ABOUT)))
+        .subcommand(
+            with_configuration_args(SubCommand::with_name(SUBCOMMAND_COMPILE).about(COMPILE_ABOUT)).arg(
+                Arg::with_name(ARG_OUT_DIR)
+                    .short("o")
+                    .long("out-dir")
+                    .value_name("DIRECTORY")
+                    .takes_value(true)
+                    .required(true)
+                    .help("the directory to write the compiled modules to"),
+            ),
+        )
+        .arg(
+            Arg::with_name(ARG_ADMIN_LISTEN_ON)
+                .long("admin-listen")
+                .value_name("IP_PORT")
+                .takes_value(true)
+                .help("if set, the IP address and port on which to serve admin endpoints, such as GET /routes. This should not be reachable by clients. Default: no admin endpoints"),
+        );
+    with_configuration_args(app)
+}
+
+// The arguments that say what to serve and how, shared by the server and
+// the routes subcommand
+fn with_configuration_args(app: App<'static, 'static>) -> App<'static, 'static> {
+    app
     .arg(
         Arg::with_name(ARG_MODULES_CONFIG)
             .short("c")
@@ -136,6 +207,13 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to the cache.toml configuration file for configuring the Wasm optimization cache")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name(ARG_PRECOMPILED_DIR)
+            .long("precompiled-dir")
+            .value_name("DIRECTORY")
+            .takes_value(true)
+            .help("a directory of modules compiled by 'wagi compile'. Modules found there are loaded instead of being compiled; others, or ones compiled with incompatible settings, are compiled as usual"),
+    )
     .arg(
         Arg::with_name(ARG_LISTEN_ON)
             .short("l")
@@ -166,6 +244,115 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to a directory where module logs should be stored. This directory will have a separate subdirectory created within it per running module. Default is to create a tempdir.")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name(ARG_STRICT_ROUTES)
+            .long("strict-routes")
+            .help("if set, fail to start if any routes are duplicated or can never be reached, instead of logging warnings")
+            .required(false)
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name(ARG_ERROR_PAGES)
+            .long("error-page")
+            .value_name("STATUS=FILE")
+            .help("a file to send as the body of error responses with the given status code, e.g. '500=/var/www/500.html'. The content type is inferred from the file extension. Multiple pages can be specified.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name(ARG_SHOW_ERROR_DETAILS)
+            .long("show-error-details")
+            .help("if set, include the failure message and Wasm backtrace in the response when a module fails. This exposes module internals to clients, so should be used only in development.")
+            .required(false)
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name(ARG_METRICS)
+            .long("metrics")
+            .help("if set, serve request metrics in the Prometheus text format at /metrics")
+            .required(false)
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name(ARG_MODULE_TIMEOUT)
+            .long("module-timeout")
+            .value_name("MILLISECONDS")
+            .takes_value(true)
+            .help("how long a module may run to handle a request before it is stopped and the client gets 504 Gateway Timeout. Modules can set their own timeout with 'timeout_ms'. Default: no timeout"),
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_MEMORY_MB)
+            .long("max-memory-mb")
+            .value_name("MEGABYTES")
+            .takes_value(true)
+            .help("the linear memory a module may use to handle a request. Modules can set their own limit with 'max_memory_mb'. Default: no limit"),
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_TABLE_ELEMENTS)
+            .long("max-table-elements")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("the number of elements a module's tables may grow to. Modules can set their own limit with 'max_table_elements'. Default: no limit"),
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_INSTANCES)
+            .long("max-instances")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("the number of instances a module may create to handle a request, including itself. Modules can set their own limit with 'max_instances'. Default: 10000"),
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_BODY_SIZE)
+            .long("max-body-size")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("the largest request body a module may be sent. Larger requests get 413 Payload Too Large. Modules can set their own limit with 'max_body_size'. Default: no limit"),
+    )
+    .arg(
+        Arg::with_name(ARG_CLIENT_TIMEOUT)
+            .long("client-timeout")
+            .value_name("MILLISECONDS")
+            .takes_value(true)
+            .help("how long a module may wait for a client to receive more of the response, or send more of the request body, before the module is stopped. Default: 30000"),
+    )
+    .arg(
+        Arg::with_name(ARG_INSTANCE_POOL_SIZE)
+            .long("instance-pool-size")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("allocate module instances from a pool of this many, set aside at startup, rather than on demand. Must be at least the number of workers. Default: no pool"),
+    )
+    .arg(
+        Arg::with_name(ARG_INSTANCE_POOL_MEMORY_MB)
+            .long("instance-pool-memory-mb")
+            .value_name("MEGABYTES")
+            .takes_value(true)
+            .requires(ARG_INSTANCE_POOL_SIZE)
+            .help("the linear memory each instance in the pool has room for. Default: --max-memory-mb if set, otherwise 10"),
+    )
+    .arg(
+        Arg::with_name(ARG_INSTANCE_POOL_TABLE_ELEMENTS)
+            .long("instance-pool-table-elements")
+            .value_name("COUNT")
+            .takes_value(true)
+            .requires(ARG_INSTANCE_POOL_SIZE)
+            .help("the number of table elements each instance in the pool has room for. Default: 10000"),
+    )
+    .arg(
+        Arg::with_name(ARG_WORKERS)
+            .long("workers")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("the number of requests to modules that may run at once. Default: the number of CPUs"),
+    )
+    .arg(
+        Arg::with_name(ARG_WORKER_QUEUE_DEPTH)
+            .long("worker-queue-depth")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("the number of requests to modules that may wait for a worker when all are busy. Further requests get 503 Service Unavailable. Default: 128"),
+    )
     .arg(
         Arg::with_name(ARG_TLS_CERT_FILE)
             .long("tls-cert")
@@ -203,7 +390,7 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
     )
 }
 
-pub fn parse_command_line() -> anyhow::Result<WagiConfiguration> {
+pub fn parse_command_line() -> anyhow::Result<WagiCommand> {
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -211,7 +398,19 @@ pub fn parse_command_line() -> anyhow::Result<WagiConfiguration> {
     let wagi_app = wagi_app_definition();
 
     let matches = wagi_app.get_matches();
-    parse_configuration_from(matches)
+    parse_command_from(matches)
+}
+
+pub fn parse_command_from(matches: ArgMatches) -> anyhow::Result<WagiCommand> {
+    match matches.subcommand() {
+        (SUBCOMMAND_ROUTES, Some(routes_matches)) => Ok(WagiCommand::PrintRoutes(parse_configuration_from(routes_matches.clone())?)),
+        (SUBCOMMAND_COMPILE, Some(compile_matches)) => {
+            // Required by clap
+            let out_dir = std::path::PathBuf::from(compile_matches.value_of(ARG_OUT_DIR).unwrap_or_default());
+            Ok(WagiCommand::Compile(parse_configuration_from(compile_matches.clone())?, out_dir))
+        }
+        _ => Ok(WagiCommand::Serve(parse_configuration_from(matches)?)),
+    }
 }
 
 pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfiguration> {
@@ -229,6 +428,12 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
         .unwrap_or("cache.toml")
         .to_owned();
 
+    let admin_addr: Option<SocketAddr> = matches
+        .value_of(ARG_ADMIN_LISTEN_ON)
+        .map(|a| a.parse())
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Invalid admin listen address: {}", e))?;
+
     let hostname = matches
         .value_of(ARG_DEFAULT_HOSTNAME)
         .unwrap_or("localhost:3000");
@@ -244,7 +449,8 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
         Some(m) => std::path::PathBuf::from(m),
         None => {
             let tempdir = tempfile::tempdir()?;
-            println!(
+            // This goes to stderr so as not to mix with the output of subcommands
+            eprintln!(
                 "No log_dir specified, using temporary directory {} for logs",
                 tempdir.path().display()
             );
@@ -261,6 +467,14 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
 
     let handlers = parse_handler_configuration_source(&matches)?;
     let tls_config = parse_tls_config(tls_cert, tls_key)?;
+    let error_responses = parse_error_response_settings(&matches)?;
+    let worker_pool = parse_worker_pool(&matches)?;
+    let resource_limits = ResourceLimits {
+        max_memory_mb: parse_count(&matches, ARG_MAX_MEMORY_MB)?,
+        max_table_elements: parse_count(&matches, ARG_MAX_TABLE_ELEMENTS)?,
+        max_instances: parse_count(&matches, ARG_MAX_INSTANCES)?,
+    };
+    let instance_pool = parse_instance_pool(&matches, &resource_limits, &worker_pool)?;
 
     let configuration = WagiConfiguration {
         handlers,
@@ -269,10 +483,21 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
             listen_on: addr,
             default_hostname: hostname.to_owned(),
             tls: tls_config,
+            admin_listen_on: admin_addr,
         },
         wasm_cache_config_file: std::path::PathBuf::from(cache_config_path),
         asset_cache_dir: mc,
         log_dir,
+        strict_routes: matches.is_present(ARG_STRICT_ROUTES),
+        error_responses: Arc::new(error_responses),
+        metrics: matches.is_present(ARG_METRICS).then(|| Arc::new(Metrics::default())),
+        module_timeout: parse_count(&matches, ARG_MODULE_TIMEOUT)?.map(Duration::from_millis),
+        resource_limits,
+        max_body_size: parse_count(&matches, ARG_MAX_BODY_SIZE)?,
+        client_timeout: parse_count(&matches, ARG_CLIENT_TIMEOUT)?.map(Duration::from_millis).unwrap_or(DEFAULT_CLIENT_TIMEOUT),
+        worker_pool: Arc::new(worker_pool),
+        instance_pool,
+        precompiled_dir: matches.value_of(ARG_PRECOMPILED_DIR).map(std::path::PathBuf::from),
     };
 
     Ok(configuration)
@@ -396,6 +621,65 @@ fn parse_tls_config(
     }
 }
 
+fn parse_error_response_settings(matches: &ArgMatches) -> anyhow::Result<ErrorResponseSettings> {
+    let pages = match matches.values_of(ARG_ERROR_PAGES) {
+        Some(v) => v.into_iter().map(parse_error_page).collect::<anyhow::Result<_>>()?,
+        None => HashMap::new(),
+    };
+    Ok(ErrorResponseSettings {
+        pages,
+        show_details: matches.is_present(ARG_SHOW_ERROR_DETAILS),
+    })
+}
+
+fn parse_worker_pool(matches: &ArgMatches) -> anyhow::Result<WorkerPool> {
+    let size = parse_count(matches, ARG_WORKERS)?.unwrap_or_else(WorkerPool::default_size);
+    let queue_depth = parse_count(matches, ARG_WORKER_QUEUE_DEPTH)?.unwrap_or(DEFAULT_QUEUE_DEPTH);
+    WorkerPool::new(size, queue_depth)
+}
+
+fn parse_instance_pool(matches: &ArgMatches, resource_limits: &ResourceLimits, worker_pool: &WorkerPool) -> anyhow::Result<Option<InstancePoolConfig>> {
+    let size: u32 = match parse_count(matches, ARG_INSTANCE_POOL_SIZE)? {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+    // Every request being handled needs at least one instance
+    if (size as usize) < worker_pool.size() {
+        anyhow::bail!(
+            "The instance pool size ({}) must be at least the number of workers ({}). Raise --instance-pool-size, or lower --workers",
+            size,
+            worker_pool.size()
+        );
+    }
+    let max_memory_mb = parse_count(matches, ARG_INSTANCE_POOL_MEMORY_MB)?
+        .or(resource_limits.max_memory_mb)
+        .unwrap_or(InstancePoolConfig::DEFAULT_MAX_MEMORY_MB);
+    let max_table_elements = parse_count(matches, ARG_INSTANCE_POOL_TABLE_ELEMENTS)?
+        .unwrap_or(InstancePoolConfig::DEFAULT_MAX_TABLE_ELEMENTS);
+    Ok(Some(InstancePoolConfig { size, max_memory_mb, max_table_elements }))
+}
+
+fn parse_count<T: std::str::FromStr>(matches: &ArgMatches, arg: &str) -> anyhow::Result<Option<T>> {
+    matches
+        .value_of(arg)
+        .map(|v| v.parse().map_err(|_| anyhow::anyhow!("Invalid value '{}' for --{}: expected a whole number", v, arg.replace('_', "-"))))
+        .transpose()
+}
+
+fn parse_error_page(val: &str) -> anyhow::Result<(StatusCode, ErrorPage)> {
+    let (status, path) = val
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Invalid error page '{}', expected STATUS=FILE", val))?;
+    let status = status
+        .trim()
+        .parse::<StatusCode>()
+        .ok()
+        .filter(|s| s.is_client_error() || s.is_server_error())
+        .ok_or_else(|| anyhow::anyhow!("Invalid error page '{}': '{}' is not an HTTP error status", val, status))?;
+    let page = ErrorPage::load(std::path::Path::new(path.trim()))?;
+    Ok((status, page))
+}
+
 /// Merge environment variables defined in a file with those defined on the CLI.
 fn merge_env_vars(matches: &ArgMatches) -> anyhow::Result<HashMap<String, String>> {
     let mut env_vars: HashMap<String, String> = match matches.values_of(ARG_ENV_FILES) {
@@ -518,6 +802,45 @@ mod test {
         parse_env_var("=bar").expect_err("Missing key should fail");
     }
 
+    #[test]
+    fn test_routes_subcommand() {
+        let config_path = "testdata/module-maps/test1.toml";
+
+        let matches = wagi_app_definition().get_matches_from(vec!["wagi", "routes", "-c", config_path]);
+        match parse_command_from(matches).expect("routes subcommand should parse") {
+            WagiCommand::PrintRoutes(configuration) => assert!(configuration.http_configuration.admin_listen_on.is_none()),
+            _ => panic!("Expected routes subcommand"),
+        }
+
+        let matches = wagi_app_definition().get_matches_from(vec!["wagi", "-c", config_path, "--admin-listen", "127.0.0.1:3001"]);
+        match parse_command_from(matches).expect("server command should parse") {
+            WagiCommand::Serve(configuration) => assert_eq!(
+                Some("127.0.0.1:3001".parse().unwrap()),
+                configuration.http_configuration.admin_listen_on
+            ),
+            _ => panic!("Expected server command"),
+        }
+
+        assert!(wagi_app_definition().get_matches_from_safe(vec!["wagi", "routes"]).is_err());
+    }
+
+    #[test]
+    fn test_compile_subcommand() {
+        let config_path = "testdata/module-maps/test1.toml";
+
+        let matches = wagi_app_definition().get_matches_from(vec!["wagi", "compile", "-c", config_path, "--out-dir", "compiled"]);
+        match parse_command_from(matches).expect("compile subcommand should parse") {
+            WagiCommand::Compile(_, out_dir) => assert_eq!(std::path::PathBuf::from("compiled"), out_dir),
+            _ => panic!("Expected compile subcommand"),
+        }
+
+        let matches = wagi_app_definition().get_matches_from(vec!["wagi", "-c", config_path, "--precompiled-dir", "compiled"]);
+        let configuration = parse_configuration_from(matches).expect("server command should parse");
+        assert_eq!(Some(std::path::PathBuf::from("compiled")), configuration.precompiled_dir);
+
+        assert!(wagi_app_definition().get_matches_from_safe(vec!["wagi", "compile", "-c", config_path]).is_err());
+    }
+
     #[tokio::test]
     async fn test_env_var_merge() {
         // Make sure that env vars are correctly merged together.
@@ -1,8 +1,13 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::{
     bindle_util::BindleConnectionInfo,
+    error_response::ErrorResponseSettings,
+    metrics::Metrics,
+    resource_limits::ResourceLimits,
+    worker_pool::WorkerPool,
     handler_loader::WasmCompilationSettings,
+    wasm_module::InstancePoolConfig,
     request::RequestGlobalContext,
 };
 
@@ -17,6 +22,23 @@ pub struct WagiConfiguration {
     pub wasm_cache_config_file: PathBuf,
     pub asset_cache_dir: PathBuf,
     pub log_dir: PathBuf,
+    pub strict_routes: bool,
+    pub error_responses: Arc<ErrorResponseSettings>,
+    // If None, metrics are not collected
+    pub metrics: Option<Arc<Metrics>>,
+    // If None, modules may run for as long as they like
+    pub module_timeout: Option<Duration>,
+    // Limits that apply to modules that do not set their own
+    pub resource_limits: ResourceLimits,
+    // If None, request bodies may be any size
+    pub max_body_size: Option<u64>,
+    // How long a module waits for a client that has stopped sending or receiving
+    pub client_timeout: Duration,
+    pub worker_pool: Arc<WorkerPool>,
+    // If None, instances are allocated on demand rather than from a pool
+    pub instance_pool: Option<InstancePoolConfig>,
+    // Where to look for modules compiled by `wagi compile`, if anywhere
+    pub precompiled_dir: Option<PathBuf>,
 }
 
 #[derive(Clone)]
@@ -31,6 +53,8 @@ pub struct HttpConfiguration {
     pub listen_on: SocketAddr,
     pub default_hostname: String,
     pub tls: Option<TlsConfiguration>,
+    // Where to serve admin endpoints, if anywhere
+    pub admin_listen_on: Option<SocketAddr>,
 }
 
 #[derive(Clone, Debug)]
@@ -46,12 +70,24 @@ impl WagiConfiguration {
             default_host: self.http_configuration.default_hostname.to_owned(),
             use_tls: self.http_configuration.tls.is_some(),
             global_env_vars: self.env_vars.clone(),
+            error_responses: self.error_responses.clone(),
+            metrics: self.metrics.clone(),
+            module_timeout: self.module_timeout,
+            resource_limits: self.resource_limits,
+            max_body_size: self.max_body_size,
+            client_timeout: self.client_timeout,
+            worker_pool: self.worker_pool.clone(),
         }
     }
 
     pub fn wasm_compilation_settings(&self) -> WasmCompilationSettings {
         WasmCompilationSettings {
             cache_config_path: self.wasm_cache_config_file.clone(),
+            instance_pool: self.instance_pool,
+            resource_limits: self.resource_limits,
+            module_timeout: self.module_timeout,
+            precompiled_dir: self.precompiled_dir.clone(),
+            asset_cache_dir: self.asset_cache_dir.clone(),
         }
     }
 }
@@ -1,53 +1,257 @@
 use std::{fmt::Debug, sync::{Arc, RwLock}, path::Path};
 
-use wasi_common::pipe::{ReadPipe, WritePipe};
+use anyhow::Context;
 use wasmtime::*;
 
-// In future this might be pre-instantiated or something like that, so we will
-// just abstract it to be safe.
+use crate::request_body::BodyReadFailure;
+use crate::resource_limits::instances_needed;
+use crate::wasm_runner::{prelink, ModuleState};
+
 #[derive(Clone)]
-pub enum WasmModuleSource {
-    Compiled(Module, Engine),
+pub struct WasmModuleSource {
+    module: Module,
+    engine: Engine,
+    /// If set, the module was linked once, when it was loaded, so that a
+    /// request only has to create a store and instantiate the module.
+    /// Otherwise it is linked afresh for each request.
+    instance_pre: Option<InstancePre<ModuleState>>,
+    /// The number of instances instantiating the module creates. Not known
+    /// for precompiled modules.
+    instances_needed: Option<usize>,
 }
 
-impl Debug for WasmModuleSource {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Compiled(m, _) => f.write_fmt(format_args!("Compiled(Module={:?})", m.name())),
+/// The engines that modules are compiled with, shared by every module the
+/// server loads. Fuel metering is a setting of the engine, and has a cost,
+/// so modules with a fuel limit share a second engine that has it turned on.
+/// That engine is only created if some module needs it, as with instance
+/// pooling each engine reserves its own pool.
+#[derive(Clone)]
+pub struct WasmEngines {
+    standard: Engine,
+    metered: Option<Engine>,
+}
+
+/// Sizes the pool of instance slots that the pooling allocator sets aside
+/// up front, so that instantiating a module does not have to allocate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstancePoolConfig {
+    /// The number of instances that may exist at once, across all requests.
+    pub size: u32,
+    /// The linear memory each instance slot has room for.
+    pub max_memory_mb: u64,
+    /// The number of elements each instance slot has room for in its table.
+    pub max_table_elements: u32,
+}
+
+impl InstancePoolConfig {
+    pub const DEFAULT_MAX_MEMORY_MB: u64 = 10;
+    pub const DEFAULT_MAX_TABLE_ELEMENTS: u32 = 10000;
+
+    fn allocation_strategy(&self) -> InstanceAllocationStrategy {
+        InstanceAllocationStrategy::Pooling {
+            strategy: PoolingAllocationStrategy::ReuseAffinity,
+            module_limits: self.module_limits(),
+            instance_limits: InstanceLimits { count: self.size },
         }
     }
+
+    fn module_limits(&self) -> ModuleLimits {
+        ModuleLimits {
+            memory_pages: self.max_memory_mb * WASM_PAGES_PER_MB,
+            table_elements: self.max_table_elements,
+            // These only size each slot's bookkeeping, not its memory, so
+            // are set well above what typical modules need.
+            functions: 100_000,
+            types: 10_000,
+            globals: 1_000,
+            ..ModuleLimits::default()
+        }
+    }
+
+    /// Checks that the memories and tables a module (binary or text) declares,
+    /// and those of any modules nested in it, fit in an instance slot. Wasmtime
+    /// refuses to compile a module that does not fit, but its error does not
+    /// say which setting to change.
+    pub fn check_module_fits(&self, wasm: &[u8]) -> anyhow::Result<()> {
+        let wasm = wat::parse_bytes(wasm)?;
+        let limits = self.module_limits();
+        // The number of memories and tables defined by each module being parsed
+        let mut defined: Vec<(u32, u32)> = vec![(0, 0)];
+        for payload in wasmparser::Parser::new(0).parse_all(&wasm) {
+            match payload? {
+                wasmparser::Payload::MemorySection(memories) => {
+                    current(&mut defined).0 += memories.get_count();
+                    for memory in memories {
+                        let memory = memory?;
+                        if memory.memory64 {
+                            anyhow::bail!("The module declares a 64-bit memory, which an instance pool slot cannot hold");
+                        }
+                        if memory.initial > limits.memory_pages {
+                            anyhow::bail!(
+                                "The module declares a memory of {} KB, which is more than an instance pool slot holds ({} MB). Raise --instance-pool-memory-mb",
+                                memory.initial * 64,
+                                self.max_memory_mb
+                            );
+                        }
+                    }
+                }
+                wasmparser::Payload::TableSection(tables) => {
+                    current(&mut defined).1 += tables.get_count();
+                    for table in tables {
+                        let table = table?;
+                        if table.initial > limits.table_elements {
+                            anyhow::bail!(
+                                "The module declares a table of {} elements, which is more than an instance pool slot holds ({}). Raise --instance-pool-table-elements",
+                                table.initial,
+                                self.max_table_elements
+                            );
+                        }
+                    }
+                }
+                wasmparser::Payload::ModuleSectionEntry { .. } => defined.push((0, 0)),
+                wasmparser::Payload::End => {
+                    let (memories, tables) = defined.pop().expect("Parser reported more module ends than starts");
+                    if memories > limits.memories {
+                        anyhow::bail!("A module defines {} memories, but an instance pool slot holds {}", memories, limits.memories);
+                    }
+                    if tables > limits.tables {
+                        anyhow::bail!("A module defines {} tables, but an instance pool slot holds {}", tables, limits.tables);
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
 }
 
-impl WasmModuleSource {
-    /// Create a new Wasm Engine and configure it.
-    fn new_engine(cache_config_path: &Path) -> anyhow::Result<Engine> {
-        let mut config = Config::default();
+fn current(defined: &mut [(u32, u32)]) -> &mut (u32, u32) {
+    defined.last_mut().expect("Parser reported more module ends than starts")
+}
 
-        // Enable multi memory and module linking support.
-        config.wasm_multi_memory(true);
-        config.wasm_module_linking(true);
+const WASM_PAGES_PER_MB: u64 = 16;
 
-        if let Ok(p) = std::fs::canonicalize(cache_config_path) {
-            config.cache_config_load(p)?;
-        };
+impl WasmEngines {
+    pub fn new(cache_config_path: &Path, instance_pool: Option<&InstancePoolConfig>, metered: bool) -> anyhow::Result<Self> {
+        Ok(Self {
+            standard: new_engine(cache_config_path, false, instance_pool)?,
+            metered: if metered {
+                Some(new_engine(cache_config_path, true, instance_pool)?)
+            } else {
+                None
+            },
+        })
+    }
 
-        Engine::new(&config)
+    /// The engine for a module. If `consume_fuel` is set, every store created
+    /// from the engine must be given fuel before use.
+    pub fn for_module(&self, consume_fuel: bool) -> anyhow::Result<&Engine> {
+        if consume_fuel {
+            self.metered.as_ref().ok_or_else(|| anyhow::anyhow!("No engine with fuel metering was created"))
+        } else {
+            Ok(&self.standard)
+        }
     }
+}
+
+/// Create a new Wasm Engine and configure it.
+fn new_engine(cache_config_path: &Path, consume_fuel: bool, instance_pool: Option<&InstancePoolConfig>) -> anyhow::Result<Engine> {
+    let mut config = Config::default();
+
+    // Enable multi memory and module linking support.
+    config.wasm_multi_memory(true);
+    config.wasm_module_linking(true);
+
+    // Allows modules to be interrupted when they time out
+    config.epoch_interruption(true);
+
+    config.consume_fuel(consume_fuel);
+
+    if let Some(instance_pool) = instance_pool {
+        config.allocation_strategy(instance_pool.allocation_strategy());
+    }
+
+    if let Ok(p) = std::fs::canonicalize(cache_config_path) {
+        config.cache_config_load(p)?;
+    };
 
+    Engine::new(&config)
+}
+
+impl Debug for WasmModuleSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.instance_pre.is_some() { "PreLinked" } else { "Compiled" };
+        f.write_fmt(format_args!("{}(Module={:?})", kind, self.module.name()))
+    }
+}
+
+impl WasmModuleSource {
+    /// Compiles a module from Wasm (binary or text). Precompiled modules are
+    /// refused, wherever they came from: they can only be loaded from the
+    /// operator's precompiled directory, with `from_precompiled`.
     pub fn from_module_bytes(
         data: Arc<Vec<u8>>,
-        cache_config_path: &Path,
+        engine: &Engine,
     ) -> anyhow::Result<WasmModuleSource> {
-        let engine = Self::new_engine(cache_config_path)?;
-        let module = wasmtime::Module::new(&engine, &**data)?;
-        Ok(WasmModuleSource::Compiled(module, engine))
+        if is_precompiled(&data) {
+            anyhow::bail!("The module is precompiled native code, not Wasm. Precompiled modules can only be loaded from --precompiled-dir");
+        }
+        let wasm = wat::parse_bytes(&data)?;
+        let module = Module::new(engine, &wasm)?;
+        let instances_needed = Some(instances_needed(&wasm)?);
+        Ok(Self::from_module(module, engine, instances_needed))
     }
 
-    pub fn get_compiled_module(&self) -> anyhow::Result<(Module, Engine)> {
-        match self {
-            Self::Compiled(m, e) => Ok((m.clone(), e.clone())),
+    /// Loads a module precompiled by `wagi compile`. Precompiled modules are
+    /// native code, which is run as it is. The native code no longer says how
+    /// many instances the module creates, so `wagi compile` records that too.
+    ///
+    /// # Safety
+    ///
+    /// Wasmtime checks that the module was compiled by this version of
+    /// Wasmtime with compatible settings, but cannot check the code itself.
+    /// `data` must come from `wagi compile`, by way of a source the operator
+    /// trusts as they trust the WAGI binary.
+    pub unsafe fn from_precompiled(data: &[u8], instances_needed: usize, engine: &Engine) -> anyhow::Result<WasmModuleSource> {
+        let module = Module::deserialize(engine, data)
+            .context("The module was precompiled by a different version of WAGI, or with different settings. Recompile it with `wagi compile`")?;
+        Ok(Self::from_module(module, engine, Some(instances_needed)))
+    }
+
+    fn from_module(module: Module, engine: &Engine, instances_needed: Option<usize>) -> Self {
+        WasmModuleSource {
+            instance_pre: prelink(&module, engine),
+            module,
+            engine: engine.clone(),
+            instances_needed,
         }
     }
+
+    /// The compiled module, in the form `from_precompiled` can load.
+    pub fn serialize(&self) -> anyhow::Result<Vec<u8>> {
+        let (module, _) = self.get_compiled_module()?;
+        module.serialize()
+    }
+
+    pub fn get_compiled_module(&self) -> anyhow::Result<(Module, Engine)> {
+        Ok((self.module.clone(), self.engine.clone()))
+    }
+
+    /// The module linked ahead of time, if it could be.
+    pub fn instance_pre(&self) -> Option<&InstancePre<ModuleState>> {
+        self.instance_pre.as_ref()
+    }
+
+    /// The number of instances instantiating the module creates, if known.
+    pub fn instances_needed(&self) -> Option<usize> {
+        self.instances_needed
+    }
+}
+
+/// Whether `data` is a module that has already been compiled to native code
+/// (which Wasmtime stores as an ELF object), rather than Wasm.
+pub fn is_precompiled(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
 }
 
 // This is currently separated out because it has different ownership
@@ -55,12 +259,88 @@ impl WasmModuleSource {
 // (I don't want to .clone() the fields even though that would work,
 // because that is misleading about the semantics.)
 pub struct IOStreamRedirects {
-    pub stdin: ReadPipe<std::io::Cursor<Vec<u8>>>,
-    pub stdout: WritePipe<Vec<u8>>,
+    pub stdin: Box<dyn wasi_common::WasiFile>,
+    pub stdout: Box<dyn wasi_common::WasiFile>,
     pub stderr: wasi_cap_std_sync::file::File,
 }
 
 pub struct IORedirectionInfo {
     pub streams: IOStreamRedirects,
     pub stdout_mutex: Arc<RwLock<Vec<u8>>>,
+    pub body_failure: BodyReadFailure,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PLAIN_WAT: &str = r#"(module
+        (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+        (func (export "_start")))"#;
+    const HTTP_WAT: &str = r#"(module
+        (import "wasi_experimental_http" "close" (func (param i32) (result i32)))
+        (func (export "_start")))"#;
+
+    #[test]
+    fn modules_are_prelinked_unless_they_make_http_requests() {
+        let engines = WasmEngines::new(Path::new("no-such-cache.toml"), None, false).unwrap();
+        let engine = engines.for_module(false).unwrap();
+
+        let plain = WasmModuleSource::from_module_bytes(Arc::new(PLAIN_WAT.as_bytes().to_vec()), engine).unwrap();
+        assert!(plain.instance_pre().is_some());
+
+        let http = WasmModuleSource::from_module_bytes(Arc::new(HTTP_WAT.as_bytes().to_vec()), engine).unwrap();
+        assert!(http.instance_pre().is_none());
+
+        // Modules share the engine they were compiled with
+        let (_, plain_engine) = plain.get_compiled_module().unwrap();
+        let (_, http_engine) = http.get_compiled_module().unwrap();
+        assert!(Engine::same(&plain_engine, &http_engine));
+    }
+
+    #[test]
+    fn precompiled_modules_are_only_loaded_as_precompiled() {
+        let engines = WasmEngines::new(Path::new("no-such-cache.toml"), None, true).unwrap();
+        let standard = engines.for_module(false).unwrap();
+        let metered = engines.for_module(true).unwrap();
+
+        let compiled = WasmModuleSource::from_module_bytes(Arc::new(PLAIN_WAT.as_bytes().to_vec()), standard).unwrap();
+        let precompiled = compiled.serialize().unwrap();
+        assert!(is_precompiled(&precompiled));
+        assert!(!is_precompiled(PLAIN_WAT.as_bytes()));
+
+        let loaded = unsafe { WasmModuleSource::from_precompiled(&precompiled, 1, standard) }.unwrap();
+        assert!(loaded.instance_pre().is_some());
+        assert_eq!(compiled.instances_needed(), loaded.instances_needed());
+
+        // Fuel metering changes the compiled code
+        let error = unsafe { WasmModuleSource::from_precompiled(&precompiled, 1, metered) }.unwrap_err();
+        assert!(error.to_string().contains("Recompile it with `wagi compile`"), "Unexpected error: {:#}", error);
+
+        // Native code is never taken for a module
+        let error = WasmModuleSource::from_module_bytes(Arc::new(precompiled), standard).unwrap_err();
+        assert!(error.to_string().contains("--precompiled-dir"), "Unexpected error: {:#}", error);
+    }
+
+    #[test]
+    fn modules_declaring_more_than_a_pool_slot_are_recognised() {
+        let instance_pool = InstancePoolConfig { size: 1, max_memory_mb: 1, max_table_elements: 10 };
+        let engines = WasmEngines::new(Path::new("no-such-cache.toml"), Some(&instance_pool), false).unwrap();
+        let engine = engines.for_module(false).unwrap();
+
+        let fits = b"(module (memory 16) (table 10 funcref))";
+        instance_pool.check_module_fits(fits).unwrap();
+        assert!(WasmModuleSource::from_module_bytes(Arc::new(fits.to_vec()), engine).is_ok());
+
+        for (wat, expected) in [
+            (&b"(module (memory 17))"[..], "Raise --instance-pool-memory-mb"),
+            (b"(module (table 11 funcref))", "Raise --instance-pool-table-elements"),
+            (b"(module (memory 1) (memory 1))", "defines 2 memories"),
+        ] {
+            let error = instance_pool.check_module_fits(wat).unwrap_err();
+            assert!(error.to_string().contains(expected), "Unexpected error: {:#}", error);
+            // These are what Wasmtime would have refused anyway
+            assert!(WasmModuleSource::from_module_bytes(Arc::new(wat.to_vec()), engine).is_err());
+        }
+    }
 }
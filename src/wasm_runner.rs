@@ -1,20 +1,81 @@
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasi_common::pipe::WritePipe;
 use wasmtime::*;
 use wasmtime_wasi::*;
+use wasi_cap_std_sync::WasiCtxBuilder;
 
 use tracing::debug;
 
+use crate::epoch::deadline_ticks;
+use crate::error_response::{ModuleOutOfFuel, ModuleTimedOut};
 use crate::request::RequestGlobalContext;
+use crate::request_body::{BodyReadFailure, RequestBody};
+use crate::resource_limits::{ModuleLimiter, ResourceLimits};
+use crate::response_stream::ClientWait;
 use crate::wasm_module::WasmModuleSource;
 
 const STDERR_FILE: &str = "module.stderr";
 
+/// What the store holds for a module while it handles a request.
+pub struct ModuleState {
+    wasi: WasiCtx,
+    limiter: ModuleLimiter,
+}
+
+/// Limits on the resources a module may use while handling a request.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExecutionLimits {
+    // How long the module may run, including instantiation
+    pub timeout: Option<Duration>,
+    // How much fuel the module may consume, including instantiation. The
+    // module's engine must have fuel metering enabled if this is set.
+    pub max_fuel: Option<u64>,
+    pub resources: ResourceLimits,
+}
+
+impl ExecutionLimits {
+    pub fn apply_to(&self, store: &mut Store<ModuleState>) -> Result<(), Error> {
+        // Every store needs a deadline, as the engine has epoch interruption
+        // enabled and the default deadline has already passed
+        store.set_epoch_deadline(deadline_ticks(self.timeout));
+        if let Some(max_fuel) = self.max_fuel {
+            store.add_fuel(max_fuel)?;
+        }
+        Ok(())
+    }
+
+    /// A module that is interrupted because it ran out of time or fuel traps.
+    /// If that is why the module trapped with `error`, returns the error to
+    /// report instead.
+    pub fn interruption(&self, error: &Error, started: Instant, fuel_consumed: Option<u64>) -> Option<Error> {
+        if !error.is::<Trap>() {
+            return None;
+        }
+        match (self.max_fuel, fuel_consumed, self.timeout) {
+            (Some(max_fuel), Some(fuel_consumed), _) if fuel_consumed >= max_fuel => Some(ModuleOutOfFuel(max_fuel).into()),
+            (_, _, Some(timeout)) if started.elapsed() >= timeout => Some(ModuleTimedOut(timeout).into()),
+            _ => None,
+        }
+    }
+}
+
+impl ModuleState {
+    /// Attributes `error` to the resource limit the module exceeded, if it
+    /// exceeded one.
+    pub fn explain(&self, error: Error) -> Error {
+        self.limiter.explain(error)
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct WasmLinkOptions {
     pub http_allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
+    // Incremented for each outbound HTTP request the module makes
+    pub http_request_counter: Option<Arc<AtomicU64>>,
 }
 
 impl WasmLinkOptions {
@@ -33,7 +94,12 @@ impl WasmLinkOptions {
         result
     }
 
-    pub fn apply_to(&self, linker: &mut Linker<WasiCtx>) -> anyhow::Result<()> {
+    pub fn with_http_request_counter(mut self, counter: Option<Arc<AtomicU64>>) -> Self {
+        self.http_request_counter = counter;
+        self
+    }
+
+    pub fn apply_to(&self, linker: &mut Linker<ModuleState>) -> anyhow::Result<()> {
         let http = wasi_experimental_http_wasmtime::HttpCtx::new(
             self.http_allowed_hosts.clone(),
             self.http_max_concurrency,
@@ -45,14 +111,16 @@ impl WasmLinkOptions {
 }
 
 pub fn prepare_stdio_streams(
-    body: Vec<u8>,
+    body: RequestBody,
+    wait: ClientWait,
     global_context: &RequestGlobalContext,
     handler_id: String,
 ) -> Result<crate::wasm_module::IORedirectionInfo, Error> {
-    let stdin = ReadPipe::from(body);
+    let body_failure = BodyReadFailure::default();
+    let stdin = body.into_stdin(wait, body_failure.clone());
     let stdout_buf: Vec<u8> = vec![];
     let stdout_mutex = Arc::new(RwLock::new(stdout_buf));
-    let stdout = WritePipe::from_shared(stdout_mutex.clone());
+    let stdout = Box::new(WritePipe::from_shared(stdout_mutex.clone()));
     let log_dir = global_context.base_log_dir.join(handler_id);
 
     // The spec does not say what to do with STDERR.
@@ -76,60 +144,149 @@ pub fn prepare_stdio_streams(
             stderr,
         },
         stdout_mutex,
+        body_failure,
     })
 }
 
 
+/// Appends a line to the stderr log of the handler with the given ID, for
+/// problems the module's author needs to know about.
+pub fn append_to_module_log(global_context: &RequestGlobalContext, handler_id: &str, message: &str) {
+    use std::io::Write;
+    let log_file = global_context.base_log_dir.join(handler_id).join(STDERR_FILE);
+    let written = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&log_file)
+        .and_then(|mut f| writeln!(f, "{}", message));
+    if let Err(e) = written {
+        tracing::error!(error = %e, log_file = %log_file.display(), "Failed to write to module log");
+    }
+}
+
+/// A store for a module that creates `instances_needed` instances, if that is
+/// known, so that failures can be put down to the instance limit.
 pub fn new_store(
     ctx: WasiCtx,
-    engine: &Engine
-) -> Result<Store<WasiCtx>, anyhow::Error> {
-    Ok(Store::new(engine, ctx))
+    engine: &Engine,
+    resource_limits: ResourceLimits,
+    instances_needed: Option<usize>,
+) -> Result<Store<ModuleState>, anyhow::Error> {
+    let state = ModuleState {
+        wasi: ctx,
+        limiter: ModuleLimiter::new(resource_limits, instances_needed),
+    };
+    let mut store = Store::new(engine, state);
+    store.limiter(|state| &mut state.limiter);
+    Ok(store)
+}
+
+pub fn add_wasi_to_linker(linker: &mut Linker<ModuleState>) -> Result<(), Error> {
+    wasmtime_wasi::add_to_linker(linker, |state: &mut ModuleState| &mut state.wasi)
+}
+
+/// Links a module with WASI ahead of time, if it can be. Modules that make
+/// outbound HTTP requests are linked for each request instead, because each
+/// request needs its own HTTP state (its response handles and its count of
+/// requests in flight). Modules that cannot be linked are also left to be
+/// linked for each request, so that the failure is reported as before.
+pub fn prelink(module: &Module, engine: &Engine) -> Option<InstancePre<ModuleState>> {
+    if module.imports().any(|i| i.module() == wasi_experimental_http_wasmtime::HttpCtx::MODULE) {
+        return None;
+    }
+    let mut linker = Linker::new(engine);
+    add_wasi_to_linker(&mut linker).ok()?;
+    // Host functions do not belong to any one store, so the instance can be
+    // created in any store from the same engine
+    let mut store = new_store(WasiCtxBuilder::new().build(), engine, ResourceLimits::default(), None).ok()?;
+    match linker.instantiate_pre(&mut store, module) {
+        Ok(instance_pre) => Some(instance_pre),
+        Err(e) => {
+            debug!(error = %e, "Module could not be linked ahead of time");
+            None
+        }
+    }
 }
 
 pub fn prepare_wasm_instance(
     ctx: WasiCtx,
     wasm_module: &WasmModuleSource,
     link_options: WasmLinkOptions,
-) -> Result<(Store<WasiCtx>, Instance), Error> {
+    limits: ExecutionLimits,
+) -> Result<(Store<ModuleState>, Instance), Error> {
     debug!("Cloning module object");
     let (module, engine) = wasm_module.get_compiled_module()?;
-    let mut store = new_store(ctx, &engine)?;
+    let mut store = new_store(ctx, &engine, limits.resources, wasm_module.instances_needed())?;
+    limits.apply_to(&mut store)?;
 
-    debug!("Configuring linker");
-    let mut linker = Linker::new(&engine);
-    wasmtime_wasi::add_to_linker(&mut linker, |cx| cx)?;
-    link_options.apply_to(&mut linker)?;
+    let instance = match wasm_module.instance_pre() {
+        Some(instance_pre) => {
+            debug!("instantiating pre-linked module");
+            instance_pre.instantiate(&mut store)
+        }
+        None => {
+            debug!("Configuring linker");
+            let mut linker = Linker::new(&engine);
+            add_wasi_to_linker(&mut linker)?;
+            link_options.apply_to(&mut linker)?;
+            if let Some(counter) = link_options.http_request_counter {
+                count_calls(&mut linker, &mut store, wasi_experimental_http_wasmtime::HttpCtx::MODULE, "req", counter)?;
+            }
 
-    debug!("instantiating module in linker");
-    let instance = linker.instantiate(&mut store, &module)?;
+            debug!("instantiating module in linker");
+            linker.instantiate(&mut store, &module)
+        }
+    };
+    let instance = instance.map_err(|e| store.data().explain(e))?;
     Ok((store, instance))
 }
 
+// Replaces the named import with one that counts calls before passing them on
+fn count_calls(
+    linker: &mut Linker<ModuleState>,
+    store: &mut Store<ModuleState>,
+    module: &str,
+    name: &str,
+    counter: Arc<AtomicU64>,
+) -> Result<(), Error> {
+    let original = match linker.get(&mut *store, module, Some(name)) {
+        Some(Extern::Func(func)) => func,
+        _ => return Ok(()),
+    };
+    let ty = original.ty(&*store);
+    linker.allow_shadowing(true);
+    linker.func_new(module, name, ty, move |mut caller, params, results| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        original.call(&mut caller, params, results).map_err(Trap::from)
+    })?;
+    Ok(())
+}
+
 pub fn run_prepared_wasm_instance(
     instance: Instance,
-    mut store: Store<WasiCtx>,
+    store: &mut Store<ModuleState>,
     entrypoint: &str,
     wasm_module_name: &str,
 ) -> Result<(), Error> {
-    let start = instance.get_func(&mut store, entrypoint).ok_or_else(|| {
+    let start = instance.get_func(&mut *store, entrypoint).ok_or_else(|| {
         anyhow::anyhow!("No such function '{}' in {}", entrypoint, wasm_module_name)
     })?;
     tracing::trace!("Calling Wasm entry point");
-    start.call(&mut store, &[], &mut vec![])?;
+    start.call(&mut *store, &[], &mut vec![])
+        .map_err(|e| store.data().explain(e))?;
     tracing::trace!("Module execution complete");
     Ok(())
 }
 
 pub fn run_prepared_wasm_instance_if_present(
     instance: Instance,
-    mut store: Store<WasiCtx>,
+    mut store: Store<ModuleState>,
     entrypoint: &str,
 ) -> RunWasmResult<(), Error> {
     match instance.get_func(&mut store, entrypoint) {
         Some(func) => match func.call(&mut store, &[], &mut vec![]) {
             Ok(_) => RunWasmResult::Ok(()),
-            Err(e) => RunWasmResult::WasmError(e),
+            Err(e) => RunWasmResult::WasmError(store.data().explain(e)),
         },
         None => RunWasmResult::EntrypointNotFound,
     }
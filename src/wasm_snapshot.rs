@@ -0,0 +1,736 @@
+//! Pre-initialises modules, in the manner of Wizer. A module that exports a
+//! `wizer.initialize` function has it run once, when the module is loaded, and
+//! its memory and globals are then written back into the module as its
+//! initial state, so that each request starts from the initialised state
+//! instead of repeating the work.
+//!
+//! The initializer runs under the same limits as the module's requests, so
+//! that a module that loops or allocates without end fails to load rather
+//! than holding up the server.
+//!
+//! Wasmtime cannot say which function a table element refers to, so the state
+//! of a module's tables cannot be written back. Modules with code that changes
+//! tables are therefore refused, as Wizer refuses them.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use wasi_cap_std_sync::WasiCtxBuilder;
+use wasm_encoder::{ConstExpr, DataCountSection, DataSection, Encode, ExportKind, ExportSection, GlobalSection, Instruction, MemorySection, RawSection};
+use wasmparser::{DataKind, ExternalKind, ImportSectionEntryType, Operator, Type};
+use wasmtime::{Config, Engine, Linker, Module, TrapCode, Val};
+
+use crate::epoch::EpochTicker;
+use crate::error_response::ModuleResourceLimitExceeded;
+use crate::resource_limits::instances_needed;
+use crate::wasm_runner::{add_wasi_to_linker, new_store, ExecutionLimits};
+
+/// The export that marks a module as wanting to be pre-initialised.
+pub const INITIALIZER_EXPORT: &str = "wizer.initialize";
+
+// Exports added so that the module's state can be read once initialised
+const GLOBAL_EXPORT_PREFIX: &str = "__wagi_snapshot_global_";
+const MEMORY_EXPORT_PREFIX: &str = "__wagi_snapshot_memory_";
+// Functions added to find out which passive data segments were dropped
+const DATA_PROBE_EXPORT_PREFIX: &str = "__wagi_snapshot_data_";
+
+// Runs of zeroes shorter than this are kept in data segments rather than
+// splitting them, as each segment has a few bytes of overhead
+const MIN_ZERO_GAP: usize = 32;
+
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const MEMORY_SECTION: u8 = 5;
+const GLOBAL_SECTION: u8 = 6;
+const EXPORT_SECTION: u8 = 7;
+const START_SECTION: u8 = 8;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+const DATA_COUNT_SECTION: u8 = 12;
+// Module linking sections, from which a snapshot cannot be taken
+const FIRST_MODULE_LINKING_SECTION: u8 = 14;
+
+// How long an initializer may run if the module has no timeout of its own.
+// Unlike a request, nothing else can happen until it finishes.
+const DEFAULT_INITIALIZER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// If the module exports an initializer, returns the module as it is once
+/// initialised, taking it from `cache_dir` if it has been made before.
+/// Otherwise returns `None`, and the module should be used as it is.
+/// The initializer runs under `limits`, and if it has no timeout, under
+/// `DEFAULT_INITIALIZER_TIMEOUT`.
+pub fn preinitialize(module_bytes: &[u8], cache_dir: &Path, limits: ExecutionLimits) -> anyhow::Result<Option<Vec<u8>>> {
+    // Precompiled modules have already been through this
+    if crate::wasm_module::is_precompiled(module_bytes) {
+        return Ok(None);
+    }
+    let wasm = wat::parse_bytes(module_bytes)?;
+    if !exports_initializer(&wasm)? {
+        return Ok(None);
+    }
+
+    let cache_path = snapshot_path(cache_dir, module_bytes);
+    if let Ok(snapshot) = std::fs::read(&cache_path) {
+        tracing::debug!(path = %cache_path.display(), "Using cached pre-initialised module");
+        return Ok(Some(snapshot));
+    }
+
+    let snapshot = snapshot(&wasm, limits)?;
+    // Write then rename, so that another server sharing the cache never sees
+    // a partly written module
+    let write_cache = || -> anyhow::Result<()> {
+        std::fs::create_dir_all(cache_dir)?;
+        let mut temp = tempfile::NamedTempFile::new_in(cache_dir)?;
+        std::io::Write::write_all(&mut temp, &snapshot)?;
+        temp.persist(&cache_path)?;
+        Ok(())
+    };
+    if let Err(e) = write_cache() {
+        tracing::warn!(path = %cache_path.display(), error = %e, "Failed to cache pre-initialised module");
+    }
+    Ok(Some(snapshot))
+}
+
+// Pre-initialised modules are named for the module they were made from, so
+// that a changed module is initialised afresh.
+fn snapshot_path(cache_dir: &Path, module_bytes: &[u8]) -> PathBuf {
+    cache_dir.join(format!("{:x}.initialized.wasm", Sha256::digest(module_bytes)))
+}
+
+fn exports_initializer(wasm: &[u8]) -> anyhow::Result<bool> {
+    for section in sections(wasm)? {
+        if section.id == EXPORT_SECTION {
+            for export in wasmparser::ExportSectionReader::new(section.data, section.offset)? {
+                if export?.field == INITIALIZER_EXPORT {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Runs the module's initializer, and returns a module whose initial state
+/// is the state the initializer left it in.
+fn snapshot(wasm: &[u8], limits: ExecutionLimits) -> anyhow::Result<Vec<u8>> {
+    let layout = ModuleLayout::read(wasm)?;
+    let state = initialize(wasm, &layout, limits)?;
+    rewrite(wasm, &layout, &state)
+}
+
+/// What a module defines, as far as snapshotting is concerned.
+struct ModuleLayout {
+    memories: Vec<wasmparser::MemoryType>,
+    globals: Vec<wasmparser::GlobalType>,
+    types: u32,
+    functions: u32,
+    // The index and length of each passive data segment
+    passive_data: Vec<(u32, usize)>,
+    // Whether the code can use data segments, which needs a data count section
+    uses_data: bool,
+}
+
+/// The state of a module's memories and globals after initialisation.
+struct InitializedState {
+    memories: Vec<(Vec<u8>, u64)>,
+    globals: Vec<Val>,
+    // The passive data segments the module dropped
+    dropped_data: HashSet<u32>,
+}
+
+impl ModuleLayout {
+    fn read(wasm: &[u8]) -> anyhow::Result<Self> {
+        let mut layout = Self { memories: vec![], globals: vec![], types: 0, functions: 0, passive_data: vec![], uses_data: false };
+        let mut has_code = false;
+        let mut has_data_count = false;
+        for section in sections(wasm)? {
+            match section.id {
+                TYPE_SECTION => {
+                    layout.types = wasmparser::TypeSectionReader::new(section.data, section.offset)?.get_count();
+                }
+                IMPORT_SECTION => {
+                    for import in wasmparser::ImportSectionReader::new(section.data, section.offset)? {
+                        match import?.ty {
+                            ImportSectionEntryType::Memory(_) => anyhow::bail!("Modules that import memories cannot be pre-initialised"),
+                            ImportSectionEntryType::Global(_) => anyhow::bail!("Modules that import globals cannot be pre-initialised"),
+                            ImportSectionEntryType::Function(_) => layout.functions += 1,
+                            _ => (),
+                        }
+                    }
+                }
+                FUNCTION_SECTION => {
+                    layout.functions += wasmparser::FunctionSectionReader::new(section.data, section.offset)?.get_count();
+                }
+                MEMORY_SECTION => {
+                    for memory in wasmparser::MemorySectionReader::new(section.data, section.offset)? {
+                        let memory = memory?;
+                        if memory.memory64 || memory.shared {
+                            anyhow::bail!("Modules with 64-bit or shared memories cannot be pre-initialised");
+                        }
+                        layout.memories.push(memory);
+                    }
+                }
+                GLOBAL_SECTION => {
+                    for global in wasmparser::GlobalSectionReader::new(section.data, section.offset)? {
+                        let global = global?;
+                        if !matches!(global.ty.content_type, Type::I32 | Type::I64 | Type::F32 | Type::F64 | Type::V128) {
+                            anyhow::bail!("Modules with reference-typed globals cannot be pre-initialised");
+                        }
+                        layout.globals.push(global.ty);
+                    }
+                }
+                CODE_SECTION => {
+                    has_code = true;
+                    check_tables_unchanged(&section)?;
+                }
+                DATA_COUNT_SECTION => {
+                    has_data_count = true;
+                }
+                DATA_SECTION => {
+                    for (index, segment) in wasmparser::DataSectionReader::new(section.data, section.offset)?.into_iter().enumerate() {
+                        let segment = segment?;
+                        if let DataKind::Passive = segment.kind {
+                            layout.passive_data.push((index as u32, segment.data.len()));
+                        }
+                    }
+                }
+                id if id >= FIRST_MODULE_LINKING_SECTION => anyhow::bail!("Modules that use module linking cannot be pre-initialised"),
+                _ => (),
+            }
+        }
+        layout.uses_data = has_code && has_data_count;
+        Ok(layout)
+    }
+
+    // The passive data segments the initializer could have dropped in a way
+    // that shows. An empty segment is the same dropped or not, and without a
+    // memory, a segment cannot be read.
+    fn droppable_data(&self) -> Vec<u32> {
+        if !self.uses_data || self.memories.is_empty() {
+            return vec![];
+        }
+        self.passive_data.iter().filter(|(_, len)| *len > 0).map(|(index, _)| *index).collect()
+    }
+}
+
+// Element segments are applied afresh each time the module is instantiated,
+// so any change the initializer made to a table would be lost
+fn check_tables_unchanged(section: &Section) -> anyhow::Result<()> {
+    for body in wasmparser::CodeSectionReader::new(section.data, section.offset)? {
+        let mut operators = body?.get_operators_reader()?;
+        while !operators.eof() {
+            match operators.read()? {
+                Operator::TableSet { .. }
+                | Operator::TableGrow { .. }
+                | Operator::TableFill { .. }
+                | Operator::TableCopy { .. }
+                | Operator::TableInit { .. }
+                | Operator::ElemDrop { .. } => {
+                    anyhow::bail!("Modules that change their tables cannot be pre-initialised")
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Runs the initializer in a copy of the module that exports all its memories
+// and globals, so that their state can be read afterwards. The initializer
+// gets WASI, but no arguments, environment variables, files or stdio.
+fn initialize(wasm: &[u8], layout: &ModuleLayout, limits: ExecutionLimits) -> anyhow::Result<InitializedState> {
+    let limits = ExecutionLimits {
+        timeout: limits.timeout.or(Some(DEFAULT_INITIALIZER_TIMEOUT)),
+        ..limits
+    };
+    let mut config = Config::new();
+    config.wasm_multi_memory(true);
+    config.epoch_interruption(true);
+    config.consume_fuel(limits.max_fuel.is_some());
+    let engine = Engine::new(&config)?;
+    let instrumented = instrument(wasm, layout)?;
+    let module = Module::new(&engine, &instrumented)?;
+
+    let mut linker = Linker::new(&engine);
+    add_wasi_to_linker(&mut linker)?;
+    let mut store = new_store(WasiCtxBuilder::new().build(), &engine, limits.resources, Some(instances_needed(&instrumented)?))?;
+    limits.apply_to(&mut store)?;
+
+    // Only needs to tick for as long as the initializer runs
+    let _ticker = EpochTicker::start([engine.clone()]);
+    let started = Instant::now();
+    let result = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| {
+            instance
+                .get_typed_func::<(), (), _>(&mut store, INITIALIZER_EXPORT)?
+                .call(&mut store, ())?;
+            Ok(instance)
+        });
+    let instance = result
+        .map_err(|e| {
+            let e = store.data().explain(e);
+            if e.is::<ModuleResourceLimitExceeded>() {
+                return e;
+            }
+            limits.interruption(&e, started, store.fuel_consumed()).unwrap_or(e)
+        })
+        .context("The module's initializer failed")?;
+
+    let memories = (0..layout.memories.len())
+        .map(|index| {
+            let memory = instance
+                .get_memory(&mut store, &format!("{}{}", MEMORY_EXPORT_PREFIX, index))
+                .context("Instrumented memory export missing")?;
+            Ok((memory.data(&store).to_vec(), memory.size(&store)))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let globals = (0..layout.globals.len())
+        .map(|index| {
+            let global = instance
+                .get_global(&mut store, &format!("{}{}", GLOBAL_EXPORT_PREFIX, index))
+                .context("Instrumented global export missing")?;
+            Ok(global.get(&mut store))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let mut dropped_data = HashSet::new();
+    for segment in layout.droppable_data() {
+        let probe = instance
+            .get_typed_func::<(), (), _>(&mut store, &format!("{}{}", DATA_PROBE_EXPORT_PREFIX, segment))
+            .context("Instrumented data probe missing")?;
+        match probe.call(&mut store, ()) {
+            Ok(()) => (),
+            Err(trap) if trap.trap_code() == Some(TrapCode::MemoryOutOfBounds) => {
+                dropped_data.insert(segment);
+            }
+            Err(trap) => return Err(trap).context("Failed to read the state of the module's data segments"),
+        }
+    }
+    Ok(InitializedState { memories, globals, dropped_data })
+}
+
+// The module with every memory and global exported, and a probe function for
+// each passive data segment the initializer could drop. A probe copies
+// nothing from the end of its segment, which traps only if the segment has
+// been dropped, as that leaves it empty.
+fn instrument(wasm: &[u8], layout: &ModuleLayout) -> anyhow::Result<Vec<u8>> {
+    let probes = layout.droppable_data();
+    let probe_lengths: Vec<_> = layout.passive_data.iter().filter(|(index, _)| probes.contains(index)).collect();
+    let mut module = wasm_encoder::Module::new();
+    for section in sections(wasm)? {
+        match section.id {
+            EXPORT_SECTION => {
+                let mut exports = copy_exports(&section, false)?;
+                for index in 0..layout.memories.len() as u32 {
+                    exports.export(&format!("{}{}", MEMORY_EXPORT_PREFIX, index), ExportKind::Memory, index);
+                }
+                for index in 0..layout.globals.len() as u32 {
+                    exports.export(&format!("{}{}", GLOBAL_EXPORT_PREFIX, index), ExportKind::Global, index);
+                }
+                for (offset, segment) in probes.iter().enumerate() {
+                    exports.export(&format!("{}{}", DATA_PROBE_EXPORT_PREFIX, segment), ExportKind::Func, layout.functions + offset as u32);
+                }
+                module.section(&exports);
+            }
+            TYPE_SECTION if !probes.is_empty() => {
+                // The probes' type, () -> ()
+                let data = extend_section(&section, 1, &[0x60, 0x00, 0x00])?;
+                module.section(&RawSection { id: section.id, data: &data });
+            }
+            FUNCTION_SECTION if !probes.is_empty() => {
+                let mut entries = vec![];
+                for _ in &probes {
+                    layout.types.encode(&mut entries);
+                }
+                let data = extend_section(&section, probes.len() as u32, &entries)?;
+                module.section(&RawSection { id: section.id, data: &data });
+            }
+            CODE_SECTION if !probes.is_empty() => {
+                let mut entries = vec![];
+                for (segment, len) in &probe_lengths {
+                    let mut probe = wasm_encoder::Function::new(vec![]);
+                    probe.instruction(&Instruction::I32Const(0));
+                    probe.instruction(&Instruction::I32Const(*len as i32));
+                    probe.instruction(&Instruction::I32Const(0));
+                    probe.instruction(&Instruction::MemoryInit { mem: 0, data_index: *segment });
+                    probe.instruction(&Instruction::End);
+                    probe.encode(&mut entries);
+                }
+                let data = extend_section(&section, probes.len() as u32, &entries)?;
+                module.section(&RawSection { id: section.id, data: &data });
+            }
+            _ => {
+                module.section(&section.raw());
+            }
+        }
+    }
+    Ok(module.finish())
+}
+
+// The section's data with `count` more entries, already encoded, at the end
+fn extend_section(section: &Section, count: u32, entries: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut reader = wasmparser::BinaryReader::new_with_offset(section.data, section.offset);
+    let existing = reader.read_var_u32()?;
+    let mut data = vec![];
+    (existing + count).encode(&mut data);
+    data.extend_from_slice(&section.data[reader.current_position()..]);
+    data.extend_from_slice(entries);
+    Ok(data)
+}
+
+// The original module, with the initialised state in place of its initial
+// state. The start function has already run, and the initializer must not run
+// again, so both are removed.
+fn rewrite(wasm: &[u8], layout: &ModuleLayout, state: &InitializedState) -> anyhow::Result<Vec<u8>> {
+    let mut module = wasm_encoder::Module::new();
+    let data = snapshot_data(wasm, state)?;
+    let has_data_section = sections(wasm)?.iter().any(|s| s.id == DATA_SECTION);
+    for section in sections(wasm)? {
+        match section.id {
+            MEMORY_SECTION => {
+                let mut memories = MemorySection::new();
+                for (memory, (_, pages)) in layout.memories.iter().zip(&state.memories) {
+                    memories.memory(wasm_encoder::MemoryType {
+                        minimum: *pages,
+                        maximum: memory.maximum,
+                        memory64: false,
+                        shared: false,
+                        page_size_log2: None,
+                    });
+                }
+                module.section(&memories);
+            }
+            GLOBAL_SECTION => {
+                let mut globals = GlobalSection::new();
+                for (global, value) in layout.globals.iter().zip(&state.globals) {
+                    let global_type = wasm_encoder::GlobalType {
+                        val_type: value_type(global.content_type)?,
+                        mutable: global.mutable,
+                        shared: false,
+                    };
+                    globals.global(global_type, &const_expr(value)?);
+                }
+                module.section(&globals);
+            }
+            EXPORT_SECTION => {
+                module.section(&copy_exports(&section, true)?);
+            }
+            START_SECTION => (),
+            DATA_COUNT_SECTION => {
+                module.section(&DataCountSection { count: data.len() });
+            }
+            DATA_SECTION => {
+                module.section(&data);
+            }
+            CODE_SECTION if !has_data_section => {
+                // The data section, if there is one, follows the code section
+                module.section(&section.raw());
+                module.section(&data);
+            }
+            _ => {
+                module.section(&section.raw());
+            }
+        }
+    }
+    Ok(module.finish())
+}
+
+// The module's own data segments, then the segments for the initialised
+// memories. The module's segments keep their indices, so that code that uses
+// them by index still finds them. Active segments are emptied, as their data
+// is in the initialised memories; like an empty segment, they cannot be
+// copied from once the module is instantiated. Passive segments are left for
+// the module to use as before, unless the initializer dropped them.
+fn snapshot_data(wasm: &[u8], state: &InitializedState) -> anyhow::Result<DataSection> {
+    let mut data = DataSection::new();
+    for section in sections(wasm)? {
+        if section.id == DATA_SECTION {
+            for (index, segment) in wasmparser::DataSectionReader::new(section.data, section.offset)?.into_iter().enumerate() {
+                let segment = segment?;
+                match segment.kind {
+                    DataKind::Active { memory_index, .. } => {
+                        data.active(memory_index, &ConstExpr::i32_const(0), []);
+                    }
+                    DataKind::Passive if state.dropped_data.contains(&(index as u32)) => {
+                        data.passive([]);
+                    }
+                    DataKind::Passive => {
+                        data.passive(segment.data.iter().copied());
+                    }
+                }
+            }
+        }
+    }
+    for (index, (bytes, _)) in state.memories.iter().enumerate() {
+        for (offset, run) in non_zero_runs(bytes) {
+            data.active(index as u32, &ConstExpr::i32_const(offset as i32), run.iter().copied());
+        }
+    }
+    Ok(data)
+}
+
+// Memory starts zeroed, so only the parts that are not need data segments
+fn non_zero_runs(bytes: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut runs = vec![];
+    let mut start: Option<usize> = None;
+    let mut zeroes = 0;
+    for (index, byte) in bytes.iter().enumerate() {
+        if *byte == 0 {
+            zeroes += 1;
+            if let Some(run_start) = start {
+                if zeroes >= MIN_ZERO_GAP {
+                    runs.push((run_start, &bytes[run_start..index + 1 - zeroes]));
+                    start = None;
+                }
+            }
+        } else {
+            zeroes = 0;
+            start.get_or_insert(index);
+        }
+    }
+    if let Some(run_start) = start {
+        runs.push((run_start, &bytes[run_start..bytes.len() - zeroes]));
+    }
+    runs
+}
+
+fn copy_exports(section: &Section, remove_initializer: bool) -> anyhow::Result<ExportSection> {
+    let mut exports = ExportSection::new();
+    for export in wasmparser::ExportSectionReader::new(section.data, section.offset)? {
+        let export = export?;
+        if remove_initializer && export.field == INITIALIZER_EXPORT {
+            continue;
+        }
+        let kind = match export.kind {
+            ExternalKind::Function => ExportKind::Func,
+            ExternalKind::Table => ExportKind::Table,
+            ExternalKind::Memory => ExportKind::Memory,
+            ExternalKind::Global => ExportKind::Global,
+            ExternalKind::Tag => ExportKind::Tag,
+            other => anyhow::bail!("Modules that export a {:?} cannot be pre-initialised", other),
+        };
+        exports.export(export.field, kind, export.index);
+    }
+    Ok(exports)
+}
+
+fn value_type(ty: Type) -> anyhow::Result<wasm_encoder::ValType> {
+    Ok(match ty {
+        Type::I32 => wasm_encoder::ValType::I32,
+        Type::I64 => wasm_encoder::ValType::I64,
+        Type::F32 => wasm_encoder::ValType::F32,
+        Type::F64 => wasm_encoder::ValType::F64,
+        Type::V128 => wasm_encoder::ValType::V128,
+        other => anyhow::bail!("Globals of type {:?} cannot be pre-initialised", other),
+    })
+}
+
+fn const_expr(value: &Val) -> anyhow::Result<ConstExpr> {
+    Ok(match value {
+        Val::I32(v) => ConstExpr::i32_const(*v),
+        Val::I64(v) => ConstExpr::i64_const(*v),
+        Val::F32(bits) => ConstExpr::f32_const(f32::from_bits(*bits)),
+        Val::F64(bits) => ConstExpr::f64_const(f64::from_bits(*bits)),
+        Val::V128(v) => ConstExpr::v128_const(*v as i128),
+        other => anyhow::bail!("Globals of type {:?} cannot be pre-initialised", other.ty()),
+    })
+}
+
+/// A section of a Wasm module, undecoded.
+struct Section<'a> {
+    id: u8,
+    data: &'a [u8],
+    // Where the data starts in the module, for error messages
+    offset: usize,
+}
+
+impl<'a> Section<'a> {
+    fn raw(&self) -> RawSection<'a> {
+        RawSection { id: self.id, data: self.data }
+    }
+}
+
+fn sections(wasm: &[u8]) -> anyhow::Result<Vec<Section<'_>>> {
+    const HEADER_LEN: usize = 8;
+    if wasm.len() < HEADER_LEN || &wasm[..4] != b"\0asm" {
+        anyhow::bail!("Not a Wasm module");
+    }
+    let mut sections = vec![];
+    let mut reader = wasmparser::BinaryReader::new_with_offset(&wasm[HEADER_LEN..], HEADER_LEN);
+    while !reader.eof() {
+        let id = reader.read_u8()? as u8;
+        let len = reader.read_var_u32()? as usize;
+        let offset = reader.original_position();
+        let data = reader.read_bytes(len)?;
+        sections.push(Section { id, data, offset });
+    }
+    Ok(sections)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The initializer sets a global and writes to memory; _start reports both
+    const INITIALIZED_WAT: &str = r#"(module
+        (memory (export "memory") 1)
+        (global $greeting_len (mut i32) (i32.const 0))
+        (global $calls (mut i64) (i64.const 0))
+        (data (i32.const 0) "Hello")
+        (start $on_start)
+        (func $on_start (global.set $calls (i64.add (global.get $calls) (i64.const 1))))
+        (func (export "wizer.initialize")
+            (drop (memory.grow (i32.const 1)))
+            (i32.store8 (i32.const 70000) (i32.const 33))
+            (global.set $greeting_len (i32.const 5))
+            (global.set $calls (i64.add (global.get $calls) (i64.const 1))))
+        (func (export "greeting_len") (result i32) (global.get $greeting_len))
+        (func (export "calls") (result i64) (global.get $calls)))"#;
+
+    fn run_i32(wasm: &[u8], export: &str) -> (i32, Vec<u8>) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let result = instance.get_typed_func::<(), i32, _>(&mut store, export).unwrap().call(&mut store, ()).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap().data(&store).to_vec();
+        (result, memory)
+    }
+
+    #[test]
+    fn initialized_state_becomes_the_initial_state() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let initialized = preinitialize(INITIALIZED_WAT.as_bytes(), cache_dir.path(), ExecutionLimits::default()).unwrap().expect("Expected module to be initialised");
+
+        let (greeting_len, memory) = run_i32(&initialized, "greeting_len");
+        assert_eq!(5, greeting_len);
+        assert_eq!(b"Hello", &memory[..5]);
+        // Including memory the initializer grew
+        assert_eq!(2 * 65536, memory.len());
+        assert_eq!(33, memory[70000]);
+
+        // The start function ran once, before the initializer, and does not run again
+        let engine = Engine::default();
+        let module = Module::new(&engine, &initialized).unwrap();
+        assert!(module.get_export(INITIALIZER_EXPORT).is_none());
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let calls = instance.get_typed_func::<(), i64, _>(&mut store, "calls").unwrap().call(&mut store, ()).unwrap();
+        assert_eq!(2, calls);
+
+        // The result is cached
+        assert!(snapshot_path(cache_dir.path(), INITIALIZED_WAT.as_bytes()).exists());
+        let cached = preinitialize(INITIALIZED_WAT.as_bytes(), cache_dir.path(), ExecutionLimits::default()).unwrap().unwrap();
+        assert_eq!(initialized, cached);
+    }
+
+    #[test]
+    fn modules_without_an_initializer_are_left_alone() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module (func (export "_start")))"#;
+        assert!(preinitialize(wat.as_bytes(), cache_dir.path(), ExecutionLimits::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn initializer_failures_are_reported() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module (func (export "wizer.initialize") unreachable))"#;
+        let error = preinitialize(wat.as_bytes(), cache_dir.path(), ExecutionLimits::default()).unwrap_err();
+        assert!(error.to_string().contains("initializer failed"), "Unexpected error: {:#}", error);
+    }
+
+    #[test]
+    fn initializers_that_do_not_finish_are_stopped() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module (func (export "wizer.initialize") (loop (br 0))))"#;
+
+        let limits = ExecutionLimits { timeout: Some(Duration::from_millis(50)), ..Default::default() };
+        let error = preinitialize(wat.as_bytes(), cache_dir.path(), limits).unwrap_err();
+        assert!(error.is::<crate::error_response::ModuleTimedOut>(), "Unexpected error: {:#}", error);
+
+        let limits = ExecutionLimits { max_fuel: Some(10_000), ..Default::default() };
+        let error = preinitialize(wat.as_bytes(), cache_dir.path(), limits).unwrap_err();
+        assert!(error.is::<crate::error_response::ModuleOutOfFuel>(), "Unexpected error: {:#}", error);
+
+        assert!(!snapshot_path(cache_dir.path(), wat.as_bytes()).exists());
+    }
+
+    #[test]
+    fn initializers_are_held_to_memory_limits() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module
+            (memory 1)
+            (func (export "wizer.initialize")
+                (if (i32.eq (memory.grow (i32.const 32)) (i32.const -1)) (then unreachable))))"#;
+
+        let limits = ExecutionLimits {
+            resources: crate::resource_limits::ResourceLimits { max_memory_mb: Some(1), ..Default::default() },
+            ..Default::default()
+        };
+        let error = preinitialize(wat.as_bytes(), cache_dir.path(), limits).unwrap_err();
+        assert!(error.is::<ModuleResourceLimitExceeded>(), "Unexpected error: {:#}", error);
+    }
+
+    #[test]
+    fn data_segments_keep_their_indices() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "abc")
+            (data $greeting "Hello")
+            (data $farewell "Bye")
+            (func (export "wizer.initialize")
+                (i32.store8 (i32.const 100) (i32.const 1))
+                (data.drop $farewell))
+            (func (export "copy_greeting") (result i32)
+                (memory.init $greeting (i32.const 200) (i32.const 0) (i32.const 5))
+                (i32.const 0))
+            (func (export "copy_farewell") (result i32)
+                (memory.init $farewell (i32.const 300) (i32.const 0) (i32.const 3))
+                (i32.const 0)))"#;
+        let initialized = preinitialize(wat.as_bytes(), cache_dir.path(), ExecutionLimits::default()).unwrap().expect("Expected module to be initialised");
+
+        let (_, memory) = run_i32(&initialized, "copy_greeting");
+        assert_eq!(b"abc", &memory[..3]);
+        assert_eq!(1, memory[100]);
+        assert_eq!(b"Hello", &memory[200..205]);
+
+        // The initializer dropped this segment, so it stays dropped
+        let engine = Engine::default();
+        let module = Module::new(&engine, &initialized).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let copy_farewell = instance.get_typed_func::<(), i32, _>(&mut store, "copy_farewell").unwrap();
+        assert!(copy_farewell.call(&mut store, ()).is_err());
+    }
+
+    #[test]
+    fn modules_that_change_their_tables_are_refused() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module
+            (table 1 funcref)
+            (elem declare func $handler)
+            (func $handler)
+            (func (export "wizer.initialize")
+                (table.set (i32.const 0) (ref.func $handler))))"#;
+        let error = preinitialize(wat.as_bytes(), cache_dir.path(), ExecutionLimits::default()).unwrap_err();
+        assert!(error.to_string().contains("change their tables"), "Unexpected error: {:#}", error);
+    }
+
+    #[test]
+    fn memory_is_split_into_runs_of_non_zero_bytes() {
+        let mut memory = vec![0u8; 200];
+        memory[10] = 1;
+        memory[12] = 2;
+        memory[100] = 3;
+        memory[101] = 4;
+        let runs = non_zero_runs(&memory);
+        assert_eq!(vec![(10, &[1u8, 0, 2][..]), (100, &[3u8, 4][..])], runs);
+        assert!(non_zero_runs(&[0u8; 64]).is_empty());
+    }
+}
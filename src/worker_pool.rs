@@ -0,0 +1,123 @@
+//! Runs Wasm handlers on the blocking thread pool, so that a slow or CPU-heavy
+//! module does not hold up other connections served by the same async worker.
+
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+pub const DEFAULT_QUEUE_DEPTH: usize = 128;
+
+/// Limits how many handlers run at once, and how many requests may wait for
+/// a turn. Requests beyond that are turned away rather than queued without
+/// limit.
+#[derive(Debug)]
+pub struct WorkerPool {
+    size: usize,
+    // Permits to run a handler
+    workers: Arc<Semaphore>,
+    // Permits to run or wait to run: size + queue_depth
+    admissions: Arc<Semaphore>,
+}
+
+#[derive(Debug)]
+pub enum WorkerPoolError {
+    /// Every worker is busy and the queue is full.
+    Full,
+    /// The handler panicked.
+    Panicked(tokio::task::JoinError),
+}
+
+impl WorkerPool {
+    pub fn new(size: usize, queue_depth: usize) -> anyhow::Result<Self> {
+        if size == 0 {
+            anyhow::bail!("The worker pool must have at least one worker");
+        }
+        Ok(Self {
+            size,
+            workers: Arc::new(Semaphore::new(size)),
+            admissions: Arc::new(Semaphore::new(size + queue_depth)),
+        })
+    }
+
+    /// The number of handlers that may run at once.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// One worker per CPU.
+    pub fn default_size() -> usize {
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+
+    /// Runs `f` on a blocking thread once a worker is free, or fails at once
+    /// if the queue is full.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, WorkerPoolError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let admission = self.admissions.clone().try_acquire_owned().map_err(|_| WorkerPoolError::Full)?;
+        // The semaphore is never closed
+        let worker = self.workers.clone().acquire_owned().await.expect("Worker pool semaphore closed");
+        // The permits go with the closure, so that if the request is dropped
+        // (e.g. the client disconnects) the worker stays counted as busy until
+        // the handler actually finishes.
+        tokio::task::spawn_blocking(move || {
+            let _permits = (admission, worker);
+            f()
+        })
+        .await
+        .map_err(WorkerPoolError::Panicked)
+    }
+}
+
+impl std::fmt::Display for WorkerPoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Full => write!(f, "All workers are busy and the queue is full"),
+            Self::Panicked(e) => write!(f, "Handler panicked: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WorkerPoolError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn runs_work_and_returns_result() {
+        let pool = WorkerPool::new(2, 0).unwrap();
+        assert_eq!(42, pool.run(|| 6 * 7).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_work_when_queue_is_full() {
+        let pool = Arc::new(WorkerPool::new(1, 1).unwrap());
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let busy = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.run(move || released.recv().unwrap()).await })
+        };
+        let queued = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.run(|| ()).await })
+        };
+        // Give both a chance to be admitted
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(matches!(pool.run(|| ()).await, Err(WorkerPoolError::Full)));
+
+        release.send(()).unwrap();
+        busy.await.unwrap().unwrap();
+        queued.await.unwrap().unwrap();
+        assert!(pool.run(|| ()).await.is_ok());
+    }
+
+    #[test]
+    fn needs_at_least_one_worker() {
+        assert!(WorkerPool::new(0, 10).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\nSet-Cookie: a=1\nSet-Cookie: b=2\nX-Folded: one\n  two\n\nbody\n")
+    (data (i32.const 256) "Content-Type: text/plain\nX-Bad: \01bad\n\nbody\n")
+
+    (func $write (param $offset i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $offset))
+        (i32.store (i32.const 4) (local.get $len))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20)))
+    )
+
+    (func (export "_start")
+        (call $write (i32.const 64) (i32.const 83))
+    )
+
+    (func (export "invalid")
+        (call $write (i32.const 256) (i32.const 43))
+    )
+)
@@ -0,0 +1,41 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\nRead\n")
+
+    ;; Reads up to 4096 bytes of stdin into the buffer at 4096, and returns how
+    ;; many were read
+    (func $read (result i32)
+        (i32.store (i32.const 8) (i32.const 4096))
+        (i32.store (i32.const 12) (i32.const 4096))
+
+        (call $fd_read
+            (i32.const 0)
+            (i32.const 8)
+            (i32.const 1)
+            (i32.const 24)
+        )
+        drop
+        (i32.load (i32.const 24))
+    )
+
+    ;; Reads the whole request body before writing anything
+    (func (export "_start")
+        (loop $consume
+            (br_if $consume (i32.ne (call $read) (i32.const 0)))
+        )
+
+        (i32.store (i32.const 0) (i32.const 64))
+        (i32.store (i32.const 4) (i32.const 31))
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+)
@@ -0,0 +1,51 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: application/octet-stream\n\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    ;; Reads up to 4096 bytes of stdin into the buffer at 4096, and returns how
+    ;; many were read
+    (func $read (result i32)
+        (i32.store (i32.const 8) (i32.const 4096))
+        (i32.store (i32.const 12) (i32.const 4096))
+
+        (call $fd_read
+            (i32.const 0)
+            (i32.const 8)
+            (i32.const 1)
+            (i32.const 24)
+        )
+        drop
+        (i32.load (i32.const 24))
+    )
+
+    ;; Writes the request body back as the response body
+    (func (export "_start")
+        (local $len i32)
+        (call $print (i32.const 64) (i32.const 40))
+        (block $done
+            (loop $copy
+                (local.set $len (call $read))
+                (br_if $done (i32.eqz (local.get $len)))
+                (call $print (i32.const 4096) (local.get $len))
+                (br $copy)
+            )
+        )
+    )
+)
@@ -0,0 +1,34 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Some text, but no headers\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    (func $fail
+        unreachable
+    )
+
+    ;; Traps
+    (func (export "_start")
+        (call $fail)
+    )
+
+    ;; Runs to completion, but does not print a valid CGI response
+    (func (export "no_headers")
+        (call $print (i32.const 64) (i32.const 26))
+    )
+)
@@ -0,0 +1,30 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    ;; Writes the headers, then writes the same 32 KB over and over, for as
+    ;; long as it is allowed to
+    (func (export "_start")
+        (call $print (i32.const 64) (i32.const 26))
+        (loop $flood
+            (call $print (i32.const 1024) (i32.const 32768))
+            (br $flood)
+        )
+    )
+)
@@ -0,0 +1,11 @@
+(module
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; Never finishes
+    (func (export "_start")
+        (loop $forever
+            (br $forever)
+        )
+    )
+)
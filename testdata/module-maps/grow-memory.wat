@@ -0,0 +1,24 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\nGrew\n")
+
+    ;; Grows memory to 4 MB, and traps if it can't
+    (func (export "_start")
+        (if (i32.eq (memory.grow (i32.const 63)) (i32.const -1))
+            (then unreachable)
+        )
+
+        (i32.store (i32.const 0) (i32.const 64))
+        (i32.store (i32.const 4) (i32.const 31))
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+)
@@ -0,0 +1,39 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "/submit submit POST,PUT\n/inherit inherit\n")
+    (data (i32.const 113) "content-type: text/plain\n\nmain\n")
+    (data (i32.const 152) "content-type: text/plain\n\nsubmit\n")
+    (data (i32.const 193) "content-type: text/plain\n\ninherit\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    (func (export "_routes")
+        (call $print (i32.const 64) (i32.const 41))
+    )
+
+    (func (export "_start")
+        (call $print (i32.const 113) (i32.const 31))
+    )
+
+    (func (export "submit")
+        (call $print (i32.const 152) (i32.const 33))
+    )
+
+    (func (export "inherit")
+        (call $print (i32.const 193) (i32.const 34))
+    )
+)
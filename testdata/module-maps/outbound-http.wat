@@ -0,0 +1,33 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_experimental_http" "req" (func $req (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\nDone\n")
+    (data (i32.const 128) "https://example.com/")
+    (data (i32.const 160) "GET")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    ;; Makes two outbound requests. The module is not allowed any hosts, so
+    ;; they fail without using the network.
+    (func (export "_start")
+        (call $req (i32.const 128) (i32.const 20) (i32.const 160) (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 192) (i32.const 196))
+        drop
+        (call $req (i32.const 128) (i32.const 20) (i32.const 160) (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 192) (i32.const 196))
+        drop
+        (call $print (i32.const 64) (i32.const 31))
+    )
+)
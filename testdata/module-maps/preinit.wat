@@ -0,0 +1,21 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The body is only there once the initializer has run
+    (data (i32.const 64) "Content-Type: text/plain\n\n")
+    (data (i32.const 256) "Initialised\n")
+    (global $output_len (mut i32) (i32.const 26))
+
+    (func (export "wizer.initialize")
+        (memory.copy (i32.const 90) (i32.const 256) (i32.const 12))
+        (global.set $output_len (i32.const 38))
+    )
+
+    (func (export "_start")
+        (i32.store (i32.const 0) (i32.const 64))
+        (i32.store (i32.const 4) (global.get $output_len))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20)))
+    )
+)
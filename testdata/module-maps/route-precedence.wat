@@ -0,0 +1,54 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "/one/... one\n/one/two/three/... three\n/one/two/... two\n/dup dup_first\n/dup dup_second\n")
+    (data (i32.const 158) "content-type: text/plain\n\nmain\n")
+    (data (i32.const 197) "content-type: text/plain\n\none\n")
+    (data (i32.const 235) "content-type: text/plain\n\ntwo\n")
+    (data (i32.const 273) "content-type: text/plain\n\nthree\n")
+    (data (i32.const 313) "content-type: text/plain\n\ndup_first\n")
+    (data (i32.const 357) "content-type: text/plain\n\ndup_second\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    (func (export "_routes")
+        (call $print (i32.const 64) (i32.const 86))
+    )
+
+    (func (export "_start")
+        (call $print (i32.const 158) (i32.const 31))
+    )
+
+    (func (export "one")
+        (call $print (i32.const 197) (i32.const 30))
+    )
+
+    (func (export "two")
+        (call $print (i32.const 235) (i32.const 30))
+    )
+
+    (func (export "three")
+        (call $print (i32.const 273) (i32.const 32))
+    )
+
+    (func (export "dup_first")
+        (call $print (i32.const 313) (i32.const 36))
+    )
+
+    (func (export "dup_second")
+        (call $print (i32.const 357) (i32.const 37))
+    )
+)
@@ -0,0 +1,41 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "clock_time_get" (func $clock_time_get (param i32 i64 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\nDone spinning\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    ;; Nanoseconds on the monotonic clock
+    (func $now (result i64)
+        (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 32))
+        drop
+        (i64.load (i32.const 32))
+    )
+
+    ;; Keeps the CPU busy for a second, without yielding
+    (func (export "_start")
+        (local $deadline i64)
+        (local.set $deadline (i64.add (call $now) (i64.const 1000000000)))
+        (block $done
+            (loop $spin
+                (br_if $done (i64.ge_u (call $now) (local.get $deadline)))
+                (br $spin)
+            )
+        )
+        (call $print (i32.const 64) (i32.const 40))
+    )
+)
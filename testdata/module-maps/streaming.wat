@@ -0,0 +1,44 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "clock_time_get" (func $clock_time_get (param i32 i64 i32) (result i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\nFirst\n")
+    (data (i32.const 128) "Second\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    ;; Nanoseconds on the monotonic clock
+    (func $now (result i64)
+        (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 32))
+        drop
+        (i64.load (i32.const 32))
+    )
+
+    ;; Writes the headers and the first line straight away, then spins for
+    ;; half a second before writing the second line
+    (func (export "_start")
+        (local $deadline i64)
+        (call $print (i32.const 64) (i32.const 32))
+        (local.set $deadline (i64.add (call $now) (i64.const 500000000)))
+        (block $done
+            (loop $spin
+                (br_if $done (i64.ge_u (call $now) (local.get $deadline)))
+                (br $spin)
+            )
+        )
+        (call $print (i32.const 128) (i32.const 7))
+    )
+)
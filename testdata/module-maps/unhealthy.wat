@@ -0,0 +1,32 @@
+(module
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 64) "Content-Type: text/plain\n\nHello\n")
+    (data (i32.const 128) "Database unreachable\n")
+
+    (func $print (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+
+        (call $fd_write
+            (i32.const 1)
+            (i32.const 0)
+            (i32.const 1)
+            (i32.const 20)
+        )
+        drop
+    )
+
+    (func (export "_start")
+        (call $print (i32.const 64) (i32.const 32))
+    )
+
+    ;; Reports that the module is unhealthy
+    (func (export "_health")
+        (call $print (i32.const 128) (i32.const 21))
+        (call $proc_exit (i32.const 1))
+    )
+)